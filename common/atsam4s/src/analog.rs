@@ -0,0 +1,126 @@
+// Copyright 2026 Jacob Alexander
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! Analog (depth) KLL triggers
+//!
+//! Analog triggers are written in the KLL files as `S<switch>(<distance>) : S<virtual switch>;`
+//! and compiled into the ANALOG_TRIGGERS table by build.rs.
+//! Once the switch travels past the given (calibrated) distance the virtual switch is pressed,
+//! the virtual switch is then mapped like any other switch, e.g.
+//!
+//! ```text
+//! S0x2A(400) : S0x80;  # Press deep for shift
+//! S0x80 : U"LShift";
+//! ```
+//!
+//! Several triggers may use the same switch (e.g. dual-stage actions).
+
+use crate::constants::*;
+use kll_core::{trigger::Phro, TriggerEvent};
+
+// ----- Structs -----
+
+/// Analog trigger, generated by build.rs
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub struct AnalogTrigger {
+    /// Switch (KLL trigger index) being measured
+    pub switch: u16,
+    /// Activation distance (calibrated distance, see DEFAULT_ACTIVATION_DIST)
+    pub distance: i16,
+    /// Virtual switch (KLL trigger index) pressed while past the distance
+    pub virtual_switch: u16,
+}
+
+/// Current state of an analog trigger
+#[derive(Clone, Copy)]
+struct TriggerState {
+    active: bool,
+    /// Samples since the last state change
+    cycles: u32,
+}
+
+/// Analog trigger evaluation
+pub struct AnalogTriggers {
+    triggers: &'static [AnalogTrigger],
+    states: [TriggerState; MAX_ANALOG_TRIGGERS],
+}
+
+impl AnalogTriggers {
+    pub fn new(triggers: &'static [AnalogTrigger]) -> Self {
+        assert!(
+            triggers.len() <= MAX_ANALOG_TRIGGERS,
+            "MAX_ANALOG_TRIGGERS too small"
+        );
+        Self {
+            triggers,
+            states: [TriggerState {
+                active: false,
+                cycles: 0,
+            }; MAX_ANALOG_TRIGGERS],
+        }
+    }
+
+    pub fn triggers(&self) -> &'static [AnalogTrigger] {
+        self.triggers
+    }
+
+    /// Updates the triggers of a switch using the latest distance
+    /// Press and Release events are emitted on state changes, Hold while active.
+    /// Triggers release ANALOG_TRIGGER_HYSTERESIS below the activation distance.
+    pub fn update(&mut self, switch: u16, distance: i16, mut emit: impl FnMut(TriggerEvent)) {
+        for (trigger, state) in self.triggers.iter().zip(self.states.iter_mut()) {
+            if trigger.switch != switch {
+                continue;
+            }
+
+            let active = if state.active {
+                distance >= trigger.distance - ANALOG_TRIGGER_HYSTERESIS
+            } else {
+                distance >= trigger.distance
+            };
+
+            let phro = match (state.active, active) {
+                (false, true) => Phro::Press,
+                (true, true) => Phro::Hold,
+                (true, false) => Phro::Release,
+                (false, false) => Phro::Off,
+            };
+            if active != state.active {
+                state.active = active;
+                state.cycles = 0;
+            }
+            state.cycles = state.cycles.saturating_add(1);
+
+            if phro != Phro::Off {
+                emit(TriggerEvent::Switch {
+                    state: phro,
+                    index: trigger.virtual_switch,
+                    last_state: state.cycles,
+                });
+            }
+        }
+    }
+
+    /// Index is the virtual switch of an analog trigger
+    pub fn is_virtual(&self, index: u16) -> bool {
+        self.triggers.iter().any(|t| t.virtual_switch == index)
+    }
+
+    /// Hold event of an active virtual switch (used for off-state lookups)
+    /// Off events are not generated
+    pub fn event(&self, virtual_switch: u16) -> Option<TriggerEvent> {
+        self.triggers
+            .iter()
+            .zip(self.states.iter())
+            .find(|(trigger, state)| trigger.virtual_switch == virtual_switch && state.active)
+            .map(|(_, state)| TriggerEvent::Switch {
+                state: Phro::Hold,
+                index: virtual_switch,
+                last_state: state.cycles,
+            })
+    }
+}
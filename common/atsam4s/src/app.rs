@@ -0,0 +1,1440 @@
+// Copyright 2026 Jacob Alexander
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! RTIC application templates
+//!
+//! Each macro expands to a complete `#[rtic::app]` module containing the standard kiibohd tasks
+//! (keyscanning, macro processing, USB, HID-IO, watchdog) for the enabled feature set.
+//! Board crates only need to supply the pin map, constants and any board-specific hooks.
+//!
+//! The invoking crate must provide:
+//! - `mod constants;` with `CSIZE`, `RSIZE`, `MSIZE`, `SWITCH_REMAP`, `VERGEN_GIT_SEMVER` and
+//!   `VERGEN_GIT_COMMIT_COUNT` (plus any feature specific constants)
+//! - A library crate (`board`) exporting `kll` and `Pins`
+//! - `rtic` and `rtic-monotonics` dependencies
+//!
+//! Optional board-specific hooks are plain functions:
+//! - `init_hook: fn(&mut Pins)` - Called once after the pins are configured
+//! - `rtt_hook: fn()` - Called on every RTT activity tick
+//! - `oneshot_hook: fn(&OneShotStatus, &mut [LedMask])` - Called when the one-shot key state changes
+//!
+//! The VIA raw HID interface (see via.rs) is enabled with the `via` feature of the board crate,
+//! which must forward to `kiibohd-atsam4s/via`.
+
+/// RTIC app template for `keyscanning` (mechanical switch) keyboards
+///
+/// ```ignore
+/// kiibohd_atsam4s::keyscanning_app! {
+///     board: gemini,
+///     debug_led: Pb0<Output<PushPull>>,
+///     strobes: [strobe1, strobe2, strobe3],
+///     senses: [sense1, sense2, sense3],
+/// }
+/// ```
+///
+/// Also requires `SCAN_PERIOD_US` and `GHOST_MODE` (anti-ghosting, `GhostMode::Off` for matrices
+/// with diodes) to be defined in the board `constants` module.
+///
+/// I2C ISSI LED drivers are enabled with the `issi-i2c` feature of the board crate, which must
+/// forward to `kiibohd-atsam4s/issi-i2c`.
+/// Also requires `ISSI_CHIP`, `ISSI_I2C_ADDR`, `ISSI_DEFAULT_BRIGHTNESS`, `ISSI_DEFAULT_ENABLE` and
+/// `LED_LOCK_MASK` to be defined in the board `constants` module.
+///
+/// The UART0 serial console is enabled with the `serial-console` feature of the board crate
+/// (forwarding to `kiibohd-atsam4s/serial-console`) and requires the `uart0_rx` and `uart0_tx` pins.
+///
+/// `oneshot_hook: fn(&OneShotStatus, &mut [LedMask])` is called from `macro_process` whenever the
+/// one-shot key state changes, with the indicator LED masks (`LED_LOCK_MASK`, empty without
+/// `issi-i2c`).
+#[cfg(feature = "keyscanning")]
+#[macro_export]
+macro_rules! keyscanning_app {
+    (
+        board: $board:ident,
+        debug_led: $debug_led:ty,
+        strobes: [$($strobe:ident),+ $(,)?],
+        senses: [$($sense:ident),+ $(,)?]
+        $(, init_hook: $init_hook:path)?
+        $(, rtt_hook: $rtt_hook:path)?
+        $(, oneshot_hook: $oneshot_hook:path)?
+        $(,)?
+    ) => {
+        use crate::constants::*;
+        use $board::{kll, Pins};
+        use $crate::{
+            constants::*,
+            hal::{
+                clock::{Enabled, MainClock, SlowClock, Tc0Clock, Tc1Clock},
+                gpio::*,
+                pac::TC0,
+                prelude::*,
+                timer::TimerCounterChannel,
+                udp::{usb_device::bus::UsbBusAllocator, usb_device::device::UsbDeviceState, UdpBus},
+                watchdog::Watchdog,
+                ToggleableOutputPin,
+            },
+            heapless::{
+                spsc::{Consumer, Producer, Queue},
+                String,
+            },
+            kiibohd_usb, LayerState, UsbState,
+        };
+        use rtic_monotonics::systick::*;
+
+        // ----- RTIC -----
+
+        // RTIC requires that unused interrupts are declared in an extern block when
+        // using software tasks; these free interrupts will be used to dispatch the
+        // software tasks.
+        #[rtic::app(device = $crate::hal::pac, peripherals = true, dispatchers = [UART1, USART0, USART1, SSC, PWM, ACC, ADC, SPI])]
+        mod app {
+            use super::*;
+
+            // ----- Types -----
+
+            type LayerLookup = $crate::kll_core::layout::LayerLookup<'static, LAYOUT_SIZE>;
+            type Matrix = $crate::keyscanning::DebouncedMatrix<CSIZE, RSIZE, MSIZE, SCAN_PERIOD_US>;
+
+            // ----- Structs -----
+
+            //
+            // Shared resources used by tasks/interrupts
+            //
+            #[shared]
+            struct Shared {
+                #[cfg(feature = "serial-console")]
+                console: $crate::serial::SerialConsole,
+                hidio_intf: $crate::HidioCommandInterface,
+                #[cfg(feature = "issi-i2c")]
+                issi: [$crate::issi_i2c::IssiI2c; ISSI_DRIVER_CHIPS],
+                layer_state: $crate::LayerState,
+                #[cfg(feature = "issi-i2c")]
+                led_lock_mask: [$crate::LedMask; LED_LOCK_MASK.len()],
+                /// No indicator LEDs (see oneshot_hook)
+                #[cfg(not(feature = "issi-i2c"))]
+                led_lock_mask: [$crate::LedMask; 0],
+                #[cfg(feature = "issi-i2c")]
+                led_test: $crate::LedTest,
+                matrix: Matrix,
+                usb_dev: $crate::UsbDevice,
+                usb_hid: $crate::HidInterface,
+            }
+
+            //
+            // Local resources, static mut variables, no locking necessary
+            // (e.g. can be initialized in init and used in 1 other task function)
+            //
+            #[local]
+            struct Local {
+                ctrl_producer: Producer<'static, kiibohd_usb::CtrlState, CTRL_QUEUE_SIZE>,
+                debug_led: $debug_led,
+                efc: $crate::hal::efc::Efc,
+                kbd_led_consumer: Consumer<'static, kiibohd_usb::LedState, KBD_LED_QUEUE_SIZE>,
+                kbd_producer: Producer<'static, kiibohd_usb::KeyState, KBD_QUEUE_SIZE>,
+                mouse_producer: Producer<'static, kiibohd_usb::MouseState, MOUSE_QUEUE_SIZE>,
+                rtt: $crate::RealTimeTimer,
+                tcc0: TimerCounterChannel<TC0, Tc0Clock<Enabled>, 0, TCC0_FREQ>,
+                tcc1: TimerCounterChannel<TC0, Tc1Clock<Enabled>, 1, TCC1_FREQ>,
+                usb_state: UsbDeviceState,
+                #[cfg(feature = "issi-i2c")]
+                usb_state_consumer: Consumer<'static, $crate::UsbState, USB_STATE_QUEUE_SIZE>,
+                usb_state_producer: Producer<'static, $crate::UsbState, USB_STATE_QUEUE_SIZE>,
+                via: $crate::ViaInterface,
+                wdt: Watchdog,
+            }
+
+            //
+            // Initialization
+            //
+            #[init(
+                local = [
+                    ctrl_queue: Queue<kiibohd_usb::CtrlState, CTRL_QUEUE_SIZE> = Queue::new(),
+                    kbd_queue: Queue<kiibohd_usb::KeyState, KBD_QUEUE_SIZE> = Queue::new(),
+                    kbd_led_queue: Queue<kiibohd_usb::LedState, KBD_LED_QUEUE_SIZE> = Queue::new(),
+                    mouse_queue: Queue<kiibohd_usb::MouseState, MOUSE_QUEUE_SIZE> = Queue::new(),
+                    usb_state_queue: Queue<UsbState, USB_STATE_QUEUE_SIZE> = Queue::new(),
+                    serial_number: String<126> = String::new(),
+                    usb_bus: Option<UsbBusAllocator<UdpBus>> = None,
+            ])]
+            fn init(cx: init::Context) -> (Shared, Local) {
+                let (wdt, clocks, chip, mut tc0_chs, rtt, gpio_ports, mut efc) =
+                    $crate::initial_init(
+                        cx.device.CHIPID,
+                        cx.device.EFC0,
+                        cx.device.PIOA,
+                        cx.device.PIOB,
+                        cx.device.PMC,
+                        cx.device.RTT,
+                        &cx.device.SUPC,
+                        cx.device.TC0,
+                        cx.device.WDT,
+                        MainClock::Crystal12Mhz,
+                        SlowClock::RcOscillator32Khz,
+                        cx.local.serial_number,
+                        VERGEN_GIT_COMMIT_COUNT.parse().unwrap(),
+                    );
+
+                // Setup pins
+                #[allow(unused_mut)]
+                let mut pins = Pins::new(gpio_ports, &cx.device.MATRIX);
+
+                // Board-specific initialization
+                $($init_hook(&mut pins);)?
+
+                // Setup Keyscanning Matrix
+                let matrix = $crate::keyscanning::init::<CSIZE, RSIZE, MSIZE, SCAN_PERIOD_US>(
+                    [$(pins.$strobe.downgrade()),+],
+                    [$(pins.$sense.downgrade()),+],
+                    &mut tc0_chs,
+                    GHOST_MODE,
+                    $crate::stages::TriggerStages::new(
+                        kll::COMBOS,
+                        kll::TAP_HOLD_KEYS,
+                        kll::ONE_SHOT_KEYS,
+                        kll::DYNAMIC_MACRO_KEYS,
+                        kll::LEADER_KEYS,
+                        SOCD_PAIRS,
+                    ),
+                );
+
+                // Setup kll-core
+                let loop_condition_lookup: &[u32] = &[0]; // TODO: Use KLL Compiler
+
+                // Load datastructures into kll-core
+                let layer_lookup = LayerLookup::new(
+                    kll::LAYER_LOOKUP,
+                    kll::TRIGGER_GUIDES,
+                    kll::RESULT_GUIDES,
+                    kll::TRIGGER_RESULT_MAPPING,
+                    loop_condition_lookup,
+                );
+
+                // Initialize LayerState for kll-core
+                let layer_state = LayerState::new(layer_lookup, 0);
+
+                // ISSI + I2C Driver setup
+                // Transfers are started by led_frame_process once the drivers are in place
+                #[cfg(feature = "issi-i2c")]
+                let issi = {
+                    let mut issi = $crate::issi_i2c::init(
+                        &mut pins.issi_sdb,
+                        ISSI_CHIP,
+                        ISSI_I2C_ADDR,
+                        ISSI_DEFAULT_BRIGHTNESS,
+                        ISSI_DEFAULT_ENABLE,
+                        cx.device.TWI0,
+                        clocks.peripheral_clocks.twi0.into_enabled_clock(),
+                        pins.issi0_sda,
+                        pins.issi0_scl,
+                        cx.device.TWI1,
+                        clocks.peripheral_clocks.twi1.into_enabled_clock(),
+                        pins.issi1_sda,
+                        pins.issi1_scl,
+                    );
+                    for chip in issi.iter_mut() {
+                        chip.pwm_page_buf().iter_mut().for_each(|e| *e = 255);
+                        chip.scaling_page_buf().iter_mut().for_each(|e| *e = 100);
+                    }
+                    issi
+                };
+
+                // Serial console setup
+                #[cfg(feature = "serial-console")]
+                let console = $crate::serial::init(
+                    cx.device.UART0,
+                    clocks.peripheral_clocks.uart0.into_enabled_clock(),
+                    pins.uart0_rx,
+                    pins.uart0_tx,
+                );
+
+                // Setup USB + HID-IO interface
+                let (usb_state_producer, _usb_state_consumer) = cx.local.usb_state_queue.split();
+                let usb_state = UsbDeviceState::Default;
+                let (
+                    usb_dev,
+                    usb_hid,
+                    via,
+                    mut hidio_intf,
+                    ctrl_producer,
+                    kbd_led_consumer,
+                    kbd_producer,
+                    mouse_producer,
+                ) = $crate::usb_init(
+                    &chip,
+                    cx.local.ctrl_queue,
+                    cx.local.kbd_led_queue,
+                    cx.local.kbd_queue,
+                    VERGEN_GIT_COMMIT_COUNT.parse().unwrap(),
+                    VERGEN_GIT_SEMVER,
+                    kll::LAYOUT_USB_KEYS,
+                    cx.local.mouse_queue,
+                    cx.local.serial_number,
+                    cx.device.UDP,
+                    clocks.peripheral_clocks.udp,
+                    pins.udp_ddm,
+                    pins.udp_ddp,
+                    cx.local.usb_bus,
+                );
+
+                // LED defaults, replaced by the stored settings
+                #[cfg(feature = "issi-i2c")]
+                {
+                    let led_settings = &mut hidio_intf.mut_interface().led_settings;
+                    led_settings.brightness = ISSI_DEFAULT_BRIGHTNESS;
+                    led_settings.enabled = ISSI_DEFAULT_ENABLE;
+                }
+
+                // Load stored settings (e.g. dynamic macros)
+                $crate::storage::load(&mut efc, &mut hidio_intf);
+
+                // LED Frame Timer
+                let mut tcc1 = tc0_chs.ch1;
+                tcc1.clock_input(TCC1_DIV);
+                tcc1.start(17_u32.millis()); // 17 ms -> ~60 fps (16.6667 ms)
+                defmt::trace!("TCC1 started - LED Frame Scheduling");
+                tcc1.enable_interrupt();
+
+                // DWT cycle counter (profiling)
+                $crate::profiling::init(cx.core.DCB, cx.core.DWT);
+
+                // Initialize tickless monotonic timer
+                let mono_token = rtic_monotonics::create_systick_token!();
+                Systick::start(cx.core.SYST, MCU_FREQ, mono_token);
+                defmt::trace!("Systick (Monotonic) started");
+
+                (
+                    Shared {
+                        #[cfg(feature = "serial-console")]
+                        console,
+                        hidio_intf,
+                        #[cfg(feature = "issi-i2c")]
+                        issi,
+                        layer_state,
+                        #[cfg(feature = "issi-i2c")]
+                        led_lock_mask: LED_LOCK_MASK,
+                        #[cfg(not(feature = "issi-i2c"))]
+                        led_lock_mask: [],
+                        #[cfg(feature = "issi-i2c")]
+                        led_test: $crate::LedTest::Disabled,
+                        matrix,
+                        usb_dev,
+                        usb_hid,
+                    },
+                    Local {
+                        ctrl_producer,
+                        debug_led: pins.debug_led,
+                        efc,
+                        kbd_led_consumer,
+                        kbd_producer,
+                        mouse_producer,
+                        rtt,
+                        tcc0: tc0_chs.ch0,
+                        tcc1,
+                        usb_state,
+                        #[cfg(feature = "issi-i2c")]
+                        usb_state_consumer: _usb_state_consumer,
+                        usb_state_producer,
+                        via,
+                        wdt,
+                    },
+                )
+            }
+
+            /// Timer task (TC0)
+            /// - Keyscanning Task (Uses tcc0)
+            ///   High-priority scheduled tasks as consistency is more important than speed for scanning
+            ///   key states
+            ///   Scans one strobe at a time
+            #[task(priority = 13, binds = TC0, local = [
+                tcc0,
+            ], shared = [
+                hidio_intf,
+                layer_state,
+                matrix,
+            ])]
+            fn tc0(cx: tc0::Context) {
+                let start = $crate::profiling::start();
+                let hidio_intf = cx.shared.hidio_intf;
+                let layer_state = cx.shared.layer_state;
+                let matrix = cx.shared.matrix;
+
+                // Check for keyscanning interrupt (tcc0)
+                (hidio_intf, layer_state, matrix).lock(|hidio_intf, layer_state, matrix| {
+                    let process_macros = $crate::keyscanning::tc0_irq::<
+                        CSIZE,
+                        RSIZE,
+                        MSIZE,
+                        SCAN_PERIOD_US,
+                    >(
+                        hidio_intf, layer_state, matrix, SWITCH_REMAP, cx.local.tcc0
+                    );
+
+                    // If a full matrix scanning cycle has finished, process macros
+                    let profile = &mut hidio_intf.mut_interface().profile;
+                    if process_macros {
+                        profile.scan_complete();
+                        if macro_process::spawn().is_err() {
+                            defmt::warn!("Could not schedule macro_process");
+                            profile.overrun($crate::profiling::Overrun::MacroProcess);
+                        }
+                    }
+                    profile.record($crate::profiling::Task::Scan, start);
+                });
+            }
+
+            /// Timer task (TC1)
+            /// - LED frame scheduling (Uses tcc1)
+            ///   Schedules a lower priority task which is skipped if the previous frame is still
+            ///   processing
+            #[task(priority = 13, binds = TC1, local = [
+                tcc1,
+            ], shared = [])]
+            fn tc1(cx: tc1::Context) {
+                // Check for LED frame scheduling interrupt
+                if cx.local.tcc1.clear_interrupt_flags() {
+                    // Attempt to schedule LED frame
+                    if led_frame_process::spawn().is_err() {
+                        defmt::warn!("Unable to schedule frame...FPS unstable");
+                    }
+                }
+            }
+
+            /// Activity tick
+            /// Used visually determine MCU status
+            /// Also writes pending settings to flash (lowest priority)
+            #[task(priority = 1, binds = RTT, local = [
+                debug_led,
+                efc,
+                rtt,
+                wdt,
+            ], shared = [
+                hidio_intf,
+            ])]
+            fn rtt(mut cx: rtt::Context) {
+                cx.local.rtt.clear_interrupt_flags();
+
+                // Feed watchdog
+                cx.local.wdt.feed();
+
+                // Blink debug led
+                // TODO: Remove (or use feature flag)
+                cx.local.debug_led.toggle().ok();
+
+                // Save settings
+                // The lock isn't held while writing, flash writes are slow
+                let pending = cx.shared.hidio_intf.lock($crate::storage::take_pending);
+                if let Some(pending) = pending {
+                    $crate::storage::save(cx.local.efc, &pending);
+                }
+
+                // Board-specific activity tick
+                $($rtt_hook();)?
+            }
+
+            /// LED Frame Processing Task
+            /// Handles each LED frame, triggered at a constant rate.
+            /// Frames are skipped if the previous frame is still processing.
+            #[cfg(feature = "issi-i2c")]
+            #[task(priority = 8, local = [
+                layer_leds: $crate::layer_led::LayerLedOverlay = $crate::layer_led::LayerLedOverlay::new(kll::LAYER_LEDS),
+                usb_state_consumer,
+            ], shared = [
+                hidio_intf,
+                issi,
+                led_lock_mask,
+                led_test,
+            ])]
+            async fn led_frame_process(cx: led_frame_process::Context) {
+                (
+                    cx.shared.hidio_intf,
+                    cx.shared.issi,
+                    cx.shared.led_lock_mask,
+                    cx.shared.led_test,
+                )
+                    .lock(|hidio_intf, issi, led_lock_mask, led_test| {
+                        // Look for manufacturing test commands
+                        let (regular_processing, spawn_led_test) =
+                            $crate::issi_i2c::led_frame_process_manufacturing_tests_task(
+                                hidio_intf, issi, led_test,
+                            );
+
+                        if spawn_led_test {
+                            led_test::spawn().unwrap();
+                        }
+
+                        // Queue frame and start I2C transfers
+                        $crate::issi_i2c::led_frame_process_is31fl37xx_i2c_task(
+                            hidio_intf,
+                            issi,
+                            led_lock_mask,
+                            cx.local.layer_leds,
+                            regular_processing,
+                            cx.local.usb_state_consumer,
+                        );
+                    });
+            }
+
+            /// LED Test Results
+            /// Asynchronous task to handle LED test results (both short and open).
+            /// Rescheduled until the I2C reads of the results have completed.
+            #[cfg(feature = "issi-i2c")]
+            #[task(priority = 7, shared = [
+                hidio_intf,
+                issi,
+                led_test,
+            ])]
+            async fn led_test(cx: led_test::Context) {
+                // AN-107 - 1 ms is required for detection, use 2 ms to match the SPI driver
+                Systick::delay(2_u32.millis()).await;
+                // Check for test results
+                (cx.shared.hidio_intf, cx.shared.issi, cx.shared.led_test).lock(
+                    |hidio_intf, issi, led_test| {
+                        // Check if we need to schedule led_test and led_frame_process
+                        if $crate::issi_i2c::led_test_task(hidio_intf, issi, led_test) {
+                            led_test::spawn().unwrap();
+                            led_frame_process::spawn().ok(); // Attempt to schedule frame earlier
+                        }
+                    },
+                );
+            }
+
+            /// LED Frame Processing Task
+            /// Handles each LED frame, triggered at a constant rate.
+            /// Frames are skipped if the previous frame is still processing.
+            #[cfg(not(feature = "issi-i2c"))]
+            #[task(priority = 8, shared = [
+                hidio_intf,
+            ])]
+            async fn led_frame_process(mut cx: led_frame_process::Context) {
+                // No LEDs, only clears pending HID-IO LED requests
+                cx.shared
+                    .hidio_intf
+                    .lock($crate::led_frame_process_no_leds_task);
+            }
+
+            /// Macro Processing Task
+            /// Handles incoming key scan triggers and turns them into results (actions and hid events)
+            /// Has a lower priority than keyscanning to schedule around it.
+            #[task(priority = 10, local = [
+                ctrl_producer,
+                dynamic_macros: $crate::dynamic_macro::DynamicMacros = $crate::dynamic_macro::DynamicMacros::new(),
+                kbd_led_consumer,
+                kbd_producer,
+                leader: $crate::leader::Leader = $crate::leader::Leader::new(kll::LEADER_SEQUENCES),
+                mouse_producer,
+                oneshot_status: $crate::oneshot::OneShotStatus = $crate::oneshot::OneShotStatus::new(),
+            ], shared = [
+                hidio_intf,
+                layer_state,
+                led_lock_mask,
+                matrix,
+            ])]
+            async fn macro_process(mut cx: macro_process::Context) {
+                let start = $crate::profiling::start();
+                (cx.shared.layer_state, cx.shared.matrix).lock(|layer_state, matrix| {
+                    // Query HID LED Events
+                    // Dynamic macro requests and playback
+                    // Leader sequences
+                    // Host injected key events
+                    let injected = cx.shared.hidio_intf.lock(|hidio_intf| {
+                        $crate::macro_process_led_events_task(
+                            cx.local.kbd_led_consumer,
+                            hidio_intf,
+                            layer_state,
+                        );
+                        let injected =
+                            $crate::inject_task(hidio_intf, layer_state, matrix.stages_mut());
+                        $crate::dynamic_macro_task(
+                            cx.local.dynamic_macros,
+                            &mut matrix.stages_mut().macros,
+                            hidio_intf,
+                            cx.local.kbd_producer,
+                        );
+                        $crate::leader_task(
+                            cx.local.leader,
+                            &mut matrix.stages_mut().leader,
+                            hidio_intf,
+                            cx.local.kbd_producer,
+                        );
+                        injected
+                    });
+
+                    // Remapped keys (keymap overlay)
+                    $crate::keymap_task(&mut matrix.stages_mut().keymap, cx.local.kbd_producer);
+
+                    // Process macros
+                    $crate::macro_process_task::<CSIZE, MSIZE, Matrix>(
+                        cx.local.ctrl_producer,
+                        cx.local.kbd_producer,
+                        cx.local.mouse_producer,
+                        cx.local.dynamic_macros,
+                        cx.local.leader,
+                        &injected,
+                        layer_state,
+                        matrix,
+                    );
+
+                    // Layer change notifications
+                    // HID-IO terminal commands (e.g. keystate snapshot)
+                    cx.shared.hidio_intf.lock(|hidio_intf| {
+                        $crate::layer_notify_task(hidio_intf, layer_state, kll::LAYER_ACTIVATIONS);
+                        $crate::terminal::hidio_terminal_task::<MSIZE, Matrix>(
+                            hidio_intf,
+                            layer_state,
+                            matrix,
+                        );
+                    });
+
+                    // One-shot indicators
+                    let status = matrix.stages().oneshot.status();
+                    if status != *cx.local.oneshot_status {
+                        *cx.local.oneshot_status = status;
+                        $(cx.shared.led_lock_mask.lock(|led_lock_mask| {
+                            $oneshot_hook(&status, led_lock_mask);
+                        });)?
+                    }
+                });
+
+                // Schedule USB processing
+                let overrun = usb_process::spawn().is_err();
+                if overrun {
+                    defmt::warn!("Could not schedule usb_process");
+                }
+                cx.shared.hidio_intf.lock(|hidio_intf| {
+                    let profile = &mut hidio_intf.mut_interface().profile;
+                    if overrun {
+                        profile.overrun($crate::profiling::Overrun::UsbProcess);
+                    }
+                    profile.record($crate::profiling::Task::MacroProcess, start);
+                });
+            }
+
+            /// USB Outgoing Events Task
+            /// Sends outgoing USB HID events generated by the macro_process task
+            /// Has a lower priority than keyscanning to schedule around it.
+            #[task(priority = 11, local = [
+                usb_state,
+                usb_state_producer,
+            ], shared = [
+                hidio_intf,
+                usb_dev,
+                usb_hid,
+            ])]
+            async fn usb_process(cx: usb_process::Context) {
+                let start = $crate::profiling::start();
+                let mut hidio_intf = cx.shared.hidio_intf;
+                let usb_dev = cx.shared.usb_dev;
+                let usb_hid = cx.shared.usb_hid;
+                (usb_hid, usb_dev).lock(|usb_hid, usb_dev| {
+                    $crate::usb_process_task(
+                        usb_dev,
+                        usb_hid,
+                        cx.local.usb_state,
+                        cx.local.usb_state_producer,
+                    );
+                });
+
+                hidio_intf.lock(|hidio_intf| {
+                    let profile = &mut hidio_intf.mut_interface().profile;
+                    profile.record($crate::profiling::Task::UsbProcess, start);
+                    profile.usb_complete();
+                });
+            }
+
+            /// ISSI I2C0 Interrupt
+            #[cfg(feature = "issi-i2c")]
+            #[task(priority = 12, binds = TWI0, shared = [
+                issi,
+            ])]
+            fn twi0(mut cx: twi0::Context) {
+                cx.shared.issi.lock(|issi| {
+                    $crate::issi_i2c::twi_irq(&mut issi[0]);
+                });
+            }
+
+            /// ISSI I2C1 Interrupt
+            #[cfg(feature = "issi-i2c")]
+            #[task(priority = 12, binds = TWI1, shared = [
+                issi,
+            ])]
+            fn twi1(mut cx: twi1::Context) {
+                cx.shared.issi.lock(|issi| {
+                    $crate::issi_i2c::twi_irq(&mut issi[1]);
+                });
+            }
+
+            /// Serial Console Processing Task
+            /// Low priority as this locks the matrix while dumping state
+            #[cfg(feature = "serial-console")]
+            #[task(priority = 2, shared = [
+                console,
+                hidio_intf,
+                layer_state,
+                matrix,
+            ])]
+            async fn console_process(
+                cx: console_process::Context,
+                command: Result<$crate::console::Command, $crate::console::ParseError>,
+            ) {
+                (
+                    cx.shared.console,
+                    cx.shared.hidio_intf,
+                    cx.shared.layer_state,
+                    cx.shared.matrix,
+                )
+                    .lock(|console, hidio_intf, layer_state, matrix| {
+                        $crate::serial::console_process_task::<MSIZE, Matrix>(
+                            console,
+                            command,
+                            hidio_intf,
+                            layer_state,
+                            matrix,
+                        );
+                    });
+            }
+
+            /// Serial Console Interrupt (UART0)
+            #[cfg(feature = "serial-console")]
+            #[task(priority = 9, binds = UART0, shared = [
+                console,
+            ])]
+            fn uart0(mut cx: uart0::Context) {
+                cx.shared.console.lock(|console| {
+                    if let Some(command) = $crate::serial::uart_irq(console) {
+                        if console_process::spawn(command).is_err() {
+                            defmt::warn!("Console busy, dropping command");
+                        }
+                    }
+                });
+            }
+
+            /// USB Device Interupt
+            #[task(priority = 14, binds = UDP, local = [
+                via,
+            ], shared = [
+                hidio_intf,
+                usb_dev,
+                usb_hid,
+            ])]
+            fn udp(cx: udp::Context) {
+                let usb_dev = cx.shared.usb_dev;
+                let usb_hid = cx.shared.usb_hid;
+                let hidio_intf = cx.shared.hidio_intf;
+
+                // Poll USB endpoints
+                (usb_dev, usb_hid, hidio_intf).lock(|usb_dev, usb_hid, hidio_intf| {
+                    $crate::udp_irq(usb_dev, usb_hid, cx.local.via, hidio_intf);
+                });
+            }
+        }
+    };
+}
+
+/// RTIC app template for `hall-effect` + `issi-spi` (analog switch) keyboards
+///
+/// ```ignore
+/// kiibohd_atsam4s::hall_effect_app! {
+///     board: keystonetkl,
+///     strobes: [strobe1, strobe2, strobe3],
+/// }
+/// ```
+///
+/// Also requires `ADC_BUF_SIZE`, `ISSI_DEFAULT_BRIGHTNESS`, `ISSI_DEFAULT_ENABLE` and
+/// `LED_LOCK_MASK` to be defined in the board `constants` module.
+///
+/// `oneshot_hook: fn(&OneShotStatus, &mut [LedMask])` is called from `macro_process` whenever the
+/// one-shot key state changes so the indicator LEDs (`LED_LOCK_MASK`) can be updated (set the
+/// mask and reset `frames_since_update`).
+#[cfg(all(feature = "hall-effect", feature = "issi-spi"))]
+#[macro_export]
+macro_rules! hall_effect_app {
+    (
+        board: $board:ident,
+        strobes: [$($strobe:ident),+ $(,)?]
+        $(, init_hook: $init_hook:path)?
+        $(, rtt_hook: $rtt_hook:path)?
+        $(, oneshot_hook: $oneshot_hook:path)?
+        $(,)?
+    ) => {
+        use crate::constants::*;
+        use $board::{kll, Pins};
+        use $crate::{
+            constants::*,
+            hal::{
+                clock::{Enabled, MainClock, SlowClock, Tc0Clock, Tc1Clock},
+                pac::TC0,
+                pdc::ReadDma,
+                prelude::*,
+                timer::TimerCounterChannel,
+                udp::{usb_device::bus::UsbBusAllocator, usb_device::device::UsbDeviceState, UdpBus},
+                watchdog::Watchdog,
+            },
+            heapless::{
+                self,
+                spsc::{Consumer, Producer, Queue},
+                String,
+            },
+            kiibohd_hid_io, kiibohd_usb, LayerState, UsbState,
+        };
+        use rtic_monotonics::systick::*;
+
+        // ----- RTIC -----
+
+        // RTIC requires that unused interrupts are declared in an extern block when
+        // using software tasks; these free interrupts will be used to dispatch the
+        // software tasks.
+        #[rtic::app(device = $crate::hal::pac, peripherals = true, dispatchers = [UART1, USART0, USART1, SSC, PWM, ACC, TWI0, TWI1])]
+        mod app {
+            use super::*;
+
+            // ----- Types -----
+
+            type LayerLookup = $crate::kll_core::layout::LayerLookup<'static, LAYOUT_SIZE>;
+            type Matrix = $crate::hall_effect::HallMatrix<CSIZE, MSIZE>;
+
+            // ----- Structs -----
+
+            //
+            // Shared resources used by tasks/interrupts
+            //
+            #[shared]
+            struct Shared {
+                adc: Option<$crate::hall_effect::AdcTransfer<ADC_BUF_SIZE>>,
+                hidio_intf: $crate::HidioCommandInterface,
+                issi: $crate::issi_spi::Is31fl3743bAtsam4Dma<
+                    ISSI_DRIVER_CHIPS,
+                    ISSI_DRIVER_QUEUE_SIZE,
+                >,
+                layer_state: LayerState,
+                led_lock_mask: [$crate::LedMask; LED_MASK_SIZE],
+                led_test: $crate::LedTest,
+                manu_test_data: heapless::Vec<u8, { kiibohd_hid_io::MESSAGE_LEN - 4 }>,
+                matrix: Matrix,
+                scan_timing: $crate::hall_effect::ScanTiming,
+                spi: Option<$crate::issi_spi::SpiParkedDma>,
+                spi_rxtx: Option<$crate::issi_spi::SpiTransferRxTx>,
+                tcc0: TimerCounterChannel<TC0, Tc0Clock<Enabled>, 0, TCC0_FREQ>,
+                usb_dev: $crate::UsbDevice,
+                usb_hid: $crate::HidInterface,
+            }
+
+            //
+            // Local resources, static mut variables, no locking necessary
+            // (e.g. can be initialized in init and used in 1 other task function)
+            //
+            #[local]
+            struct Local {
+                ctrl_producer: Producer<'static, kiibohd_usb::CtrlState, CTRL_QUEUE_SIZE>,
+                efc: $crate::hal::efc::Efc,
+                kbd_led_consumer: Consumer<'static, kiibohd_usb::LedState, KBD_LED_QUEUE_SIZE>,
+                kbd_producer: Producer<'static, kiibohd_usb::KeyState, KBD_QUEUE_SIZE>,
+                led_indicators: $crate::IndicatorLeds<LED_MASK_SIZE>,
+                mouse_producer: Producer<'static, kiibohd_usb::MouseState, MOUSE_QUEUE_SIZE>,
+                rtt: $crate::RealTimeTimer,
+                sense_pins: $crate::hall_effect::SensePins,
+                tcc1: TimerCounterChannel<TC0, Tc1Clock<Enabled>, 1, TCC1_FREQ>,
+                usb_state: UsbDeviceState,
+                usb_state_consumer: Consumer<'static, $crate::UsbState, USB_STATE_QUEUE_SIZE>,
+                usb_state_producer: Producer<'static, $crate::UsbState, USB_STATE_QUEUE_SIZE>,
+                via: $crate::ViaInterface,
+                wdt: Watchdog,
+            }
+
+            //
+            // Initialization
+            //
+            #[init(
+                local = [
+                    adc_buf: [u16; ADC_BUF_SIZE] = [0; ADC_BUF_SIZE],
+                    ctrl_queue: Queue<kiibohd_usb::CtrlState, CTRL_QUEUE_SIZE> = Queue::new(),
+                    kbd_queue: Queue<kiibohd_usb::KeyState, KBD_QUEUE_SIZE> = Queue::new(),
+                    kbd_led_queue: Queue<kiibohd_usb::LedState, KBD_LED_QUEUE_SIZE> = Queue::new(),
+                    mouse_queue: Queue<kiibohd_usb::MouseState, MOUSE_QUEUE_SIZE> = Queue::new(),
+                    usb_state_queue: Queue<UsbState, USB_STATE_QUEUE_SIZE> = Queue::new(),
+                    serial_number: String<126> = String::new(),
+                    spi_tx_buf: [u32; SPI_TX_BUF_SIZE] = [0; SPI_TX_BUF_SIZE],
+                    spi_rx_buf: [u32; SPI_RX_BUF_SIZE] = [0; SPI_RX_BUF_SIZE],
+                    usb_bus: Option<UsbBusAllocator<UdpBus>> = None,
+            ])]
+            fn init(cx: init::Context) -> (Shared, Local) {
+                let (wdt, mut clocks, chip, tc0_chs, rtt, gpio_ports, mut efc) =
+                    $crate::initial_init(
+                        cx.device.CHIPID,
+                        cx.device.EFC0,
+                        cx.device.PIOA,
+                        cx.device.PIOB,
+                        cx.device.PMC,
+                        cx.device.RTT,
+                        &cx.device.SUPC,
+                        cx.device.TC0,
+                        cx.device.WDT,
+                        MainClock::Crystal12Mhz,
+                        SlowClock::RcOscillator32Khz,
+                        cx.local.serial_number,
+                        VERGEN_GIT_COMMIT_COUNT.parse().unwrap(),
+                    );
+
+                // Setup pins
+                let mut pins = Pins::new(gpio_ports, &cx.device.MATRIX);
+
+                // Board-specific initialization
+                $($init_hook(&mut pins);)?
+
+                let mut sense_pins = $crate::hall_effect::SensePins {
+                    sense1: pins.sense1,
+                    sense2: pins.sense2,
+                    sense3: pins.sense3,
+                    sense4: pins.sense4,
+                    sense5: pins.sense5,
+                    sense6: pins.sense6,
+                };
+
+                // Setup hall effect matrix
+                let mut tcc0 = tc0_chs.ch0;
+                let mut scan_timing = $crate::hall_effect::ScanTiming::new();
+                let (adc, matrix) = $crate::hall_effect::init::<CSIZE, RSIZE, MSIZE>(
+                    cx.device.ADC,
+                    clocks.peripheral_clocks.adc.into_enabled_clock(),
+                    [$(pins.$strobe.downgrade()),+],
+                    &mut sense_pins,
+                    &mut tcc0,
+                    &mut scan_timing,
+                    kll::ANALOG_TRIGGERS,
+                    $crate::stages::TriggerStages::new(
+                        kll::COMBOS,
+                        kll::TAP_HOLD_KEYS,
+                        kll::ONE_SHOT_KEYS,
+                        kll::DYNAMIC_MACRO_KEYS,
+                        kll::LEADER_KEYS,
+                        SOCD_PAIRS,
+                    ),
+                );
+
+                // Setup kll-core
+                let loop_condition_lookup: &[u32] = &[0]; // TODO: Use KLL Compiler
+
+                // Load datastructures into kll-core
+                let layer_lookup = LayerLookup::new(
+                    kll::LAYER_LOOKUP,
+                    kll::TRIGGER_GUIDES,
+                    kll::RESULT_GUIDES,
+                    kll::TRIGGER_RESULT_MAPPING,
+                    loop_condition_lookup,
+                );
+
+                // Initialize LayerState for kll-core
+                let layer_state = LayerState::new(layer_lookup, 0);
+
+                // ISSI + SPI Driver setup
+                let mut tcc1 = tc0_chs.ch1;
+                let (spi_rxtx, mut issi) = $crate::issi_spi::init(
+                    &mut pins.debug_led,
+                    ISSI_DEFAULT_BRIGHTNESS,
+                    ISSI_DEFAULT_ENABLE,
+                    cx.device.SPI,
+                    clocks.peripheral_clocks.spi.into_enabled_clock(),
+                    pins.spi_miso,
+                    pins.spi_mosi,
+                    cx.local.spi_rx_buf,
+                    pins.spi_sck,
+                    cx.local.spi_tx_buf,
+                    &mut tcc1,
+                );
+
+                for chip in issi.pwm_page_buf() {
+                    chip.iter_mut().for_each(|e| *e = 255);
+                }
+                for chip in issi.scaling_page_buf() {
+                    chip.iter_mut().for_each(|e| *e = 100);
+                }
+                issi.scaling().unwrap();
+                issi.pwm().unwrap();
+
+                // Set indicator LEDs
+                let led_lock_mask = LED_LOCK_MASK;
+                let led_indicators = $crate::IndicatorLeds::new();
+
+                // Setup USB + HID-IO interface
+                let (usb_state_producer, usb_state_consumer) = cx.local.usb_state_queue.split();
+                let usb_state = UsbDeviceState::Default;
+                let (
+                    usb_dev,
+                    usb_hid,
+                    via,
+                    mut hidio_intf,
+                    ctrl_producer,
+                    kbd_led_consumer,
+                    kbd_producer,
+                    mouse_producer,
+                ) = $crate::usb_init(
+                    &chip,
+                    cx.local.ctrl_queue,
+                    cx.local.kbd_led_queue,
+                    cx.local.kbd_queue,
+                    VERGEN_GIT_COMMIT_COUNT.parse().unwrap(),
+                    VERGEN_GIT_SEMVER,
+                    kll::LAYOUT_USB_KEYS,
+                    cx.local.mouse_queue,
+                    cx.local.serial_number,
+                    cx.device.UDP,
+                    clocks.peripheral_clocks.udp,
+                    pins.udp_ddm,
+                    pins.udp_ddp,
+                    cx.local.usb_bus,
+                );
+                // Keyscanning is aligned to USB frames
+                $crate::hall_effect::enable_sof();
+
+                // LED defaults, replaced by the stored settings
+                let led_settings = &mut hidio_intf.mut_interface().led_settings;
+                led_settings.brightness = ISSI_DEFAULT_BRIGHTNESS;
+                led_settings.enabled = ISSI_DEFAULT_ENABLE;
+
+                // Load stored settings (e.g. DKS)
+                $crate::storage::load(&mut efc, &mut hidio_intf);
+
+                // DWT cycle counter (profiling)
+                $crate::profiling::init(cx.core.DCB, cx.core.DWT);
+
+                // Initialize tickless monotonic timer
+                let mono_token = rtic_monotonics::create_systick_token!();
+                Systick::start(cx.core.SYST, MCU_FREQ, mono_token);
+                defmt::trace!("Systick (Monotonic) started");
+
+                // Manufacturing test data buffer
+                // Ready for data and start from column 0
+                let manu_test_data = heapless::Vec::from_slice(&[0, 0]).unwrap();
+
+                (
+                    Shared {
+                        adc: Some(adc.read(cx.local.adc_buf)),
+                        hidio_intf,
+                        issi,
+                        layer_state,
+                        led_test: $crate::LedTest::Disabled,
+                        led_lock_mask,
+                        manu_test_data,
+                        matrix,
+                        scan_timing,
+                        spi: None,
+                        spi_rxtx: Some(spi_rxtx),
+                        tcc0,
+                        usb_dev,
+                        usb_hid,
+                    },
+                    Local {
+                        ctrl_producer,
+                        efc,
+                        kbd_led_consumer,
+                        kbd_producer,
+                        led_indicators,
+                        mouse_producer,
+                        rtt,
+                        sense_pins,
+                        tcc1,
+                        usb_state,
+                        usb_state_consumer,
+                        usb_state_producer,
+                        via,
+                        wdt,
+                    },
+                )
+            }
+
+            /// Timer task (TC0)
+            /// - Keyscanning Task (Uses tcc0)
+            ///   High-priority scheduled tasks as consistency is more important than speed for scanning
+            ///   key states
+            ///   Scans one strobe at a time
+            #[task(priority = 13, binds = TC0, local = [
+            ], shared = [
+                adc,
+                scan_timing,
+                tcc0,
+            ])]
+            fn tc0(cx: tc0::Context) {
+                // Check for keyscanning interrupt (tcc0)
+                (cx.shared.adc, cx.shared.scan_timing, cx.shared.tcc0).lock(
+                    |adc, scan_timing, tcc0| {
+                        $crate::hall_effect::tc0_irq(adc, tcc0, scan_timing);
+                    },
+                );
+            }
+
+            /// Timer task (TC1)
+            /// - LED frame scheduling (Uses tcc1)
+            ///   Schedules a lower priority task which is skipped if the previous frame is still
+            ///   processing
+            #[task(priority = 13, binds = TC1, local = [
+                tcc1,
+            ], shared = [])]
+            fn tc1(cx: tc1::Context) {
+                // Check for LED frame scheduling interrupt
+                if cx.local.tcc1.clear_interrupt_flags() {
+                    // Attempt to schedule LED frame
+                    if led_frame_process::spawn().is_err() {
+                        defmt::warn!("Unable to schedule frame...FPS unstable");
+                    }
+                }
+            }
+
+            /// Activity tick
+            /// Used visually determine MCU status
+            /// Also writes pending settings to flash (lowest priority)
+            #[task(priority = 1, binds = RTT, local = [
+                efc,
+                led_indicators,
+                rtt,
+                wdt,
+            ], shared = [
+                hidio_intf,
+                issi,
+                led_lock_mask,
+            ])]
+            fn rtt(mut cx: rtt::Context) {
+                cx.local.rtt.clear_interrupt_flags();
+
+                // Feed watchdog
+                cx.local.wdt.feed();
+
+                // Update activity LED
+                let status = cx.local.led_indicators.get(0);
+                cx.local.led_indicators.set(0, !status);
+
+                // Update lock LEDs
+                // TODO Move to issi_spi.rs
+                // TODO Add way to register state indicators
+                //      LED 0
+                //       - NumLock, CapsLock, ScrollLock
+                //      LED 1
+                //       - NKRO/6KRO, HIDIO, LowLatency/Normal/Test
+                // TODO Better breathing effect (will need timing from tc1 and rtt to compute this)
+                (cx.shared.issi, cx.shared.led_lock_mask).lock(|issi, led_lock_mask| {
+                    if status {
+                        led_lock_mask[0].mask = [0, 0, 0];
+                    } else {
+                        led_lock_mask[0].mask = [0, 50, 0];
+                    }
+                    led_lock_mask[0].frames_since_update = 0;
+
+                    issi.pwm().unwrap();
+                });
+
+                // Save settings
+                // The lock isn't held while writing, flash writes are slow
+                let pending = cx.shared.hidio_intf.lock($crate::storage::take_pending);
+                if let Some(pending) = pending {
+                    $crate::storage::save(cx.local.efc, &pending);
+                }
+
+                // Board-specific activity tick
+                $($rtt_hook();)?
+            }
+
+            /// LED Frame Processing Task
+            /// Handles each LED frame, triggered at a constant rate.
+            /// Frames are skipped if the previous frame is still processing.
+            #[task(priority = 8, local = [
+                layer_leds: $crate::layer_led::LayerLedOverlay = $crate::layer_led::LayerLedOverlay::new(kll::LAYER_LEDS),
+                usb_state_consumer,
+            ], shared = [
+                hidio_intf,
+                issi,
+                led_lock_mask,
+                led_test,
+                spi,
+                spi_rxtx,
+            ])]
+            async fn led_frame_process(cx: led_frame_process::Context) {
+                (
+                    cx.shared.hidio_intf,
+                    cx.shared.issi,
+                    cx.shared.led_lock_mask,
+                    cx.shared.led_test,
+                )
+                    .lock(|hidio_intf, issi, led_lock_mask, led_test| {
+                        // Look for manufacturing test commands
+                        let (regular_processing, spawn_led_test) =
+                            $crate::issi_spi::led_frame_process_manufacturing_tests_task(
+                                hidio_intf, issi, led_test,
+                            );
+
+                        if spawn_led_test {
+                            led_test::spawn().unwrap();
+                        }
+
+                        // Enable SPI DMA to update frame
+                        (cx.shared.spi, cx.shared.spi_rxtx).lock(|spi_periph, spi_rxtx| {
+                            $crate::issi_spi::led_frame_process_is31fl3743b_dma_task(
+                                hidio_intf,
+                                issi,
+                                spi_periph,
+                                spi_rxtx,
+                                led_lock_mask,
+                                cx.local.layer_leds,
+                                regular_processing,
+                                cx.local.usb_state_consumer,
+                            );
+                        });
+                    });
+            }
+
+            /// LED Test Results
+            /// Asynchronous task to handle LED test results (both short and open).
+            /// This task is schedule at least 750 us after the test is started.
+            #[task(priority = 7, shared = [
+                hidio_intf,
+                issi,
+                led_test,
+            ])]
+            async fn led_test(cx: led_test::Context) {
+                // Even though AN-107 - OPEN SHORT TEST FUNCTION OF IS31FL3743B says
+                // that only 1ms is required, in practice 2ms seems more reliable.
+                Systick::delay(2_u32.millis()).await;
+                // Check for test results
+                (cx.shared.hidio_intf, cx.shared.issi, cx.shared.led_test).lock(
+                    |hidio_intf, issi, led_test| {
+                        // Check if we need to schedule led_test and led_frame_process
+                        if $crate::issi_spi::led_test_task(hidio_intf, issi, led_test) {
+                            led_test::spawn().unwrap();
+                            led_frame_process::spawn().ok(); // Attempt to schedule frame earlier
+                        }
+                    },
+                );
+            }
+
+            /// Macro Processing Task
+            /// Handles incoming key scan triggers and turns them into results (actions and hid events)
+            /// Has a lower priority than keyscanning to schedule around it.
+            #[task(priority = 10, local = [
+                ctrl_producer,
+                dynamic_macros: $crate::dynamic_macro::DynamicMacros = $crate::dynamic_macro::DynamicMacros::new(),
+                kbd_led_consumer,
+                kbd_producer,
+                leader: $crate::leader::Leader = $crate::leader::Leader::new(kll::LEADER_SEQUENCES),
+                mouse_producer,
+                oneshot_status: $crate::oneshot::OneShotStatus = $crate::oneshot::OneShotStatus::new(),
+            ], shared = [
+                hidio_intf,
+                layer_state,
+                led_lock_mask,
+                matrix,
+            ])]
+            async fn macro_process(mut cx: macro_process::Context) {
+                let start = $crate::profiling::start();
+                (cx.shared.layer_state, cx.shared.matrix).lock(|layer_state, matrix| {
+                    // Query HID LED Events
+                    // Dynamic macro requests and playback
+                    // Leader sequences
+                    // Host injected key events
+                    let injected = cx.shared.hidio_intf.lock(|hidio_intf| {
+                        $crate::macro_process_led_events_task(
+                            cx.local.kbd_led_consumer,
+                            hidio_intf,
+                            layer_state,
+                        );
+                        let injected =
+                            $crate::inject_task(hidio_intf, layer_state, matrix.stages_mut());
+                        $crate::dynamic_macro_task(
+                            cx.local.dynamic_macros,
+                            &mut matrix.stages_mut().macros,
+                            hidio_intf,
+                            cx.local.kbd_producer,
+                        );
+                        $crate::leader_task(
+                            cx.local.leader,
+                            &mut matrix.stages_mut().leader,
+                            hidio_intf,
+                            cx.local.kbd_producer,
+                        );
+                        injected
+                    });
+
+                    // Remapped keys (keymap overlay)
+                    $crate::keymap_task(&mut matrix.stages_mut().keymap, cx.local.kbd_producer);
+
+                    // Process macros
+                    $crate::macro_process_task::<CSIZE, MSIZE, Matrix>(
+                        cx.local.ctrl_producer,
+                        cx.local.kbd_producer,
+                        cx.local.mouse_producer,
+                        cx.local.dynamic_macros,
+                        cx.local.leader,
+                        &injected,
+                        layer_state,
+                        matrix,
+                    );
+
+                    // Layer change notifications
+                    // HID-IO terminal commands (e.g. keystate snapshot)
+                    cx.shared.hidio_intf.lock(|hidio_intf| {
+                        $crate::layer_notify_task(hidio_intf, layer_state, kll::LAYER_ACTIVATIONS);
+                        $crate::terminal::hidio_terminal_task::<MSIZE, Matrix>(
+                            hidio_intf,
+                            layer_state,
+                            matrix,
+                        );
+                    });
+
+                    // One-shot indicators
+                    let status = matrix.stages().oneshot.status();
+                    if status != *cx.local.oneshot_status {
+                        *cx.local.oneshot_status = status;
+                        $(cx.shared.led_lock_mask.lock(|led_lock_mask| {
+                            $oneshot_hook(&status, led_lock_mask);
+                        });)?
+                    }
+                });
+
+                // Schedule USB processing
+                let overrun = usb_process::spawn().is_err();
+                if overrun {
+                    defmt::warn!("Could not schedule usb_process");
+                }
+                cx.shared.hidio_intf.lock(|hidio_intf| {
+                    let profile = &mut hidio_intf.mut_interface().profile;
+                    if overrun {
+                        profile.overrun($crate::profiling::Overrun::UsbProcess);
+                    }
+                    profile.record($crate::profiling::Task::MacroProcess, start);
+                });
+            }
+
+            /// USB Outgoing Events Task
+            /// Sends outgoing USB HID events generated by the macro_process task
+            /// Has a lower priority than keyscanning to schedule around it.
+            #[task(priority = 11, local = [
+                usb_state,
+                usb_state_producer,
+            ], shared = [
+                hidio_intf,
+                usb_dev,
+                usb_hid,
+            ])]
+            async fn usb_process(cx: usb_process::Context) {
+                let start = $crate::profiling::start();
+                let mut hidio_intf = cx.shared.hidio_intf;
+                let usb_dev = cx.shared.usb_dev;
+                let usb_hid = cx.shared.usb_hid;
+                (usb_hid, usb_dev).lock(|usb_hid, usb_dev| {
+                    $crate::usb_process_task(
+                        usb_dev,
+                        usb_hid,
+                        cx.local.usb_state,
+                        cx.local.usb_state_producer,
+                    );
+                });
+
+                hidio_intf.lock(|hidio_intf| {
+                    let profile = &mut hidio_intf.mut_interface().profile;
+                    profile.record($crate::profiling::Task::UsbProcess, start);
+                    profile.usb_complete();
+                });
+            }
+
+            /// ADC Interrupt
+            #[task(priority = 14, binds = ADC, local = [
+                sense_pins,
+            ], shared = [
+                adc,
+                hidio_intf,
+                layer_state,
+                manu_test_data,
+                matrix,
+                scan_timing,
+                tcc0,
+            ])]
+            fn adc(cx: adc::Context) {
+                let start = $crate::profiling::start();
+                let adc = cx.shared.adc;
+                let hidio_intf = cx.shared.hidio_intf;
+                let layer_state = cx.shared.layer_state;
+                let manu_test_data = cx.shared.manu_test_data;
+                let matrix = cx.shared.matrix;
+                let scan_timing = cx.shared.scan_timing;
+                let sense_pins = cx.local.sense_pins;
+                let tcc0 = cx.shared.tcc0;
+
+                (adc, hidio_intf, layer_state, manu_test_data, matrix, scan_timing, tcc0).lock(
+                    |adc_pdc, hidio_intf, layer_state, manu_test_data, matrix, scan_timing, tcc0| {
+                        let strobe =
+                            $crate::hall_effect::adc_irq::<CSIZE, RSIZE, MSIZE, ADC_BUF_SIZE>(
+                                adc_pdc,
+                                sense_pins,
+                                tcc0,
+                                scan_timing,
+                                hidio_intf,
+                                layer_state,
+                                manu_test_data,
+                                matrix,
+                                SWITCH_REMAP,
+                            );
+
+                        // Process macros after full strobe cycle
+                        let profile = &mut hidio_intf.mut_interface().profile;
+                        if strobe == 0 {
+                            profile.scan_complete();
+                            if macro_process::spawn().is_err() {
+                                defmt::warn!("Could not schedule macro_process");
+                                profile.overrun($crate::profiling::Overrun::MacroProcess);
+                            }
+                        }
+                        profile.record($crate::profiling::Task::Scan, start);
+                    },
+                );
+            }
+
+            /// SPI Interrupt
+            #[task(priority = 12, binds = SPI, shared = [
+                issi,
+                spi,
+                spi_rxtx,
+            ])]
+            fn spi(cx: spi::Context) {
+                let issi = cx.shared.issi;
+                let spi_periph = cx.shared.spi;
+                let spi_rxtx = cx.shared.spi_rxtx;
+
+                // Handle SPI DMA transfers
+                (issi, spi_periph, spi_rxtx).lock(|issi, spi_periph, spi_rxtx| {
+                    $crate::issi_spi::spi_irq(issi, spi_periph, spi_rxtx);
+                });
+            }
+
+            /// USB Device Interupt
+            #[task(priority = 14, binds = UDP, local = [
+                via,
+            ], shared = [
+                adc,
+                hidio_intf,
+                scan_timing,
+                tcc0,
+                usb_dev,
+                usb_hid,
+            ])]
+            fn udp(cx: udp::Context) {
+                let usb_dev = cx.shared.usb_dev;
+                let usb_hid = cx.shared.usb_hid;
+                let hidio_intf = cx.shared.hidio_intf;
+
+                // Align keyscanning to USB frames
+                let udp_status = $crate::hall_effect::udp_status();
+                (cx.shared.adc, cx.shared.scan_timing, cx.shared.tcc0).lock(
+                    |adc, scan_timing, tcc0| {
+                        $crate::hall_effect::sof_irq(udp_status, adc, tcc0, scan_timing);
+                    },
+                );
+
+                // Poll USB endpoints
+                (usb_dev, usb_hid, hidio_intf).lock(|usb_dev, usb_hid, hidio_intf| {
+                    $crate::udp_irq(usb_dev, usb_hid, cx.local.via, hidio_intf);
+                });
+                $crate::hall_effect::udp_sof_ack(udp_status);
+            }
+        }
+    };
+}
@@ -0,0 +1,538 @@
+// Copyright 2026 Jacob Alexander
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! Combo (chord) triggers
+//!
+//! Combos are written in the KLL files as `S<switch> + S<switch> [+ ...] : S<virtual switch>;`
+//! and compiled into the COMBOS table by build.rs, e.g. J+K for Escape:
+//!
+//! ```text
+//! S0x24 + S0x25 : S0xA0;
+//! S0xA0 : U"Esc";
+//! ```
+//!
+//! Presses of combo keys are held back for the combo term. Once every key of a combo has been
+//! pressed, the individual presses are rolled back (never sent) and the virtual switch is pressed
+//! until one of the combo keys is released. Otherwise (timeout, release or another key) the held
+//! back events are replayed in order.
+//! Longer combos win if they are completed within the combo term.
+//!
+//! Combos written in a layer file (KLL_LAYERS) are only evaluated while their layer is in the
+//! layer stack, combos of the base map and layer 0 are always evaluated.
+
+use crate::constants::*;
+use heapless::Deque;
+use kll_core::{trigger::Phro, TriggerEvent};
+
+// ----- Constants -----
+
+// Keys of an active combo are tracked using a u16 bitmask
+const _: () = assert!(MAX_COMBO_KEYS <= 16);
+// Every press of the longest combo can be held back
+const _: () = assert!(COMBO_BUFFER_SIZE >= MAX_COMBO_KEYS);
+
+// ----- Structs -----
+
+/// Combo, generated by build.rs
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub struct Combo {
+    /// Switches (KLL trigger indices) that have to be pressed together
+    pub keys: &'static [u16],
+    /// Virtual switch (KLL trigger index) pressed while the combo is active
+    pub virtual_switch: u16,
+    /// Layer of the KLL file (0 for the base map)
+    pub layer: u8,
+}
+
+/// Active combo
+#[derive(Clone, Copy)]
+struct ComboState {
+    /// Cycles since the combo was triggered
+    cycles: u32,
+    /// Combo keys that are still pressed (bit per key)
+    pressed: u16,
+    /// Virtual switch has been released (a combo key was released)
+    released: bool,
+}
+
+/// Combo evaluation
+pub struct Combos {
+    combos: &'static [Combo],
+    term_ms: u32,
+    /// Held back events of combo keys
+    buffer: Deque<TriggerEvent, COMBO_BUFFER_SIZE>,
+    /// Time since the first held back press
+    elapsed_us: u32,
+    states: [Option<ComboState>; MAX_COMBOS],
+    /// Layers in the layer stack (bit per layer, layer 0 is always set)
+    layers: u16,
+}
+
+impl Combos {
+    pub fn new(combos: &'static [Combo]) -> Self {
+        Self {
+            combos,
+            term_ms: COMBO_TERM_MS,
+            buffer: Deque::new(),
+            elapsed_us: 0,
+            states: [None; MAX_COMBOS],
+            layers: 1,
+        }
+    }
+
+    pub fn combos(&self) -> &'static [Combo] {
+        self.combos
+    }
+
+    pub fn set_term(&mut self, ms: u32) {
+        self.term_ms = ms;
+    }
+
+    /// Updates the layers in the layer stack (bit per layer, see crate::active_layers)
+    /// Used for keys pressed from now on, active combos are kept until released.
+    pub fn set_layers(&mut self, layers: u16) {
+        self.layers = layers | 1;
+    }
+
+    /// Combo is evaluated on the current layers
+    fn enabled(&self, combo: &Combo) -> bool {
+        self.layers.checked_shr(combo.layer.into()).unwrap_or(0) & 1 != 0
+    }
+
+    /// Passes a trigger event through the combo stage
+    pub fn process(&mut self, event: TriggerEvent, emit: &mut impl FnMut(TriggerEvent)) {
+        let (state, index) = match event {
+            TriggerEvent::Switch { state, index, .. } => (state, index),
+            _ => {
+                emit(event);
+                return;
+            }
+        };
+
+        // Keys of an active combo
+        if self.consumed(index) {
+            if state == Phro::Release {
+                self.release(index, emit);
+            }
+            return;
+        }
+
+        if !self
+            .combos
+            .iter()
+            .any(|combo| self.enabled(combo) && combo.keys.contains(&index))
+        {
+            // Any other key gives up on the held back keys
+            if state == Phro::Press {
+                self.flush_all(emit);
+            }
+            emit(event);
+            return;
+        }
+
+        match state {
+            Phro::Press => {
+                if self.buffer.is_full() {
+                    self.flush(emit);
+                }
+                if self.buffer.is_empty() {
+                    self.elapsed_us = 0;
+                }
+                self.buffer.push_back(event).ok();
+                self.check(false, emit);
+            }
+            // Released before the combo was completed
+            Phro::Release if self.buffered(index) => {
+                if self.buffer.push_back(event).is_err() {
+                    self.flush(emit);
+                    self.process(event, emit);
+                    return;
+                }
+                self.flush(emit);
+            }
+            // Hold events of held back presses are dropped (regenerated by the scan once replayed)
+            _ if self.buffered(index) => {}
+            _ => {
+                emit(event);
+            }
+        }
+    }
+
+    /// Advances the combo term timer, called once per full scan
+    pub fn tick(&mut self, elapsed_us: u32, emit: &mut impl FnMut(TriggerEvent)) {
+        for (combo, state) in self.combos.iter().zip(self.states.iter_mut()) {
+            if let Some(state) = state {
+                if !state.released {
+                    state.cycles = state.cycles.saturating_add(1);
+                    emit(Self::event(Phro::Hold, combo.virtual_switch, state.cycles));
+                }
+            }
+        }
+
+        if !self.buffer.is_empty() {
+            self.elapsed_us = self.elapsed_us.saturating_add(elapsed_us);
+            if self.elapsed_us >= self.term_ms.saturating_mul(1000) {
+                self.check(true, emit);
+            }
+        }
+    }
+
+    /// Triggers a completed combo, or gives up if no combo can be completed
+    /// Completed combos wait for longer combos until the combo term has passed.
+    fn check(&mut self, timeout: bool, emit: &mut impl FnMut(TriggerEvent)) {
+        let mut complete = None;
+        let mut possible = false;
+        for (i, combo) in self.combos.iter().enumerate() {
+            if self.states[i].is_some() || !self.enabled(combo) {
+                continue;
+            }
+            let pressed = combo.keys.iter().filter(|key| self.buffered(**key)).count();
+            if pressed == combo.keys.len() {
+                if complete.map_or(true, |c: usize| self.combos[c].keys.len() < pressed) {
+                    complete = Some(i);
+                }
+            } else if self.buffered_presses() == pressed {
+                // Every held back key is part of this (longer) combo
+                possible = true;
+            }
+        }
+
+        match complete {
+            Some(combo) if timeout || !possible => self.trigger(combo, emit),
+            None if timeout || !possible => self.flush(emit),
+            _ => {}
+        }
+    }
+
+    /// Presses the virtual switch of a combo, the held back presses of its keys are rolled back
+    fn trigger(&mut self, combo: usize, emit: &mut impl FnMut(TriggerEvent)) {
+        let keys = self.combos[combo].keys;
+        emit(Self::event(
+            Phro::Press,
+            self.combos[combo].virtual_switch,
+            0,
+        ));
+        self.states[combo] = Some(ComboState {
+            cycles: 0,
+            pressed: (1 << keys.len()) - 1,
+            released: false,
+        });
+
+        // Replay the remaining events (may start another combo)
+        let mut buffer = core::mem::replace(&mut self.buffer, Deque::new());
+        while let Some(event) = buffer.pop_front() {
+            match event {
+                TriggerEvent::Switch { index, .. } if keys.contains(&index) => {}
+                _ => self.process(event, emit),
+            }
+        }
+    }
+
+    /// Gives up on the first held back key and replays the remaining events
+    fn flush(&mut self, emit: &mut impl FnMut(TriggerEvent)) {
+        let mut buffer = core::mem::replace(&mut self.buffer, Deque::new());
+        if let Some(event) = buffer.pop_front() {
+            emit(event);
+        }
+        while let Some(event) = buffer.pop_front() {
+            self.process(event, emit);
+        }
+    }
+
+    /// Replays every held back event without waiting for a combo
+    fn flush_all(&mut self, emit: &mut impl FnMut(TriggerEvent)) {
+        while !self.buffer.is_empty() {
+            self.flush(emit);
+        }
+    }
+
+    /// Release of a key of an active combo, the virtual switch is released with the first key
+    fn release(&mut self, index: u16, emit: &mut impl FnMut(TriggerEvent)) {
+        for (combo, state) in self.combos.iter().zip(self.states.iter_mut()) {
+            let current = match state {
+                Some(current) => current,
+                None => continue,
+            };
+            let bit = match combo.keys.iter().position(|key| *key == index) {
+                Some(pos) => 1 << pos,
+                None => continue,
+            };
+            if current.pressed & bit == 0 {
+                continue;
+            }
+            current.pressed &= !bit;
+            if !current.released {
+                current.released = true;
+                emit(Self::event(
+                    Phro::Release,
+                    combo.virtual_switch,
+                    current.cycles,
+                ));
+            }
+            if current.pressed == 0 {
+                *state = None;
+            }
+        }
+    }
+
+    fn buffered_presses(&self) -> usize {
+        self.buffer
+            .iter()
+            .filter(|event| {
+                matches!(
+                    event,
+                    TriggerEvent::Switch {
+                        state: Phro::Press,
+                        ..
+                    }
+                )
+            })
+            .count()
+    }
+
+    /// Press of the switch is being held back
+    fn buffered(&self, index: u16) -> bool {
+        self.buffer.iter().any(|event| {
+            matches!(event, TriggerEvent::Switch { state: Phro::Press, index: i, .. } if *i == index)
+        })
+    }
+
+    /// Switch is a (still pressed) key of an active combo
+    fn consumed(&self, index: u16) -> bool {
+        self.combos
+            .iter()
+            .zip(self.states.iter())
+            .any(
+                |(combo, state)| match (state, combo.keys.iter().position(|key| *key == index)) {
+                    (Some(state), Some(pos)) => state.pressed & (1 << pos) != 0,
+                    _ => false,
+                },
+            )
+    }
+
+    fn event(state: Phro, virtual_switch: u16, cycles: u32) -> TriggerEvent {
+        TriggerEvent::Switch {
+            state,
+            index: virtual_switch,
+            last_state: cycles,
+        }
+    }
+
+    /// Switch events are held back or consumed by a combo
+    pub fn suppressed(&self, index: u16) -> bool {
+        self.buffered(index) || self.consumed(index)
+    }
+
+    /// Index is the virtual switch of a combo
+    pub fn is_virtual(&self, index: u16) -> bool {
+        self.combos
+            .iter()
+            .any(|combo| combo.virtual_switch == index)
+    }
+
+    /// Hold event of an active combo (used for off-state lookups)
+    /// Off events are not generated
+    pub fn virtual_event(&self, virtual_switch: u16) -> Option<TriggerEvent> {
+        self.combos
+            .iter()
+            .zip(self.states.iter())
+            .find_map(|(combo, state)| match state {
+                Some(state) if combo.virtual_switch == virtual_switch && !state.released => {
+                    Some(Self::event(Phro::Hold, virtual_switch, state.cycles))
+                }
+                _ => None,
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    extern crate std;
+    use std::vec::Vec;
+
+    const PAIR: &[Combo] = &[Combo {
+        keys: &[1, 2],
+        virtual_switch: 100,
+        layer: 0,
+    }];
+
+    const NESTED: &[Combo] = &[
+        Combo {
+            keys: &[1, 2],
+            virtual_switch: 100,
+            layer: 0,
+        },
+        Combo {
+            keys: &[1, 2, 3],
+            virtual_switch: 101,
+            layer: 0,
+        },
+    ];
+
+    const EIGHT: &[Combo] = &[Combo {
+        keys: &[1, 2, 3, 4, 5, 6, 7, 8],
+        virtual_switch: 100,
+        layer: 0,
+    }];
+
+    const LAYERED: &[Combo] = &[Combo {
+        keys: &[1, 2],
+        virtual_switch: 100,
+        layer: 1,
+    }];
+
+    fn switch(state: Phro, index: u16) -> TriggerEvent {
+        TriggerEvent::Switch {
+            state,
+            index,
+            last_state: 0,
+        }
+    }
+
+    /// Switch events of a timeline step (state, index)
+    fn events(out: Vec<TriggerEvent>) -> Vec<(Phro, u16)> {
+        out.into_iter()
+            .filter_map(|event| match event {
+                TriggerEvent::Switch { state, index, .. } => Some((state, index)),
+                _ => None,
+            })
+            .collect()
+    }
+
+    fn process(combos: &mut Combos, state: Phro, index: u16) -> Vec<(Phro, u16)> {
+        let mut out = Vec::new();
+        combos.process(switch(state, index), &mut |event| out.push(event));
+        events(out)
+    }
+
+    fn tick(combos: &mut Combos, elapsed_ms: u32) -> Vec<(Phro, u16)> {
+        let mut out = Vec::new();
+        combos.tick(elapsed_ms * 1000, &mut |event| out.push(event));
+        events(out)
+    }
+
+    #[test]
+    fn completed_within_term() {
+        let mut combos = Combos::new(PAIR);
+        assert_eq!(process(&mut combos, Phro::Press, 1), []);
+        assert_eq!(tick(&mut combos, 10), []);
+        assert_eq!(process(&mut combos, Phro::Press, 2), [(Phro::Press, 100)]);
+        assert_eq!(tick(&mut combos, 1), [(Phro::Hold, 100)]);
+        assert_eq!(process(&mut combos, Phro::Hold, 1), []);
+        assert_eq!(
+            process(&mut combos, Phro::Release, 1),
+            [(Phro::Release, 100)]
+        );
+        assert_eq!(tick(&mut combos, 1), []);
+        assert_eq!(process(&mut combos, Phro::Release, 2), []);
+        assert!(!combos.suppressed(1) && !combos.suppressed(2));
+    }
+
+    #[test]
+    fn timeout_replays() {
+        let mut combos = Combos::new(PAIR);
+        assert_eq!(process(&mut combos, Phro::Press, 1), []);
+        assert_eq!(process(&mut combos, Phro::Hold, 1), []);
+        assert_eq!(tick(&mut combos, COMBO_TERM_MS - 1), []);
+        assert_eq!(tick(&mut combos, 1), [(Phro::Press, 1)]);
+        // Too late for the combo
+        assert_eq!(process(&mut combos, Phro::Hold, 1), [(Phro::Hold, 1)]);
+        assert_eq!(process(&mut combos, Phro::Press, 2), []);
+        assert_eq!(tick(&mut combos, COMBO_TERM_MS), [(Phro::Press, 2)]);
+    }
+
+    #[test]
+    fn released_before_completion() {
+        let mut combos = Combos::new(PAIR);
+        assert_eq!(process(&mut combos, Phro::Press, 1), []);
+        assert_eq!(
+            process(&mut combos, Phro::Release, 1),
+            [(Phro::Press, 1), (Phro::Release, 1)]
+        );
+        assert_eq!(tick(&mut combos, COMBO_TERM_MS), []);
+    }
+
+    #[test]
+    fn longer_combo_wins() {
+        let mut combos = Combos::new(NESTED);
+        assert_eq!(process(&mut combos, Phro::Press, 1), []);
+        assert_eq!(process(&mut combos, Phro::Press, 2), []);
+        assert_eq!(process(&mut combos, Phro::Press, 3), [(Phro::Press, 101)]);
+        assert_eq!(
+            process(&mut combos, Phro::Release, 2),
+            [(Phro::Release, 101)]
+        );
+
+        // Shorter combo once the term has passed
+        let mut combos = Combos::new(NESTED);
+        assert_eq!(process(&mut combos, Phro::Press, 1), []);
+        assert_eq!(process(&mut combos, Phro::Press, 2), []);
+        assert_eq!(tick(&mut combos, COMBO_TERM_MS), [(Phro::Press, 100)]);
+        assert_eq!(process(&mut combos, Phro::Press, 3), []);
+    }
+
+    #[test]
+    fn unrelated_key_flushes() {
+        let mut combos = Combos::new(PAIR);
+        assert_eq!(process(&mut combos, Phro::Press, 1), []);
+        assert_eq!(
+            process(&mut combos, Phro::Press, 9),
+            [(Phro::Press, 1), (Phro::Press, 9)]
+        );
+        assert_eq!(process(&mut combos, Phro::Press, 2), []);
+        assert_eq!(tick(&mut combos, COMBO_TERM_MS), [(Phro::Press, 2)]);
+    }
+
+    /// The buffer only holds presses of a possible combo, at most MAX_COMBO_KEYS
+    /// (COMBO_BUFFER_SIZE) presses are held back
+    #[test]
+    fn full_buffer() {
+        let mut combos = Combos::new(EIGHT);
+        for key in 1..8 {
+            assert_eq!(process(&mut combos, Phro::Press, key), []);
+        }
+        assert_eq!(process(&mut combos, Phro::Press, 8), [(Phro::Press, 100)]);
+        assert_eq!(
+            process(&mut combos, Phro::Release, 8),
+            [(Phro::Release, 100)]
+        );
+        for key in 1..8 {
+            assert!(combos.suppressed(key));
+            assert_eq!(process(&mut combos, Phro::Release, key), []);
+        }
+        // Every key has been released, the combo can be used again
+        assert!(!combos.suppressed(1));
+        assert_eq!(tick(&mut combos, 1), []);
+
+        // Overflowing presses (duplicated) give up on the first held back key
+        let mut combos = Combos::new(EIGHT);
+        for key in 1..8 {
+            combos.buffer.push_back(switch(Phro::Press, key)).unwrap();
+        }
+        combos.buffer.push_back(switch(Phro::Press, 1)).unwrap();
+        assert_eq!(process(&mut combos, Phro::Press, 8)[0], (Phro::Press, 1));
+    }
+
+    #[test]
+    fn layer_combos() {
+        let mut combos = Combos::new(LAYERED);
+        assert_eq!(process(&mut combos, Phro::Press, 1), [(Phro::Press, 1)]);
+        assert_eq!(process(&mut combos, Phro::Press, 2), [(Phro::Press, 2)]);
+
+        let mut combos = Combos::new(LAYERED);
+        combos.set_layers(1 << 1);
+        assert_eq!(process(&mut combos, Phro::Press, 1), []);
+        assert_eq!(process(&mut combos, Phro::Press, 2), [(Phro::Press, 100)]);
+        // Kept until released
+        combos.set_layers(0);
+        assert_eq!(
+            process(&mut combos, Phro::Release, 1),
+            [(Phro::Release, 100)]
+        );
+    }
+}
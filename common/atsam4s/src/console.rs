@@ -0,0 +1,1062 @@
+// Copyright 2026 Jacob Alexander
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! Serial console command parser
+//!
+//! See terminal.rs for command handling and serial.rs for the UART driver.
+
+// ----- Constants -----
+
+pub const HELP: &str = "\
+Commands:
+  help                       This message
+  matrix                     Dump key matrix state
+  keystate                   Snapshot of every key and the layer stack
+  layers                     Dump layer stack and active layer
+  leds [chip]                Dump LED buffer (all chips if not specified)
+  hall                       Dump hall effect calibration
+  hallstats [index|reset]    Hall effect sensor statistics (bad sensors, or 16 keys from index)
+  profile [reset]            Task timing, scan to USB latency and overruns
+  adcnoise [reset]           Hall effect ADC noise for each ADC clock
+  adctrace [index]           Captured raw ADC buffers (8 buffers from index)
+  adctrace start <strobe>    Capture raw ADC buffers of a strobe (noise cancellation traces)
+  dks                        Dynamic keystroke (DKS) keys
+  dks <switch> <actuation> <bottom> <press> <bottom> <bottomrel> <release>
+                             Actions: - (none), <vswitch> (tap), <vswitch>h (hold)
+  dks clear <switch>
+  dks save                   Store the DKS keys in flash
+  macro                      Recorded dynamic macros
+  macro delete <slot>        Delete a dynamic macro (saved in flash)
+  keymap                     Keymap overlay entries
+  keymap get <layer> <switch>
+  keymap set <layer> <switch> <action>
+                             Actions: S<switch> (KLL switch), U<code> (USB HID key), none
+  keymap reset [<layer> <switch>]
+                             Remove an entry (all entries if not specified)
+  keymap save                Store the keymap overlay in flash
+  inject                     Host injected keys and lockout state
+  inject <switch> <press|release|tap>
+                             Inject a switch (KLL trigger index) event
+  inject release             Release all injected keys
+  set ledctrl <disable|start|pause>
+  set ledreset <soft|hard>
+  set ledtest <short|open>
+  set hallmode <normal|lowlatency|test>
+  set adcclock <12|20|30>    Hall effect ADC clock (MHz)
+  set samplefilter <mean|median|trimmed>
+  set socd <pair> <off|last|first|neutral>
+                             SOCD cleaning of an opposing key pair
+  set tapterm <ms>           Tap-hold tapping term
+  set comboterm <ms>         Combo (chord) term
+  set permissivehold <on|off>
+  set debounce <press_us> [release_us]
+  set keydebounce <index> <press_us> [release_us]
+  set keydebounce <index> default
+  set idle <ms>
+  bootloader                 Reset into the bootloader
+";
+
+/// Experimental commands, only listed (and parsed) when built with their feature
+#[cfg(feature = "noise-cancel")]
+pub const HELP_EXPERIMENTAL: &str = "\
+Experimental:
+  set noisecancel <on|off>   Hall effect channel 11 noise cancellation (noise-cancel feature)
+";
+
+// ----- Enums -----
+
+/// LED control mode (mirrors HID-IO h0021 control)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum LedControlMode {
+    Disable,
+    Start,
+    Pause,
+}
+
+/// LED driver reset type
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum LedResetMode {
+    Soft,
+    Hard,
+}
+
+/// LED driver manufacturing test
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum LedTestMode {
+    Short,
+    Open,
+}
+
+/// Hall effect sensor mode
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum HallMode {
+    Normal,
+    LowLatency,
+    Test,
+}
+
+/// Hall effect ADC clock
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum AdcClockMode {
+    Mhz12,
+    Mhz20,
+    Mhz30,
+}
+
+/// Hall effect sample filter (ADC_SAMPLES > 1)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum SampleFilterMode {
+    Mean,
+    Median,
+    TrimmedMean,
+}
+
+/// SOCD resolution of an opposing key pair
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum SocdModeArg {
+    Off,
+    LastInput,
+    FirstInput,
+    Neutral,
+}
+
+/// hallstats argument
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum HallStatsArg {
+    /// Only sensors flagged as bad
+    Bad,
+    /// Page of keys starting at the given index
+    From(u16),
+    Reset,
+}
+
+/// adctrace argument
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum AdcTraceArg {
+    /// Page of captured buffers starting at the given buffer
+    From(u16),
+    /// Start capturing the given strobe
+    Start(u8),
+}
+
+/// DKS action (virtual switch)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub struct DksActionArg {
+    pub virtual_switch: u16,
+    pub hold: bool,
+}
+
+/// dks argument
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum DksArg {
+    List,
+    /// Switch, actuation and bottom-out distance, action for each travel phase
+    Set {
+        switch: u16,
+        actuation: i16,
+        bottom_out: i16,
+        actions: [Option<DksActionArg>; 4],
+    },
+    Clear(u16),
+    Save,
+}
+
+/// macro argument
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum MacroArg {
+    List,
+    Delete(u8),
+}
+
+/// Keymap overlay action (mirrors keymap::KeymapAction)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum KeymapActionArg {
+    /// Switch (KLL trigger index) of the compiled layout
+    Switch(u16),
+    /// USB HID keyboard code
+    Key(u8),
+    Disabled,
+}
+
+/// keymap argument
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum KeymapArg {
+    List,
+    Get {
+        layer: u8,
+        switch: u16,
+    },
+    Set {
+        layer: u8,
+        switch: u16,
+        action: KeymapActionArg,
+    },
+    /// Layer and switch of the entry, None for all entries
+    Reset(Option<(u8, u16)>),
+    Save,
+}
+
+/// Injected switch event
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum InjectAction {
+    Press,
+    Release,
+    /// Press followed by a release
+    Tap,
+}
+
+/// inject argument
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum InjectArg {
+    Status,
+    Switch(u16, InjectAction),
+    ReleaseAll,
+}
+
+/// Press/release debounce times
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub struct DebounceTiming {
+    pub press_us: u32,
+    pub release_us: u32,
+}
+
+/// Runtime settings that can be changed from the console
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum Setting {
+    LedControl(LedControlMode),
+    LedReset(LedResetMode),
+    LedTest(LedTestMode),
+    HallMode(HallMode),
+    AdcClock(AdcClockMode),
+    #[cfg(feature = "noise-cancel")]
+    NoiseCancel(bool),
+    SampleFilter(SampleFilterMode),
+    /// SOCD pair (index of SOCD_PAIRS) and mode
+    Socd(u8, SocdModeArg),
+    /// Tap-hold tapping term in ms (keys without their own tapping term)
+    TappingTerm(u32),
+    PermissiveHold(bool),
+    /// Combo term in ms
+    ComboTerm(u32),
+    /// Debounce for all keys without an override
+    Debounce(DebounceTiming),
+    /// Per-key debounce override (matrix index), None to use the global debounce
+    KeyDebounce(u16, Option<DebounceTiming>),
+    /// Idle timeout in ms
+    Idle(u32),
+}
+
+/// Console commands
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum Command {
+    Help,
+    Matrix,
+    Keystate,
+    Layers,
+    /// Optional chip index
+    Leds(Option<u8>),
+    Hall,
+    HallStats(HallStatsArg),
+    /// Task profiling report, true to reset the statistics
+    Profile(bool),
+    /// ADC noise report, true to reset the statistics
+    AdcNoise(bool),
+    AdcTrace(AdcTraceArg),
+    Dks(DksArg),
+    Macro(MacroArg),
+    Keymap(KeymapArg),
+    Inject(InjectArg),
+    Set(Setting),
+    Bootloader,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum ParseError {
+    /// Empty line
+    Empty,
+    /// Unknown command
+    UnknownCommand,
+    /// Unknown setting name
+    UnknownSetting,
+    /// Missing argument
+    MissingArgument,
+    /// Invalid argument value
+    InvalidArgument,
+    /// Too many arguments
+    TooManyArguments,
+    /// Line exceeded the line buffer
+    LineTooLong,
+}
+
+impl ParseError {
+    /// Human readable error, printed on the console
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ParseError::Empty => "",
+            ParseError::UnknownCommand => "Unknown command (try 'help')",
+            ParseError::UnknownSetting => "Unknown setting (try 'help')",
+            ParseError::MissingArgument => "Missing argument",
+            ParseError::InvalidArgument => "Invalid argument",
+            ParseError::TooManyArguments => "Too many arguments",
+            ParseError::LineTooLong => "Line too long",
+        }
+    }
+
+    /// Stable identifier used by the HID-IO terminal status line
+    pub fn code(&self) -> &'static str {
+        match self {
+            ParseError::Empty => "empty",
+            ParseError::UnknownCommand => "unknown_command",
+            ParseError::UnknownSetting => "unknown_setting",
+            ParseError::MissingArgument => "missing_argument",
+            ParseError::InvalidArgument => "invalid_argument",
+            ParseError::TooManyArguments => "too_many_arguments",
+            ParseError::LineTooLong => "line_too_long",
+        }
+    }
+}
+
+// ----- Structs -----
+
+/// Line buffer for incoming console characters
+/// Handles backspace and line endings (CR, LF or CRLF)
+pub struct LineBuffer<const N: usize> {
+    buf: [u8; N],
+    len: usize,
+    overflow: bool,
+}
+
+/// Result of pushing a character into the LineBuffer
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum LineEvent {
+    /// Character was ignored
+    None,
+    /// Character was added (should be echoed)
+    Char(u8),
+    /// Last character was removed
+    Backspace,
+    /// Line is complete, use take_line() to retrieve it
+    Line,
+    /// Line was too long and has been discarded
+    Overflow,
+}
+
+impl<const N: usize> Default for LineBuffer<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<const N: usize> LineBuffer<N> {
+    pub const fn new() -> Self {
+        Self {
+            buf: [0; N],
+            len: 0,
+            overflow: false,
+        }
+    }
+
+    /// Adds a character to the buffer
+    /// After a LineEvent::Line, take_line() must be called before pushing more characters
+    pub fn push(&mut self, byte: u8) -> LineEvent {
+        match byte {
+            b'\r' | b'\n' => {
+                if self.overflow {
+                    self.overflow = false;
+                    self.len = 0;
+                    LineEvent::Overflow
+                } else if self.len > 0 {
+                    LineEvent::Line
+                } else {
+                    // Ignore empty lines (and the LF of CRLF)
+                    LineEvent::None
+                }
+            }
+            // Backspace and DEL
+            0x08 | 0x7F => {
+                if self.len > 0 && !self.overflow {
+                    self.len -= 1;
+                    LineEvent::Backspace
+                } else {
+                    LineEvent::None
+                }
+            }
+            // Printable ASCII
+            0x20..=0x7E => {
+                if self.len >= N {
+                    self.overflow = true;
+                    return LineEvent::None;
+                }
+                self.buf[self.len] = byte;
+                self.len += 1;
+                LineEvent::Char(byte)
+            }
+            _ => LineEvent::None,
+        }
+    }
+
+    /// Takes the current line, clearing the buffer
+    pub fn take_line(&mut self) -> &str {
+        let len = self.len;
+        self.len = 0;
+        // Only printable ASCII is ever stored
+        core::str::from_utf8(&self.buf[..len]).unwrap_or("")
+    }
+}
+
+// ----- Functions -----
+
+/// Parses a single console line into a command
+pub fn parse(line: &str) -> Result<Command, ParseError> {
+    let mut args = line.split_ascii_whitespace();
+    let cmd = args.next().ok_or(ParseError::Empty)?;
+
+    let ret = match cmd {
+        "help" | "?" => Command::Help,
+        "matrix" => Command::Matrix,
+        "keystate" => Command::Keystate,
+        "layers" => Command::Layers,
+        "leds" => Command::Leds(match args.next() {
+            Some(chip) => Some(chip.parse().map_err(|_| ParseError::InvalidArgument)?),
+            None => None,
+        }),
+        "hall" => Command::Hall,
+        "hallstats" => Command::HallStats(match args.next() {
+            None => HallStatsArg::Bad,
+            Some("reset") => HallStatsArg::Reset,
+            Some(index) => {
+                HallStatsArg::From(index.parse().map_err(|_| ParseError::InvalidArgument)?)
+            }
+        }),
+        "profile" => Command::Profile(parse_reset(&mut args)?),
+        "adcnoise" => Command::AdcNoise(parse_reset(&mut args)?),
+        "adctrace" => Command::AdcTrace(match args.next() {
+            None => AdcTraceArg::From(0),
+            Some("start") => AdcTraceArg::Start(parse_number(&mut args)?),
+            Some(index) => {
+                AdcTraceArg::From(index.parse().map_err(|_| ParseError::InvalidArgument)?)
+            }
+        }),
+        "dks" => Command::Dks(parse_dks(&mut args)?),
+        "macro" => Command::Macro(match args.next() {
+            None => MacroArg::List,
+            Some("delete") => MacroArg::Delete(parse_number(&mut args)?),
+            Some(_) => {
+                return Err(ParseError::InvalidArgument);
+            }
+        }),
+        "keymap" => Command::Keymap(parse_keymap(&mut args)?),
+        "inject" => Command::Inject(match args.next() {
+            None => InjectArg::Status,
+            Some("release") => InjectArg::ReleaseAll,
+            Some(switch) => InjectArg::Switch(
+                switch.parse().map_err(|_| ParseError::InvalidArgument)?,
+                match args.next().ok_or(ParseError::MissingArgument)? {
+                    "press" => InjectAction::Press,
+                    "release" => InjectAction::Release,
+                    "tap" => InjectAction::Tap,
+                    _ => {
+                        return Err(ParseError::InvalidArgument);
+                    }
+                },
+            ),
+        }),
+        "set" => {
+            let setting = args.next().ok_or(ParseError::MissingArgument)?;
+            Command::Set(parse_setting(setting, &mut args)?)
+        }
+        "bootloader" => Command::Bootloader,
+        _ => {
+            return Err(ParseError::UnknownCommand);
+        }
+    };
+
+    if args.next().is_some() {
+        return Err(ParseError::TooManyArguments);
+    }
+    Ok(ret)
+}
+
+/// Parses an optional `reset` argument
+fn parse_reset<'a>(args: &mut impl Iterator<Item = &'a str>) -> Result<bool, ParseError> {
+    match args.next() {
+        Some("reset") => Ok(true),
+        Some(_) => Err(ParseError::InvalidArgument),
+        None => Ok(false),
+    }
+}
+
+fn parse_number<'a, T: core::str::FromStr>(
+    args: &mut impl Iterator<Item = &'a str>,
+) -> Result<T, ParseError> {
+    args.next()
+        .ok_or(ParseError::MissingArgument)?
+        .parse()
+        .map_err(|_| ParseError::InvalidArgument)
+}
+
+/// Parses the dks arguments
+fn parse_dks<'a>(args: &mut impl Iterator<Item = &'a str>) -> Result<DksArg, ParseError> {
+    let mut args = args.peekable();
+    match args.peek() {
+        None => {
+            return Ok(DksArg::List);
+        }
+        Some(&"clear") => {
+            args.next();
+            return Ok(DksArg::Clear(parse_number(&mut args)?));
+        }
+        Some(&"save") => {
+            args.next();
+            return Ok(DksArg::Save);
+        }
+        Some(_) => {}
+    }
+
+    let switch = parse_number(&mut args)?;
+    let actuation = parse_number(&mut args)?;
+    let bottom_out = parse_number(&mut args)?;
+    let mut actions = [None; 4];
+    for action in actions.iter_mut() {
+        let arg = args.next().ok_or(ParseError::MissingArgument)?;
+        if arg == "-" {
+            continue;
+        }
+        let (virtual_switch, hold) = match arg.strip_suffix('h') {
+            Some(virtual_switch) => (virtual_switch, true),
+            None => (arg, false),
+        };
+        *action = Some(DksActionArg {
+            virtual_switch: virtual_switch
+                .parse()
+                .map_err(|_| ParseError::InvalidArgument)?,
+            hold,
+        });
+    }
+    if args.next().is_some() {
+        return Err(ParseError::TooManyArguments);
+    }
+
+    Ok(DksArg::Set {
+        switch,
+        actuation,
+        bottom_out,
+        actions,
+    })
+}
+
+/// Parses the keymap arguments
+fn parse_keymap<'a>(args: &mut impl Iterator<Item = &'a str>) -> Result<KeymapArg, ParseError> {
+    Ok(match args.next() {
+        None => KeymapArg::List,
+        Some("get") => KeymapArg::Get {
+            layer: parse_number(args)?,
+            switch: parse_number(args)?,
+        },
+        Some("set") => KeymapArg::Set {
+            layer: parse_number(args)?,
+            switch: parse_number(args)?,
+            action: parse_keymap_action(args.next().ok_or(ParseError::MissingArgument)?)?,
+        },
+        Some("reset") => KeymapArg::Reset(match args.next() {
+            Some(layer) => Some((
+                layer.parse().map_err(|_| ParseError::InvalidArgument)?,
+                parse_number(args)?,
+            )),
+            None => None,
+        }),
+        Some("save") => KeymapArg::Save,
+        Some(_) => {
+            return Err(ParseError::InvalidArgument);
+        }
+    })
+}
+
+/// Parses a keymap action: `S<switch>`, `U<code>` or `none`
+fn parse_keymap_action(arg: &str) -> Result<KeymapActionArg, ParseError> {
+    if arg == "none" {
+        return Ok(KeymapActionArg::Disabled);
+    }
+    let (kind, value) = arg.split_at(arg.len().min(1));
+    match kind {
+        "S" => value.parse().map(KeymapActionArg::Switch),
+        "U" => value.parse().map(KeymapActionArg::Key),
+        _ => {
+            return Err(ParseError::InvalidArgument);
+        }
+    }
+    .map_err(|_| ParseError::InvalidArgument)
+}
+
+/// Parses `<press_us> [release_us]`, release defaults to press
+fn parse_debounce<'a>(
+    args: &mut core::iter::Peekable<impl Iterator<Item = &'a str>>,
+) -> Result<DebounceTiming, ParseError> {
+    let press_us = parse_number(args)?;
+    let release_us = match args.peek() {
+        Some(_) => parse_number(args)?,
+        None => press_us,
+    };
+    Ok(DebounceTiming {
+        press_us,
+        release_us,
+    })
+}
+
+fn parse_setting<'a>(
+    setting: &str,
+    args: &mut impl Iterator<Item = &'a str>,
+) -> Result<Setting, ParseError> {
+    // Settings with numeric arguments
+    match setting {
+        "debounce" => {
+            return Ok(Setting::Debounce(parse_debounce(&mut args.peekable())?));
+        }
+        "keydebounce" => {
+            let index = parse_number(args)?;
+            let mut args = args.peekable();
+            if args.peek() == Some(&"default") {
+                args.next();
+                return Ok(Setting::KeyDebounce(index, None));
+            }
+            return Ok(Setting::KeyDebounce(
+                index,
+                Some(parse_debounce(&mut args)?),
+            ));
+        }
+        "idle" => {
+            return Ok(Setting::Idle(parse_number(args)?));
+        }
+        "tapterm" => {
+            return Ok(Setting::TappingTerm(parse_number(args)?));
+        }
+        "comboterm" => {
+            return Ok(Setting::ComboTerm(parse_number(args)?));
+        }
+        "socd" => {
+            let pair = parse_number(args)?;
+            let mode = match args.next().ok_or(ParseError::MissingArgument)? {
+                "off" => SocdModeArg::Off,
+                "last" => SocdModeArg::LastInput,
+                "first" => SocdModeArg::FirstInput,
+                "neutral" => SocdModeArg::Neutral,
+                _ => {
+                    return Err(ParseError::InvalidArgument);
+                }
+            };
+            return Ok(Setting::Socd(pair, mode));
+        }
+        "ledctrl" | "ledreset" | "ledtest" | "hallmode" | "adcclock" | "samplefilter"
+        | "permissivehold" => {}
+        #[cfg(feature = "noise-cancel")]
+        "noisecancel" => {}
+        _ => {
+            return Err(ParseError::UnknownSetting);
+        }
+    }
+    let value = args.next().ok_or(ParseError::MissingArgument)?;
+
+    Ok(match (setting, value) {
+        ("ledctrl", "disable") => Setting::LedControl(LedControlMode::Disable),
+        ("ledctrl", "start") => Setting::LedControl(LedControlMode::Start),
+        ("ledctrl", "pause") => Setting::LedControl(LedControlMode::Pause),
+        ("ledreset", "soft") => Setting::LedReset(LedResetMode::Soft),
+        ("ledreset", "hard") => Setting::LedReset(LedResetMode::Hard),
+        ("ledtest", "short") => Setting::LedTest(LedTestMode::Short),
+        ("ledtest", "open") => Setting::LedTest(LedTestMode::Open),
+        ("hallmode", "normal") => Setting::HallMode(HallMode::Normal),
+        ("hallmode", "lowlatency") => Setting::HallMode(HallMode::LowLatency),
+        ("hallmode", "test") => Setting::HallMode(HallMode::Test),
+        ("adcclock", "12") => Setting::AdcClock(AdcClockMode::Mhz12),
+        ("adcclock", "20") => Setting::AdcClock(AdcClockMode::Mhz20),
+        ("adcclock", "30") => Setting::AdcClock(AdcClockMode::Mhz30),
+        #[cfg(feature = "noise-cancel")]
+        ("noisecancel", "on") => Setting::NoiseCancel(true),
+        #[cfg(feature = "noise-cancel")]
+        ("noisecancel", "off") => Setting::NoiseCancel(false),
+        ("permissivehold", "on") => Setting::PermissiveHold(true),
+        ("permissivehold", "off") => Setting::PermissiveHold(false),
+        ("samplefilter", "mean") => Setting::SampleFilter(SampleFilterMode::Mean),
+        ("samplefilter", "median") => Setting::SampleFilter(SampleFilterMode::Median),
+        ("samplefilter", "trimmed") => Setting::SampleFilter(SampleFilterMode::TrimmedMean),
+        _ => {
+            return Err(ParseError::InvalidArgument);
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn set(line: &str) -> Result<Setting, ParseError> {
+        match parse(line)? {
+            Command::Set(setting) => Ok(setting),
+            command => panic!("{line}: {command:?}"),
+        }
+    }
+
+    fn debounce(press_us: u32, release_us: u32) -> DebounceTiming {
+        DebounceTiming {
+            press_us,
+            release_us,
+        }
+    }
+
+    #[test]
+    fn commands() {
+        assert_eq!(parse("help"), Ok(Command::Help));
+        assert_eq!(parse("?"), Ok(Command::Help));
+        assert_eq!(parse("matrix"), Ok(Command::Matrix));
+        assert_eq!(parse("keystate"), Ok(Command::Keystate));
+        assert_eq!(parse("layers"), Ok(Command::Layers));
+        assert_eq!(parse("leds"), Ok(Command::Leds(None)));
+        assert_eq!(parse("leds 2"), Ok(Command::Leds(Some(2))));
+        assert_eq!(parse("hall"), Ok(Command::Hall));
+        assert_eq!(
+            parse("hallstats"),
+            Ok(Command::HallStats(HallStatsArg::Bad))
+        );
+        assert_eq!(
+            parse("hallstats 16"),
+            Ok(Command::HallStats(HallStatsArg::From(16)))
+        );
+        assert_eq!(
+            parse("hallstats reset"),
+            Ok(Command::HallStats(HallStatsArg::Reset))
+        );
+        assert_eq!(parse("profile"), Ok(Command::Profile(false)));
+        assert_eq!(parse("profile reset"), Ok(Command::Profile(true)));
+        assert_eq!(parse("adcnoise"), Ok(Command::AdcNoise(false)));
+        assert_eq!(parse("adcnoise reset"), Ok(Command::AdcNoise(true)));
+        assert_eq!(
+            parse("adctrace"),
+            Ok(Command::AdcTrace(AdcTraceArg::From(0)))
+        );
+        assert_eq!(
+            parse("adctrace 8"),
+            Ok(Command::AdcTrace(AdcTraceArg::From(8)))
+        );
+        assert_eq!(
+            parse("adctrace start 3"),
+            Ok(Command::AdcTrace(AdcTraceArg::Start(3)))
+        );
+        assert_eq!(parse("bootloader"), Ok(Command::Bootloader));
+
+        // Surrounding and repeated whitespace is ignored
+        assert_eq!(parse("  leds \t 3 "), Ok(Command::Leds(Some(3))));
+    }
+
+    #[test]
+    fn malformed_commands() {
+        assert_eq!(parse(""), Err(ParseError::Empty));
+        assert_eq!(parse("   "), Err(ParseError::Empty));
+        assert_eq!(parse("reboot"), Err(ParseError::UnknownCommand));
+        assert_eq!(parse("Matrix"), Err(ParseError::UnknownCommand));
+        assert_eq!(parse("matrix 1"), Err(ParseError::TooManyArguments));
+        assert_eq!(parse("bootloader now"), Err(ParseError::TooManyArguments));
+        assert_eq!(parse("leds x"), Err(ParseError::InvalidArgument));
+        assert_eq!(parse("leds 256"), Err(ParseError::InvalidArgument));
+        assert_eq!(parse("leds 1 2"), Err(ParseError::TooManyArguments));
+        assert_eq!(parse("hallstats -1"), Err(ParseError::InvalidArgument));
+        assert_eq!(parse("profile clear"), Err(ParseError::InvalidArgument));
+        assert_eq!(parse("adcnoise reset 1"), Err(ParseError::TooManyArguments));
+        assert_eq!(parse("adctrace start"), Err(ParseError::MissingArgument));
+        assert_eq!(parse("adctrace start x"), Err(ParseError::InvalidArgument));
+        assert_eq!(parse("adctrace x"), Err(ParseError::InvalidArgument));
+    }
+
+    #[test]
+    fn dks() {
+        assert_eq!(parse("dks"), Ok(Command::Dks(DksArg::List)));
+        assert_eq!(parse("dks save"), Ok(Command::Dks(DksArg::Save)));
+        assert_eq!(parse("dks clear 12"), Ok(Command::Dks(DksArg::Clear(12))));
+        let action = |virtual_switch, hold| {
+            Some(DksActionArg {
+                virtual_switch,
+                hold,
+            })
+        };
+        assert_eq!(
+            parse("dks 12 -100 300 - 200 201h -"),
+            Ok(Command::Dks(DksArg::Set {
+                switch: 12,
+                actuation: -100,
+                bottom_out: 300,
+                actions: [None, action(200, false), action(201, true), None],
+            }))
+        );
+
+        assert_eq!(parse("dks clear"), Err(ParseError::MissingArgument));
+        assert_eq!(parse("dks clear x"), Err(ParseError::InvalidArgument));
+        assert_eq!(parse("dks save 1"), Err(ParseError::TooManyArguments));
+        assert_eq!(
+            parse("dks 12 100 300 - -"),
+            Err(ParseError::MissingArgument)
+        );
+        assert_eq!(
+            parse("dks 12 100 300 - - - xh"),
+            Err(ParseError::InvalidArgument)
+        );
+        assert_eq!(
+            parse("dks 12 100 300 - - - h"),
+            Err(ParseError::InvalidArgument)
+        );
+        assert_eq!(
+            parse("dks 12 100 300 - - - - -"),
+            Err(ParseError::TooManyArguments)
+        );
+        assert_eq!(
+            parse("dks 12 40000 300 - - - -"),
+            Err(ParseError::InvalidArgument)
+        );
+    }
+
+    #[test]
+    fn dynamic_macros() {
+        assert_eq!(parse("macro"), Ok(Command::Macro(MacroArg::List)));
+        assert_eq!(
+            parse("macro delete 2"),
+            Ok(Command::Macro(MacroArg::Delete(2)))
+        );
+
+        assert_eq!(parse("macro delete"), Err(ParseError::MissingArgument));
+        assert_eq!(parse("macro delete x"), Err(ParseError::InvalidArgument));
+        assert_eq!(parse("macro delete 1 2"), Err(ParseError::TooManyArguments));
+        assert_eq!(parse("macro play 1"), Err(ParseError::InvalidArgument));
+    }
+
+    #[test]
+    fn keymap() {
+        assert_eq!(parse("keymap"), Ok(Command::Keymap(KeymapArg::List)));
+        assert_eq!(
+            parse("keymap get 1 40"),
+            Ok(Command::Keymap(KeymapArg::Get {
+                layer: 1,
+                switch: 40
+            }))
+        );
+        for (arg, action) in [
+            ("S10", KeymapActionArg::Switch(10)),
+            ("U4", KeymapActionArg::Key(4)),
+            ("none", KeymapActionArg::Disabled),
+        ] {
+            assert_eq!(
+                parse(&["keymap set 0 3 ", arg].concat()),
+                Ok(Command::Keymap(KeymapArg::Set {
+                    layer: 0,
+                    switch: 3,
+                    action
+                }))
+            );
+        }
+        assert_eq!(
+            parse("keymap reset"),
+            Ok(Command::Keymap(KeymapArg::Reset(None)))
+        );
+        assert_eq!(
+            parse("keymap reset 1 40"),
+            Ok(Command::Keymap(KeymapArg::Reset(Some((1, 40)))))
+        );
+        assert_eq!(parse("keymap save"), Ok(Command::Keymap(KeymapArg::Save)));
+
+        assert_eq!(parse("keymap get 1"), Err(ParseError::MissingArgument));
+        assert_eq!(parse("keymap get x 1"), Err(ParseError::InvalidArgument));
+        assert_eq!(parse("keymap set 0 3"), Err(ParseError::MissingArgument));
+        assert_eq!(parse("keymap set 0 3 X1"), Err(ParseError::InvalidArgument));
+        assert_eq!(parse("keymap set 0 3 S"), Err(ParseError::InvalidArgument));
+        assert_eq!(
+            parse("keymap set 0 3 U256"),
+            Err(ParseError::InvalidArgument)
+        );
+        assert_eq!(
+            parse("keymap set 0 3 S1 S2"),
+            Err(ParseError::TooManyArguments)
+        );
+        assert_eq!(parse("keymap reset 1"), Err(ParseError::MissingArgument));
+        assert_eq!(parse("keymap clear"), Err(ParseError::InvalidArgument));
+    }
+
+    #[test]
+    fn inject() {
+        assert_eq!(parse("inject"), Ok(Command::Inject(InjectArg::Status)));
+        assert_eq!(
+            parse("inject release"),
+            Ok(Command::Inject(InjectArg::ReleaseAll))
+        );
+        for (arg, action) in [
+            ("press", InjectAction::Press),
+            ("release", InjectAction::Release),
+            ("tap", InjectAction::Tap),
+        ] {
+            assert_eq!(
+                parse(&["inject 5 ", arg].concat()),
+                Ok(Command::Inject(InjectArg::Switch(5, action)))
+            );
+        }
+
+        assert_eq!(parse("inject 5"), Err(ParseError::MissingArgument));
+        assert_eq!(parse("inject 5 hold"), Err(ParseError::InvalidArgument));
+        assert_eq!(parse("inject x press"), Err(ParseError::InvalidArgument));
+        assert_eq!(parse("inject 5 tap 6"), Err(ParseError::TooManyArguments));
+        assert_eq!(parse("inject release 5"), Err(ParseError::TooManyArguments));
+    }
+
+    #[test]
+    fn settings() {
+        for (line, setting) in [
+            (
+                "ledctrl disable",
+                Setting::LedControl(LedControlMode::Disable),
+            ),
+            ("ledctrl start", Setting::LedControl(LedControlMode::Start)),
+            ("ledctrl pause", Setting::LedControl(LedControlMode::Pause)),
+            ("ledreset soft", Setting::LedReset(LedResetMode::Soft)),
+            ("ledreset hard", Setting::LedReset(LedResetMode::Hard)),
+            ("ledtest short", Setting::LedTest(LedTestMode::Short)),
+            ("ledtest open", Setting::LedTest(LedTestMode::Open)),
+            ("hallmode normal", Setting::HallMode(HallMode::Normal)),
+            (
+                "hallmode lowlatency",
+                Setting::HallMode(HallMode::LowLatency),
+            ),
+            ("hallmode test", Setting::HallMode(HallMode::Test)),
+            ("adcclock 12", Setting::AdcClock(AdcClockMode::Mhz12)),
+            ("adcclock 20", Setting::AdcClock(AdcClockMode::Mhz20)),
+            ("adcclock 30", Setting::AdcClock(AdcClockMode::Mhz30)),
+            (
+                "samplefilter mean",
+                Setting::SampleFilter(SampleFilterMode::Mean),
+            ),
+            (
+                "samplefilter median",
+                Setting::SampleFilter(SampleFilterMode::Median),
+            ),
+            (
+                "samplefilter trimmed",
+                Setting::SampleFilter(SampleFilterMode::TrimmedMean),
+            ),
+            ("socd 0 off", Setting::Socd(0, SocdModeArg::Off)),
+            ("socd 1 last", Setting::Socd(1, SocdModeArg::LastInput)),
+            ("socd 1 first", Setting::Socd(1, SocdModeArg::FirstInput)),
+            ("socd 1 neutral", Setting::Socd(1, SocdModeArg::Neutral)),
+            ("tapterm 250", Setting::TappingTerm(250)),
+            ("comboterm 40", Setting::ComboTerm(40)),
+            ("permissivehold on", Setting::PermissiveHold(true)),
+            ("permissivehold off", Setting::PermissiveHold(false)),
+            ("debounce 5000", Setting::Debounce(debounce(5000, 5000))),
+            (
+                "debounce 5000 8000",
+                Setting::Debounce(debounce(5000, 8000)),
+            ),
+            ("keydebounce 7 default", Setting::KeyDebounce(7, None)),
+            (
+                "keydebounce 7 100",
+                Setting::KeyDebounce(7, Some(debounce(100, 100))),
+            ),
+            (
+                "keydebounce 7 100 200",
+                Setting::KeyDebounce(7, Some(debounce(100, 200))),
+            ),
+            ("idle 60000", Setting::Idle(60000)),
+        ] {
+            assert_eq!(set(&["set ", line].concat()), Ok(setting), "{line}");
+        }
+    }
+
+    #[test]
+    fn noise_cancel_setting() {
+        #[cfg(feature = "noise-cancel")]
+        {
+            assert_eq!(set("set noisecancel on"), Ok(Setting::NoiseCancel(true)));
+            assert_eq!(set("set noisecancel off"), Ok(Setting::NoiseCancel(false)));
+            assert_eq!(set("set noisecancel 1"), Err(ParseError::InvalidArgument));
+        }
+        // Experimental, unknown without the noise-cancel feature
+        #[cfg(not(feature = "noise-cancel"))]
+        assert_eq!(set("set noisecancel on"), Err(ParseError::UnknownSetting));
+    }
+
+    #[test]
+    fn malformed_settings() {
+        for (line, err) in [
+            ("set", ParseError::MissingArgument),
+            ("set brightness 10", ParseError::UnknownSetting),
+            ("set ledctrl", ParseError::MissingArgument),
+            ("set ledctrl stop", ParseError::InvalidArgument),
+            ("set ledctrl start now", ParseError::TooManyArguments),
+            ("set hallmode fast", ParseError::InvalidArgument),
+            ("set adcclock 22", ParseError::InvalidArgument),
+            ("set samplefilter", ParseError::MissingArgument),
+            ("set socd 0", ParseError::MissingArgument),
+            ("set socd x off", ParseError::InvalidArgument),
+            ("set socd 0 both", ParseError::InvalidArgument),
+            ("set socd 256 off", ParseError::InvalidArgument),
+            ("set tapterm", ParseError::MissingArgument),
+            ("set tapterm -1", ParseError::InvalidArgument),
+            ("set comboterm 1 2", ParseError::TooManyArguments),
+            ("set permissivehold yes", ParseError::InvalidArgument),
+            ("set debounce", ParseError::MissingArgument),
+            ("set debounce 1 x", ParseError::InvalidArgument),
+            ("set debounce 1 2 3", ParseError::TooManyArguments),
+            ("set keydebounce", ParseError::MissingArgument),
+            ("set keydebounce 7", ParseError::MissingArgument),
+            ("set keydebounce 7 default 1", ParseError::TooManyArguments),
+            ("set idle soon", ParseError::InvalidArgument),
+        ] {
+            assert_eq!(parse(line), Err(err), "{line}");
+        }
+    }
+
+    #[test]
+    fn line_buffer() {
+        let mut buffer = LineBuffer::<8>::new();
+        for byte in *b"ledz" {
+            assert_eq!(buffer.push(byte), LineEvent::Char(byte));
+        }
+        assert_eq!(buffer.push(0x7F), LineEvent::Backspace);
+        assert_eq!(buffer.push(b's'), LineEvent::Char(b's'));
+
+        // Control characters are ignored
+        assert_eq!(buffer.push(0x1B), LineEvent::None);
+        assert_eq!(buffer.push(b'\t'), LineEvent::None);
+        assert_eq!(buffer.push(b'\r'), LineEvent::Line);
+        assert_eq!(buffer.take_line(), "leds");
+
+        // LF of CRLF and empty lines
+        assert_eq!(buffer.push(b'\n'), LineEvent::None);
+        assert_eq!(buffer.push(b'\r'), LineEvent::None);
+
+        // Backspace on an empty line
+        assert_eq!(buffer.push(0x08), LineEvent::None);
+        assert_eq!(buffer.push(b'x'), LineEvent::Char(b'x'));
+        assert_eq!(buffer.push(b'\n'), LineEvent::Line);
+        assert_eq!(buffer.take_line(), "x");
+    }
+
+    #[test]
+    fn line_buffer_overflow() {
+        let mut buffer = LineBuffer::<8>::new();
+        for byte in *b"keystate" {
+            assert_eq!(buffer.push(byte), LineEvent::Char(byte));
+        }
+
+        // The rest of the line is dropped, backspace doesn't recover it
+        assert_eq!(buffer.push(b' '), LineEvent::None);
+        assert_eq!(buffer.push(b'1'), LineEvent::None);
+        assert_eq!(buffer.push(0x7F), LineEvent::None);
+        assert_eq!(buffer.push(b'\r'), LineEvent::Overflow);
+        assert_eq!(buffer.push(b'\n'), LineEvent::None);
+
+        // The next line starts empty
+        for byte in *b"matrix" {
+            assert_eq!(buffer.push(byte), LineEvent::Char(byte));
+        }
+        assert_eq!(buffer.push(b'\r'), LineEvent::Line);
+        assert_eq!(buffer.take_line(), "matrix");
+    }
+}
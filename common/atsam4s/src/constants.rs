@@ -9,7 +9,7 @@ use atsam4_hal::timer::ClockSource;
 use const_env::from_env;
 
 #[cfg(feature = "hall-effect")]
-use crate::hall_effect::{AdcClock, SensorMode, SILO_ATSAM4S_LC605_GAIN_4X};
+use crate::hall_effect::{AdcClock, SampleFilter, SensorMode, SILO_ATSAM4S_LC605_GAIN_4X};
 
 // ----- Flash Config -----
 
@@ -41,8 +41,11 @@ pub const SERIALIZATION_LEN: usize = 277;
 pub const TX_BUF: usize = 8;
 
 // Hall Effect Constants
-pub const ADC_SAMPLES: usize = 1; // Number of samples per key per strobe
-                                  // for the previous strobe's last sample)
+// Number of samples per key per strobe (ADC_BUF_SIZE = ADC_SAMPLES * 2 * RSIZE)
+// The interleaved ADC sequence is repeated for each sample, more samples reduce noise (see
+// DEFAULT_SAMPLE_FILTER) but lengthen the strobe period.
+#[from_env]
+pub const ADC_SAMPLES: usize = 1;
 pub const MAX_DEVIATION: usize = 32; // Maximum deviation between samples taken with a buffer
                                      // Used to reject outlier samples affected by noise
 pub const IDLE_LIMIT: usize = 40_000; // Number of idle samples before a key is considered idle
@@ -51,12 +54,34 @@ pub const IDLE_LIMIT: usize = 40_000; // Number of idle samples before a key is
 pub const DEFAULT_ACTIVATION_DIST: i16 = 223;
 // Distance on the sensor to deactivate the switch (calibrated distance, not raw)
 pub const DEFAULT_DEACTIVATION_DIST: i16 = 123;
+// Sensor statistics limits, sensors exceeding any of these are flagged as bad
+pub const SENSOR_MAX_VARIANCE: u32 = 16; // Variance of samples at rest
+pub const SENSOR_MAX_REJECTED_PERCENT: u64 = 1; // Rejected samples
+pub const SENSOR_MAX_CROSSTALK: u16 = 32; // Mean channel 11 reading after the sensor
+pub const HALL_STATS_PER_PAGE: usize = 16; // Keys per hallstats page (terminal output size)
+// Channel 11 common-mode noise cancellation (experimental, set noisecancel with the noise-cancel feature)
+pub const DEFAULT_NOISE_CANCEL: bool = false;
+pub const NOISE_CANCEL_BASELINE_SHIFT: u32 = 8; // Baseline averaging (2^N scans per strobe)
+pub const ADC_TRACE_SIZE: usize = 768; // Raw ADC words captured by adctrace (trace replay)
+pub const ADC_TRACE_PER_PAGE: usize = 8; // Buffers per adctrace page (terminal output size)
+// USB 2.0 FS frame (SOF) period
+pub const USB_FRAME_US: u32 = 1000;
+// Full scans finish at least this long before the next SOF (leaves time for macro processing)
+pub const SOF_GUARD_US: u32 = 100;
+// Strobe period margin over the measured ADC sequence time (1 / N)
+pub const ADC_SEQUENCE_MARGIN: u32 = 4;
+// Falls back to a free-running scan if no SOF is received within this many frames
+pub const SOF_TIMEOUT_FRAMES: u32 = 3;
 
 #[cfg(feature = "hall-effect")]
 pub const DEFAULT_ADC_ANALYSIS_MODE: SensorMode =
     SensorMode::LowLatency(&SILO_ATSAM4S_LC605_GAIN_4X);
+// 20 MHz is the fastest clock within the 22 MHz ADC specification that also meets the minimum
+// tracking time (1300 ns vs 1285 ns), 30 MHz can be selected at runtime (set adcclock)
+#[cfg(feature = "hall-effect")]
+pub const DEFAULT_ADC_CLOCK: AdcClock = AdcClock::Mhz20;
 #[cfg(feature = "hall-effect")]
-pub const DEFAULT_ADC_CLOCK: AdcClock = AdcClock::Mhz30;
+pub const DEFAULT_SAMPLE_FILTER: SampleFilter = SampleFilter::Median;
 
 pub const INVERT_STROBE: bool = true; // P-Mosfets need to be inverted
 pub const ISSI_DRIVER_CHANNELS: usize = 198;
@@ -70,6 +95,12 @@ pub const LED_MASK_SIZE: usize = 2; // Number of indicator LEDs used in the ISSI
 pub const SPI_TX_BUF_SIZE: usize = 512;
 // Size is determined by the largest SPI rx transaction
 pub const SPI_RX_BUF_SIZE: usize = (32 + 2) * ISSI_DRIVER_CHIPS;
+// I2C (TWI) bus frequency for the ISSI LED drivers
+pub const TWI_FREQ: u32 = 400_000;
+// Size is determined by the largest I2C tx transaction (register + pwm page)
+pub const TWI_TX_BUF_SIZE: usize = ISSI_DRIVER_CHANNELS + 1;
+// Size is determined by the largest I2C rx transaction (open/short registers)
+pub const TWI_RX_BUF_SIZE: usize = 33;
 
 pub const CTRL_QUEUE_SIZE: usize = 5;
 pub const KBD_QUEUE_SIZE: usize = 25;
@@ -77,7 +108,25 @@ pub const KBD_LED_QUEUE_SIZE: usize = 3;
 pub const MOUSE_QUEUE_SIZE: usize = 10;
 pub const USB_STATE_QUEUE_SIZE: usize = 2;
 
+// VIA Constants (raw HID configuration, see via.rs)
+#[cfg(feature = "via")]
+pub const VIA_LAYERS: usize = 4; // Layers that can be edited
+#[cfg(feature = "via")]
+pub const VIA_MATRIX_COLS: usize = 16; // switch = row * VIA_MATRIX_COLS + col
+#[cfg(feature = "via")]
+pub const VIA_MACRO_BUFFER_SIZE: usize = 384; // Serialized dynamic macros (all slots)
+#[cfg(feature = "via")]
+pub const KEYMAP_SAVE_DELAY_TICKS: u8 = 4; // RTT ticks (500 ms) without keymap edits before saving
+
+// Console Constants (serial and HID-IO terminal)
+pub const CONSOLE_BAUD: u32 = 115_200;
+pub const CONSOLE_LINE_SIZE: usize = 64; // Longest accepted command line
+pub const CONSOLE_TX_BUF_SIZE: usize = 1024; // Output is dropped if the buffer is full
+pub const TERMINAL_OUT_BUF_SIZE: usize = 2048; // HID-IO terminal output (keystate snapshot + status line)
+pub const TERMINAL_BOOTLOADER_DELAY: u32 = 100; // hidio_terminal_task calls after the last output before resetting (USB tx)
+
 // Keyscanning Constants
+// Defaults, can be changed at runtime (set debounce/keydebounce/idle)
 pub const DEBOUNCE_US: u32 = 5000; // 5 ms TODO Tuning
 pub const IDLE_MS: u32 = 600_000; // 600 seconds TODO Tuning
 
@@ -90,6 +139,32 @@ pub const MAX_LAYER_STACK_CACHE: usize = 64;
 pub const MAX_LAYER_LOOKUP_SIZE: usize = 64;
 pub const MAX_OFF_STATE_LOOKUP: usize = 16;
 pub const STATE_SIZE: usize = 32;
+pub const MAX_ANALOG_TRIGGERS: usize = 32; // Analog (depth) triggers, see analog.rs
+pub const ANALOG_TRIGGER_HYSTERESIS: i16 = 16; // Release distance below the activation distance
+pub const MAX_DKS_KEYS: usize = 16; // Keys with dynamic keystrokes, see dks.rs
+pub const MAX_SOCD_PAIRS: usize = 8; // Opposing key pairs (SOCD_PAIRS), see socd.rs
+pub const MAX_COMBOS: usize = 16; // Combo (chord) triggers, see combo.rs
+pub const MAX_COMBO_KEYS: usize = 8; // Keys per combo (up to 16), also set in common/build.rs
+pub const COMBO_BUFFER_SIZE: usize = 8; // Events held back while a combo may be completed
+pub const COMBO_TERM_MS: u32 = 30; // Default, can be changed at runtime (set comboterm)
+pub const MAX_TAP_HOLD_KEYS: usize = 16; // Tap-hold keys, see taphold.rs
+pub const TAP_HOLD_BUFFER_SIZE: usize = 8; // Events buffered while a tap-hold key is undecided
+pub const MAX_ONE_SHOT_KEYS: usize = 8; // One-shot keys, see oneshot.rs
+pub const ONE_SHOT_TIMEOUT_MS: u32 = 5000; // Unused one-shots are released after this time
+pub const MAX_DYNAMIC_MACROS: usize = 4; // Dynamic macro slots, see dynamic_macro.rs
+pub const MAX_MACRO_EVENTS: usize = 34; // Events of all slots (flash user signature space)
+pub const MAX_KEYMAP_ENTRIES: usize = 24; // Keymap overlay entries, see keymap.rs
+pub const KEYMAP_KEY_QUEUE_SIZE: usize = 8; // USB HID codes of remapped keys waiting to be sent
+pub const MAX_LEADER_KEYS: usize = 4; // Keys per leader sequence, see leader.rs
+pub const LEADER_TIMEOUT_MS: u32 = 1000; // Default time allowed between sequence keys
+pub const MAX_INJECT_KEYS: usize = 8; // Keys held at once by the host, see inject.rs
+pub const INJECT_QUEUE_SIZE: usize = 16; // Injected events waiting for macro_process
+pub const INJECT_RATE_LIMIT: u32 = 40; // Injected events per second before the lockout
+pub const INJECT_LOCKOUT_MS: u32 = 5000; // Injection lockout after the rate limit was exceeded
+pub const INJECT_HOLD_TIMEOUT_MS: u32 = 10000; // Injected keys are released after this time
+// Defaults, can be changed at runtime (set tapterm/permissivehold)
+pub const TAPPING_TERM_MS: u32 = 200;
+pub const PERMISSIVE_HOLD: bool = false;
 #[cfg(feature = "keyscanning")]
 pub const MAX_PER_KEY_EVENTS: usize = 1;
 #[cfg(feature = "hall-effect")]
@@ -0,0 +1,395 @@
+// Copyright 2026 Jacob Alexander
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! Dynamic keystrokes (DKS)
+//!
+//! Up to 4 actions per key, each bound to a phase of the key travel.
+//! Actions are virtual switches (KLL trigger indices, see analog.rs) which are mapped in KLL like
+//! any other switch.
+//! A tap action presses the virtual switch for a single sample, a hold action keeps it pressed
+//! until the next phase with an action (or the full release).
+//!
+//! Configured with the `dks` console/HID-IO terminal command and saved in the flash user
+//! signature (see storage.rs).
+
+use crate::constants::*;
+use core::fmt;
+use kll_core::{trigger::Phro, TriggerEvent};
+
+// ----- Constants -----
+
+/// Number of user signature words used by a DksConfig
+pub const DKS_WORDS: usize = 1 + MAX_DKS_KEYS * 4;
+
+/// Stored config marker and version ("DKS", 1)
+const DKS_MAGIC: u32 = 0x444B_5301;
+
+/// Unused switch/action in the stored config
+const DKS_NONE: u16 = 0xFFFF;
+
+/// Hold flag of a stored action
+const DKS_HOLD: u16 = 0x8000;
+
+// ----- Enums -----
+
+/// Key travel phases
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum Phase {
+    /// Key travels past the actuation point
+    Press,
+    /// Key travels past the bottom-out point
+    BottomOut,
+    /// Key is released from bottom-out
+    BottomRelease,
+    /// Key is released past the actuation point
+    Release,
+}
+
+impl Phase {
+    pub const COUNT: usize = 4;
+}
+
+// ----- Structs -----
+
+/// Action of a single phase
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub struct DksAction {
+    /// Virtual switch (KLL trigger index)
+    pub virtual_switch: u16,
+    /// Keep the virtual switch pressed until the next phase with an action
+    pub hold: bool,
+}
+
+impl DksAction {
+    fn to_u16(action: Option<DksAction>) -> u16 {
+        match action {
+            Some(action) => action.virtual_switch | if action.hold { DKS_HOLD } else { 0 },
+            None => DKS_NONE,
+        }
+    }
+
+    fn from_u16(val: u16) -> Option<DksAction> {
+        if val == DKS_NONE {
+            return None;
+        }
+        Some(DksAction {
+            virtual_switch: val & !DKS_HOLD,
+            hold: val & DKS_HOLD != 0,
+        })
+    }
+}
+
+/// DKS settings of a single key
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub struct DksKey {
+    /// Switch (KLL trigger index)
+    pub switch: u16,
+    /// Actuation distance (calibrated distance, see DEFAULT_ACTIVATION_DIST)
+    pub actuation: i16,
+    /// Bottom-out distance
+    pub bottom_out: i16,
+    /// Action for each Phase
+    pub actions: [Option<DksAction>; Phase::COUNT],
+}
+
+/// DKS settings of all keys
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub struct DksConfig {
+    keys: [Option<DksKey>; MAX_DKS_KEYS],
+}
+
+impl DksConfig {
+    pub const fn new() -> Self {
+        Self {
+            keys: [None; MAX_DKS_KEYS],
+        }
+    }
+
+    pub fn keys(&self) -> impl Iterator<Item = &DksKey> {
+        self.keys.iter().flatten()
+    }
+
+    /// Adds or replaces the settings of a key
+    /// Returns false if there are already MAX_DKS_KEYS keys
+    pub fn set(&mut self, key: DksKey) -> bool {
+        let slot = match self
+            .keys
+            .iter()
+            .position(|k| matches!(k, Some(k) if k.switch == key.switch))
+        {
+            Some(slot) => slot,
+            None => match self.keys.iter().position(|k| k.is_none()) {
+                Some(slot) => slot,
+                None => {
+                    return false;
+                }
+            },
+        };
+        self.keys[slot] = Some(key);
+        true
+    }
+
+    /// Removes the settings of a key
+    /// Returns false if the key has no settings
+    pub fn clear(&mut self, switch: u16) -> bool {
+        match self
+            .keys
+            .iter_mut()
+            .find(|k| matches!(k, Some(k) if k.switch == switch))
+        {
+            Some(key) => {
+                *key = None;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Serializes the config (DKS_WORDS) for the user signature
+    pub fn to_words(&self, words: &mut [u32]) {
+        words[0] = DKS_MAGIC;
+        for (key, words) in self.keys.iter().zip(words[1..].chunks_mut(4)) {
+            match key {
+                Some(key) => {
+                    words[0] = key.switch as u32 | (key.actuation as u16 as u32) << 16;
+                    words[1] = key.bottom_out as u16 as u32;
+                    words[2] = DksAction::to_u16(key.actions[0]) as u32
+                        | (DksAction::to_u16(key.actions[1]) as u32) << 16;
+                    words[3] = DksAction::to_u16(key.actions[2]) as u32
+                        | (DksAction::to_u16(key.actions[3]) as u32) << 16;
+                }
+                None => {
+                    words.fill(0xFFFF_FFFF);
+                }
+            }
+        }
+    }
+
+    /// Deserializes the config from the user signature
+    /// Returns None if no config has been stored
+    pub fn from_words(words: &[u32]) -> Option<Self> {
+        if words.first() != Some(&DKS_MAGIC) {
+            return None;
+        }
+        let mut config = Self::new();
+        for (key, words) in config.keys.iter_mut().zip(words[1..].chunks(4)) {
+            if words[0] as u16 == DKS_NONE {
+                continue;
+            }
+            *key = Some(DksKey {
+                switch: words[0] as u16,
+                actuation: (words[0] >> 16) as i16,
+                bottom_out: words[1] as i16,
+                actions: [
+                    DksAction::from_u16(words[2] as u16),
+                    DksAction::from_u16((words[2] >> 16) as u16),
+                    DksAction::from_u16(words[3] as u16),
+                    DksAction::from_u16((words[3] >> 16) as u16),
+                ],
+            });
+        }
+        Some(config)
+    }
+
+    /// Writes a human readable list of the configured keys
+    pub fn report(&self, out: &mut dyn fmt::Write) -> fmt::Result {
+        writeln!(out, "switch act bottom press bottom bottomrel release")?;
+        for key in self.keys() {
+            write!(
+                out,
+                "{:6} {:3} {:6}",
+                key.switch, key.actuation, key.bottom_out
+            )?;
+            for action in key.actions {
+                match action {
+                    Some(action) => write!(
+                        out,
+                        " {:5}{}",
+                        action.virtual_switch,
+                        if action.hold { 'h' } else { ' ' }
+                    )?,
+                    None => write!(out, "      -")?,
+                }
+            }
+            writeln!(out)?;
+        }
+        Ok(())
+    }
+}
+
+impl Default for DksConfig {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Current state of a DKS key
+#[derive(Clone, Copy)]
+struct KeyState {
+    /// Past the actuation point
+    down: bool,
+    /// Past the bottom-out point
+    bottomed: bool,
+    /// Virtual switch held by a hold action
+    held: Option<u16>,
+    /// Virtual switch pressed by a tap action (released on the next sample)
+    tap: Option<u16>,
+    /// Samples since the last phase
+    cycles: u32,
+}
+
+impl KeyState {
+    const fn new() -> Self {
+        Self {
+            down: false,
+            bottomed: false,
+            held: None,
+            tap: None,
+            cycles: 0,
+        }
+    }
+}
+
+/// DKS evaluation
+pub struct Dks {
+    config: DksConfig,
+    states: [KeyState; MAX_DKS_KEYS],
+}
+
+impl Dks {
+    pub const fn new() -> Self {
+        Self {
+            config: DksConfig::new(),
+            states: [KeyState::new(); MAX_DKS_KEYS],
+        }
+    }
+
+    pub fn config(&self) -> &DksConfig {
+        &self.config
+    }
+
+    /// Replaces the config, any pressed virtual switches are released first
+    pub fn set_config(&mut self, config: DksConfig, mut emit: impl FnMut(TriggerEvent)) {
+        for state in self.states.iter_mut() {
+            for virtual_switch in [state.held.take(), state.tap.take()].into_iter().flatten() {
+                emit(Self::event(Phro::Release, virtual_switch, state.cycles));
+            }
+            *state = KeyState::new();
+        }
+        self.config = config;
+    }
+
+    /// Updates the DKS state of a switch using the latest distance
+    pub fn update(&mut self, switch: u16, distance: i16, mut emit: impl FnMut(TriggerEvent)) {
+        for (key, state) in self.config.keys.iter().zip(self.states.iter_mut()) {
+            let key = match key {
+                Some(key) if key.switch == switch => key,
+                _ => continue,
+            };
+            state.cycles = state.cycles.saturating_add(1);
+
+            // Taps only last a single sample
+            if let Some(virtual_switch) = state.tap.take() {
+                emit(Self::event(Phro::Release, virtual_switch, state.cycles));
+            }
+
+            let mut phases: heapless::Vec<Phase, 2> = heapless::Vec::new();
+            if !state.down && distance >= key.actuation {
+                state.down = true;
+                phases.push(Phase::Press).unwrap();
+            }
+            if state.down && !state.bottomed && distance >= key.bottom_out {
+                state.bottomed = true;
+                phases.push(Phase::BottomOut).unwrap();
+            }
+            if state.bottomed && distance < key.bottom_out - ANALOG_TRIGGER_HYSTERESIS {
+                state.bottomed = false;
+                phases.push(Phase::BottomRelease).unwrap();
+            }
+            if state.down && distance < key.actuation - ANALOG_TRIGGER_HYSTERESIS {
+                state.down = false;
+                phases.push(Phase::Release).unwrap();
+            }
+
+            for phase in phases {
+                Self::phase(key, state, phase, &mut emit);
+            }
+
+            // Held virtual switches (not pressed during this sample)
+            if let (Some(virtual_switch), true) = (state.held, state.cycles > 0) {
+                emit(Self::event(Phro::Hold, virtual_switch, state.cycles));
+            }
+        }
+    }
+
+    fn phase(
+        key: &DksKey,
+        state: &mut KeyState,
+        phase: Phase,
+        emit: &mut impl FnMut(TriggerEvent),
+    ) {
+        let action = key.actions[phase as usize];
+
+        // Holds end at the next action, or once the key is fully released
+        if action.is_some() || phase == Phase::Release {
+            if let Some(virtual_switch) = state.held.take() {
+                emit(Self::event(Phro::Release, virtual_switch, state.cycles));
+            }
+        }
+        // Two taps in the same sample (e.g. Press and BottomOut)
+        if let Some(virtual_switch) = state.tap.take() {
+            emit(Self::event(Phro::Release, virtual_switch, state.cycles));
+        }
+
+        if let Some(action) = action {
+            state.cycles = 0;
+            emit(Self::event(
+                Phro::Press,
+                action.virtual_switch,
+                state.cycles,
+            ));
+            if action.hold {
+                state.held = Some(action.virtual_switch);
+            } else {
+                state.tap = Some(action.virtual_switch);
+            }
+        }
+    }
+
+    fn event(state: Phro, virtual_switch: u16, cycles: u32) -> TriggerEvent {
+        TriggerEvent::Switch {
+            state,
+            index: virtual_switch,
+            last_state: cycles,
+        }
+    }
+
+    /// Index is the virtual switch of a DKS action
+    pub fn is_virtual(&self, index: u16) -> bool {
+        self.config.keys().any(|key| {
+            key.actions
+                .iter()
+                .flatten()
+                .any(|action| action.virtual_switch == index)
+        })
+    }
+
+    /// Hold event of a pressed virtual switch (used for off-state lookups)
+    /// Off events are not generated
+    pub fn virtual_event(&self, virtual_switch: u16) -> Option<TriggerEvent> {
+        self.states
+            .iter()
+            .find(|state| state.held == Some(virtual_switch) || state.tap == Some(virtual_switch))
+            .map(|state| Self::event(Phro::Hold, virtual_switch, state.cycles))
+    }
+}
+
+impl Default for Dks {
+    fn default() -> Self {
+        Self::new()
+    }
+}
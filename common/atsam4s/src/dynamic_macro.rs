@@ -0,0 +1,400 @@
+// Copyright 2026 Jacob Alexander
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! Dynamic macros (recorded on the keyboard)
+//!
+//! Record and play keys are written in the KLL files as `S<switch> : MacroRecord(<slot>);` and
+//! `S<switch> : MacroPlay(<slot>);` and compiled into the DYNAMIC_MACRO_KEYS table by build.rs:
+//!
+//! ```text
+//! S0x50 : MacroRecord(0);
+//! S0x51 : MacroPlay(0);
+//! ```
+//!
+//! Pressing a record key starts recording the HID keyboard results of finalize_triggers, any
+//! record key stops the recording and stores it in the slot. Play keys replay the slot through
+//! the keyboard queue with the recorded timing.
+//!
+//! Recorded macros are saved in the flash user signature (see storage.rs) and can be listed and
+//! deleted with the `macro` console/HID-IO terminal command.
+
+use crate::constants::*;
+use core::fmt;
+use heapless::{Deque, Vec};
+use kll_core::{trigger::Phro, TriggerEvent};
+
+// ----- Constants -----
+
+/// Number of user signature words used by a MacroStore
+pub const MACRO_WORDS: usize = 2 + MAX_MACRO_EVENTS;
+
+/// Stored macros marker and version ("DMC", 1)
+const MACRO_MAGIC: u32 = 0x444D_4301;
+
+/// Press flag of a stored event
+const MACRO_PRESS: u32 = 0x100;
+
+const CYCLES_PER_US: u32 = MCU_FREQ / 1_000_000;
+
+// Slot lengths are stored in a single word
+const _: () = assert!(MAX_DYNAMIC_MACROS <= 4 && MAX_MACRO_EVENTS <= u8::MAX as usize);
+
+// ----- Enums -----
+
+/// Action of a dynamic macro key
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum MacroAction {
+    /// Start recording the slot (or stop the current recording)
+    Record(u8),
+    /// Play the slot
+    Play(u8),
+}
+
+// ----- Structs -----
+
+/// Dynamic macro key, generated by build.rs
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub struct MacroKey {
+    /// Switch (KLL trigger index)
+    pub switch: u16,
+    pub action: MacroAction,
+}
+
+/// Recorded HID keyboard event
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub struct MacroEvent {
+    /// Time since the previous event (ms)
+    pub delay_ms: u16,
+    /// USB HID keyboard code
+    pub key: u8,
+    pub press: bool,
+}
+
+impl MacroEvent {
+    fn to_word(self) -> u32 {
+        self.key as u32 | if self.press { MACRO_PRESS } else { 0 } | (self.delay_ms as u32) << 16
+    }
+
+    fn from_word(word: u32) -> Self {
+        Self {
+            delay_ms: (word >> 16) as u16,
+            key: word as u8,
+            press: word & MACRO_PRESS != 0,
+        }
+    }
+}
+
+/// Recorded macros (all slots share MAX_MACRO_EVENTS)
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MacroStore {
+    /// Events of every slot, in slot order
+    events: Vec<MacroEvent, MAX_MACRO_EVENTS>,
+    /// Number of events in each slot
+    lengths: [u8; MAX_DYNAMIC_MACROS],
+}
+
+impl MacroStore {
+    pub const fn new() -> Self {
+        Self {
+            events: Vec::new(),
+            lengths: [0; MAX_DYNAMIC_MACROS],
+        }
+    }
+
+    /// Events of a slot (empty if the slot is unused or invalid)
+    pub fn get(&self, slot: u8) -> &[MacroEvent] {
+        let slot = slot as usize;
+        if slot >= MAX_DYNAMIC_MACROS {
+            return &[];
+        }
+        let start: usize = self.lengths[..slot].iter().map(|len| *len as usize).sum();
+        &self.events[start..start + self.lengths[slot] as usize]
+    }
+
+    /// Replaces the events of a slot
+    /// Returns false if the slot is invalid or there isn't enough space left
+    pub fn set(&mut self, slot: u8, events: &[MacroEvent]) -> bool {
+        let slot = slot as usize;
+        if slot >= MAX_DYNAMIC_MACROS
+            || self.events.len() - self.lengths[slot] as usize + events.len() > MAX_MACRO_EVENTS
+        {
+            return false;
+        }
+
+        let mut store = Self::new();
+        for (i, len) in store.lengths.iter_mut().enumerate() {
+            let slot_events = if i == slot { events } else { self.get(i as u8) };
+            store.events.extend_from_slice(slot_events).unwrap();
+            *len = slot_events.len() as u8;
+        }
+        *self = store;
+        true
+    }
+
+    /// Removes the events of a slot
+    /// Returns false if the slot is empty or invalid
+    pub fn delete(&mut self, slot: u8) -> bool {
+        !self.get(slot).is_empty() && self.set(slot, &[])
+    }
+
+    /// Serializes the macros (MACRO_WORDS) for the user signature
+    pub fn to_words(&self, words: &mut [u32]) {
+        words[0] = MACRO_MAGIC;
+        words[1] = self
+            .lengths
+            .iter()
+            .enumerate()
+            .fold(0, |word, (i, len)| word | (*len as u32) << (i * 8));
+        for (word, event) in words[2..].iter_mut().zip(self.events.iter()) {
+            *word = event.to_word();
+        }
+    }
+
+    /// Deserializes the macros from the user signature
+    /// Returns None if no macros have been stored
+    pub fn from_words(words: &[u32]) -> Option<Self> {
+        if words.first() != Some(&MACRO_MAGIC) {
+            return None;
+        }
+        let mut store = Self::new();
+        for (i, len) in store.lengths.iter_mut().enumerate() {
+            *len = (words[1] >> (i * 8)) as u8;
+        }
+        let count: usize = store.lengths.iter().map(|len| *len as usize).sum();
+        if count > MAX_MACRO_EVENTS {
+            return None;
+        }
+        for word in &words[2..2 + count] {
+            store.events.push(MacroEvent::from_word(*word)).unwrap();
+        }
+        Some(store)
+    }
+
+    /// Writes a human readable list of the recorded macros
+    pub fn report(&self, out: &mut dyn fmt::Write) -> fmt::Result {
+        writeln!(out, "slot events duration")?;
+        for slot in 0..MAX_DYNAMIC_MACROS as u8 {
+            let events = self.get(slot);
+            if events.is_empty() {
+                continue;
+            }
+            let duration: u32 = events.iter().map(|event| event.delay_ms as u32).sum();
+            writeln!(out, "{:4} {:6} {:6} ms", slot, events.len(), duration)?;
+        }
+        writeln!(
+            out,
+            "{}/{} events used",
+            self.events.len(),
+            MAX_MACRO_EVENTS
+        )
+    }
+}
+
+impl Default for MacroStore {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Dynamic macro keys (trigger stage)
+/// Events of the keys are replaced by record/play requests, handled by macro_process.
+pub struct MacroKeys {
+    keys: &'static [MacroKey],
+    requests: Deque<MacroAction, 4>,
+}
+
+impl MacroKeys {
+    pub fn new(keys: &'static [MacroKey]) -> Self {
+        Self {
+            keys,
+            requests: Deque::new(),
+        }
+    }
+
+    pub fn keys(&self) -> &'static [MacroKey] {
+        self.keys
+    }
+
+    /// Passes a trigger event through the dynamic macro stage
+    pub fn process(&mut self, event: TriggerEvent, emit: &mut impl FnMut(TriggerEvent)) {
+        let key = match event {
+            TriggerEvent::Switch { state, index, .. } => self
+                .keys
+                .iter()
+                .find(|key| key.switch == index)
+                .map(|key| (key, state)),
+            _ => None,
+        };
+        match key {
+            Some((key, Phro::Press)) => {
+                if self.requests.push_back(key.action).is_err() {
+                    defmt::warn!("Dropped dynamic macro request: {}", key.action);
+                }
+            }
+            Some(_) => {}
+            None => emit(event),
+        }
+    }
+
+    /// Takes the next record/play request
+    pub fn take_request(&mut self) -> Option<MacroAction> {
+        self.requests.pop_front()
+    }
+
+    /// Switch is a dynamic macro key (events are not sent to kll-core)
+    pub fn suppressed(&self, index: u16) -> bool {
+        self.keys.iter().any(|key| key.switch == index)
+    }
+}
+
+/// Dynamic macro recording and playback
+pub struct DynamicMacros {
+    /// Slot being recorded
+    recording: Option<u8>,
+    recorded: Vec<MacroEvent, MAX_MACRO_EVENTS>,
+    /// Macro being played and the next event
+    playback: Vec<MacroEvent, MAX_MACRO_EVENTS>,
+    position: usize,
+    /// Keys pressed by the playback (bit per HID code), released once the playback ends
+    pressed: [u32; 8],
+    /// Time since the last recorded or played event
+    elapsed_us: u32,
+    /// Cycle count of the last tick
+    cycles: Option<u32>,
+}
+
+impl DynamicMacros {
+    pub const fn new() -> Self {
+        Self {
+            recording: None,
+            recorded: Vec::new(),
+            playback: Vec::new(),
+            position: 0,
+            pressed: [0; 8],
+            elapsed_us: 0,
+            cycles: None,
+        }
+    }
+
+    pub fn recording(&self) -> Option<u8> {
+        self.recording
+    }
+
+    pub fn playing(&self) -> bool {
+        self.position < self.playback.len()
+    }
+
+    /// Starts recording a slot, ignored while playing
+    pub fn start_recording(&mut self, slot: u8) {
+        if self.playing() {
+            defmt::warn!("Dynamic macro playing, not recording slot {}", slot);
+            return;
+        }
+        defmt::info!("Recording dynamic macro {}", slot);
+        self.recording = Some(slot);
+        self.recorded.clear();
+        self.elapsed_us = 0;
+    }
+
+    /// Stops recording
+    /// Returns the slot and the recorded events
+    pub fn stop_recording(&mut self) -> Option<(u8, &[MacroEvent])> {
+        let slot = self.recording.take()?;
+        defmt::info!(
+            "Recorded dynamic macro {} ({} events)",
+            slot,
+            self.recorded.len()
+        );
+        Some((slot, &self.recorded))
+    }
+
+    /// Records a HID keyboard event, the recording stops once MAX_MACRO_EVENTS is reached
+    pub fn record(&mut self, key: u8, press: bool) {
+        if self.recording.is_none() {
+            return;
+        }
+        let delay_ms = if self.recorded.is_empty() {
+            0
+        } else {
+            (self.elapsed_us / 1000).min(u16::MAX as u32) as u16
+        };
+        if self
+            .recorded
+            .push(MacroEvent {
+                delay_ms,
+                key,
+                press,
+            })
+            .is_err()
+        {
+            defmt::warn!("MAX_MACRO_EVENTS reached, dropping events");
+            return;
+        }
+        self.elapsed_us = 0;
+    }
+
+    /// Plays the events of a slot, ignored while recording or playing
+    pub fn play(&mut self, events: &[MacroEvent]) {
+        if self.recording.is_some() || self.playing() {
+            defmt::warn!("Dynamic macro busy, not playing");
+            return;
+        }
+        self.playback.clear();
+        self.playback.extend_from_slice(events).unwrap();
+        self.position = 0;
+        self.elapsed_us = 0;
+    }
+
+    /// Advances the recording/playback time using the DWT cycle count (see profiling::start)
+    /// Emits the played events (HID code, press) that are due.
+    pub fn tick(&mut self, cycles: u32, mut emit: impl FnMut(u8, bool)) {
+        let elapsed_us = match self.cycles.replace(cycles) {
+            Some(last) => cycles.wrapping_sub(last) / CYCLES_PER_US,
+            None => 0,
+        };
+        if self.recording.is_none() && !self.playing() {
+            return;
+        }
+        self.elapsed_us = self.elapsed_us.saturating_add(elapsed_us);
+
+        while let Some(event) = self.playback.get(self.position) {
+            let delay_us = event.delay_ms as u32 * 1000;
+            if self.elapsed_us < delay_us {
+                return;
+            }
+            self.elapsed_us -= delay_us;
+            self.position += 1;
+
+            let bit = 1 << (event.key % 32);
+            let pressed = &mut self.pressed[event.key as usize / 32];
+            if event.press {
+                *pressed |= bit;
+            } else {
+                *pressed &= !bit;
+            }
+            emit(event.key, event.press);
+        }
+
+        // Release any keys still pressed by the macro
+        if self.pressed.iter().any(|bits| *bits != 0) {
+            for key in 0..=u8::MAX {
+                if self.pressed[key as usize / 32] & (1 << (key % 32)) != 0 {
+                    emit(key, false);
+                }
+            }
+            self.pressed = [0; 8];
+        }
+        self.playback.clear();
+        self.position = 0;
+    }
+}
+
+impl Default for DynamicMacros {
+    fn default() -> Self {
+        Self::new()
+    }
+}
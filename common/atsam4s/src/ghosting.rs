@@ -0,0 +1,154 @@
+// Copyright 2026 Jacob Alexander
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! Key ghosting analyzer for diode-less matrices
+//!
+//! Without diodes, pressing 3 corners of a rectangle (2 strobes x 2 senses) also reads the 4th
+//! corner as pressed. Once that happens there is no way to tell which of the 4 keys are real.
+//!
+//! Matrix indices use the keyscanning layout (strobe * RSIZE + sense).
+
+// ----- Enums -----
+
+/// Anti-ghosting behaviour, selected per keyboard (GHOST_MODE in the board constants)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum GhostMode {
+    /// No analysis (matrix has diodes)
+    Off,
+    /// Ambiguous keys are logged, events are not changed
+    Detect,
+    /// Ambiguous keys keep their previous state until the ghosting pattern is gone
+    Block,
+}
+
+// ----- Functions -----
+
+/// Finds keys that are part of a ghosting rectangle (3 or 4 pressed corners)
+/// pressed is the raw state of every key after a full scan, ambiguous is set for every key in
+/// a ghosting rectangle (including the unpressed corner).
+/// Returns the number of ambiguous keys.
+pub fn find_ghosts<const CSIZE: usize, const RSIZE: usize, const MSIZE: usize>(
+    pressed: &[bool; MSIZE],
+    ambiguous: &mut [bool; MSIZE],
+) -> usize {
+    debug_assert!(RSIZE <= u32::BITS as usize, "Too many senses for the row masks");
+
+    // Pressed senses for each strobe
+    let mut masks = [0u32; CSIZE];
+    for (strobe, mask) in masks.iter_mut().enumerate() {
+        for sense in 0..RSIZE {
+            if pressed[strobe * RSIZE + sense] {
+                *mask |= 1 << sense;
+            }
+        }
+    }
+
+    ambiguous.fill(false);
+
+    // A pair of strobes is ambiguous if they share a pressed sense and have at least one other
+    // pressed sense between them. Every corner using those senses is ambiguous.
+    for a in 0..CSIZE {
+        if masks[a] == 0 {
+            continue;
+        }
+        for b in a + 1..CSIZE {
+            let both = masks[a] & masks[b];
+            let any = masks[a] | masks[b];
+            if both == 0 || any.count_ones() < 2 {
+                continue;
+            }
+            for sense in 0..RSIZE {
+                if any & (1 << sense) != 0 {
+                    ambiguous[a * RSIZE + sense] = true;
+                    ambiguous[b * RSIZE + sense] = true;
+                }
+            }
+        }
+    }
+
+    ambiguous.iter().filter(|a| **a).count()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CSIZE: usize = 4;
+    const RSIZE: usize = 4;
+    const MSIZE: usize = CSIZE * RSIZE;
+
+    /// Runs the analyzer on the pressed (strobe, sense) keys
+    /// Returns the ambiguous keys as (strobe, sense)
+    fn ghosts(keys: &[(usize, usize)]) -> ([(usize, usize); MSIZE], usize) {
+        let mut pressed = [false; MSIZE];
+        for (strobe, sense) in keys {
+            pressed[strobe * RSIZE + sense] = true;
+        }
+        // Leftovers from a previous scan are cleared
+        let mut ambiguous = [true; MSIZE];
+        let count = find_ghosts::<CSIZE, RSIZE, MSIZE>(&pressed, &mut ambiguous);
+
+        let mut keys = [(0, 0); MSIZE];
+        let mut found = 0;
+        for (index, _) in ambiguous.iter().enumerate().filter(|(_, a)| **a) {
+            keys[found] = (index / RSIZE, index % RSIZE);
+            found += 1;
+        }
+        assert_eq!(count, found);
+        (keys, count)
+    }
+
+    #[test]
+    fn three_key_l() {
+        let (keys, count) = ghosts(&[(0, 0), (0, 1), (1, 0)]);
+        // The 4th corner (1, 1) is a ghost
+        assert_eq!(keys[..count], [(0, 0), (0, 1), (1, 0), (1, 1)]);
+    }
+
+    #[test]
+    fn four_key_rectangle() {
+        let (keys, count) = ghosts(&[(1, 2), (1, 3), (3, 2), (3, 3)]);
+        assert_eq!(keys[..count], [(1, 2), (1, 3), (3, 2), (3, 3)]);
+    }
+
+    #[test]
+    fn same_sense() {
+        assert_eq!(ghosts(&[(0, 1), (2, 1)]).1, 0);
+        assert_eq!(ghosts(&[(0, 1), (1, 1), (2, 1), (3, 1)]).1, 0);
+        // Same strobe
+        assert_eq!(ghosts(&[(2, 0), (2, 1), (2, 3)]).1, 0);
+    }
+
+    #[test]
+    fn disjoint_strobes() {
+        assert_eq!(ghosts(&[(0, 0), (1, 1)]).1, 0);
+        assert_eq!(ghosts(&[(0, 0), (0, 1), (1, 2), (1, 3)]).1, 0);
+        assert_eq!(ghosts(&[]).1, 0);
+    }
+
+    #[test]
+    fn overlapping_rectangles() {
+        // Strobes 0 and 1 share sense 1, strobes 1 and 2 share sense 2
+        let (keys, count) = ghosts(&[(0, 0), (0, 1), (1, 1), (1, 2), (2, 2)]);
+        assert_eq!(
+            keys[..count],
+            [
+                (0, 0),
+                (0, 1),
+                (0, 2),
+                (1, 0),
+                (1, 1),
+                (1, 2),
+                (2, 1),
+                (2, 2)
+            ]
+        );
+        // Unrelated key on strobe 3 isn't ambiguous
+        let (keys, count) = ghosts(&[(0, 0), (0, 1), (1, 0), (3, 3)]);
+        assert_eq!(keys[..count], [(0, 0), (0, 1), (1, 0), (1, 1)]);
+    }
+}
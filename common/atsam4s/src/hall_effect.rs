@@ -5,7 +5,11 @@
 // http://opensource.org/licenses/MIT>, at your option. This file may not be
 // copied, modified, or distributed except according to those terms.
 
+use crate::analog::{AnalogTrigger, AnalogTriggers};
 use crate::constants::*;
+use crate::dks::Dks;
+use crate::hall_stats::KeyStats;
+use crate::stages::TriggerStages;
 use crate::*;
 pub use kiibohd_hall_effect_keyscanning::lookup::{
     SILO_ATSAM4S_LC605_GAIN_2X, SILO_ATSAM4S_LC605_GAIN_4X,
@@ -22,12 +26,15 @@ use hal::{
     pdc::{ReadDmaPaused, RxDma, Transfer, W},
     timer::TimerCounterChannel,
 };
+use cortex_m::peripheral::DWT;
+use kiibohd_keyscanning::KeyScanning;
+use kll_core::TriggerEvent;
 
 // ----- Types -----
 
 pub type AdcTransfer<const ADC_BUF_SIZE: usize> =
     Transfer<W, &'static mut [u16; ADC_BUF_SIZE], RxDma<AdcPayload<SingleSequence>>>;
-pub type HallMatrix<const CSIZE: usize, const MSIZE: usize> =
+pub type SensorMatrix<const CSIZE: usize, const MSIZE: usize> =
     Matrix<PioX<Output<PushPull>>, CSIZE, MSIZE, INVERT_STROBE>;
 pub type TCC0 = TimerCounterChannel<TC0, Tc0Clock<Enabled>, 0, TCC0_FREQ>;
 
@@ -47,6 +54,28 @@ pub enum AdcClock {
     Mhz30,
 }
 
+impl AdcClock {
+    const COUNT: usize = 3;
+
+    pub fn mhz(&self) -> u8 {
+        match self {
+            AdcClock::Mhz12 => 12,
+            AdcClock::Mhz20 => 20,
+            AdcClock::Mhz30 => 30,
+        }
+    }
+
+    /// Estimated time of a single conversion (tracking + 20 conversion clocks)
+    /// Only used until the sequence time has been measured
+    pub const fn conversion_ns(&self) -> u32 {
+        match self {
+            AdcClock::Mhz12 => (2 + 20) * 1000 / 12,
+            AdcClock::Mhz20 => (11 + 20) * 1000 / 20,
+            AdcClock::Mhz30 => (16 + 20) * 1000 / 30,
+        }
+    }
+}
+
 // ----- Structs -----
 
 /// Container struct so it's easier to pass the sense pins around
@@ -61,6 +90,646 @@ pub struct SensePins {
     pub sense6: Pa22<ExFn>,
 }
 
+/// ADC noise statistics for a single clock
+#[derive(Clone, Copy, Default)]
+pub struct NoiseStats {
+    /// Number of samples
+    pub samples: u32,
+    /// Sum of the deviation from the averaged sensor value
+    pub total: u64,
+    /// Largest deviation from the averaged sensor value
+    pub max: u16,
+}
+
+impl NoiseStats {
+    const fn new() -> Self {
+        Self {
+            samples: 0,
+            total: 0,
+            max: 0,
+        }
+    }
+
+    /// Average deviation (x100 for fixed-point output)
+    pub fn avg_x100(&self) -> u32 {
+        if self.samples == 0 {
+            0
+        } else {
+            (self.total * 100 / self.samples as u64) as u32
+        }
+    }
+}
+
+/// ADC noise statistics for each ADC clock
+/// Only keys at rest are sampled so key movement isn't counted as noise.
+pub struct AdcNoise {
+    clocks: [NoiseStats; AdcClock::COUNT],
+    /// Clock of the most recent sample
+    active: AdcClock,
+}
+
+impl AdcNoise {
+    pub const fn new() -> Self {
+        Self {
+            clocks: [NoiseStats::new(); AdcClock::COUNT],
+            active: DEFAULT_ADC_CLOCK,
+        }
+    }
+
+    pub fn reset(&mut self) {
+        *self = Self::new();
+    }
+
+    /// Records the deviation of a sample from the averaged sensor value
+    pub fn record(&mut self, clock: AdcClock, sample: u16, value: u16) {
+        let deviation = sample.abs_diff(value);
+        let stats = &mut self.clocks[clock as usize];
+        stats.samples = stats.samples.saturating_add(1);
+        stats.total = stats.total.saturating_add(deviation as u64);
+        stats.max = stats.max.max(deviation);
+        self.active = clock;
+    }
+
+    pub fn stats(&self, clock: AdcClock) -> &NoiseStats {
+        &self.clocks[clock as usize]
+    }
+
+    /// Writes the noise statistics of each clock (the active clock is marked with *)
+    pub fn report(&self, out: &mut dyn core::fmt::Write) -> core::fmt::Result {
+        writeln!(out, "clock     samples      avg  max")?;
+        for clock in [AdcClock::Mhz12, AdcClock::Mhz20, AdcClock::Mhz30] {
+            let stats = self.stats(clock);
+            let avg = stats.avg_x100();
+            writeln!(
+                out,
+                "{}{:2} MHz {:10} {:5}.{:02} {:4}",
+                if clock == self.active { '*' } else { ' ' },
+                clock.mhz(),
+                stats.samples,
+                avg / 100,
+                avg % 100,
+                stats.max,
+            )?;
+        }
+        Ok(())
+    }
+}
+
+impl Default for AdcNoise {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Filter used to combine the ADC_SAMPLES samples of a key taken during a single strobe
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum SampleFilter {
+    /// Mean of all samples
+    Mean,
+    /// Median of all samples (mean of the middle two for an even count)
+    Median,
+    /// Mean without the smallest and largest sample (needs at least 3 samples)
+    TrimmedMean,
+}
+
+impl SampleFilter {
+    /// Combines the given samples (sorts the slice)
+    pub fn apply(&self, samples: &mut [u16]) -> u16 {
+        let mean = |samples: &[u16]| {
+            (samples.iter().map(|s| *s as u32).sum::<u32>() / samples.len() as u32) as u16
+        };
+
+        match samples.len() {
+            0 => 0,
+            1 => samples[0],
+            len => match self {
+                SampleFilter::Mean => mean(samples),
+                SampleFilter::Median => {
+                    samples.sort_unstable();
+                    mean(&samples[(len - 1) / 2..len / 2 + 1])
+                }
+                SampleFilter::TrimmedMean => {
+                    samples.sort_unstable();
+                    if len < 3 {
+                        mean(samples)
+                    } else {
+                        mean(&samples[1..len - 1])
+                    }
+                }
+            },
+        }
+    }
+}
+
+/// Common-mode noise and drift cancellation (experimental, `noise-cancel` feature)
+///
+/// Channel 11 is disconnected and sampled after every sense channel. Its readings are residual
+/// charge of the preceding channel and depend on the key positions, so the median reading of a
+/// strobe is used as its reference (a single pressed key doesn't move it) and each strobe has its
+/// own long-term baseline. The difference between the reference and the baseline is treated as
+/// common-mode noise (or drift) and subtracted from every sensor sample of the strobe.
+pub struct NoiseCancel<const CSIZE: usize> {
+    enabled: bool,
+    /// Long-term channel 11 reference of each strobe (16.16 fixed point)
+    baselines: [Option<i32>; CSIZE],
+}
+
+impl<const CSIZE: usize> NoiseCancel<CSIZE> {
+    pub const fn new(enabled: bool) -> Self {
+        Self {
+            enabled,
+            baselines: [None; CSIZE],
+        }
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.enabled
+    }
+
+    pub fn set_enabled(&mut self, enabled: bool) {
+        self.enabled = enabled;
+    }
+
+    /// Updates the baseline of a strobe with its channel 11 readings
+    /// Returns the offset to subtract from the strobe samples (0 when disabled)
+    /// The baselines are always tracked so enabling the cancellation doesn't need to settle.
+    pub fn update(&mut self, strobe: usize, readings: &mut [u16]) -> i16 {
+        if readings.is_empty() {
+            return 0;
+        }
+        let reference = (SampleFilter::Median.apply(readings) as i32) << 16;
+        // Exponential moving average, the fractional bits keep the remainder of each step
+        let baseline = match self.baselines[strobe] {
+            Some(baseline) => baseline + ((reference - baseline) >> NOISE_CANCEL_BASELINE_SHIFT),
+            None => reference,
+        };
+        self.baselines[strobe] = Some(baseline);
+
+        if self.enabled {
+            ((reference - baseline + (1 << 15)) >> 16) as i16
+        } else {
+            0
+        }
+    }
+
+    /// Updates the baseline of a strobe with the channel 11 readings of a raw ADC buffer
+    /// Returns the offset to subtract from the strobe samples (0 when disabled)
+    pub fn update_buffer<const RSIZE: usize>(&mut self, strobe: usize, buf: &[u16]) -> i16 {
+        // One reading per row and sample (RSIZE can't be used in array length expressions)
+        let mut readings = [[0u16; ADC_SAMPLES]; RSIZE];
+        let readings = readings.as_flattened_mut();
+        let mut count = 0;
+        for sample in buf.iter().filter(|sample| (*sample & 0xF000) >> 12 == 11) {
+            if count < readings.len() {
+                readings[count] = (sample & 0x0FFF) >> 2;
+                count += 1;
+            }
+        }
+        self.update(strobe, &mut readings[..count])
+    }
+}
+
+/// Applies a noise cancellation offset to a 10-bit sample
+pub fn cancel_noise(sample: u16, offset: i16) -> u16 {
+    (sample as i16 - offset).clamp(0, 0x3FF) as u16
+}
+
+/// Raw ADC buffers of a single strobe, captured for noise cancellation trace replay (adctrace)
+pub struct AdcTrace {
+    /// Strobe being captured, None once the buffer is full
+    capturing: Option<usize>,
+    strobe: usize,
+    /// Words per ADC buffer
+    words: usize,
+    buf: heapless::Vec<u16, ADC_TRACE_SIZE>,
+}
+
+impl AdcTrace {
+    pub const fn new() -> Self {
+        Self {
+            capturing: None,
+            strobe: 0,
+            words: 0,
+            buf: heapless::Vec::new(),
+        }
+    }
+
+    /// Discards the previous capture and captures the next buffers of the strobe
+    pub fn start(&mut self, strobe: usize) {
+        self.capturing = Some(strobe);
+        self.strobe = strobe;
+        self.buf.clear();
+    }
+
+    /// Records the raw ADC buffer of a strobe, if it's being captured
+    pub fn record(&mut self, strobe: usize, buf: &[u16]) {
+        if self.capturing != Some(strobe) {
+            return;
+        }
+        self.words = buf.len();
+        if self.buf.extend_from_slice(buf).is_err() || self.buf.len() + buf.len() > ADC_TRACE_SIZE {
+            self.capturing = None;
+        }
+    }
+
+    /// Captured buffers
+    pub fn buffers(&self) -> impl Iterator<Item = &[u16]> {
+        self.buf.chunks(self.words.max(1))
+    }
+
+    /// Writes a page of captured buffers (hex words) starting at the given buffer
+    pub fn report(&self, out: &mut dyn core::fmt::Write, first: usize) -> core::fmt::Result {
+        writeln!(
+            out,
+            "ADC trace strobe {}: {} buffers{}",
+            self.strobe,
+            self.buffers().count(),
+            if self.capturing.is_some() {
+                " (capturing)"
+            } else {
+                ""
+            }
+        )?;
+        for (i, buf) in self
+            .buffers()
+            .enumerate()
+            .skip(first)
+            .take(ADC_TRACE_PER_PAGE)
+        {
+            write!(out, "{:3}:", i)?;
+            for word in buf {
+                write!(out, " {:04x}", word)?;
+            }
+            writeln!(out)?;
+        }
+        Ok(())
+    }
+}
+
+impl Default for AdcTrace {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Groups the sensor samples of a raw ADC buffer by row (10-bit, noise cancellation offset applied)
+/// Samples may arrive out of order in some situations (usually due to timing being too
+/// aggressive). Each channel 11 reading is passed to crosstalk with the row of the preceding
+/// sensor.
+pub fn decode_samples<const RSIZE: usize>(
+    buf: &[u16],
+    offset: i16,
+    mut crosstalk: impl FnMut(usize, u16),
+) -> ([[u16; ADC_SAMPLES]; RSIZE], [usize; RSIZE]) {
+    // With ADC_SAMPLES > 1 the sequence repeats, group the samples of each row
+    let mut samples = [[0u16; ADC_SAMPLES]; RSIZE];
+    let mut counts = [0usize; RSIZE];
+    let mut prev_row = None;
+    for sample in buf.iter() {
+        // Handle multiple samples from the same buffer
+        let channel = (sample & 0xF000) >> 12;
+        // Mask 12 bits and shift right 2 bits to get 10-bit sample
+        let sample = (sample & 0x0FFF) >> 2;
+
+        // Remap channels to rows
+        let row = match channel {
+            0..=3 => channel,
+            8 => 4,
+            9 => 5,
+            11 => {
+                if let Some(row) = prev_row.take() {
+                    crosstalk(row, sample);
+                }
+                continue;
+            }
+            _ => continue,
+        } as usize;
+        if row >= RSIZE {
+            continue;
+        }
+
+        prev_row = Some(row);
+        if counts[row] < ADC_SAMPLES {
+            samples[row][counts[row]] = cancel_noise(sample, offset);
+            counts[row] += 1;
+        }
+    }
+    (samples, counts)
+}
+
+/// Hall effect sensor matrix with per-key sensor statistics, analog triggers, DKS and trigger
+/// stages
+pub struct HallMatrix<const CSIZE: usize, const MSIZE: usize> {
+    matrix: SensorMatrix<CSIZE, MSIZE>,
+    stats: [KeyStats; MSIZE],
+    noise_cancel: NoiseCancel<CSIZE>,
+    sample_filter: SampleFilter,
+    analog: AnalogTriggers,
+    dks: Dks,
+    stages: TriggerStages,
+}
+
+impl<const CSIZE: usize, const MSIZE: usize> HallMatrix<CSIZE, MSIZE> {
+    pub fn new(
+        matrix: SensorMatrix<CSIZE, MSIZE>,
+        analog_triggers: &'static [AnalogTrigger],
+        stages: TriggerStages,
+    ) -> Self {
+        Self {
+            matrix,
+            stats: [KeyStats::new(); MSIZE],
+            noise_cancel: NoiseCancel::new(DEFAULT_NOISE_CANCEL),
+            sample_filter: DEFAULT_SAMPLE_FILTER,
+            analog: AnalogTriggers::new(analog_triggers),
+            dks: Dks::new(),
+            stages,
+        }
+    }
+
+    pub fn sensors(&self) -> &SensorMatrix<CSIZE, MSIZE> {
+        &self.matrix
+    }
+
+    pub fn stats(&self) -> &[KeyStats; MSIZE] {
+        &self.stats
+    }
+
+    pub fn reset_stats(&mut self) {
+        self.stats = [KeyStats::new(); MSIZE];
+    }
+
+    pub fn noise_cancel(&self) -> &NoiseCancel<CSIZE> {
+        &self.noise_cancel
+    }
+
+    pub fn sample_filter(&self) -> SampleFilter {
+        self.sample_filter
+    }
+
+    pub fn dks(&self) -> &Dks {
+        &self.dks
+    }
+
+    pub fn stages(&self) -> &TriggerStages {
+        &self.stages
+    }
+
+    pub fn stages_mut(&mut self) -> &mut TriggerStages {
+        &mut self.stages
+    }
+}
+
+impl<const CSIZE: usize, const MSIZE: usize> KeyScanning<MAX_PER_KEY_EVENTS>
+    for HallMatrix<CSIZE, MSIZE>
+{
+    fn generate_events(&self, index: usize) -> heapless::Vec<TriggerEvent, MAX_PER_KEY_EVENTS> {
+        let index16 = index as u16;
+
+        // Keys held back by a stage are not pressed as far as kll-core is concerned
+        if self.stages.suppressed(index16) {
+            return heapless::Vec::new();
+        }
+
+        // Virtual switches of analog triggers, DKS actions and tap-hold keys
+        if self.analog.is_virtual(index16)
+            || self.dks.is_virtual(index16)
+            || self.stages.is_virtual(index16)
+        {
+            let mut events = heapless::Vec::new();
+            if let Some(event) = self
+                .analog
+                .event(index16)
+                .or_else(|| self.dks.virtual_event(index16))
+                .or_else(|| self.stages.virtual_event(index16))
+            {
+                events.push(event).unwrap();
+            }
+            return events;
+        }
+        self.matrix.generate_events(index)
+    }
+}
+
+/// Hall effect strobe timing
+///
+/// The strobe period is calculated from the measured ADC sequence time so that a full matrix
+/// scan fits into a whole number of USB frames (1 ms for USB 2.0 FS).
+/// While USB SOFs (Start of Frame) are being received, each full scan is started on a SOF so the
+/// scan results line up with the USB polling. Without SOFs (e.g. suspended) the timer free-runs.
+pub struct ScanTiming {
+    /// Current sensor mode
+    mode: SensorMode,
+    /// Current ADC clock
+    adc_clock: AdcClock,
+    /// Minimum number of USB frames for a full scan (sensor mode)
+    min_frames: u32,
+    /// Number of USB frames for a full scan
+    frames: u32,
+    /// Current strobe period
+    period_us: u32,
+    /// Measured (peak) ADC sequence time
+    sequence_cycles: u32,
+    /// Cycle count when the current ADC sequence was started
+    start: Option<u32>,
+    /// Full scan has finished, next scan is started on SOF
+    waiting_for_sof: bool,
+    /// Timer ticks spent waiting for a SOF
+    wait_ticks: u32,
+    /// SOF received since the last full scan
+    sof_seen: bool,
+}
+
+impl ScanTiming {
+    pub const fn new() -> Self {
+        Self {
+            mode: DEFAULT_ADC_ANALYSIS_MODE,
+            adc_clock: DEFAULT_ADC_CLOCK,
+            min_frames: 1,
+            frames: 1,
+            period_us: USB_FRAME_US,
+            sequence_cycles: 0,
+            start: None,
+            waiting_for_sof: false,
+            wait_ticks: 0,
+            sof_seen: false,
+        }
+    }
+
+    pub fn mode(&self) -> SensorMode {
+        self.mode
+    }
+
+    pub fn adc_clock(&self) -> AdcClock {
+        self.adc_clock
+    }
+
+    pub fn period_us(&self) -> u32 {
+        self.period_us
+    }
+
+    pub fn frames(&self) -> u32 {
+        self.frames
+    }
+
+    /// Measured ADC sequence time
+    pub fn sequence_us(&self) -> u32 {
+        self.sequence_cycles / (MCU_FREQ / 1_000_000)
+    }
+
+    /// Sets the sensor mode and ADC clock, restarting the timer with an estimated period
+    pub fn set_mode<const CSIZE: usize, const RSIZE: usize>(
+        &mut self,
+        mode: SensorMode,
+        adc_clock: AdcClock,
+        tcc0: &mut TCC0,
+    ) {
+        self.mode = mode;
+        self.adc_clock = adc_clock;
+        self.min_frames = match mode {
+            SensorMode::Test(_) => 5,
+            SensorMode::LowLatency(_) => 1,
+            SensorMode::Normal(_) => 2,
+        };
+
+        // Channel 11 is interleaved with each sense channel
+        let sequence_ns = adc_clock.conversion_ns() * ADC_SAMPLES as u32 * 2 * RSIZE as u32;
+        self.sequence_cycles = sequence_ns * (MCU_FREQ / 1_000_000) / 1000;
+        self.start = None;
+        self.waiting_for_sof = false;
+        self.update::<CSIZE>(tcc0, true);
+    }
+
+    /// Calculates the strobe period, restarting the timer if it has changed (or forced)
+    fn update<const CSIZE: usize>(&mut self, tcc0: &mut TCC0, force: bool) {
+        // Minimum strobe period is the ADC sequence time plus margin
+        let sequence_us = self.sequence_us();
+        let min_us = sequence_us + sequence_us / ADC_SEQUENCE_MARGIN + 1;
+
+        // Round up to a whole number of USB frames, leaving time for macro processing before the
+        // next SOF
+        let scan_us = min_us * CSIZE as u32 + SOF_GUARD_US;
+        let frames = ((scan_us + USB_FRAME_US - 1) / USB_FRAME_US).max(self.min_frames);
+        let period_us = (frames * USB_FRAME_US - SOF_GUARD_US) / CSIZE as u32;
+
+        if force || period_us != self.period_us {
+            defmt::debug!(
+                "Strobe period {} us ({} frames, ADC sequence {} us)",
+                period_us,
+                frames,
+                sequence_us
+            );
+            self.frames = frames;
+            self.period_us = period_us;
+            tcc0.start(period_us.micros());
+        }
+    }
+
+    /// ADC sequence has been started
+    fn sequence_started(&mut self) {
+        self.start = Some(DWT::cycle_count());
+    }
+
+    /// ADC sequence has finished, updates the measured (peak) sequence time
+    /// The peak slowly decays so a single slow sequence doesn't hold the period forever
+    fn sequence_done(&mut self) {
+        if let Some(start) = self.start.take() {
+            let cycles = DWT::cycle_count().wrapping_sub(start);
+            if cycles > self.sequence_cycles {
+                self.sequence_cycles = cycles;
+            } else {
+                self.sequence_cycles -= (self.sequence_cycles - cycles) / 64;
+            }
+        }
+    }
+
+    /// Full scan has finished
+    fn scan_complete<const CSIZE: usize>(&mut self, tcc0: &mut TCC0) {
+        self.update::<CSIZE>(tcc0, false);
+
+        // Only wait for the next SOF if USB is active
+        self.waiting_for_sof = self.sof_seen;
+        self.sof_seen = false;
+        self.wait_ticks = 0;
+    }
+
+    /// Timer tick, returns true if the next ADC sequence should be started
+    fn tick(&mut self) -> bool {
+        if !self.waiting_for_sof {
+            return true;
+        }
+
+        // Give up on SOF sync if no SOF has been received for a while
+        self.wait_ticks += 1;
+        if self.wait_ticks * self.period_us > SOF_TIMEOUT_FRAMES * USB_FRAME_US {
+            defmt::debug!("No SOF, free-running keyscanning");
+            self.waiting_for_sof = false;
+            return true;
+        }
+        false
+    }
+
+    /// USB SOF received, returns true if the next full scan should be started
+    fn sof(&mut self) -> bool {
+        self.sof_seen = true;
+        if self.waiting_for_sof {
+            self.waiting_for_sof = false;
+            return true;
+        }
+        false
+    }
+}
+
+impl Default for ScanTiming {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// ----- Traits -----
+
+impl<const CSIZE: usize, const MSIZE: usize> crate::terminal::MatrixSnapshot
+    for HallMatrix<CSIZE, MSIZE>
+{
+    fn key_snapshot(&self, index: usize) -> crate::terminal::KeySnapshot {
+        let (calibration, distance) = match self.matrix.state(index) {
+            Some(sense) => (Some(sense.cal as u8), Some(sense.analysis.distance)),
+            None => (None, None),
+        };
+
+        crate::terminal::KeySnapshot {
+            pressed: crate::terminal::pressed(self.generate_events(index)),
+            debouncing: false,
+            idle: false,
+            calibration,
+            distance,
+        }
+    }
+
+    /// Dumps the calibration status and last reading of each sensor
+    fn dump_calibration(&self, out: &mut dyn core::fmt::Write) -> core::fmt::Result {
+        for index in 0..MSIZE {
+            if let Some(sense) = self.matrix.state(index) {
+                writeln!(
+                    out,
+                    "{:3}: {:?} {}",
+                    index,
+                    sense.cal,
+                    sense.data().value()
+                )?;
+            }
+        }
+        Ok(())
+    }
+
+    fn dump_stats(
+        &self,
+        out: &mut dyn core::fmt::Write,
+        first: Option<usize>,
+    ) -> core::fmt::Result {
+        crate::hall_stats::report(&self.stats, out, first)
+    }
+}
+
 // ----- Initialization Functions -----
 
 /// Initialize Hall Effect Matrix
@@ -71,13 +740,16 @@ pub fn init<const CSIZE: usize, const RSIZE: usize, const MSIZE: usize>(
     cols: [PioX<Output<PushPull>>; CSIZE],
     sense_pins: &mut SensePins,
     tcc0: &mut TCC0,
+    scan_timing: &mut ScanTiming,
+    analog_triggers: &'static [AnalogTrigger],
+    stages: TriggerStages,
 ) -> (
     hal::adc::AdcDma<hal::adc::SingleSequence>,
     HallMatrix<CSIZE, MSIZE>,
 ) {
     // Setup hall effect matrix
     defmt::trace!("HE Matrix initialization");
-    let mut matrix = HallMatrix::new(
+    let mut matrix = SensorMatrix::new(
         cols,
         DEFAULT_ADC_ANALYSIS_MODE,
         DEFAULT_ACTIVATION_DIST,
@@ -117,11 +789,12 @@ pub fn init<const CSIZE: usize, const RSIZE: usize, const MSIZE: usize>(
     tcc0.clock_input(TCC0_DIV);
 
     // Setup default analysis mode
-    let mut adc = set_analysis_mode::<RSIZE>(
+    let mut adc = set_analysis_mode::<CSIZE, RSIZE>(
         DEFAULT_ADC_ANALYSIS_MODE,
         DEFAULT_ADC_CLOCK,
         adc,
         tcc0,
+        scan_timing,
         sense_pins,
     );
 
@@ -133,15 +806,16 @@ pub fn init<const CSIZE: usize, const RSIZE: usize, const MSIZE: usize>(
     defmt::trace!("TCC0 started - Keyscanning");
     tcc0.enable_interrupt();
 
-    (adc, matrix)
+    (adc, HallMatrix::new(matrix, analog_triggers, stages))
 }
 
 /// Configures ADC + timer according to the analysis mode and sample rate
-pub fn set_analysis_mode<const RSIZE: usize>(
+pub fn set_analysis_mode<const CSIZE: usize, const RSIZE: usize>(
     mode: SensorMode,
     adc_clock: AdcClock,
     adc: hal::adc::Adc,
     tcc0: &mut TCC0,
+    scan_timing: &mut ScanTiming,
     sense_pins: &mut SensePins,
 ) -> hal::adc::Adc {
     // Set ADC timing
@@ -152,41 +826,27 @@ pub fn set_analysis_mode<const RSIZE: usize>(
     };
 
     // Set ADC levels
-    let (gain, offset, timing) = match mode {
+    // Full scan timing is set by ScanTiming (minimum of 5, 1 and 2 USB frames respectively)
+    let (gain, offset) = match mode {
         // Non-optimized mode for testing, signficantly reduces sensitivity but allows for full
         // hall effect sensor range (both positive and negative polarities).
         SensorMode::Test(_) => {
             defmt::debug!("ADC Test mode");
-            // TODO calculate minimum latency
-            (
-                SingleEndedGain::Gain2x,
-                true,
-                5000_u32.micros() / RSIZE as u32,
-            )
+            (SingleEndedGain::Gain2x, true)
         }
         // Configures ADC to be optimized for Input Club Silo switches and low latency
+        // Consistency will be negatively affected if key polling rate doesn't match
+        // the USB polling rate for USB 2.0 FS.
         SensorMode::LowLatency(_) => {
             defmt::debug!("ADC Low Latency mode");
-            // Absolute minimum latency is 1 ms / total number of columns (RSIZE)
-            // Consistency will be negatively affected if key polling rate doesn't match
-            // the USB polling rate for USB 2.0 FS.
-            (
-                SingleEndedGain::Gain4x,
-                true,
-                1000_u32.micros() / RSIZE as u32,
-            )
+            (SingleEndedGain::Gain4x, true)
         }
         // Configures ADC to be optimized for Input Club Silo switches
         // Will not work if magnets are not with a specific strength range
         // Adds at least 10x more range compared to test_mode()
         SensorMode::Normal(_) => {
             defmt::debug!("ADC Full Analysis mode");
-            // TODO calculate minimum latency
-            (
-                SingleEndedGain::Gain4x,
-                true,
-                2000_u32.micros() / RSIZE as u32,
-            )
+            (SingleEndedGain::Gain4x, true)
         }
     };
 
@@ -210,7 +870,7 @@ pub fn set_analysis_mode<const RSIZE: usize>(
     adc.autocalibration(true);
 
     // Setup timer
-    tcc0.start(timing);
+    scan_timing.set_mode::<CSIZE, RSIZE>(mode, adc_clock, tcc0);
 
     adc
 }
@@ -326,6 +986,7 @@ pub fn adc_irq<
     adc_pdc: &mut Option<AdcTransfer<ADC_BUF_SIZE>>,
     sense_pins: &mut SensePins,
     tcc0: &mut TCC0,
+    scan_timing: &mut ScanTiming,
     hidio_intf: &mut HidioCommandInterface,
     layer_state: &mut LayerState,
     manu_test_data: &mut heapless::Vec<u8, { kiibohd_hid_io::MESSAGE_LEN - 4 }>,
@@ -333,7 +994,7 @@ pub fn adc_irq<
     switch_remap: &[u8],
 ) -> usize {
     // Current strobe
-    let strobe = matrix.strobe();
+    let strobe = matrix.matrix.strobe();
 
     // The first byte is used to check if the buffer is not complete yet
     // 0 - Not ready
@@ -347,6 +1008,7 @@ pub fn adc_irq<
         return strobe;
     }
     let (buf, adc) = adc_pdc.take().unwrap().wait();
+    scan_timing.sequence_done();
 
     // Manufacturing test data
     // Used to accumulate ADC data for manufacturing tests
@@ -362,6 +1024,12 @@ pub fn adc_irq<
             .unwrap();
     }
 
+    // Raw buffer capture (adctrace)
+    hidio_intf
+        .mut_interface()
+        .adc_trace
+        .record(strobe, &buf[..]);
+
     // Process retrieved ADC buffer
     // Loop through buffer, samples may arrive out of order in some situations
     // (usually due to timing being too aggressive).
@@ -379,26 +1047,40 @@ pub fn adc_irq<
     //    0   -      11      9   N/A (crosstalk isolation)
     //    0   5       9      10  6 * 0 + 5 = 5
     //    0   -      11      11  N/A (crosstalk isolation)
-    for (i, sample) in buf.iter().enumerate() {
-        // Handle multiple samples from the same buffer
-        let channel = (sample & 0xF000) >> 12;
-        // Mask 12 bits and shift right 2 bits to get 10-bit sample
-        let sample = (sample & 0x0FFF) >> 2;
-
-        // Remap channels to rows
-        let row = match channel {
-            0..=3 => channel,
-            8 => 4,
-            9 => 5,
-            _ => continue,
-        } as usize;
+    // Channel 11 readings are recorded as crosstalk of the preceding channel
+    // and are used as the common-mode noise reference of the strobe.
+    #[cfg(feature = "noise-cancel")]
+    let offset = matrix.noise_cancel.update_buffer::<RSIZE>(strobe, &buf[..]);
+    #[cfg(not(feature = "noise-cancel"))]
+    let offset = 0;
+
+    let stats = &mut matrix.stats;
+    let (mut samples, counts) = decode_samples::<RSIZE>(&buf[..], offset, |row, sample| {
+        stats[row * CSIZE + strobe].record_crosstalk(sample)
+    });
+
+    for row in 0..RSIZE {
+        if counts[row] == 0 {
+            continue;
+        }
 
-        // Lookup switch index and record sample
+        // Lookup switch index and record filtered sample
+        let sample = matrix.sample_filter.apply(&mut samples[row][..counts[row]]);
         let index = row * CSIZE + strobe;
-        match matrix.record::<IDLE_LIMIT>(index, sample) {
+        match matrix.matrix.record::<IDLE_LIMIT>(index, sample) {
             Ok(val) => {
                 // If sample is valid and sensor is calibrated, pass to the next stage.
                 if let Some(sense) = val {
+                    // Noise statistics (keys at rest only)
+                    if sense.analysis.distance < DEFAULT_DEACTIVATION_DIST {
+                        hidio_intf.mut_interface().adc_noise.record(
+                            scan_timing.adc_clock(),
+                            sample,
+                            sense.data().value(),
+                        );
+                        matrix.stats[index].record(sample);
+                    }
+
                     // Store data for manufacturing test results
                     if collect_manu_test_data {
                         let data_pos = manu_test_data.len() - RSIZE * 2;
@@ -411,23 +1093,29 @@ pub fn adc_irq<
                     for event in sense
                         .trigger_events::<MAX_PER_KEY_EVENTS>(switch_remap[index] as usize, false)
                     {
-                        let hidio_event = HidIoEvent::TriggerEvent(event);
-
-                        // Enqueue KLL trigger event
-                        let ret = layer_state.process_trigger::<MAX_LAYER_LOOKUP_SIZE>(event);
-                        assert!(ret.is_ok(), "Failed to enqueue: {:?} - {:?}", event, ret);
-
-                        // Enqueue HID-IO trigger event
-                        if let Err(err) = hidio_intf.process_event(hidio_event) {
-                            defmt::error!("Hidio TriggerEvent Error: {:?}", err);
-                        }
+                        enqueue_trigger(&mut matrix.stages, layer_state, hidio_intf, event);
                     }
+
+                    // Analog (depth) triggers
+                    matrix.analog.update(
+                        switch_remap[index] as u16,
+                        sense.analysis.distance,
+                        |event| enqueue_trigger(&mut matrix.stages, layer_state, hidio_intf, event),
+                    );
+
+                    // Dynamic keystrokes
+                    matrix.dks.update(
+                        switch_remap[index] as u16,
+                        sense.analysis.distance,
+                        |event| enqueue_trigger(&mut matrix.stages, layer_state, hidio_intf, event),
+                    );
                 }
             }
             Err(e) => {
+                matrix.stats[index].record_rejected();
                 defmt::error!(
                     "Sample record failed ({}, {}, {}):{} -> {}",
-                    i,
+                    row,
                     strobe,
                     index,
                     sample,
@@ -438,7 +1126,16 @@ pub fn adc_irq<
     }
 
     // Strobe next column
-    if let Ok(strobe) = matrix.next_strobe() {
+    if let Ok(strobe) = matrix.matrix.next_strobe() {
+        if strobe == 0 {
+            // Tapping term timing
+            matrix
+                .stages
+                .tick(scan_timing.period_us() * CSIZE as u32, |event| {
+                    send_trigger(layer_state, hidio_intf, event)
+                });
+            scan_timing.scan_complete::<CSIZE>(tcc0);
+        }
         if collect_manu_test_data {
             // Set next strobe to collect
             manu_test_data[1] = strobe as u8;
@@ -450,23 +1147,66 @@ pub fn adc_irq<
         }
     }
 
-    // Change sensor mode
-    let adc = if hidio_intf
-        .mut_interface()
-        .manufacturing_config
-        .hall_effect_mode_switch
-        .is_some()
-    {
-        set_analysis_mode::<RSIZE>(
-            hidio_intf
-                .mut_interface()
-                .manufacturing_config
-                .hall_effect_mode_switch
-                .take()
-                .unwrap(),
-            DEFAULT_ADC_CLOCK,
+    // Update DKS config
+    if core::mem::take(&mut hidio_intf.mut_interface().dks_changed) {
+        let config = hidio_intf.interface().dks;
+        matrix.dks.set_config(config, |event| {
+            enqueue_trigger(&mut matrix.stages, layer_state, hidio_intf, event)
+        });
+    }
+
+    // Apply stage setting changes (console or HID-IO)
+    let stage_settings = core::mem::take(&mut hidio_intf.mut_interface().stage_settings);
+    matrix.stages.apply(stage_settings, |event| {
+        send_trigger(layer_state, hidio_intf, event)
+    });
+
+    // Update the layers of the keymap overlay and combos
+    matrix
+        .stages
+        .keymap
+        .set_layer(crate::active_layer(layer_state));
+    matrix
+        .stages
+        .combos
+        .set_layers(crate::active_layers(layer_state));
+    if core::mem::take(&mut hidio_intf.mut_interface().keymap_changed) {
+        let config = hidio_intf.interface().keymap;
+        matrix.stages.keymap.set_config(config, &mut |event| {
+            send_trigger(layer_state, hidio_intf, event)
+        });
+    }
+
+    // Clear sensor statistics
+    let config = &mut hidio_intf.mut_interface().manufacturing_config;
+    if config.hall_stats_reset {
+        config.hall_stats_reset = false;
+        matrix.reset_stats();
+    }
+
+    // Toggle noise cancellation
+    #[cfg(feature = "noise-cancel")]
+    if let Some(enabled) = config.hall_noise_cancel.take() {
+        defmt::info!("Noise cancellation: {}", enabled);
+        matrix.noise_cancel.set_enabled(enabled);
+    }
+
+    // Change sample filter
+    if let Some(filter) = config.hall_sample_filter.take() {
+        defmt::info!("Sample filter: {}", filter);
+        matrix.sample_filter = filter;
+    }
+
+    // Change sensor mode and/or ADC clock
+    let mode_switch = config.hall_effect_mode_switch.take();
+    let clock_switch = config.hall_effect_adc_clock.take();
+    let adc = if mode_switch.is_some() || clock_switch.is_some() {
+        set_analysis_mode::<CSIZE, RSIZE>(
+            mode_switch.unwrap_or(scan_timing.mode()),
+            clock_switch.unwrap_or(scan_timing.adc_clock()),
             adc.revert(),
             tcc0,
+            scan_timing,
             sense_pins,
         )
         .with_pdc()
@@ -480,6 +1220,83 @@ pub fn adc_irq<
     strobe
 }
 
+/// Keyscanning timer interrupt (TC0)
+/// Starts the next ADC sequence (unless the next full scan is waiting for a USB SOF)
+pub fn tc0_irq<const ADC_BUF_SIZE: usize>(
+    adc_pdc: &mut Option<AdcTransfer<ADC_BUF_SIZE>>,
+    tcc0: &mut TCC0,
+    scan_timing: &mut ScanTiming,
+) {
+    if tcc0.clear_interrupt_flags() && scan_timing.tick() {
+        // Start next ADC DMA buffer read
+        if let Some(adc) = adc_pdc {
+            adc.resume();
+            scan_timing.sequence_started();
+        }
+    }
+}
+
+/// UDP interrupt flags used to align the keyscanning to the USB frames
+/// Read (without clearing anything) at the start of the UDP interrupt, see udp_status().
+#[derive(Clone, Copy, Debug, defmt::Format)]
+pub struct UdpStatus {
+    /// Start of Frame received
+    sof: bool,
+    /// USB bus reset, disables the SOF interrupt
+    bus_reset: bool,
+}
+
+/// Reads the UDP interrupt status, call from the UDP interrupt before udp_irq()
+/// Read-only, the UDP driver handles (and clears) its own interrupt flags while polling.
+pub fn udp_status() -> UdpStatus {
+    // atsam4-hal UdpBus doesn't expose the SOF or bus reset flags
+    let udp = unsafe { &*hal::pac::UDP::ptr() };
+    let isr = udp.isr.read();
+    UdpStatus {
+        sof: isr.sofint().bit_is_set(),
+        bus_reset: isr.endbusres().bit_is_set(),
+    }
+}
+
+/// Enables the USB SOF interrupt
+/// Called once after the USB device is initialized and by udp_sof_ack() after a bus reset.
+/// The UDP driver doesn't use SOFs, only the SOF interrupt enable is changed.
+pub fn enable_sof() {
+    let udp = unsafe { &*hal::pac::UDP::ptr() };
+    udp.ier.write(|w| w.sofint().set_bit());
+}
+
+/// USB SOF handling, call from the UDP interrupt before udp_irq() (with the udp_status())
+/// Starts the next full scan if it is waiting for a SOF (restarting the strobe timer so the scan
+/// stays aligned to the USB frame).
+pub fn sof_irq<const ADC_BUF_SIZE: usize>(
+    status: UdpStatus,
+    adc_pdc: &mut Option<AdcTransfer<ADC_BUF_SIZE>>,
+    tcc0: &mut TCC0,
+    scan_timing: &mut ScanTiming,
+) {
+    if status.sof && scan_timing.sof() {
+        tcc0.start(scan_timing.period_us().micros());
+        if let Some(adc) = adc_pdc {
+            adc.resume();
+            scan_timing.sequence_started();
+        }
+    }
+}
+
+/// Acknowledges the USB SOF, call from the UDP interrupt after udp_irq() (with the udp_status())
+/// The UDP driver has already polled, so only a SOF flag it left pending is cleared.
+/// The SOF interrupt is re-enabled after a bus reset (the reset disables it).
+pub fn udp_sof_ack(status: UdpStatus) {
+    let udp = unsafe { &*hal::pac::UDP::ptr() };
+    if status.bus_reset {
+        enable_sof();
+    }
+    if status.sof && udp.isr.read().sofint().bit_is_set() {
+        udp.icr.write(|w| w.sofint().set_bit());
+    }
+}
+
 pub fn hidio_send_irq(
     hidio_intf: &mut HidioCommandInterface,
     manu_test_data: &mut heapless::Vec<u8, { kiibohd_hid_io::MESSAGE_LEN - 4 }>,
@@ -504,3 +1321,212 @@ pub fn hidio_send_irq(
     manu_test_data.clear();
     let _ = manu_test_data.push(0);
 }
+
+// ----- Functions -----
+
+/// Enqueues a KLL trigger event through the trigger stages (and forwards it to HID-IO)
+fn enqueue_trigger(
+    stages: &mut TriggerStages,
+    layer_state: &mut LayerState,
+    hidio_intf: &mut HidioCommandInterface,
+    event: TriggerEvent,
+) {
+    stages.process(event, |event| send_trigger(layer_state, hidio_intf, event));
+}
+
+/// Enqueues a KLL trigger event (and forwards it to HID-IO)
+fn send_trigger(
+    layer_state: &mut LayerState,
+    hidio_intf: &mut HidioCommandInterface,
+    event: TriggerEvent,
+) {
+    let hidio_event = HidIoEvent::TriggerEvent(event);
+
+    // Enqueue KLL trigger event
+    let ret = layer_state.process_trigger::<MAX_LAYER_LOOKUP_SIZE>(event);
+    assert!(ret.is_ok(), "Failed to enqueue: {:?} - {:?}", event, ret);
+
+    // Enqueue HID-IO trigger event
+    if let Err(err) = hidio_intf.process_event(hidio_event) {
+        defmt::error!("Hidio TriggerEvent Error: {:?}", err);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    extern crate std;
+    use std::{fs, println, string::String, vec::Vec};
+
+    const ROWS: usize = 6;
+    const CHANNELS: [u16; ROWS] = [0, 1, 2, 3, 8, 9];
+
+    /// Raw ADC buffer, each sensor is followed by a channel 11 reading (10-bit values)
+    fn adc_buffer(sensors: [u16; ROWS], channel11: [u16; ROWS]) -> Vec<u16> {
+        let mut buf = Vec::new();
+        for _ in 0..ADC_SAMPLES {
+            for row in 0..ROWS {
+                buf.push(CHANNELS[row] << 12 | sensors[row] << 2);
+                buf.push(11 << 12 | channel11[row] << 2);
+            }
+        }
+        buf
+    }
+
+    /// Parses adctrace output (lines of `<index>: <hex words>`, other lines are ignored)
+    fn parse_trace(trace: &str) -> Vec<Vec<u16>> {
+        trace
+            .lines()
+            .filter_map(|line| {
+                let (index, words) = line.split_once(':')?;
+                index.trim().parse::<usize>().ok()?;
+                words
+                    .split_ascii_whitespace()
+                    .map(|word| u16::from_str_radix(word, 16).ok())
+                    .collect()
+            })
+            .collect()
+    }
+
+    fn variance(samples: &[f64]) -> f64 {
+        let mean = samples.iter().sum::<f64>() / samples.len() as f64;
+        samples.iter().map(|s| (s - mean) * (s - mean)).sum::<f64>() / samples.len() as f64
+    }
+
+    /// Replays the buffers of a strobe without and with noise cancellation
+    /// Returns the sample variance of each row (without, with)
+    fn replay(buffers: &[Vec<u16>]) -> [(f64, f64); ROWS] {
+        let mut noise_cancel = [NoiseCancel::<1>::new(false), NoiseCancel::<1>::new(true)];
+        let mut rows: [[Vec<f64>; 2]; ROWS] = Default::default();
+        for buf in buffers {
+            for (i, noise_cancel) in noise_cancel.iter_mut().enumerate() {
+                let offset = noise_cancel.update_buffer::<ROWS>(0, buf);
+                let (mut samples, counts) = decode_samples::<ROWS>(buf, offset, |_, _| {});
+                for row in 0..ROWS {
+                    if counts[row] > 0 {
+                        let sample = SampleFilter::Median.apply(&mut samples[row][..counts[row]]);
+                        rows[row][i].push(sample as f64);
+                    }
+                }
+            }
+        }
+        rows.map(|[without, with]| (variance(&without), variance(&with)))
+    }
+
+    /// Deterministic noise in -range..=range
+    fn noise(seed: &mut u32, range: i32) -> i32 {
+        *seed = seed.wrapping_mul(1_103_515_245).wrapping_add(12_345);
+        ((*seed >> 16) % (2 * range as u32 + 1)) as i32 - range
+    }
+
+    #[test]
+    fn decode() {
+        let buf = adc_buffer([100, 200, 300, 400, 500, 600], [10, 20, 30, 40, 50, 60]);
+        let mut crosstalk = Vec::new();
+        let (samples, counts) =
+            decode_samples::<ROWS>(&buf, 5, |row, sample| crosstalk.push((row, sample)));
+        assert_eq!(counts, [ADC_SAMPLES; ROWS]);
+        assert_eq!(samples.map(|row| row[0]), [95, 195, 295, 395, 495, 595]);
+        assert_eq!(
+            &crosstalk[..ROWS],
+            &[(0, 10), (1, 20), (2, 30), (3, 40), (4, 50), (5, 60)]
+        );
+    }
+
+    #[test]
+    fn adc_trace_capture() {
+        let mut trace = AdcTrace::new();
+        let buf = adc_buffer([100; ROWS], [10; ROWS]);
+        trace.record(2, &buf);
+        assert_eq!(trace.buffers().count(), 0);
+
+        // Only the captured strobe is recorded until the buffer is full
+        trace.start(2);
+        for strobe in 0..ADC_TRACE_SIZE {
+            trace.record(strobe % 4, &buf);
+        }
+        assert_eq!(trace.buffers().count(), ADC_TRACE_SIZE / buf.len());
+        assert!(trace.buffers().all(|captured| captured == &buf[..]));
+
+        // The report can be replayed
+        let mut out = String::new();
+        for first in (0..trace.buffers().count()).step_by(ADC_TRACE_PER_PAGE) {
+            trace.report(&mut out, first).unwrap();
+        }
+        let parsed = parse_trace(&out);
+        assert_eq!(parsed.len(), ADC_TRACE_SIZE / buf.len());
+        assert!(parsed.iter().all(|captured| captured == &buf));
+    }
+
+    #[test]
+    fn noise_cancel_common_mode() {
+        // Common-mode noise moves every channel of a strobe, including channel 11
+        let mut seed = 1;
+        let buffers: Vec<_> = (0..2000)
+            .map(|_| {
+                let common = noise(&mut seed, 8);
+                let sensors = [400, 420, 380, 410, 390, 405]
+                    .map(|value| (value + common + noise(&mut seed, 1)) as u16);
+                let channel11 = [60; ROWS].map(|value| (value + common) as u16);
+                adc_buffer(sensors, channel11)
+            })
+            .collect();
+
+        for (row, (without, with)) in replay(&buffers).iter().enumerate() {
+            assert!(
+                with * 4.0 < *without,
+                "row {}: {} -> {}",
+                row,
+                without,
+                with
+            );
+        }
+    }
+
+    #[test]
+    fn noise_cancel_independent_noise() {
+        // Channel 11 noise that isn't shared with the sensors must not add noise (median)
+        let mut seed = 7;
+        let buffers: Vec<_> = (0..2000)
+            .map(|_| {
+                let sensors = [400; ROWS].map(|value| (value + noise(&mut seed, 2)) as u16);
+                let channel11 = [60; ROWS].map(|value| (value + noise(&mut seed, 2)) as u16);
+                adc_buffer(sensors, channel11)
+            })
+            .collect();
+
+        for (row, (without, with)) in replay(&buffers).iter().enumerate() {
+            assert!(
+                *with < without * 2.0,
+                "row {}: {} -> {}",
+                row,
+                without,
+                with
+            );
+        }
+    }
+
+    /// Replays the adctrace captures in traces/ (keys at rest)
+    /// Run with `--nocapture` to see the variance of each row without and with cancellation.
+    #[test]
+    fn noise_cancel_trace_replay() {
+        let Ok(dir) = fs::read_dir(concat!(env!("CARGO_MANIFEST_DIR"), "/traces")) else {
+            return;
+        };
+        for entry in dir.flatten() {
+            let path = entry.path();
+            if path.extension() != Some("txt".as_ref()) {
+                continue;
+            }
+            let buffers = parse_trace(&fs::read_to_string(&path).unwrap());
+            assert!(!buffers.is_empty(), "{}: no ADC buffers", path.display());
+
+            println!("{} ({} buffers)", path.display(), buffers.len());
+            println!("row  variance  cancelled");
+            for (row, (without, with)) in replay(&buffers).iter().enumerate() {
+                println!("{:3} {:9.3} {:10.3}", row, without, with);
+            }
+        }
+    }
+}
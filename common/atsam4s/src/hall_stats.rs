@@ -0,0 +1,167 @@
+// Copyright 2026 Jacob Alexander
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! Per-key hall effect sensor statistics
+//!
+//! Used to find noisy or faulty sensors (e.g. during manufacturing) using the `hallstats`
+//! console/HID-IO terminal command.
+
+use crate::constants::*;
+
+use core::fmt;
+
+// ----- Structs -----
+
+/// Sensor statistics for a single key
+/// Noise statistics only include samples taken while the key is at rest.
+#[derive(Clone, Copy)]
+pub struct KeyStats {
+    /// Samples taken while at rest
+    pub samples: u32,
+    sum: u64,
+    sum_sq: u64,
+    pub min: u16,
+    pub max: u16,
+    /// Samples rejected by the sensor analysis
+    pub rejected: u32,
+    /// Channel 11 (disconnected) samples taken directly after this key
+    pub crosstalk_samples: u32,
+    crosstalk_sum: u64,
+    pub crosstalk_max: u16,
+}
+
+impl KeyStats {
+    pub const fn new() -> Self {
+        Self {
+            samples: 0,
+            sum: 0,
+            sum_sq: 0,
+            min: u16::MAX,
+            max: 0,
+            rejected: 0,
+            crosstalk_samples: 0,
+            crosstalk_sum: 0,
+            crosstalk_max: 0,
+        }
+    }
+
+    /// Records a sample taken while the key is at rest
+    pub fn record(&mut self, sample: u16) {
+        self.samples = self.samples.saturating_add(1);
+        self.sum = self.sum.saturating_add(sample as u64);
+        self.sum_sq = self.sum_sq.saturating_add(sample as u64 * sample as u64);
+        self.min = self.min.min(sample);
+        self.max = self.max.max(sample);
+    }
+
+    pub fn record_rejected(&mut self) {
+        self.rejected = self.rejected.saturating_add(1);
+    }
+
+    /// Records the channel 11 sample that follows this key in the ADC sequence
+    /// Channel 11 is disconnected, any reading is charge left over from the previous channel.
+    pub fn record_crosstalk(&mut self, sample: u16) {
+        self.crosstalk_samples = self.crosstalk_samples.saturating_add(1);
+        self.crosstalk_sum = self.crosstalk_sum.saturating_add(sample as u64);
+        self.crosstalk_max = self.crosstalk_max.max(sample);
+    }
+
+    /// Mean sample value (x16)
+    pub fn mean_x16(&self) -> u32 {
+        if self.samples == 0 {
+            return 0;
+        }
+        (self.sum * 16 / self.samples as u64) as u32
+    }
+
+    /// Sample variance (x256)
+    pub fn variance_x256(&self) -> u32 {
+        if self.samples == 0 {
+            return 0;
+        }
+        // (n * sum(x^2) - sum(x)^2) / n^2, 128-bit to avoid losing precision
+        let n = self.samples as u128;
+        let sum = self.sum as u128;
+        let var_x256 = (n * self.sum_sq as u128).saturating_sub(sum * sum) * 256 / (n * n);
+        var_x256.min(u32::MAX as u128) as u32
+    }
+
+    /// Mean channel 11 reading
+    pub fn crosstalk_mean(&self) -> u16 {
+        if self.crosstalk_samples == 0 {
+            return 0;
+        }
+        (self.crosstalk_sum / self.crosstalk_samples as u64) as u16
+    }
+
+    /// Sensor is likely faulty (too noisy, too many rejected samples or too much crosstalk)
+    pub fn bad(&self) -> bool {
+        let range = if self.samples == 0 {
+            0
+        } else {
+            (self.max - self.min) as usize
+        };
+        range > MAX_DEVIATION
+            || self.variance_x256() > SENSOR_MAX_VARIANCE * 256
+            || self.rejected as u64 * 100
+                > (self.samples as u64 + self.rejected as u64) * SENSOR_MAX_REJECTED_PERCENT
+            || self.crosstalk_mean() > SENSOR_MAX_CROSSTALK
+    }
+}
+
+impl Default for KeyStats {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// ----- Functions -----
+
+/// Writes the statistics of the given keys
+/// With first set, HALL_STATS_PER_PAGE keys are written starting at first, otherwise only keys
+/// flagged as bad are written.
+pub fn report(stats: &[KeyStats], out: &mut dyn fmt::Write, first: Option<usize>) -> fmt::Result {
+    writeln!(out, "key  samples mean   var min  max  rej xt xtmax")?;
+    let (start, end) = match first {
+        Some(first) => (first, (first + HALL_STATS_PER_PAGE).min(stats.len())),
+        None => (0, stats.len()),
+    };
+
+    let mut bad = 0;
+    for (index, key) in stats.iter().enumerate().take(end).skip(start) {
+        if key.bad() {
+            bad += 1;
+        } else if first.is_none() {
+            continue;
+        }
+
+        let (min, max) = if key.samples == 0 {
+            (0, 0)
+        } else {
+            (key.min, key.max)
+        };
+        writeln!(
+            out,
+            "{:3} {:8} {:4} {:5} {:3} {:4} {:4} {:2} {:5}{}",
+            index,
+            key.samples,
+            key.mean_x16() / 16,
+            key.variance_x256() / 256,
+            min,
+            max,
+            key.rejected,
+            key.crosstalk_mean(),
+            key.crosstalk_max,
+            if key.bad() { " BAD" } else { "" },
+        )?;
+    }
+
+    if first.is_none() {
+        writeln!(out, "{} of {} sensors flagged", bad, stats.len())?;
+    }
+    Ok(())
+}
@@ -6,6 +6,13 @@
 // copied, modified, or distributed except according to those terms.
 
 use super::constants::*;
+use crate::console::{Command, ParseError, Setting};
+use crate::dynamic_macro::MacroStore;
+use crate::inject::Injector;
+use crate::keymap::KeymapConfig;
+use crate::profiling::Profile;
+use crate::stages::StageSettings;
+use crate::terminal::TerminalBuffer;
 use atsam4_hal as hal;
 use core::fmt::Write;
 use hal::chipid::ChipId;
@@ -13,7 +20,12 @@ use heapless::{String, Vec};
 use kiibohd_hid_io::*;
 
 #[cfg(feature = "hall-effect")]
-use crate::hall_effect::{SensorMode, SILO_ATSAM4S_LC605_GAIN_2X, SILO_ATSAM4S_LC605_GAIN_4X};
+use crate::dks::DksConfig;
+#[cfg(feature = "hall-effect")]
+use crate::hall_effect::{
+    AdcClock, AdcNoise, AdcTrace, SampleFilter, SensorMode, SILO_ATSAM4S_LC605_GAIN_2X,
+    SILO_ATSAM4S_LC605_GAIN_4X,
+};
 
 #[derive(defmt::Format)]
 pub struct ManufacturingConfig {
@@ -30,6 +42,18 @@ pub struct ManufacturingConfig {
     /// Hall Effect Mode Switch
     #[cfg(feature = "hall-effect")]
     pub hall_effect_mode_switch: Option<SensorMode>,
+    /// Hall Effect ADC Clock Switch
+    #[cfg(feature = "hall-effect")]
+    pub hall_effect_adc_clock: Option<AdcClock>,
+    /// Clear the per-key hall effect sensor statistics
+    #[cfg(feature = "hall-effect")]
+    pub hall_stats_reset: bool,
+    /// Enable/disable channel 11 noise cancellation
+    #[cfg(feature = "noise-cancel")]
+    pub hall_noise_cancel: Option<bool>,
+    /// Hall Effect per-strobe sample filter switch
+    #[cfg(feature = "hall-effect")]
+    pub hall_sample_filter: Option<SampleFilter>,
 }
 
 #[derive(defmt::Format)]
@@ -44,10 +68,87 @@ pub struct LedControl {
     pub next_frame: bool,
 }
 
+/// Global LED settings, applied by led_frame_process when led_settings_changed is set
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub struct LedSettings {
+    /// Global current control of the LED drivers
+    pub brightness: u8,
+    pub enabled: bool,
+}
+
+impl LedSettings {
+    /// Stored settings marker ("LE")
+    const MAGIC: u32 = 0x4C45_0000;
+
+    /// Serializes the settings (1 word) for the user signature
+    pub fn to_word(self) -> u32 {
+        Self::MAGIC | (self.enabled as u32) << 8 | self.brightness as u32
+    }
+
+    /// Deserializes the settings from the user signature
+    /// Returns None if no settings have been stored
+    pub fn from_word(word: u32) -> Option<Self> {
+        (word & 0xFFFF_0000 == Self::MAGIC).then_some(Self {
+            brightness: word as u8,
+            enabled: word & 0x100 != 0,
+        })
+    }
+}
+
 pub struct HidioInterface<const H: usize> {
     pub led_buffer: Vec<u8, { ISSI_DRIVER_CHIPS * ISSI_DRIVER_CHANNELS }>,
     pub led_control: LedControl,
+    /// Brightness and on/off (VIA lighting), defaults to ISSI_DEFAULT_BRIGHTNESS/ENABLE
+    pub led_settings: LedSettings,
+    pub led_settings_changed: bool,
+    /// Write the LED settings to flash (see storage.rs)
+    pub led_settings_save: bool,
     pub manufacturing_config: ManufacturingConfig,
+    /// Pending h0031 terminal command (processed by hidio_terminal_task)
+    pub terminal_command: Option<Result<Command, ParseError>>,
+    /// Terminal output waiting to be sent using h0034
+    pub terminal_out: TerminalBuffer,
+    /// Bootloader reset requested by a terminal command, remaining hidio_terminal_task calls
+    /// once the output has been sent
+    pub terminal_bootloader: Option<u32>,
+    /// Pending keyscanning timing change (applied by the keyscanning interrupt)
+    pub matrix_setting: Option<Setting>,
+    /// Pending trigger stage changes (applied by the scanning interrupt)
+    pub stage_settings: StageSettings,
+    /// Task profiling statistics (profile command)
+    pub profile: Profile,
+    /// Recorded dynamic macros (macro command)
+    pub dynamic_macros: MacroStore,
+    /// Write the dynamic macros to flash (see storage.rs)
+    pub dynamic_macros_save: bool,
+    /// Keymap overlay (keymap command)
+    pub keymap: KeymapConfig,
+    /// Apply the keymap overlay (see keymap.rs)
+    pub keymap_changed: bool,
+    /// Write the keymap overlay to flash (see storage.rs)
+    pub keymap_save: bool,
+    /// RTT ticks left before the keymap overlay is written (VIA edits)
+    pub keymap_save_delay: u8,
+    /// Layer stack as of the last layer_notify_task (layer notifications and LED schemes)
+    pub layer_stack: Vec<u8, MAX_ACTIVE_LAYERS>,
+    /// macro_process iterations since each layer was added to or removed from the layer stack
+    pub layer_cycles: [u32; MAX_LAYERS],
+    /// Host injected key events (inject command), sent by inject_task
+    pub inject: Injector,
+    /// ADC noise statistics for each ADC clock (adcnoise command)
+    #[cfg(feature = "hall-effect")]
+    pub adc_noise: AdcNoise,
+    /// Raw ADC buffers of a strobe (adctrace command), recorded by the ADC interrupt
+    #[cfg(feature = "hall-effect")]
+    pub adc_trace: AdcTrace,
+    /// DKS config (dks command), the matrix is updated when dks_changed is set
+    #[cfg(feature = "hall-effect")]
+    pub dks: DksConfig,
+    #[cfg(feature = "hall-effect")]
+    pub dks_changed: bool,
+    /// Write the DKS config to flash (see storage.rs)
+    #[cfg(feature = "hall-effect")]
+    pub dks_save: bool,
     mcu: Option<String<12>>,
     serial: Option<String<126>>,
     firmware_version: &'static str,
@@ -75,6 +176,14 @@ impl<const H: usize> HidioInterface<H> {
             hall_level_check: false,
             #[cfg(feature = "hall-effect")]
             hall_effect_mode_switch: None,
+            #[cfg(feature = "hall-effect")]
+            hall_effect_adc_clock: None,
+            #[cfg(feature = "hall-effect")]
+            hall_stats_reset: false,
+            #[cfg(feature = "noise-cancel")]
+            hall_noise_cancel: None,
+            #[cfg(feature = "hall-effect")]
+            hall_sample_filter: None,
         };
 
         // Default to all controls disabled
@@ -93,7 +202,38 @@ impl<const H: usize> HidioInterface<H> {
         Self {
             led_buffer,
             led_control,
+            led_settings: LedSettings {
+                brightness: 255,
+                enabled: true,
+            },
+            led_settings_changed: false,
+            led_settings_save: false,
             manufacturing_config,
+            terminal_command: None,
+            terminal_out: TerminalBuffer::new(),
+            terminal_bootloader: None,
+            matrix_setting: None,
+            stage_settings: StageSettings::default(),
+            profile: Profile::new(),
+            dynamic_macros: MacroStore::new(),
+            dynamic_macros_save: false,
+            keymap: KeymapConfig::new(),
+            keymap_changed: false,
+            keymap_save: false,
+            keymap_save_delay: 0,
+            layer_stack: Vec::new(),
+            layer_cycles: [0; MAX_LAYERS],
+            inject: Injector::new(),
+            #[cfg(feature = "hall-effect")]
+            adc_noise: AdcNoise::new(),
+            #[cfg(feature = "hall-effect")]
+            adc_trace: AdcTrace::new(),
+            #[cfg(feature = "hall-effect")]
+            dks: DksConfig::new(),
+            #[cfg(feature = "hall-effect")]
+            dks_changed: false,
+            #[cfg(feature = "hall-effect")]
+            dks_save: false,
             mcu,
             serial,
             firmware_version,
@@ -191,6 +331,21 @@ impl<const H: usize> KiibohdCommandInterface<H> for HidioInterface<H> {
         Ok(h0026::Ack {})
     }
 
+    fn h0031_terminalcmd_cmd(
+        &mut self,
+        data: h0031::Cmd<{ MESSAGE_LEN - 1 }>,
+    ) -> Result<h0031::Ack, h0031::Nak> {
+        defmt::info!("h0031_terminalcmd_cmd: {:?}", data);
+        // Only one command may be pending at a time
+        if self.terminal_command.is_some() {
+            defmt::warn!("h0031_terminalcmd_cmd: previous command still pending");
+            return Err(h0031::Nak {});
+        }
+
+        self.terminal_command = Some(crate::console::parse(&data.command));
+        Ok(h0031::Ack {})
+    }
+
     fn h0050_manufacturing_cmd(&mut self, data: h0050::Cmd) -> Result<h0050::Ack, h0050::Nak> {
         // Make sure these are valid command/arguments for this keyboard
         let ret = match data.command {
@@ -0,0 +1,419 @@
+// Copyright 2026 Jacob Alexander
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! Host injected key events
+//!
+//! The host presses and releases switches (KLL trigger indices) using the inject command
+//! (HID-IO terminal or serial console). The events are sent by macro_process through the trigger
+//! stages (combos, tap-hold, keymap overlay, etc.) to kll-core, the same way as the events of the
+//! scanning interrupt, so they follow the KLL layers and macros. Injected keys are confirmed by
+//! the off-state lookups and listed by keystate (they aren't part of the matrix state).
+//!
+//! Safety limits:
+//! - At most MAX_INJECT_KEYS keys are held at once
+//! - Injected keys are released after INJECT_HOLD_TIMEOUT_MS
+//! - More than INJECT_RATE_LIMIT events per second releases every injected key and rejects new
+//!   events for INJECT_LOCKOUT_MS
+
+use crate::constants::*;
+use core::fmt;
+use heapless::{Deque, Vec};
+use kll_core::{trigger::Phro, TriggerEvent};
+
+// ----- Constants -----
+
+const CYCLES_PER_US: u32 = MCU_FREQ / 1_000_000;
+
+// Every held key must fit into the queue when injection is locked out
+const _: () = assert!(MAX_INJECT_KEYS <= INJECT_QUEUE_SIZE);
+
+// ----- Enums -----
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum InjectError {
+    /// Rate limit was exceeded recently
+    LockedOut,
+    /// Rate limit exceeded, injection is now locked out
+    RateLimited,
+    /// Switch is outside of the layout
+    InvalidSwitch,
+    /// Too many injected keys held at once
+    TooManyKeys,
+    /// Released switch was not pressed by the host
+    NotPressed,
+    /// Events are injected faster than macro_process handles them
+    QueueFull,
+}
+
+impl InjectError {
+    /// Human readable error, printed on the console
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            InjectError::LockedOut => "Injection locked out (rate limit exceeded)",
+            InjectError::RateLimited => "Rate limit exceeded, injected keys released",
+            InjectError::InvalidSwitch => "Invalid switch",
+            InjectError::TooManyKeys => "Too many injected keys held",
+            InjectError::NotPressed => "Switch was not pressed",
+            InjectError::QueueFull => "Injection queue full",
+        }
+    }
+
+    /// Stable identifier used by the HID-IO terminal status line
+    pub fn code(&self) -> &'static str {
+        match self {
+            InjectError::LockedOut => "locked_out",
+            InjectError::RateLimited => "rate_limited",
+            InjectError::InvalidSwitch => "invalid_switch",
+            InjectError::TooManyKeys => "too_many_keys",
+            InjectError::NotPressed => "not_pressed",
+            InjectError::QueueFull => "queue_full",
+        }
+    }
+}
+
+// ----- Structs -----
+
+/// Injected key sent to kll-core
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+struct HeldKey {
+    switch: u16,
+    held_us: u32,
+    /// macro_process iterations since the press (last_state)
+    cycles: u32,
+}
+
+/// Host injected key events (inject command), sent by inject_task
+pub struct Injector {
+    /// Switch and press (true) or release (false), one event per macro_process iteration
+    queue: Deque<(u16, bool), INJECT_QUEUE_SIZE>,
+    /// Keys pressed by the host, including queued events
+    requested: Vec<u16, MAX_INJECT_KEYS>,
+    /// Keys pressed in kll-core
+    held: Vec<HeldKey, MAX_INJECT_KEYS>,
+    /// Events of the current rate limit window (1 second)
+    window_events: u32,
+    window_us: u32,
+    /// Remaining lockout time
+    lockout_us: u32,
+    /// Cycle count of the last tick
+    cycles: Option<u32>,
+}
+
+impl Injector {
+    /// Most trigger events emitted by a single tick (hold events of the held keys and the next
+    /// queued event)
+    pub const MAX_EVENTS: usize = MAX_INJECT_KEYS + 1;
+
+    pub const fn new() -> Self {
+        Self {
+            queue: Deque::new(),
+            requested: Vec::new(),
+            held: Vec::new(),
+            window_events: 0,
+            window_us: 0,
+            lockout_us: 0,
+            cycles: None,
+        }
+    }
+
+    /// Queues a press or release of a switch (KLL trigger index)
+    /// Pressing a key that is already pressed does nothing.
+    pub fn request(&mut self, switch: u16, press: bool) -> Result<(), InjectError> {
+        if self.lockout_us > 0 {
+            return Err(InjectError::LockedOut);
+        }
+        if switch as usize >= LAYOUT_SIZE {
+            return Err(InjectError::InvalidSwitch);
+        }
+        let pos = self.requested.iter().position(|key| *key == switch);
+        match (press, pos) {
+            (true, Some(_)) => {
+                return Ok(());
+            }
+            (true, None) if self.requested.is_full() => {
+                return Err(InjectError::TooManyKeys);
+            }
+            (false, None) => {
+                return Err(InjectError::NotPressed);
+            }
+            _ => {}
+        }
+        if self.queue.is_full() {
+            return Err(InjectError::QueueFull);
+        }
+
+        self.window_events += 1;
+        if self.window_events > INJECT_RATE_LIMIT {
+            defmt::warn!("Injection rate limit exceeded, locked out");
+            self.lockout_us = INJECT_LOCKOUT_MS * 1000;
+            self.release_all();
+            return Err(InjectError::RateLimited);
+        }
+
+        self.queue.push_back((switch, press)).ok();
+        match pos {
+            Some(pos) => {
+                self.requested.swap_remove(pos);
+            }
+            None => {
+                self.requested.push(switch).ok();
+            }
+        }
+        Ok(())
+    }
+
+    /// Drops the queued events and releases every injected key
+    pub fn release_all(&mut self) {
+        self.queue.clear();
+        self.requested.clear();
+        for key in &self.held {
+            self.queue.push_back((key.switch, false)).ok();
+        }
+    }
+
+    /// Advances the timers using the DWT cycle count (see profiling::start) and emits the trigger
+    /// events of this macro_process iteration (hold events of the held keys and the next queued
+    /// event)
+    pub fn tick(&mut self, cycles: u32, mut emit: impl FnMut(TriggerEvent)) {
+        let elapsed_us = match self.cycles.replace(cycles) {
+            Some(last) => cycles.wrapping_sub(last) / CYCLES_PER_US,
+            None => 0,
+        };
+        self.window_us = self.window_us.saturating_add(elapsed_us);
+        if self.window_us >= 1_000_000 {
+            self.window_us = 0;
+            self.window_events = 0;
+        }
+        if self.lockout_us > 0 {
+            self.lockout_us = self.lockout_us.saturating_sub(elapsed_us);
+            if self.lockout_us == 0 {
+                defmt::info!("Injection lockout ended");
+            }
+        }
+
+        // Release keys held for too long (e.g. the host application stopped)
+        for key in self.held.iter_mut() {
+            key.held_us = key.held_us.saturating_add(elapsed_us);
+            key.cycles = key.cycles.saturating_add(1);
+            if key.held_us < INJECT_HOLD_TIMEOUT_MS * 1000 {
+                continue;
+            }
+            if let Some(pos) = self
+                .requested
+                .iter()
+                .position(|switch| *switch == key.switch)
+            {
+                if self.queue.push_back((key.switch, false)).is_ok() {
+                    defmt::warn!("Injected switch {} held too long, released", key.switch);
+                    self.requested.swap_remove(pos);
+                }
+            }
+        }
+
+        let next = self.queue.pop_front();
+        for key in &self.held {
+            if !matches!(next, Some((switch, _)) if switch == key.switch) {
+                emit(Self::event(Phro::Hold, key.switch, key.cycles));
+            }
+        }
+        match next {
+            Some((switch, true)) => {
+                let key = HeldKey {
+                    switch,
+                    held_us: 0,
+                    cycles: 0,
+                };
+                if self.held.push(key).is_err() {
+                    defmt::warn!("Too many injected keys held, dropped switch {}", switch);
+                    return;
+                }
+                emit(Self::event(Phro::Press, switch, 0));
+            }
+            Some((switch, false)) => {
+                let cycles = match self.held.iter().position(|key| key.switch == switch) {
+                    Some(pos) => self.held.swap_remove(pos).cycles,
+                    None => 0,
+                };
+                emit(Self::event(Phro::Release, switch, cycles));
+            }
+            None => {}
+        }
+    }
+
+    /// Switches (KLL trigger indices) of the keys pressed in kll-core
+    pub fn held(&self) -> impl Iterator<Item = u16> + '_ {
+        self.held.iter().map(|key| key.switch)
+    }
+
+    /// Hold events of the keys pressed in kll-core (used for off-state lookups)
+    pub fn held_events(&self) -> Vec<TriggerEvent, MAX_INJECT_KEYS> {
+        self.held
+            .iter()
+            .map(|key| Self::event(Phro::Hold, key.switch, key.cycles))
+            .collect()
+    }
+
+    fn event(state: Phro, index: u16, cycles: u32) -> TriggerEvent {
+        TriggerEvent::Switch {
+            state,
+            index,
+            last_state: cycles,
+        }
+    }
+
+    /// Dumps the injected keys and the lockout state
+    pub fn report(&self, out: &mut dyn fmt::Write) -> fmt::Result {
+        write!(out, "Injected keys:")?;
+        for key in &self.held {
+            write!(out, " {}", key.switch)?;
+        }
+        writeln!(out)?;
+        if self.lockout_us > 0 {
+            writeln!(out, "Locked out for {} ms", self.lockout_us / 1000)?;
+        }
+        writeln!(
+            out,
+            "{}/{} events this second",
+            self.window_events.min(INJECT_RATE_LIMIT),
+            INJECT_RATE_LIMIT
+        )
+    }
+}
+
+impl Default for Injector {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    extern crate std;
+    use std::vec::Vec;
+
+    /// Ticks the injector at ms (time since the first tick), returns the switch events
+    fn tick(inject: &mut Injector, ms: u32) -> Vec<(Phro, u16)> {
+        let mut out = Vec::new();
+        inject.tick(ms.wrapping_mul(1000 * CYCLES_PER_US), |event| {
+            if let TriggerEvent::Switch { state, index, .. } = event {
+                out.push((state, index));
+            }
+        });
+        assert!(out.len() <= Injector::MAX_EVENTS);
+        out
+    }
+
+    #[test]
+    fn one_event_per_tick() {
+        let mut inject = Injector::new();
+        tick(&mut inject, 0);
+        assert_eq!(inject.request(1, true), Ok(()));
+        assert_eq!(inject.request(2, true), Ok(()));
+        assert_eq!(inject.request(1, true), Ok(()));
+        assert_eq!(tick(&mut inject, 1), [(Phro::Press, 1)]);
+        assert_eq!(tick(&mut inject, 2), [(Phro::Hold, 1), (Phro::Press, 2)]);
+        assert_eq!(inject.request(1, false), Ok(()));
+        assert_eq!(tick(&mut inject, 3), [(Phro::Hold, 2), (Phro::Release, 1)]);
+        assert_eq!(inject.held().collect::<Vec<_>>(), [2]);
+        assert_eq!(inject.request(1, false), Err(InjectError::NotPressed));
+        assert_eq!(
+            inject.request(LAYOUT_SIZE as u16, true),
+            Err(InjectError::InvalidSwitch)
+        );
+    }
+
+    #[test]
+    fn too_many_keys() {
+        let mut inject = Injector::new();
+        tick(&mut inject, 0);
+        for switch in 0..MAX_INJECT_KEYS as u16 {
+            assert_eq!(inject.request(switch, true), Ok(()));
+        }
+        assert_eq!(
+            inject.request(MAX_INJECT_KEYS as u16, true),
+            Err(InjectError::TooManyKeys)
+        );
+        for ms in 1..=MAX_INJECT_KEYS as u32 {
+            tick(&mut inject, ms);
+        }
+        assert_eq!(inject.held_events().len(), MAX_INJECT_KEYS);
+        assert_eq!(inject.request(0, false), Ok(()));
+        // Hold events of the other keys and the release
+        assert_eq!(
+            tick(&mut inject, MAX_INJECT_KEYS as u32 + 1).len(),
+            MAX_INJECT_KEYS
+        );
+    }
+
+    #[test]
+    fn rate_limit_and_lockout() {
+        let mut inject = Injector::new();
+        tick(&mut inject, 0);
+        let mut ms = 0;
+        for event in 0..INJECT_RATE_LIMIT - 1 {
+            assert_eq!(inject.request(1, event % 2 == 0), Ok(()));
+            ms += 1;
+            tick(&mut inject, ms);
+        }
+        assert_eq!(inject.request(2, true), Ok(()));
+        ms += 1;
+        tick(&mut inject, ms);
+        assert_eq!(inject.held().collect::<Vec<_>>(), [1, 2]);
+
+        // Every injected key is released
+        assert_eq!(inject.request(3, true), Err(InjectError::RateLimited));
+        let mut released = Vec::new();
+        for _ in 0..2 {
+            ms += 1;
+            released.extend(
+                tick(&mut inject, ms)
+                    .into_iter()
+                    .filter(|(state, _)| *state == Phro::Release),
+            );
+        }
+        released.sort_by_key(|(_, switch)| *switch);
+        assert_eq!(released, [(Phro::Release, 1), (Phro::Release, 2)]);
+        assert_eq!(inject.held().count(), 0);
+
+        // Locked out, the rate limit window doesn't matter
+        assert_eq!(inject.request(3, true), Err(InjectError::LockedOut));
+        ms += 1500;
+        tick(&mut inject, ms);
+        assert_eq!(inject.request(3, true), Err(InjectError::LockedOut));
+        ms += INJECT_LOCKOUT_MS;
+        tick(&mut inject, ms);
+        assert_eq!(inject.request(3, true), Ok(()));
+    }
+
+    #[test]
+    fn rate_limit_window() {
+        let mut inject = Injector::new();
+        tick(&mut inject, 0);
+        for event in 0..INJECT_RATE_LIMIT {
+            assert_eq!(inject.request(1, event % 2 == 0), Ok(()));
+            tick(&mut inject, event + 1);
+        }
+        // Next second
+        tick(&mut inject, 1000);
+        assert_eq!(inject.request(1, true), Ok(()));
+    }
+
+    #[test]
+    fn hold_timeout() {
+        let mut inject = Injector::new();
+        tick(&mut inject, 0);
+        assert_eq!(inject.request(1, true), Ok(()));
+        assert_eq!(tick(&mut inject, 1), [(Phro::Press, 1)]);
+        assert_eq!(tick(&mut inject, INJECT_HOLD_TIMEOUT_MS), [(Phro::Hold, 1)]);
+        assert_eq!(
+            tick(&mut inject, INJECT_HOLD_TIMEOUT_MS + 1),
+            [(Phro::Release, 1)]
+        );
+        assert_eq!(inject.request(1, false), Err(InjectError::NotPressed));
+    }
+}
@@ -0,0 +1,1033 @@
+// Copyright 2026 Jacob Alexander
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+use crate::constants::*;
+use crate::layer_led::LayerLedOverlay;
+use crate::*;
+
+use core::convert::Infallible;
+use hal::{
+    clock::{Enabled, Twi0Clock, Twi1Clock},
+    pac::twi0::RegisterBlock,
+    OutputPin,
+};
+use heapless::{Deque, Vec};
+
+// ----- Constants -----
+
+/// Command register write lock (same address for all supported chips)
+const ISSI_CMD_LOCK: u8 = 0xFE;
+/// Unlocks the command register for a single write
+const ISSI_CMD_LOCK_UNLOCK: u8 = 0xC5;
+/// Command register (page select)
+const ISSI_CMD: u8 = 0xFD;
+
+/// Maximum number of I2C transactions needed for a single driver command
+const MAX_STEPS: usize = 12;
+
+// ----- Enums -----
+
+/// Supported I2C ISSI LED drivers
+/// Both chips use the same command register locking scheme, but the page layout differs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum IssiChip {
+    /// 18x11 (198 channels) with per-channel scaling
+    Is31fl3743a,
+    /// 12x12 (144 channels, 192 addressable) without per-channel scaling
+    Is31fl3737,
+}
+
+impl IssiChip {
+    /// Number of addressable PWM registers
+    pub const fn channels(&self) -> usize {
+        match self {
+            IssiChip::Is31fl3743a => 198,
+            IssiChip::Is31fl3737 => 192,
+        }
+    }
+
+    /// Number of bytes in the open or short detection register block
+    pub const fn open_short_len(&self) -> usize {
+        match self {
+            IssiChip::Is31fl3743a => 33,
+            IssiChip::Is31fl3737 => 24,
+        }
+    }
+}
+
+/// Queued ISSI driver commands
+/// Each command is expanded into a sequence of I2C transactions when it is started.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum IssiCommand {
+    /// Software reset followed by the default configuration
+    Reset,
+    /// Write the full scaling buffer (ignored on chips without scaling)
+    Scaling,
+    /// Write the full pwm buffer
+    Pwm,
+    /// Set global brightness (global current control)
+    Brightness,
+    /// Leave software shutdown
+    Enable,
+    /// Enter software shutdown
+    Disable,
+    /// Start open circuit detection
+    OpenDetectSetup,
+    /// Start short circuit detection
+    ShortDetectSetup,
+    /// Read open circuit detection results
+    OpenDetectRead,
+    /// Read short circuit detection results
+    ShortDetectRead,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum IssiError {
+    /// Command queue is full
+    QueueFull,
+    /// Detection results have not been read yet
+    NotReady,
+    /// I2C bus did not acknowledge the transfer
+    Nack,
+}
+
+/// Source of the data bytes for a buffer write
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+enum Buffer {
+    Pwm,
+    Scaling,
+    /// Repeat a single value (value, length)
+    Fill(u8, u8),
+}
+
+/// Single I2C transaction
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+enum Step {
+    /// Write a single register (register, value)
+    WriteReg(u8, u8),
+    /// Write a buffer starting at the given register
+    WriteBuf(u8, Buffer),
+    /// Read a block of registers (register, length)
+    Read(u8, u8),
+}
+
+/// TWI (I2C) transfer state
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+enum TwiState {
+    Idle,
+    /// PDC is transmitting all but the last byte (ENDTX)
+    TxDma,
+    /// Waiting to send the last byte (TXRDY)
+    TxLast,
+    /// PDC is receiving all but the last two bytes (ENDRX)
+    RxDma,
+    /// Waiting for the second to last byte (RXRDY)
+    RxPenultimate,
+    /// Waiting for the last byte (RXRDY)
+    RxLast,
+    /// Waiting for the stop condition to be sent (TXCOMP)
+    Complete,
+}
+
+/// Result of servicing a TWI interrupt
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum TwiEvent {
+    /// Transfer still in progress
+    Busy,
+    /// Transfer finished
+    Done,
+    /// Device did not acknowledge, transfer aborted
+    Nack,
+}
+
+// ----- Structs -----
+
+/// TWI master using the PDC for multi-byte transfers
+///
+/// The tx and rx buffers are owned by this struct and are handed to the PDC by address.
+/// This struct must not be moved while a transfer is in progress (i.e. only start transfers
+/// once the driver has been placed in its final (RTIC resource) location).
+pub struct TwiDma {
+    twi: &'static RegisterBlock,
+    state: TwiState,
+    len: usize,
+    tx_buf: [u8; TWI_TX_BUF_SIZE],
+    rx_buf: [u8; TWI_RX_BUF_SIZE],
+}
+
+// The register block is only accessed through the owning driver
+unsafe impl Send for TwiDma {}
+
+impl TwiDma {
+    /// Configures the TWI peripheral as a master with the given SCL frequency
+    fn new(twi: &'static RegisterBlock, freq: u32) -> Self {
+        // Reset peripheral and enable master mode
+        twi.cr.write(|w| w.swrst().set_bit());
+        twi.cr.write(|w| w.msdis().set_bit().svdis().set_bit());
+        twi.cr.write(|w| w.msen().set_bit());
+        twi.idr.write(|w| unsafe { w.bits(0xFFFF_FFFF) });
+
+        // Clock waveform
+        //   Tlow = Thigh = ((DIV * 2^CKDIV) + 4) * Tmck
+        let mut ckdiv = 0;
+        let mut div = (MCU_FREQ / freq / 2).saturating_sub(4);
+        while div > 255 && ckdiv < 7 {
+            ckdiv += 1;
+            div = ((MCU_FREQ / freq / 2).saturating_sub(4)) >> ckdiv;
+        }
+        twi.cwgr.write(|w| unsafe {
+            w.cldiv()
+                .bits(div as u8)
+                .chdiv()
+                .bits(div as u8)
+                .ckdiv()
+                .bits(ckdiv)
+        });
+
+        Self {
+            twi,
+            state: TwiState::Idle,
+            len: 0,
+            tx_buf: [0; TWI_TX_BUF_SIZE],
+            rx_buf: [0; TWI_RX_BUF_SIZE],
+        }
+    }
+
+    fn is_idle(&self) -> bool {
+        self.state == TwiState::Idle
+    }
+
+    /// Writes len bytes of tx_buf to the device
+    /// The first byte is the starting register address
+    fn start_write(&mut self, addr: u8, len: usize) {
+        self.len = len;
+        self.twi.mmr.write(|w| unsafe { w.dadr().bits(addr) });
+
+        if len == 1 {
+            self.twi.thr.write(|w| unsafe { w.txdata().bits(self.tx_buf[0]) });
+            self.twi.cr.write(|w| w.stop().set_bit());
+            self.twi.ier.write(|w| w.txcomp().set_bit());
+            self.state = TwiState::Complete;
+            return;
+        }
+
+        // PDC sends all but the last byte, which is sent after setting STOP
+        self.twi
+            .tpr
+            .write(|w| unsafe { w.txptr().bits(self.tx_buf.as_ptr() as u32) });
+        self.twi
+            .tcr
+            .write(|w| unsafe { w.txctr().bits((len - 1) as u16) });
+        self.twi.ptcr.write(|w| w.txten().set_bit());
+        self.twi.ier.write(|w| w.endtx().set_bit());
+        self.state = TwiState::TxDma;
+    }
+
+    /// Reads len bytes from the device starting at register reg into rx_buf
+    fn start_read(&mut self, addr: u8, reg: u8, len: usize) {
+        self.len = len;
+        self.twi.mmr.write(|w| unsafe {
+            w.dadr()
+                .bits(addr)
+                .mread()
+                .set_bit()
+                .iadrsz()
+                .bits(1)
+        });
+        self.twi.iadr.write(|w| unsafe { w.iadr().bits(reg as u32) });
+
+        match len {
+            1 => {
+                self.twi.cr.write(|w| w.start().set_bit().stop().set_bit());
+                self.twi.ier.write(|w| w.rxrdy().set_bit());
+                self.state = TwiState::RxLast;
+            }
+            2 => {
+                self.twi.cr.write(|w| w.start().set_bit());
+                self.twi.ier.write(|w| w.rxrdy().set_bit());
+                self.state = TwiState::RxPenultimate;
+            }
+            _ => {
+                // PDC receives all but the last two bytes, STOP must be set before the last byte
+                self.twi
+                    .rpr
+                    .write(|w| unsafe { w.rxptr().bits(self.rx_buf.as_ptr() as u32) });
+                self.twi
+                    .rcr
+                    .write(|w| unsafe { w.rxctr().bits((len - 2) as u16) });
+                self.twi.ptcr.write(|w| w.rxten().set_bit());
+                self.twi.cr.write(|w| w.start().set_bit());
+                self.twi.ier.write(|w| w.endrx().set_bit());
+                self.state = TwiState::RxDma;
+            }
+        }
+    }
+
+    /// Advances the transfer state machine, call from the TWI interrupt
+    fn service(&mut self) -> TwiEvent {
+        let sr = self.twi.sr.read();
+
+        // Abort on nack
+        if sr.nack().bit_is_set() {
+            self.twi
+                .ptcr
+                .write(|w| w.txtdis().set_bit().rxtdis().set_bit());
+            self.twi.idr.write(|w| unsafe { w.bits(0xFFFF_FFFF) });
+            self.state = TwiState::Idle;
+            return TwiEvent::Nack;
+        }
+
+        match self.state {
+            TwiState::TxDma if sr.endtx().bit_is_set() => {
+                self.twi.ptcr.write(|w| w.txtdis().set_bit());
+                self.twi.idr.write(|w| w.endtx().set_bit());
+                self.twi.ier.write(|w| w.txrdy().set_bit());
+                self.state = TwiState::TxLast;
+            }
+            TwiState::TxLast if sr.txrdy().bit_is_set() => {
+                self.twi.cr.write(|w| w.stop().set_bit());
+                self.twi
+                    .thr
+                    .write(|w| unsafe { w.txdata().bits(self.tx_buf[self.len - 1]) });
+                self.twi.idr.write(|w| w.txrdy().set_bit());
+                self.twi.ier.write(|w| w.txcomp().set_bit());
+                self.state = TwiState::Complete;
+            }
+            TwiState::RxDma if sr.endrx().bit_is_set() => {
+                self.twi.ptcr.write(|w| w.rxtdis().set_bit());
+                self.twi.idr.write(|w| w.endrx().set_bit());
+                self.twi.ier.write(|w| w.rxrdy().set_bit());
+                self.state = TwiState::RxPenultimate;
+            }
+            TwiState::RxPenultimate if sr.rxrdy().bit_is_set() => {
+                self.twi.cr.write(|w| w.stop().set_bit());
+                self.rx_buf[self.len - 2] = self.twi.rhr.read().rxdata().bits();
+                self.state = TwiState::RxLast;
+            }
+            TwiState::RxLast if sr.rxrdy().bit_is_set() => {
+                self.rx_buf[self.len - 1] = self.twi.rhr.read().rxdata().bits();
+                self.twi.idr.write(|w| w.rxrdy().set_bit());
+                self.twi.ier.write(|w| w.txcomp().set_bit());
+                self.state = TwiState::Complete;
+            }
+            TwiState::Complete if sr.txcomp().bit_is_set() => {
+                self.twi.idr.write(|w| w.txcomp().set_bit());
+                self.state = TwiState::Idle;
+                return TwiEvent::Done;
+            }
+            _ => {}
+        }
+
+        TwiEvent::Busy
+    }
+}
+
+/// IS31FL3743A/IS31FL3737 I2C driver (one chip per TWI bus)
+pub struct IssiI2c {
+    addr: u8,
+    brightness: u8,
+    bus: TwiDma,
+    chip: IssiChip,
+    current: Option<IssiCommand>,
+    enable: bool,
+    open_results: [u8; TWI_RX_BUF_SIZE],
+    open_ready: bool,
+    pwm: [u8; ISSI_DRIVER_CHANNELS],
+    queue: Deque<IssiCommand, ISSI_DRIVER_QUEUE_SIZE>,
+    scaling: [u8; ISSI_DRIVER_CHANNELS],
+    short_results: [u8; TWI_RX_BUF_SIZE],
+    short_ready: bool,
+    step: usize,
+    steps: Vec<Step, MAX_STEPS>,
+}
+
+impl IssiI2c {
+    fn new(bus: TwiDma, chip: IssiChip, addr: u8, brightness: u8, enable: bool) -> Self {
+        Self {
+            addr,
+            brightness,
+            bus,
+            chip,
+            current: None,
+            enable,
+            open_results: [0; TWI_RX_BUF_SIZE],
+            open_ready: false,
+            pwm: [0; ISSI_DRIVER_CHANNELS],
+            queue: Deque::new(),
+            scaling: [0xFF; ISSI_DRIVER_CHANNELS],
+            short_results: [0; TWI_RX_BUF_SIZE],
+            short_ready: false,
+            step: 0,
+            steps: Vec::new(),
+        }
+    }
+
+    /// Chip type
+    pub fn chip(&self) -> IssiChip {
+        self.chip
+    }
+
+    /// PWM buffer, call pwm() to queue the update
+    pub fn pwm_page_buf(&mut self) -> &mut [u8; ISSI_DRIVER_CHANNELS] {
+        &mut self.pwm
+    }
+
+    /// Scaling buffer, call scaling() to queue the update
+    pub fn scaling_page_buf(&mut self) -> &mut [u8; ISSI_DRIVER_CHANNELS] {
+        &mut self.scaling
+    }
+
+    /// Queue a software reset (includes default configuration)
+    pub fn reset(&mut self) -> Result<(), IssiError> {
+        self.enqueue(IssiCommand::Reset)
+    }
+
+    /// Queue a scaling buffer update
+    pub fn scaling(&mut self) -> Result<(), IssiError> {
+        self.enqueue(IssiCommand::Scaling)
+    }
+
+    /// Queue a pwm buffer update
+    pub fn pwm(&mut self) -> Result<(), IssiError> {
+        self.enqueue(IssiCommand::Pwm)
+    }
+
+    /// Queue a global brightness update
+    pub fn brightness(&mut self, brightness: u8) -> Result<(), IssiError> {
+        self.brightness = brightness;
+        self.enqueue(IssiCommand::Brightness)
+    }
+
+    /// Queue leaving software shutdown
+    pub fn enable(&mut self) -> Result<(), IssiError> {
+        self.enable = true;
+        self.enqueue(IssiCommand::Enable)
+    }
+
+    /// Queue entering software shutdown
+    pub fn disable(&mut self) -> Result<(), IssiError> {
+        self.enable = false;
+        self.enqueue(IssiCommand::Disable)
+    }
+
+    /// Queue open circuit detection
+    pub fn open_circuit_detect_setup(&mut self) -> Result<(), IssiError> {
+        self.open_ready = false;
+        self.enqueue(IssiCommand::OpenDetectSetup)
+    }
+
+    /// Queue reading of the open circuit detection results
+    pub fn open_circuit_detect_read(&mut self) -> Result<(), IssiError> {
+        self.enqueue(IssiCommand::OpenDetectRead)
+    }
+
+    /// Open circuit detection results
+    pub fn open_circuit_raw(&self) -> Result<&[u8], IssiError> {
+        if !self.open_ready {
+            return Err(IssiError::NotReady);
+        }
+        Ok(&self.open_results[..self.chip.open_short_len()])
+    }
+
+    /// Queue short circuit detection
+    pub fn short_circuit_detect_setup(&mut self) -> Result<(), IssiError> {
+        self.short_ready = false;
+        self.enqueue(IssiCommand::ShortDetectSetup)
+    }
+
+    /// Queue reading of the short circuit detection results
+    pub fn short_circuit_detect_read(&mut self) -> Result<(), IssiError> {
+        self.enqueue(IssiCommand::ShortDetectRead)
+    }
+
+    /// Short circuit detection results
+    pub fn short_circuit_raw(&self) -> Result<&[u8], IssiError> {
+        if !self.short_ready {
+            return Err(IssiError::NotReady);
+        }
+        Ok(&self.short_results[..self.chip.open_short_len()])
+    }
+
+    /// Starts the next queued transaction if the bus is idle
+    /// Must only be called once the driver is in its final memory location (see TwiDma).
+    pub fn process(&mut self) {
+        if !self.bus.is_idle() {
+            return;
+        }
+
+        // Load the next command if the current one has finished
+        if self.step >= self.steps.len() {
+            self.finish_command();
+            match self.queue.pop_front() {
+                Some(cmd) => {
+                    self.current = Some(cmd);
+                    self.steps = self.command_steps(cmd);
+                    self.step = 0;
+                }
+                None => {
+                    return;
+                }
+            }
+        }
+
+        // Some commands have no steps on some chips (e.g. scaling)
+        if let Some(step) = self.steps.get(self.step).copied() {
+            self.start_step(step);
+        } else {
+            self.process();
+        }
+    }
+
+    /// Services the TWI interrupt and starts the next transaction
+    pub fn service(&mut self) {
+        match self.bus.service() {
+            TwiEvent::Busy => {
+                return;
+            }
+            TwiEvent::Done => {
+                self.step += 1;
+            }
+            TwiEvent::Nack => {
+                defmt::warn!(
+                    "ISSI {:?} (0x{:x}) nack: {:?}",
+                    self.chip,
+                    self.addr,
+                    self.current
+                );
+                // Drop the rest of the command
+                self.step = self.steps.len();
+            }
+        }
+        self.process();
+    }
+
+    fn enqueue(&mut self, cmd: IssiCommand) -> Result<(), IssiError> {
+        // Buffer and setting updates only need to be sent once (the values are read when the
+        // command starts), a queued Enable/Disable is replaced
+        let queued = self.queue.iter_mut().find(|c| match cmd {
+            IssiCommand::Pwm | IssiCommand::Scaling | IssiCommand::Brightness => **c == cmd,
+            IssiCommand::Enable | IssiCommand::Disable => {
+                matches!(c, IssiCommand::Enable | IssiCommand::Disable)
+            }
+            _ => false,
+        });
+        if let Some(queued) = queued {
+            *queued = cmd;
+            return Ok(());
+        }
+        self.queue.push_back(cmd).map_err(|_| IssiError::QueueFull)
+    }
+
+    /// Copies read results once a command has completed
+    fn finish_command(&mut self) {
+        match self.current.take() {
+            Some(IssiCommand::OpenDetectRead) => {
+                self.open_results.copy_from_slice(&self.bus.rx_buf);
+                self.open_ready = true;
+            }
+            Some(IssiCommand::ShortDetectRead) => {
+                self.short_results.copy_from_slice(&self.bus.rx_buf);
+                self.short_ready = true;
+            }
+            _ => {}
+        }
+    }
+
+    fn start_step(&mut self, step: Step) {
+        match step {
+            Step::WriteReg(reg, val) => {
+                self.bus.tx_buf[0] = reg;
+                self.bus.tx_buf[1] = val;
+                self.bus.start_write(self.addr, 2);
+            }
+            Step::WriteBuf(reg, buffer) => {
+                self.bus.tx_buf[0] = reg;
+                let len = match buffer {
+                    Buffer::Pwm => {
+                        let len = self.chip.channels();
+                        self.bus.tx_buf[1..=len].copy_from_slice(&self.pwm[..len]);
+                        len
+                    }
+                    Buffer::Scaling => {
+                        let len = self.chip.channels();
+                        self.bus.tx_buf[1..=len].copy_from_slice(&self.scaling[..len]);
+                        len
+                    }
+                    Buffer::Fill(val, len) => {
+                        let len = len as usize;
+                        self.bus.tx_buf[1..=len].iter_mut().for_each(|b| *b = val);
+                        len
+                    }
+                };
+                self.bus.start_write(self.addr, len + 1);
+            }
+            Step::Read(reg, len) => {
+                self.bus.start_read(self.addr, reg, len as usize);
+            }
+        }
+    }
+
+    /// Expands a command into I2C transactions
+    fn command_steps(&self, cmd: IssiCommand) -> Vec<Step, MAX_STEPS> {
+        let mut steps = Vec::new();
+        let page = |steps: &mut Vec<Step, MAX_STEPS>, page: u8| {
+            steps
+                .push(Step::WriteReg(ISSI_CMD_LOCK, ISSI_CMD_LOCK_UNLOCK))
+                .ok();
+            steps.push(Step::WriteReg(ISSI_CMD, page)).ok();
+        };
+        let ssd = self.enable as u8;
+
+        match self.chip {
+            // Page 0 - PWM (0x01-0xC6)
+            // Page 1 - Scaling (0x01-0xC6)
+            // Page 2 - Function
+            //   0x00 - Configuration (SWS[7:4] OSDE[2:1] SSD[0])
+            //   0x01 - Global current control
+            //   0x02 - Pull down/up resistor selection
+            //   0x03-0x23 - Open/short
+            //   0x2F - Reset
+            IssiChip::Is31fl3743a => match cmd {
+                IssiCommand::Reset => {
+                    page(&mut steps, 2);
+                    steps.push(Step::WriteReg(0x2F, 0xAE)).ok();
+                    steps.push(Step::WriteReg(0x00, ssd)).ok();
+                    steps.push(Step::WriteReg(0x01, self.brightness)).ok();
+                    steps.push(Step::WriteReg(0x02, 0x33)).ok();
+                    page(&mut steps, 1);
+                    steps.push(Step::WriteBuf(0x01, Buffer::Scaling)).ok();
+                    page(&mut steps, 0);
+                    steps.push(Step::WriteBuf(0x01, Buffer::Pwm)).ok();
+                }
+                IssiCommand::Scaling => {
+                    page(&mut steps, 1);
+                    steps.push(Step::WriteBuf(0x01, Buffer::Scaling)).ok();
+                }
+                IssiCommand::Pwm => {
+                    page(&mut steps, 0);
+                    steps.push(Step::WriteBuf(0x01, Buffer::Pwm)).ok();
+                }
+                IssiCommand::Brightness => {
+                    page(&mut steps, 2);
+                    steps.push(Step::WriteReg(0x01, self.brightness)).ok();
+                }
+                IssiCommand::Enable | IssiCommand::Disable => {
+                    page(&mut steps, 2);
+                    steps.push(Step::WriteReg(0x00, ssd)).ok();
+                }
+                IssiCommand::OpenDetectSetup | IssiCommand::ShortDetectSetup => {
+                    // AN-107 - Full scaling and a GCC of 0x01 before enabling detection
+                    let osde = if cmd == IssiCommand::OpenDetectSetup {
+                        0b01
+                    } else {
+                        0b10
+                    };
+                    page(&mut steps, 1);
+                    steps
+                        .push(Step::WriteBuf(0x01, Buffer::Fill(0xFF, 198)))
+                        .ok();
+                    page(&mut steps, 2);
+                    steps.push(Step::WriteReg(0x01, 0x01)).ok();
+                    steps.push(Step::WriteReg(0x00, (osde << 1) | 1)).ok();
+                }
+                IssiCommand::OpenDetectRead | IssiCommand::ShortDetectRead => {
+                    page(&mut steps, 2);
+                    steps
+                        .push(Step::Read(0x03, self.chip.open_short_len() as u8))
+                        .ok();
+                }
+            },
+            // Page 0 - LED Control
+            //   0x00-0x17 - On/Off
+            //   0x18-0x2F - Open
+            //   0x30-0x47 - Short
+            // Page 1 - PWM (0x00-0xBF)
+            // Page 3 - Function
+            //   0x00 - Configuration (OSD[2] SSD[0])
+            //   0x01 - Global current control
+            //   0x0F - SWy pull-up resistor selection
+            //   0x10 - CSx pull-down resistor selection
+            //   0x11 - Reset (read)
+            IssiChip::Is31fl3737 => match cmd {
+                IssiCommand::Reset => {
+                    page(&mut steps, 3);
+                    steps.push(Step::Read(0x11, 1)).ok();
+                    page(&mut steps, 0);
+                    steps.push(Step::WriteBuf(0x00, Buffer::Fill(0xFF, 24))).ok();
+                    page(&mut steps, 3);
+                    steps.push(Step::WriteReg(0x00, ssd)).ok();
+                    steps.push(Step::WriteReg(0x01, self.brightness)).ok();
+                    steps.push(Step::WriteReg(0x0F, 0x07)).ok();
+                    steps.push(Step::WriteReg(0x10, 0x07)).ok();
+                }
+                IssiCommand::Scaling => {}
+                IssiCommand::Pwm => {
+                    page(&mut steps, 1);
+                    steps.push(Step::WriteBuf(0x00, Buffer::Pwm)).ok();
+                }
+                IssiCommand::Brightness => {
+                    page(&mut steps, 3);
+                    steps.push(Step::WriteReg(0x01, self.brightness)).ok();
+                }
+                IssiCommand::Enable | IssiCommand::Disable => {
+                    page(&mut steps, 3);
+                    steps.push(Step::WriteReg(0x00, ssd)).ok();
+                }
+                // Open and short detection are run at the same time
+                IssiCommand::OpenDetectSetup | IssiCommand::ShortDetectSetup => {
+                    page(&mut steps, 3);
+                    steps.push(Step::WriteReg(0x01, 0x01)).ok();
+                    steps.push(Step::WriteReg(0x00, 0b100 | 1)).ok();
+                }
+                IssiCommand::OpenDetectRead => {
+                    page(&mut steps, 0);
+                    steps
+                        .push(Step::Read(0x18, self.chip.open_short_len() as u8))
+                        .ok();
+                }
+                IssiCommand::ShortDetectRead => {
+                    page(&mut steps, 0);
+                    steps
+                        .push(Step::Read(0x30, self.chip.open_short_len() as u8))
+                        .ok();
+                }
+            },
+        }
+
+        steps
+    }
+}
+
+// ----- Initialization Functions -----
+
+/// Initializes the IS31FL3743A/IS31FL3737 I2C LED drivers
+/// One driver per TWI bus (TWI0 -> chip 0, TWI1 -> chip 1)
+///
+/// The initial reset is queued, but no transfers are started until process() is called
+/// (usually by led_frame_process) as the drivers must be moved into their final location first.
+#[allow(clippy::too_many_arguments)]
+pub fn init(
+    issi_sdb: &mut dyn OutputPin<Error = Infallible>,
+    issi_chip: IssiChip,
+    issi_addr: [u8; ISSI_DRIVER_CHIPS],
+    issi_default_brightness: u8,
+    issi_default_enable: bool,
+    twi0: hal::pac::TWI0,
+    _twi0_clock: Twi0Clock<Enabled>,
+    _twi0_sda: Pa3<PfA>,
+    _twi0_scl: Pa4<PfA>,
+    twi1: hal::pac::TWI1,
+    _twi1_clock: Twi1Clock<Enabled>,
+    _twi1_sda: Pb4<PfA>,
+    _twi1_scl: Pb5<PfA>,
+) -> [IssiI2c; ISSI_DRIVER_CHIPS] {
+    // Setup TWI for LED Drivers
+    // The peripherals are only accessed through the driver from here on
+    defmt::trace!("I2C ISSI Driver initialization");
+    let _ = (twi0, twi1);
+    let twi0 = TwiDma::new(unsafe { &*hal::pac::TWI0::ptr() }, TWI_FREQ);
+    let twi1 = TwiDma::new(unsafe { &*hal::pac::TWI1::ptr() }, TWI_FREQ);
+
+    // Disable ISSI hardware shutdown
+    issi_sdb.set_high().ok();
+
+    let mut issi = [
+        IssiI2c::new(
+            twi0,
+            issi_chip,
+            issi_addr[0],
+            issi_default_brightness,
+            issi_default_enable,
+        ),
+        IssiI2c::new(
+            twi1,
+            issi_chip,
+            issi_addr[1],
+            issi_default_brightness,
+            issi_default_enable,
+        ),
+    ];
+
+    // Queue ISSI LED Driver initialization
+    for chip in issi.iter_mut() {
+        chip.reset().unwrap();
+    }
+
+    issi
+}
+
+// ----- Software Interrupt Tasks -----
+
+/// LED Frame Processing Task for manufacturing commands
+/// Handles any initial processing needed before doing normal frame processing
+/// Returns false if no more frame processing should be done on this iteration
+/// (regular_processing, spawn led_test)
+pub fn led_frame_process_manufacturing_tests_task(
+    hidio_intf: &mut HidioCommandInterface,
+    issi: &mut [IssiI2c; ISSI_DRIVER_CHIPS],
+    led_test: &mut LedTest,
+) -> (bool, bool) {
+    // Look for manufacturing test commands
+    // Only check for new tests if one is not currently running
+    match *led_test {
+        LedTest::Disabled => {
+            if hidio_intf.interface().manufacturing_config.led_short_test {
+                // Enqueue short test
+                for chip in issi.iter_mut() {
+                    chip.short_circuit_detect_setup().unwrap();
+                    chip.process();
+                }
+                *led_test = LedTest::ShortQuery;
+                hidio_intf
+                    .mut_interface()
+                    .manufacturing_config
+                    .led_short_test = false;
+                (false, true)
+            } else if hidio_intf.interface().manufacturing_config.led_open_test {
+                // Enqueue open test
+                for chip in issi.iter_mut() {
+                    chip.open_circuit_detect_setup().unwrap();
+                    chip.process();
+                }
+                *led_test = LedTest::OpenQuery;
+                hidio_intf
+                    .mut_interface()
+                    .manufacturing_config
+                    .led_open_test = false;
+                (false, true)
+            } else {
+                (true, false)
+            }
+        }
+        LedTest::Reset => {
+            // Reset LED state
+            // The PWM and Scaling registers are reset, but we have a full copy
+            // in memory on the MCU so it will be the previous state.
+            for chip in issi.iter_mut() {
+                chip.reset().unwrap();
+                chip.process();
+            }
+            *led_test = LedTest::Disabled;
+            (false, false)
+        }
+        _ => (false, false),
+    }
+}
+
+/// LED Frame Processing Task
+/// Handles each LED frame, triggered at a constant rate.
+/// Frames are skipped if the previous frame is still processing.
+pub fn led_frame_process_is31fl37xx_i2c_task(
+    hidio_intf: &mut HidioCommandInterface,
+    issi: &mut [IssiI2c; ISSI_DRIVER_CHIPS],
+    led_mask: &mut [LedMask],
+    layer_leds: &mut LayerLedOverlay,
+    regular_processing: bool,
+    usb_state_consumer: &mut Consumer<'static, UsbState, USB_STATE_QUEUE_SIZE>,
+) {
+    // Check for suspend/resume events
+    let led_settings = hidio_intf.interface().led_settings;
+    while let Some(state) = usb_state_consumer.dequeue() {
+        for chip in issi.iter_mut() {
+            match state {
+                UsbState::Suspend => {
+                    chip.disable().unwrap();
+                }
+                UsbState::Resume if led_settings.enabled => {
+                    chip.enable().unwrap();
+                }
+                UsbState::Resume => {}
+            }
+        }
+    }
+
+    // Apply brightness and on/off changes (VIA lighting or stored settings)
+    // Retried on the next frame if a queue is full
+    if hidio_intf.interface().led_settings_changed {
+        let mut queued = true;
+        for chip in issi.iter_mut() {
+            queued &= chip.brightness(led_settings.brightness).is_ok();
+            queued &= if led_settings.enabled {
+                chip.enable()
+            } else {
+                chip.disable()
+            }
+            .is_ok();
+        }
+        hidio_intf.mut_interface().led_settings_changed = !queued;
+    }
+
+    // Remove the layer LED scheme of the previous frame
+    layer_leds.restore(|chip, offset, value| issi[chip].pwm_page_buf()[offset] = value);
+
+    // Process incoming Pixel/LED Buffers
+    if regular_processing {
+        let control = hidio_intf.interface().led_control.control;
+
+        // Determine if HID-IO processing is enabled
+        if control != h0021::args::Control::Disable {
+            // Check for a reset, otherwise process frame
+            if hidio_intf.interface().led_control.hard_reset
+                || hidio_intf.interface().led_control.soft_reset
+            {
+                hidio_intf.mut_interface().led_control.soft_reset = false;
+                hidio_intf.mut_interface().led_control.hard_reset = false;
+                for chip in issi.iter_mut() {
+                    chip.reset().unwrap(); // Queue reset (includes scaling and pwm)
+                }
+            } else if (control == h0021::args::Control::EnablePause
+                && hidio_intf.interface().led_control.next_frame)
+                || control == h0021::args::Control::EnableStart
+            {
+                // Process frame
+                hidio_intf.mut_interface().led_control.next_frame = false;
+
+                // Copy data to frame buffer
+                for (i, chip) in issi.iter_mut().enumerate() {
+                    let start = i * ISSI_DRIVER_CHANNELS;
+                    let end = (i + 1) * ISSI_DRIVER_CHANNELS;
+                    chip.pwm_page_buf()
+                        .copy_from_slice(&hidio_intf.interface().led_buffer[start..end]);
+                }
+            }
+        }
+    }
+
+    // Draw the LED scheme of the active layer
+    let layer = hidio_intf
+        .interface()
+        .layer_stack
+        .last()
+        .copied()
+        .unwrap_or(0);
+    layer_leds.draw(layer, |chip, offset, value| {
+        core::mem::replace(&mut issi[chip].pwm_page_buf()[offset], value)
+    });
+
+    // Apply mask to frame buffer
+    for mask in led_mask.iter_mut() {
+        for (i, ch) in mask.mask.iter().enumerate() {
+            let mut val = *ch;
+            val = val.saturating_sub(mask.frames_since_update);
+            issi[mask.chip as usize].pwm_page_buf()[mask.offset as usize + i] = val;
+        }
+        mask.frames_since_update = mask.frames_since_update.saturating_add(1);
+    }
+
+    // Queue frame and start I2C transfers (if the bus is idle)
+    for chip in issi.iter_mut() {
+        if let Err(err) = chip.pwm() {
+            defmt::warn!("ISSI queue full, skipping frame: {:?}", err);
+        }
+        chip.process();
+    }
+}
+
+/// LED Test Results
+/// Asynchronous task to handle LED test results (both short and open).
+/// This task is schedule at least 750 us after the test is started.
+///
+/// Returns true if led_test and led_frame_process should be scheduled
+pub fn led_test_task(
+    hidio_intf: &mut HidioCommandInterface,
+    issi: &mut [IssiI2c; ISSI_DRIVER_CHIPS],
+    led_test: &mut LedTest,
+) -> bool {
+    // Check for test results
+    match *led_test {
+        LedTest::ShortQuery => {
+            // Schedule read of the short test results
+            for chip in issi.iter_mut() {
+                chip.short_circuit_detect_read().unwrap();
+                chip.process();
+            }
+            *led_test = LedTest::ShortReady;
+
+            // Spawn led_test and led_frame_process
+            true
+        }
+        LedTest::ShortReady => {
+            // Wait for all reads to finish
+            if issi.iter().any(|chip| chip.short_circuit_raw().is_err()) {
+                return true;
+            }
+
+            // 1 byte id, 1 byte length, N bytes of data, 1 byte id, ...
+            // Buffer size defined by kiibohd_hidio
+            let mut data: heapless::Vec<u8, { kiibohd_hid_io::MESSAGE_LEN - 4 }> =
+                heapless::Vec::new();
+            for (i, chip) in issi.iter().enumerate() {
+                let results = chip.short_circuit_raw().unwrap();
+                data.push(i as u8).unwrap(); // Id
+                data.push(results.len() as u8).unwrap(); // Length
+                data.extend_from_slice(results).unwrap(); // Data
+            }
+            hidio_intf
+                .h0051_manufacturingres(h0051::Cmd {
+                    command: h0051::Command::LedTestSequence,
+                    argument: h0051::Argument {
+                        led_test_sequence: h0051::args::LedTestSequence::LedShortTest,
+                    },
+                    data,
+                })
+                .unwrap();
+
+            *led_test = LedTest::Reset;
+
+            false
+        }
+        LedTest::OpenQuery => {
+            // Schedule read of the open test results
+            for chip in issi.iter_mut() {
+                chip.open_circuit_detect_read().unwrap();
+                chip.process();
+            }
+            *led_test = LedTest::OpenReady;
+
+            // Spawn led_test and led_frame_process
+            true
+        }
+        LedTest::OpenReady => {
+            // Wait for all reads to finish
+            if issi.iter().any(|chip| chip.open_circuit_raw().is_err()) {
+                return true;
+            }
+
+            // 1 byte id, 1 byte length, N bytes of data, 1 byte id, ...
+            // Buffer size defined by kiibohd_hidio
+            let mut data: heapless::Vec<u8, { kiibohd_hid_io::MESSAGE_LEN - 4 }> =
+                heapless::Vec::new();
+            for (i, chip) in issi.iter().enumerate() {
+                let results = chip.open_circuit_raw().unwrap();
+                data.push(i as u8).unwrap(); // Id
+                data.push(results.len() as u8).unwrap(); // Length
+                data.extend_from_slice(results).unwrap(); // Data
+            }
+            hidio_intf
+                .h0051_manufacturingres(h0051::Cmd {
+                    command: h0051::Command::LedTestSequence,
+                    argument: h0051::Argument {
+                        led_test_sequence: h0051::args::LedTestSequence::LedOpenCircuitTest,
+                    },
+                    data,
+                })
+                .unwrap();
+
+            *led_test = LedTest::Reset;
+
+            false
+        }
+        _ => false,
+    }
+}
+
+// ----- IRQ Functions -----
+
+/// TWI Interrupt
+pub fn twi_irq(issi: &mut IssiI2c) {
+    issi.service();
+}
@@ -6,6 +6,7 @@
 // copied, modified, or distributed except according to those terms.
 
 use crate::constants::*;
+use crate::layer_led::LayerLedOverlay;
 use crate::*;
 pub use is31fl3743b::Is31fl3743bAtsam4Dma;
 
@@ -37,29 +38,6 @@ pub type SpiParkedDma = (
 );
 pub type TCC1 = TimerCounterChannel<TC0, Tc1Clock<Enabled>, 1, TCC1_FREQ>;
 
-// ----- Structs -----
-
-/// Used to mask out LEDs that may be used for special purposes
-/// Commonly used for indicator LEDs that should not be affected by other frame processing
-#[derive(Default)]
-pub struct LedMask {
-    pub chip: u8,
-    pub offset: u16,
-    pub mask: [u8; 3],
-    pub frames_since_update: u8,
-}
-
-impl LedMask {
-    pub const fn new(chip: u8, offset: u16, mask: [u8; 3]) -> Self {
-        Self {
-            chip,
-            offset,
-            mask,
-            frames_since_update: 0,
-        }
-    }
-}
-
 // ----- Initialization Functions -----
 
 /// Initializes is31fl3743b LED driver
@@ -205,21 +183,40 @@ pub fn led_frame_process_is31fl3743b_dma_task<const LED_MASK_SIZE: usize>(
     spi_periph: &mut Option<SpiParkedDma>,
     spi_rxtx: &mut Option<SpiTransferRxTx>,
     led_mask: &mut [LedMask; LED_MASK_SIZE],
+    layer_leds: &mut LayerLedOverlay,
     regular_processing: bool,
     usb_state_consumer: &mut Consumer<'static, UsbState, USB_STATE_QUEUE_SIZE>,
 ) {
     // Check for suspend/resume events
+    let led_settings = hidio_intf.interface().led_settings;
     while let Some(state) = usb_state_consumer.dequeue() {
         match state {
             UsbState::Suspend => {
                 issi.disable().unwrap();
             }
-            UsbState::Resume => {
+            UsbState::Resume if led_settings.enabled => {
                 issi.enable().unwrap();
             }
+            UsbState::Resume => {}
         }
     }
 
+    // Apply brightness and on/off changes (VIA lighting or stored settings)
+    // Retried on the next frame if the queue is full
+    if hidio_intf.interface().led_settings_changed {
+        let queued = issi.brightness(led_settings.brightness).is_ok()
+            && if led_settings.enabled {
+                issi.enable()
+            } else {
+                issi.disable()
+            }
+            .is_ok();
+        hidio_intf.mut_interface().led_settings_changed = !queued;
+    }
+
+    // Remove the layer LED scheme of the previous frame
+    layer_leds.restore(|chip, offset, value| issi.pwm_page_buf()[chip][offset] = value);
+
     // Process incoming Pixel/LED Buffers
     if regular_processing {
         let control = hidio_intf.interface().led_control.control;
@@ -253,6 +250,17 @@ pub fn led_frame_process_is31fl3743b_dma_task<const LED_MASK_SIZE: usize>(
         }
     }
 
+    // Draw the LED scheme of the active layer
+    let layer = hidio_intf
+        .interface()
+        .layer_stack
+        .last()
+        .copied()
+        .unwrap_or(0);
+    layer_leds.draw(layer, |chip, offset, value| {
+        core::mem::replace(&mut issi.pwm_page_buf()[chip][offset], value)
+    });
+
     // Apply mask to frame buffer
     // issi.pwm().unwrap() needs to be called to queue this change (usually handled by another
     // timer interrupt)
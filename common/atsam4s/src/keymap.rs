@@ -0,0 +1,337 @@
+// Copyright 2026 Jacob Alexander
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! Runtime keymap overlay
+//!
+//! Remaps individual keys (KLL trigger indices) per layer on top of the compiled KLL layout
+//! (LAYER_LOOKUP/TRIGGER_RESULT_MAPPING), without rebuilding the firmware.
+//! An entry applies while its layer is the top active layer (layer 0 if no layers are active),
+//! the layer is resolved when the key is pressed.
+//!
+//! - Switch - The key behaves like another switch of the compiled layout (any KLL result)
+//! - Key - The key sends a USB HID keyboard code (sent by macro_process, bypasses KLL)
+//! - Disabled - The key does nothing
+//!
+//! Configured with the `keymap` console/HID-IO terminal command and saved in the flash user
+//! signature (see storage.rs).
+
+use crate::constants::*;
+use core::fmt;
+use heapless::{Deque, Vec};
+use kll_core::{trigger::Phro, TriggerEvent};
+
+// ----- Constants -----
+
+/// Number of user signature words used by a KeymapConfig
+pub const KEYMAP_WORDS: usize = 1 + MAX_KEYMAP_ENTRIES;
+
+/// Stored config marker and version ("KMP", 1)
+const KEYMAP_MAGIC: u32 = 0x4B4D_5001;
+
+/// Unused entry in the stored config
+const KEYMAP_NONE: u32 = 0xFFFF_FFFF;
+
+// Switches and layers are stored in 12 bits
+const _: () = assert!(LAYOUT_SIZE <= 256 && MAX_LAYERS <= 16);
+
+// ----- Enums -----
+
+/// Result of a remapped key
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum KeymapAction {
+    /// Switch (KLL trigger index) of the compiled layout
+    Switch(u16),
+    /// USB HID keyboard code
+    Key(u8),
+    Disabled,
+}
+
+impl KeymapAction {
+    fn to_word(self) -> u32 {
+        match self {
+            KeymapAction::Switch(switch) => (switch as u32) << 16,
+            KeymapAction::Key(key) => 0x1000 | (key as u32) << 16,
+            KeymapAction::Disabled => 0x2000,
+        }
+    }
+
+    fn from_word(word: u32) -> Option<Self> {
+        match word & 0xF000 {
+            0x0000 => Some(KeymapAction::Switch((word >> 16) as u16)),
+            0x1000 => Some(KeymapAction::Key((word >> 16) as u8)),
+            0x2000 => Some(KeymapAction::Disabled),
+            _ => None,
+        }
+    }
+}
+
+// ----- Structs -----
+
+/// Remapped key of a layer
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub struct KeymapEntry {
+    pub layer: u8,
+    /// Switch (KLL trigger index)
+    pub switch: u16,
+    pub action: KeymapAction,
+}
+
+/// Keymap overlay entries
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub struct KeymapConfig {
+    entries: [Option<KeymapEntry>; MAX_KEYMAP_ENTRIES],
+}
+
+impl KeymapConfig {
+    pub const fn new() -> Self {
+        Self {
+            entries: [None; MAX_KEYMAP_ENTRIES],
+        }
+    }
+
+    pub fn entries(&self) -> impl Iterator<Item = &KeymapEntry> {
+        self.entries.iter().flatten()
+    }
+
+    /// Entry of a key on a layer
+    pub fn get(&self, layer: u8, switch: u16) -> Option<&KeymapEntry> {
+        self.entries()
+            .find(|entry| entry.layer == layer && entry.switch == switch)
+    }
+
+    /// Adds or replaces the entry of a key
+    /// Returns false if there are already MAX_KEYMAP_ENTRIES entries
+    pub fn set(&mut self, entry: KeymapEntry) -> bool {
+        let slot = match self.entries.iter().position(
+            |e| matches!(e, Some(e) if e.layer == entry.layer && e.switch == entry.switch),
+        ) {
+            Some(slot) => slot,
+            None => match self.entries.iter().position(|e| e.is_none()) {
+                Some(slot) => slot,
+                None => {
+                    return false;
+                }
+            },
+        };
+        self.entries[slot] = Some(entry);
+        true
+    }
+
+    /// Removes the entry of a key (back to the compiled layout)
+    /// Returns false if the key has no entry
+    pub fn reset(&mut self, layer: u8, switch: u16) -> bool {
+        match self
+            .entries
+            .iter_mut()
+            .find(|e| matches!(e, Some(e) if e.layer == layer && e.switch == switch))
+        {
+            Some(entry) => {
+                *entry = None;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Removes every entry
+    pub fn reset_all(&mut self) {
+        self.entries = [None; MAX_KEYMAP_ENTRIES];
+    }
+
+    /// Serializes the config (KEYMAP_WORDS) for the user signature
+    pub fn to_words(&self, words: &mut [u32]) {
+        words[0] = KEYMAP_MAGIC;
+        for (entry, word) in self.entries.iter().zip(words[1..].iter_mut()) {
+            *word = match entry {
+                Some(entry) => {
+                    entry.switch as u32 | (entry.layer as u32) << 8 | entry.action.to_word()
+                }
+                None => KEYMAP_NONE,
+            };
+        }
+    }
+
+    /// Deserializes the config from the user signature
+    /// Returns None if no config has been stored
+    pub fn from_words(words: &[u32]) -> Option<Self> {
+        if words.first() != Some(&KEYMAP_MAGIC) {
+            return None;
+        }
+        let mut config = Self::new();
+        for (entry, word) in config.entries.iter_mut().zip(words[1..].iter()) {
+            if *word == KEYMAP_NONE {
+                continue;
+            }
+            *entry = Some(KeymapEntry {
+                layer: (*word >> 8) as u8 & 0xF,
+                switch: *word as u8 as u16,
+                action: KeymapAction::from_word(*word)?,
+            });
+        }
+        Some(config)
+    }
+
+    /// Writes a human readable list of the entries
+    pub fn report(&self, out: &mut dyn fmt::Write) -> fmt::Result {
+        writeln!(out, "layer switch action")?;
+        for entry in self.entries() {
+            write!(out, "{:5} {:6} ", entry.layer, entry.switch)?;
+            match entry.action {
+                KeymapAction::Switch(switch) => writeln!(out, "S{}", switch)?,
+                KeymapAction::Key(key) => writeln!(out, "U{}", key)?,
+                KeymapAction::Disabled => writeln!(out, "none")?,
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Default for KeymapConfig {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Keymap overlay evaluation (trigger stage)
+pub struct Keymap {
+    config: KeymapConfig,
+    /// Top active layer
+    layer: u8,
+    /// Pressed remapped keys, the action is resolved on press
+    pressed: Vec<(u16, KeymapAction, u32), MAX_KEYMAP_ENTRIES>,
+    /// USB HID keyboard codes (code, press) waiting to be sent by macro_process
+    keys: Deque<(u8, bool), KEYMAP_KEY_QUEUE_SIZE>,
+}
+
+impl Keymap {
+    pub const fn new() -> Self {
+        Self {
+            config: KeymapConfig::new(),
+            layer: 0,
+            pressed: Vec::new(),
+            keys: Deque::new(),
+        }
+    }
+
+    pub fn config(&self) -> &KeymapConfig {
+        &self.config
+    }
+
+    /// Replaces the config, any pressed remapped keys are released first
+    pub fn set_config(&mut self, config: KeymapConfig, emit: &mut impl FnMut(TriggerEvent)) {
+        while let Some((_, action, cycles)) = self.pressed.pop() {
+            self.action(action, Phro::Release, cycles, emit);
+        }
+        self.config = config;
+    }
+
+    /// Updates the top active layer (used for keys pressed from now on)
+    pub fn set_layer(&mut self, layer: u8) {
+        self.layer = layer;
+    }
+
+    /// Passes a trigger event through the keymap overlay
+    pub fn process(&mut self, event: TriggerEvent, emit: &mut impl FnMut(TriggerEvent)) {
+        let (state, index, last_state) = match event {
+            TriggerEvent::Switch {
+                state,
+                index,
+                last_state,
+            } => (state, index, last_state),
+            _ => {
+                emit(event);
+                return;
+            }
+        };
+
+        match self
+            .pressed
+            .iter()
+            .position(|(switch, _, _)| *switch == index)
+        {
+            Some(pos) => {
+                let action = self.pressed[pos].1;
+                match state {
+                    Phro::Release => {
+                        self.pressed.swap_remove(pos);
+                    }
+                    _ => {
+                        self.pressed[pos].2 = last_state;
+                    }
+                }
+                self.action(action, state, last_state, emit);
+            }
+            None if state == Phro::Press => match self.config.get(self.layer, index) {
+                Some(entry) => {
+                    if self.pressed.push((index, entry.action, 0)).is_err() {
+                        emit(event);
+                        return;
+                    }
+                    self.action(entry.action, state, last_state, emit);
+                }
+                None => emit(event),
+            },
+            None => emit(event),
+        }
+    }
+
+    fn action(
+        &mut self,
+        action: KeymapAction,
+        state: Phro,
+        last_state: u32,
+        emit: &mut impl FnMut(TriggerEvent),
+    ) {
+        match (action, state) {
+            (KeymapAction::Switch(switch), _) => emit(TriggerEvent::Switch {
+                state,
+                index: switch,
+                last_state,
+            }),
+            (KeymapAction::Key(key), Phro::Press | Phro::Release) => {
+                if self.keys.push_back((key, state == Phro::Press)).is_err() {
+                    defmt::warn!("KEYMAP_KEY_QUEUE_SIZE too small, dropped key {}", key);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// Takes the next USB HID keyboard code (code, press) of a remapped key
+    pub fn take_key(&mut self) -> Option<(u8, bool)> {
+        self.keys.pop_front()
+    }
+
+    /// Switch is a pressed remapped key (its events are replaced)
+    pub fn suppressed(&self, index: u16) -> bool {
+        self.pressed.iter().any(|(switch, _, _)| *switch == index)
+    }
+
+    /// Index is the switch of a pressed remapped key
+    pub fn is_virtual(&self, index: u16) -> bool {
+        self.pressed
+            .iter()
+            .any(|(_, action, _)| *action == KeymapAction::Switch(index))
+    }
+
+    /// Hold event of a switch pressed by a remapped key (used for off-state lookups)
+    pub fn virtual_event(&self, index: u16) -> Option<TriggerEvent> {
+        self.pressed.iter().find_map(|(_, action, cycles)| {
+            (*action == KeymapAction::Switch(index)).then_some(TriggerEvent::Switch {
+                state: Phro::Hold,
+                index,
+                last_state: *cycles,
+            })
+        })
+    }
+}
+
+impl Default for Keymap {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -5,13 +5,20 @@
 // http://opensource.org/licenses/MIT>, at your option. This file may not be
 // copied, modified, or distributed except according to those terms.
 
+use crate::console::{DebounceTiming, Setting};
 use crate::constants::*;
+use crate::ghosting::{find_ghosts, GhostMode};
+use crate::stages::TriggerStages;
 use crate::*;
 use core::convert::Infallible;
 use hal::timer::TimerCounterChannel;
+use kiibohd_keyscanning::KeyScanning;
+use kll_core::{trigger::Phro, TriggerEvent};
 
 // ----- Types -----
 
+/// Raw key matrix
+/// Debouncing is handled by DebouncedMatrix so the timing can be changed at runtime
 pub type KeyMatrix<
     const CSIZE: usize,
     const RSIZE: usize,
@@ -24,10 +31,264 @@ pub type KeyMatrix<
     RSIZE,
     MSIZE,
     SCAN_PERIOD_US,
-    DEBOUNCE_US,
+    0,
     IDLE_MS,
 >;
 
+// ----- Structs -----
+
+/// Debounce state of a single key
+#[derive(Clone, Copy, Default)]
+struct KeyDebounce {
+    /// Debounced state
+    pressed: bool,
+    /// Time the raw state has differed from the debounced state
+    pending_us: u32,
+    /// Full matrix scans since the last debounced state change
+    cycles: u32,
+    /// Per-key timing override
+    timing: Option<DebounceTiming>,
+}
+
+/// Key matrix with runtime configurable debounce and idle timing
+/// Press and release debounce can be set separately (globally and per key).
+/// A state change is only accepted once the raw state has been stable for the debounce time.
+/// With anti-ghosting enabled, keys are debounced after each full scan (instead of per strobe)
+/// so the whole matrix can be checked for ghosting patterns first.
+pub struct DebouncedMatrix<
+    const CSIZE: usize,
+    const RSIZE: usize,
+    const MSIZE: usize,
+    const SCAN_PERIOD_US: u32,
+> {
+    matrix: KeyMatrix<CSIZE, RSIZE, MSIZE, SCAN_PERIOD_US>,
+    keys: [KeyDebounce; MSIZE],
+    debounce: DebounceTiming,
+    idle_ms: u32,
+    ghost_mode: GhostMode,
+    /// Raw key state of the current scan
+    raw: [bool; MSIZE],
+    /// Keys that are part of a ghosting pattern
+    ambiguous: [bool; MSIZE],
+    /// Trigger event stages (tap-hold, SOCD)
+    stages: TriggerStages,
+}
+
+impl<const CSIZE: usize, const RSIZE: usize, const MSIZE: usize, const SCAN_PERIOD_US: u32>
+    DebouncedMatrix<CSIZE, RSIZE, MSIZE, SCAN_PERIOD_US>
+{
+    /// Time between samples of the same key
+    const CYCLE_US: u32 = SCAN_PERIOD_US * CSIZE as u32;
+
+    pub fn new(
+        matrix: KeyMatrix<CSIZE, RSIZE, MSIZE, SCAN_PERIOD_US>,
+        ghost_mode: GhostMode,
+        stages: TriggerStages,
+    ) -> Self {
+        Self {
+            matrix,
+            keys: [KeyDebounce::default(); MSIZE],
+            debounce: DebounceTiming {
+                press_us: DEBOUNCE_US,
+                release_us: DEBOUNCE_US,
+            },
+            idle_ms: IDLE_MS,
+            ghost_mode,
+            raw: [false; MSIZE],
+            ambiguous: [false; MSIZE],
+            stages,
+        }
+    }
+
+    pub fn ghost_mode(&self) -> GhostMode {
+        self.ghost_mode
+    }
+
+    pub fn stages(&self) -> &TriggerStages {
+        &self.stages
+    }
+
+    pub fn stages_mut(&mut self) -> &mut TriggerStages {
+        &mut self.stages
+    }
+
+    /// Key is part of a ghosting pattern (as of the last full scan)
+    pub fn ambiguous(&self, index: usize) -> bool {
+        self.ambiguous.get(index).copied().unwrap_or(false)
+    }
+
+    /// Global debounce timing (used by keys without an override)
+    pub fn debounce(&self) -> DebounceTiming {
+        self.debounce
+    }
+
+    pub fn set_debounce(&mut self, timing: DebounceTiming) {
+        self.debounce = timing;
+    }
+
+    /// Sets (or clears, with None) the debounce override of a single key
+    pub fn set_key_debounce(&mut self, index: usize, timing: Option<DebounceTiming>) -> bool {
+        match self.keys.get_mut(index) {
+            Some(key) => {
+                key.timing = timing;
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn idle_ms(&self) -> u32 {
+        self.idle_ms
+    }
+
+    pub fn set_idle_ms(&mut self, idle_ms: u32) {
+        self.idle_ms = idle_ms;
+    }
+
+    /// Applies a runtime setting
+    /// Returns false if the setting is not a keyscanning setting (or is invalid)
+    pub fn apply_setting(&mut self, setting: Setting) -> bool {
+        match setting {
+            Setting::Debounce(timing) => self.set_debounce(timing),
+            Setting::KeyDebounce(index, timing) => {
+                return self.set_key_debounce(index as usize, timing);
+            }
+            Setting::Idle(idle_ms) => self.set_idle_ms(idle_ms),
+            _ => {
+                return false;
+            }
+        }
+        true
+    }
+
+    /// Records a raw sample for the given key
+    /// Returns the debounced state (Press/Release on a state change)
+    fn record(&mut self, index: usize, raw: bool) -> Phro {
+        let timing = self.keys[index].timing.unwrap_or(self.debounce);
+        let key = &mut self.keys[index];
+        key.cycles = key.cycles.saturating_add(1);
+
+        if raw == key.pressed {
+            key.pending_us = 0;
+        } else {
+            key.pending_us = key.pending_us.saturating_add(Self::CYCLE_US);
+            let threshold = if raw {
+                timing.press_us
+            } else {
+                timing.release_us
+            };
+            if key.pending_us >= threshold {
+                key.pressed = raw;
+                key.pending_us = 0;
+                key.cycles = 0;
+                return if raw { Phro::Press } else { Phro::Release };
+            }
+        }
+
+        if key.pressed {
+            Phro::Hold
+        } else {
+            Phro::Off
+        }
+    }
+
+    /// Checks the last full scan for ghosting and debounces every key
+    /// Ambiguous keys keep their previous state in GhostMode::Block.
+    fn record_scan(&mut self, switch_remap: &[u8], mut emit: impl FnMut(TriggerEvent)) {
+        let ghosts = find_ghosts::<CSIZE, RSIZE, MSIZE>(&self.raw, &mut self.ambiguous);
+        if ghosts > 0 {
+            defmt::debug!("Ghosting detected: {} ambiguous keys", ghosts);
+        }
+
+        for index in 0..MSIZE {
+            let raw = if self.ghost_mode == GhostMode::Block && self.ambiguous[index] {
+                self.keys[index].pressed
+            } else {
+                self.raw[index]
+            };
+            let state = self.record(index, raw);
+            self.process(index, state, switch_remap, &mut emit);
+        }
+    }
+
+    /// Passes the trigger event of a debounced state through the trigger stages
+    fn process(
+        &mut self,
+        index: usize,
+        state: Phro,
+        switch_remap: &[u8],
+        emit: &mut impl FnMut(TriggerEvent),
+    ) {
+        if let Some(event) = self.event(index, state, switch_remap[index] as usize) {
+            self.stages.process(event, emit);
+        }
+    }
+
+    /// Key has not changed state for the idle period
+    fn idle(&self, index: usize) -> bool {
+        self.keys[index].cycles as u64 * Self::CYCLE_US as u64 >= self.idle_ms as u64 * 1000
+    }
+
+    /// Generates the trigger event for a debounced state
+    /// Off events are not generated
+    fn event(&self, index: usize, state: Phro, trigger_index: usize) -> Option<TriggerEvent> {
+        if state == Phro::Off {
+            return None;
+        }
+        Some(TriggerEvent::Switch {
+            state,
+            index: trigger_index as u16,
+            last_state: self.keys[index].cycles,
+        })
+    }
+}
+
+impl<const CSIZE: usize, const RSIZE: usize, const MSIZE: usize, const SCAN_PERIOD_US: u32>
+    KeyScanning<MAX_PER_KEY_EVENTS> for DebouncedMatrix<CSIZE, RSIZE, MSIZE, SCAN_PERIOD_US>
+{
+    fn generate_events(&self, index: usize) -> heapless::Vec<TriggerEvent, MAX_PER_KEY_EVENTS> {
+        let mut events = heapless::Vec::new();
+
+        // Virtual switches of tap-hold keys
+        if self.stages.is_virtual(index as u16) {
+            if let Some(event) = self.stages.virtual_event(index as u16) {
+                events.push(event).unwrap();
+            }
+            return events;
+        }
+
+        if let Some(key) = self.keys.get(index) {
+            // Keys held back by a stage are not pressed as far as kll-core is concerned
+            let pressed = key.pressed && !self.stages.suppressed(index as u16);
+            let state = if pressed { Phro::Hold } else { Phro::Off };
+            if let Some(event) = self.event(index, state, index) {
+                events.push(event).unwrap();
+            }
+        }
+        events
+    }
+}
+
+// ----- Traits -----
+
+/// Mechanical switches have no calibration data
+impl<const CSIZE: usize, const RSIZE: usize, const MSIZE: usize, const SCAN_PERIOD_US: u32>
+    crate::terminal::MatrixSnapshot for DebouncedMatrix<CSIZE, RSIZE, MSIZE, SCAN_PERIOD_US>
+{
+    fn key_snapshot(&self, index: usize) -> crate::terminal::KeySnapshot {
+        match self.keys.get(index) {
+            Some(key) => crate::terminal::KeySnapshot {
+                pressed: key.pressed,
+                debouncing: key.pending_us > 0,
+                idle: self.idle(index),
+                calibration: None,
+                distance: None,
+            },
+            None => crate::terminal::KeySnapshot::default(),
+        }
+    }
+}
+
 // ----- Initialization Functions -----
 
 pub fn init<
@@ -39,7 +300,9 @@ pub fn init<
     cols: [PioX<Output<PushPull>>; CSIZE],
     rows: [PioX<Input<PullDown>>; RSIZE],
     tc0_chs: &mut TimerCounterChannels,
-) -> KeyMatrix<CSIZE, RSIZE, MSIZE, SCAN_PERIOD_US> {
+    ghost_mode: GhostMode,
+    stages: TriggerStages,
+) -> DebouncedMatrix<CSIZE, RSIZE, MSIZE, SCAN_PERIOD_US> {
     // Setup Keyscanning Matrix
     defmt::trace!("Keyscanning Matrix initialization");
     let mut matrix = KeyMatrix::new(cols, rows).unwrap();
@@ -52,7 +315,7 @@ pub fn init<
     defmt::trace!("TCC0 started - Keyscanning");
     tcc0.enable_interrupt();
 
-    matrix
+    DebouncedMatrix::new(matrix, ghost_mode, stages)
 }
 
 // ----- Software Interrupt Tasks -----
@@ -73,35 +336,81 @@ pub fn tc0_irq<
 >(
     hidio_intf: &mut HidioCommandInterface,
     layer_state: &mut LayerState,
-    matrix: &mut KeyMatrix<CSIZE, RSIZE, MSIZE, SCAN_PERIOD_US>,
+    matrix: &mut DebouncedMatrix<CSIZE, RSIZE, MSIZE, SCAN_PERIOD_US>,
     switch_remap: &[u8],
     tcc0: &mut TimerCounterChannel<TC0, Tc0Clock<Enabled>, 0, TCC0_FREQ>,
 ) -> bool {
     // Check for keyscanning interrupt (tcc0)
     if tcc0.clear_interrupt_flags() {
+        // Apply runtime timing changes (console or HID-IO)
+        if let Some(setting) = hidio_intf.mut_interface().matrix_setting.take() {
+            if !matrix.apply_setting(setting) {
+                defmt::warn!("Invalid keyscanning setting: {:?}", setting);
+            }
+        }
+        let stage_settings = core::mem::take(&mut hidio_intf.mut_interface().stage_settings);
+        let keymap = core::mem::take(&mut hidio_intf.mut_interface().keymap_changed)
+            .then(|| hidio_intf.interface().keymap);
+        matrix
+            .stages
+            .keymap
+            .set_layer(crate::active_layer(layer_state));
+        matrix
+            .stages
+            .combos
+            .set_layers(crate::active_layers(layer_state));
+
+        let mut emit = |event: TriggerEvent| {
+            let hidio_event = HidIoEvent::TriggerEvent(event);
+
+            // Enqueue KLL trigger event
+            let ret = layer_state.process_trigger::<MAX_LAYER_LOOKUP_SIZE>(event);
+            debug_assert!(ret.is_ok(), "Failed to enqueue: {:?} - {:?}", event, ret);
+
+            // Enqueue HID-IO trigger event
+            if let Err(err) = hidio_intf.process_event(hidio_event) {
+                defmt::error!("Hidio TriggerEvent Error: {:?}", err);
+            }
+        };
+
+        // Apply stage setting changes (console or HID-IO)
+        matrix.stages.apply(stage_settings, &mut emit);
+        if let Some(config) = keymap {
+            matrix.stages.keymap.set_config(config, &mut emit);
+        }
+
         // Scan one strobe (strobes have already been enabled and allowed to settle)
-        if let Ok((reading, strobe)) = matrix.sense::<Infallible>() {
+        if let Ok((reading, strobe)) = matrix.matrix.sense::<Infallible>() {
             for (i, entry) in reading.iter().enumerate() {
-                for event in entry.trigger_events::<MAX_PER_KEY_EVENTS>(
-                    switch_remap[strobe * RSIZE + i] as usize,
-                    true,
-                ) {
-                    let hidio_event = HidIoEvent::TriggerEvent(event);
-
-                    // Enqueue KLL trigger event
-                    let ret = layer_state.process_trigger::<MAX_LAYER_LOOKUP_SIZE>(event);
-                    debug_assert!(ret.is_ok(), "Failed to enqueue: {:?} - {:?}", event, ret);
-
-                    // Enqueue HID-IO trigger event
-                    if let Err(err) = hidio_intf.process_event(hidio_event) {
-                        defmt::error!("Hidio TriggerEvent Error: {:?}", err);
-                    }
+                let (raw, _, _) = entry.state();
+                matrix.raw[strobe * RSIZE + i] = raw == kiibohd_keyscanning::State::On;
+            }
+
+            // Without anti-ghosting, keys are debounced as soon as they are scanned
+            if matrix.ghost_mode == GhostMode::Off {
+                for index in strobe * RSIZE..(strobe + 1) * RSIZE {
+                    let state = matrix.record(index, matrix.raw[index]);
+                    matrix.process(index, state, switch_remap, &mut emit);
                 }
             }
         }
 
         // Strobe next column
-        return matrix.next_strobe::<Infallible>().unwrap() == 0;
+        let full_scan = matrix.matrix.next_strobe::<Infallible>().unwrap() == 0;
+
+        // Whole matrix is needed for anti-ghosting
+        if full_scan && matrix.ghost_mode != GhostMode::Off {
+            matrix.record_scan(switch_remap, &mut emit);
+        }
+
+        // Tapping term timing
+        if full_scan {
+            matrix.stages.tick(
+                DebouncedMatrix::<CSIZE, RSIZE, MSIZE, SCAN_PERIOD_US>::CYCLE_US,
+                &mut emit,
+            );
+        }
+        return full_scan;
     }
 
     false
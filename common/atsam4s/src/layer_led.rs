@@ -0,0 +1,93 @@
+// Copyright 2026 Jacob Alexander
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! Active layer LED schemes
+//!
+//! Layer LED schemes are written in the KLL files and compiled into the LAYER_LEDS table by
+//! build.rs, using the pixel mapping (`P[<pixel>](<channel>:<width>, ...) : S<switch>;`) to find
+//! the LED channels of each switch:
+//!
+//! ```text
+//! LayerLed[1] : Background(0, 0, 0);           # Every pixel
+//! LayerLed[1] : Bound(0, 80, 255);             # Keys bound on layer 1
+//! LayerLed[1] : S0x10(255, 0, 0);              # Single key
+//! ```
+//!
+//! The scheme of the top active layer is drawn over each LED frame (HID-IO frames included),
+//! before the indicator masks (LED_LOCK_MASK). The replaced values are restored once the layer
+//! is no longer active.
+
+use crate::constants::*;
+use heapless::Vec;
+
+// ----- Structs -----
+
+/// LED scheme of a layer, generated by build.rs
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub struct LayerLeds {
+    pub layer: u8,
+    /// LED channel (all chips, ISSI_DRIVER_CHANNELS per chip) and value
+    pub channels: &'static [(u16, u8)],
+}
+
+/// Layer LED scheme drawn over the LED frame buffer
+pub struct LayerLedOverlay {
+    schemes: &'static [LayerLeds],
+    /// Channels of the drawn scheme and the values they replaced
+    drawn: &'static [(u16, u8)],
+    replaced: Vec<u8, { ISSI_DRIVER_CHIPS * ISSI_DRIVER_CHANNELS }>,
+}
+
+impl LayerLedOverlay {
+    pub const fn new(schemes: &'static [LayerLeds]) -> Self {
+        Self {
+            schemes,
+            drawn: &[],
+            replaced: Vec::new(),
+        }
+    }
+
+    /// Restores the values replaced by the drawn scheme
+    /// Called before the frame buffer is updated. write(chip, offset, value)
+    pub fn restore(&mut self, mut write: impl FnMut(usize, usize, u8)) {
+        for ((channel, _), value) in self.drawn.iter().zip(self.replaced.iter()) {
+            if let Some((chip, offset)) = position(*channel) {
+                write(chip, offset, *value);
+            }
+        }
+        self.drawn = &[];
+        self.replaced.clear();
+    }
+
+    /// Draws the scheme of the top active layer (if any)
+    /// Called once the frame buffer has been updated.
+    /// swap(chip, offset, value) writes the value and returns the replaced value.
+    pub fn draw(&mut self, layer: u8, mut swap: impl FnMut(usize, usize, u8) -> u8) {
+        let scheme = match self.schemes.iter().find(|scheme| scheme.layer == layer) {
+            Some(scheme) => scheme,
+            None => return,
+        };
+        for (channel, value) in scheme.channels {
+            let replaced = match position(*channel) {
+                Some((chip, offset)) => swap(chip, offset, *value),
+                None => 0,
+            };
+            if self.replaced.push(replaced).is_err() {
+                break;
+            }
+        }
+        self.drawn = &scheme.channels[..self.replaced.len()];
+    }
+}
+
+// ----- Functions -----
+
+/// Chip and offset of an LED channel
+fn position(channel: u16) -> Option<(usize, usize)> {
+    let chip = channel as usize / ISSI_DRIVER_CHANNELS;
+    (chip < ISSI_DRIVER_CHIPS).then_some((chip, channel as usize % ISSI_DRIVER_CHANNELS))
+}
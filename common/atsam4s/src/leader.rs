@@ -0,0 +1,378 @@
+// Copyright 2026 Jacob Alexander
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! Leader key sequences
+//!
+//! Leader keys and sequences are written in the KLL files and compiled into the LEADER_KEYS and
+//! LEADER_SEQUENCES tables by build.rs:
+//!
+//! ```text
+//! S0x40 : Leader;                              # Optional timeout, e.g. Leader(500ms)
+//! Leader(U"G", U"S") : "git status\n";         # HID keyboard string (US layout)
+//! Leader(U"C", U"K") : u"✓";                   # HID-IO unicode string
+//! ```
+//!
+//! Pressing a leader key starts a sequence. The next keys are matched against the sequences using
+//! the HID keyboard results of finalize_triggers and are not sent to the host. The sequence ends
+//! once it matches a single sequence, can't match any sequence, or no key has been pressed for the
+//! timeout (LEADER_TIMEOUT_MS by default). Modifiers are not part of sequences and are sent as
+//! usual.
+//!
+//! Only the leader keys are a trigger stage (LeaderKeys, before process_trigger). The sequence keys
+//! are captured after finalize_triggers rather than between process_trigger and
+//! finalize_triggers: the layers are only resolved by finalize_triggers, so matching the USB codes
+//! of its results lets a sequence be typed from any layer (or keymap overlay entry) producing the
+//! keys, where matching KLL triggers would need a sequence per switch and layer.
+
+use crate::constants::*;
+use heapless::Vec;
+use kll_core::{trigger::Phro, CapabilityEvent, TriggerEvent};
+
+// ----- Constants -----
+
+const CYCLES_PER_US: u32 = MCU_FREQ / 1_000_000;
+
+/// Modifier USB HID keyboard codes (LCtrl..RGui)
+const MODIFIERS: core::ops::RangeInclusive<u8> = 0xE0..=0xE7;
+
+// ----- Enums -----
+
+/// Result of a leader sequence
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum LeaderResult {
+    /// USB HID keyboard events (code, press), sent through the keyboard queue
+    Keyboard(&'static [(u8, bool)]),
+    /// Unicode string, sent to the host using HID-IO (h0017)
+    Unicode(&'static str),
+}
+
+// ----- Structs -----
+
+/// Leader key, generated by build.rs
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub struct LeaderKey {
+    /// Switch (KLL trigger index)
+    pub switch: u16,
+    /// Time allowed between sequence keys (LEADER_TIMEOUT_MS if None)
+    pub timeout_ms: Option<u32>,
+}
+
+/// Leader sequence, generated by build.rs
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub struct LeaderSequence {
+    /// USB HID keyboard codes (up to MAX_LEADER_KEYS)
+    pub keys: &'static [u8],
+    pub result: LeaderResult,
+}
+
+/// Leader keys (trigger stage)
+/// Events of the keys are replaced by start requests, handled by macro_process.
+pub struct LeaderKeys {
+    keys: &'static [LeaderKey],
+    /// Timeout (ms) of the last pressed leader key
+    request: Option<u32>,
+}
+
+impl LeaderKeys {
+    pub fn new(keys: &'static [LeaderKey]) -> Self {
+        Self {
+            keys,
+            request: None,
+        }
+    }
+
+    /// Passes a trigger event through the leader key stage
+    pub fn process(&mut self, event: TriggerEvent, emit: &mut impl FnMut(TriggerEvent)) {
+        let key = match event {
+            TriggerEvent::Switch { state, index, .. } => self
+                .keys
+                .iter()
+                .find(|key| key.switch == index)
+                .map(|key| (key, state)),
+            _ => None,
+        };
+        match key {
+            Some((key, Phro::Press)) => {
+                self.request = Some(key.timeout_ms.unwrap_or(LEADER_TIMEOUT_MS));
+            }
+            Some(_) => {}
+            None => emit(event),
+        }
+    }
+
+    /// Takes the start request (timeout in ms) of a pressed leader key
+    pub fn take_request(&mut self) -> Option<u32> {
+        self.request.take()
+    }
+
+    /// Switch is a leader key (events are not sent to kll-core)
+    pub fn suppressed(&self, index: u16) -> bool {
+        self.keys.iter().any(|key| key.switch == index)
+    }
+}
+
+/// Leader sequence matching and output
+pub struct Leader {
+    sequences: &'static [LeaderSequence],
+    /// Sequence in progress
+    active: bool,
+    keys: Vec<u8, MAX_LEADER_KEYS>,
+    /// Keys captured by the sequence (bit per HID code), released keys are removed
+    captured: [u32; 8],
+    timeout_us: u32,
+    /// Time since the leader key or the last sequence key
+    elapsed_us: u32,
+    /// Cycle count of the last tick
+    cycles: Option<u32>,
+    /// Keyboard events being sent and the next event
+    output: &'static [(u8, bool)],
+    position: usize,
+    unicode: Option<&'static str>,
+}
+
+impl Leader {
+    pub const fn new(sequences: &'static [LeaderSequence]) -> Self {
+        Self {
+            sequences,
+            active: false,
+            keys: Vec::new(),
+            captured: [0; 8],
+            timeout_us: 0,
+            elapsed_us: 0,
+            cycles: None,
+            output: &[],
+            position: 0,
+            unicode: None,
+        }
+    }
+
+    pub fn active(&self) -> bool {
+        self.active
+    }
+
+    /// Starts a sequence (restarts the sequence in progress)
+    pub fn start(&mut self, timeout_ms: u32) {
+        defmt::info!("Leader sequence started");
+        self.active = true;
+        self.keys.clear();
+        self.timeout_us = timeout_ms.saturating_mul(1000);
+        self.elapsed_us = 0;
+    }
+
+    /// Passes a HID keyboard result of finalize_triggers through the leader
+    /// Returns true if the key is part of a sequence (not sent to the host)
+    pub fn capture(&mut self, key: u8, state: CapabilityEvent) -> bool {
+        let bit = 1 << (key % 32);
+        let captured = &mut self.captured[key as usize / 32];
+        if *captured & bit != 0 {
+            if matches!(state, CapabilityEvent::Last) {
+                *captured &= !bit;
+            }
+            return true;
+        }
+        if !self.active || !matches!(state, CapabilityEvent::Initial) || MODIFIERS.contains(&key) {
+            return false;
+        }
+        *captured |= bit;
+
+        self.elapsed_us = 0;
+        if self.keys.push(key).is_err() {
+            self.finish(None);
+            return true;
+        }
+        let exact = self.sequences.iter().find(|seq| seq.keys == &self.keys[..]);
+        let longer = self
+            .sequences
+            .iter()
+            .any(|seq| seq.keys.len() > self.keys.len() && seq.keys.starts_with(&self.keys));
+        if !longer {
+            self.finish(exact);
+        }
+        true
+    }
+
+    /// Advances the sequence timeout using the DWT cycle count (see profiling::start)
+    pub fn tick(&mut self, cycles: u32) {
+        let elapsed_us = match self.cycles.replace(cycles) {
+            Some(last) => cycles.wrapping_sub(last) / CYCLES_PER_US,
+            None => 0,
+        };
+        if !self.active {
+            return;
+        }
+        self.elapsed_us = self.elapsed_us.saturating_add(elapsed_us);
+        if self.elapsed_us >= self.timeout_us {
+            let exact = self.sequences.iter().find(|seq| seq.keys == &self.keys[..]);
+            self.finish(exact);
+        }
+    }
+
+    fn finish(&mut self, sequence: Option<&'static LeaderSequence>) {
+        self.active = false;
+        self.keys.clear();
+        match sequence.map(|seq| seq.result) {
+            Some(LeaderResult::Keyboard(events)) => {
+                defmt::info!("Leader sequence matched ({} keyboard events)", events.len());
+                if self.position < self.output.len() {
+                    defmt::warn!("Leader output still being sent, dropped");
+                    return;
+                }
+                self.output = events;
+                self.position = 0;
+            }
+            Some(LeaderResult::Unicode(text)) => {
+                defmt::info!("Leader sequence matched: {}", text);
+                self.unicode = Some(text);
+            }
+            None => {
+                defmt::info!("Leader sequence cancelled");
+            }
+        }
+    }
+
+    /// Takes the next keyboard event (code, press) of a matched sequence
+    /// Events are sent one per macro_process iteration so each gets a USB report.
+    pub fn take_key(&mut self) -> Option<(u8, bool)> {
+        let event = self.output.get(self.position).copied()?;
+        self.position += 1;
+        Some(event)
+    }
+
+    /// Takes the unicode string of a matched sequence
+    pub fn take_unicode(&mut self) -> Option<&'static str> {
+        self.unicode.take()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const G: u8 = 0x0A;
+    const S: u8 = 0x16;
+    const C: u8 = 0x06;
+    const K: u8 = 0x0E;
+    const LSHIFT: u8 = 0xE1;
+
+    const SEQUENCES: &[LeaderSequence] = &[
+        LeaderSequence {
+            keys: &[G],
+            result: LeaderResult::Keyboard(&[(G, true), (G, false)]),
+        },
+        LeaderSequence {
+            keys: &[G, S],
+            result: LeaderResult::Unicode("git status"),
+        },
+        LeaderSequence {
+            keys: &[C, K],
+            result: LeaderResult::Unicode("✓"),
+        },
+    ];
+
+    /// Cycle count after ms
+    fn cycles(ms: u32) -> u32 {
+        ms * 1000 * CYCLES_PER_US
+    }
+
+    fn leader() -> Leader {
+        let mut leader = Leader::new(SEQUENCES);
+        leader.tick(0);
+        leader.start(100);
+        leader
+    }
+
+    #[test]
+    fn inactive_not_captured() {
+        let mut leader = Leader::new(SEQUENCES);
+        assert!(!leader.capture(G, CapabilityEvent::Initial));
+        assert!(!leader.capture(G, CapabilityEvent::Last));
+    }
+
+    #[test]
+    fn exact_match() {
+        let mut leader = leader();
+        assert!(leader.capture(C, CapabilityEvent::Initial));
+        assert!(leader.active());
+        assert!(leader.capture(K, CapabilityEvent::Initial));
+        assert!(!leader.active());
+        assert_eq!(leader.take_unicode(), Some("✓"));
+        assert_eq!(leader.take_key(), None);
+
+        // Releases of the captured keys are not sent either
+        assert!(leader.capture(C, CapabilityEvent::Last));
+        assert!(leader.capture(K, CapabilityEvent::Last));
+        assert!(!leader.capture(C, CapabilityEvent::Initial));
+    }
+
+    #[test]
+    fn partial_match_waits_for_longer_sequence() {
+        let mut leader = leader();
+        assert!(leader.capture(G, CapabilityEvent::Initial));
+        assert!(leader.active());
+        leader.tick(cycles(99));
+        assert!(leader.active());
+        assert!(leader.capture(S, CapabilityEvent::Initial));
+        assert!(!leader.active());
+        assert_eq!(leader.take_unicode(), Some("git status"));
+        assert_eq!(leader.take_key(), None);
+    }
+
+    #[test]
+    fn partial_match_used_on_timeout() {
+        let mut leader = leader();
+        assert!(leader.capture(G, CapabilityEvent::Initial));
+        leader.tick(cycles(99));
+        assert!(leader.active());
+        leader.tick(cycles(100));
+        assert!(!leader.active());
+        assert_eq!(leader.take_key(), Some((G, true)));
+        assert_eq!(leader.take_key(), Some((G, false)));
+        assert_eq!(leader.take_key(), None);
+        assert_eq!(leader.take_unicode(), None);
+    }
+
+    #[test]
+    fn partial_match_cancelled_on_timeout() {
+        let mut leader = leader();
+        assert!(leader.capture(C, CapabilityEvent::Initial));
+        leader.tick(cycles(100));
+        assert!(!leader.active());
+        assert_eq!(leader.take_key(), None);
+        assert_eq!(leader.take_unicode(), None);
+    }
+
+    #[test]
+    fn timeout_restarted_by_each_key() {
+        let mut leader = leader();
+        leader.tick(cycles(60));
+        assert!(leader.capture(C, CapabilityEvent::Initial));
+        leader.tick(cycles(120));
+        assert!(leader.active());
+        leader.tick(cycles(160));
+        assert!(!leader.active());
+    }
+
+    #[test]
+    fn mismatch_cancels() {
+        let mut leader = leader();
+        assert!(leader.capture(C, CapabilityEvent::Initial));
+        // Not sent, the sequence is cancelled
+        assert!(leader.capture(S, CapabilityEvent::Initial));
+        assert!(!leader.active());
+        assert_eq!(leader.take_key(), None);
+        assert_eq!(leader.take_unicode(), None);
+    }
+
+    #[test]
+    fn modifiers_sent() {
+        let mut leader = leader();
+        assert!(!leader.capture(LSHIFT, CapabilityEvent::Initial));
+        assert!(leader.capture(C, CapabilityEvent::Initial));
+        assert!(!leader.capture(LSHIFT, CapabilityEvent::Last));
+        assert!(leader.active());
+    }
+}
@@ -5,32 +5,74 @@
 // http://opensource.org/licenses/MIT>, at your option. This file may not be
 // copied, modified, or distributed except according to those terms.
 
-#![no_std]
+#![cfg_attr(not(test), no_std)]
 
+pub mod analog;
+mod app;
+pub mod combo;
+pub mod console;
 pub mod constants;
+pub mod dynamic_macro;
 mod hidio;
+pub mod inject;
+pub mod keymap;
+pub mod layer_led;
+pub mod leader;
+pub mod profiling;
+pub mod terminal;
+
+#[cfg(feature = "hall-effect")]
+pub mod dks;
+
+#[cfg(feature = "keyscanning")]
+pub mod ghosting;
 
 #[cfg(feature = "hall-effect")]
 pub mod hall_effect;
 
+#[cfg(feature = "hall-effect")]
+pub mod hall_stats;
+
+#[cfg(feature = "issi-i2c")]
+pub mod issi_i2c;
+
 #[cfg(feature = "issi-spi")]
 pub mod issi_spi;
 
 #[cfg(feature = "keyscanning")]
 pub mod keyscanning;
 
+#[cfg(feature = "via")]
+pub mod raw_hid;
+
+#[cfg(feature = "serial-console")]
+pub mod serial;
+
+#[cfg(feature = "via")]
+pub mod via;
+
+pub mod oneshot;
+pub mod socd;
+pub mod stages;
+pub mod storage;
+pub mod taphold;
+
 pub use atsam4_hal as hal;
 pub use heapless;
 pub use kiibohd_hid_io;
 pub use kiibohd_usb;
 pub use kll_core;
 
+// Target only, host tests use std (panics) and defmt host logging
+#[cfg(not(test))]
 use defmt_rtt as _;
+#[cfg(not(test))]
 use panic_probe as _;
 
 use crate::constants::*;
 use crate::hidio::*;
 use core::fmt::Write;
+#[cfg(not(test))]
 use cortex_m_rt::exception;
 use fugit::ExtU32;
 use hal::{
@@ -76,6 +118,33 @@ pub enum LedTest {
     ShortReady,
 }
 
+/// How a layer is activated by the KLL layout (LAYER_ACTIVATIONS, generated by build.rs)
+/// Used by the layer change notifications, see layer_notify_task.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum LayerActivation {
+    /// Layer[n] and LayerShift[n]
+    Shift,
+    /// LayerLatch[n]
+    Latch,
+    /// LayerLock[n]
+    Lock,
+}
+
+impl LayerActivation {
+    /// Layer trigger state of an activation (true) or deactivation (false)
+    pub fn state(self, active: bool) -> kll_core::trigger::LayerState {
+        use kll_core::trigger::LayerState;
+        match (self, active) {
+            (LayerActivation::Shift, true) => LayerState::ShiftActivate,
+            (LayerActivation::Shift, false) => LayerState::ShiftDeactivate,
+            (LayerActivation::Latch, true) => LayerState::LatchActivate,
+            (LayerActivation::Latch, false) => LayerState::LatchDeactivate,
+            (LayerActivation::Lock, true) => LayerState::LockActivate,
+            (LayerActivation::Lock, false) => LayerState::LockDeactivate,
+        }
+    }
+}
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
 pub enum UsbState {
     /// Transitioned to a suspended state
@@ -122,6 +191,14 @@ type TimerCounterChannels = hal::timer::TimerCounterChannels<
     TCC2_FREQ,
 >;
 pub type UsbDevice = usb_device::device::UsbDevice<'static, UdpBus>;
+/// USB keys of the compiled layout (layer, switch, USB HID keyboard code), generated by build.rs
+pub type LayoutUsbKeys = &'static [(u8, u16, Option<u8>)];
+
+/// VIA raw HID interface (unit without the via feature)
+#[cfg(feature = "via")]
+pub type ViaInterface = via::ViaInterface<'static, UdpBus>;
+#[cfg(not(feature = "via"))]
+pub type ViaInterface = ();
 
 // ----- Structs -----
 
@@ -151,6 +228,27 @@ impl<const NUM_LEDS: usize> Default for IndicatorLeds<NUM_LEDS> {
     }
 }
 
+/// Used to mask out LEDs that may be used for special purposes
+/// Commonly used for indicator LEDs that should not be affected by other frame processing
+#[derive(Default)]
+pub struct LedMask {
+    pub chip: u8,
+    pub offset: u16,
+    pub mask: [u8; 3],
+    pub frames_since_update: u8,
+}
+
+impl LedMask {
+    pub const fn new(chip: u8, offset: u16, mask: [u8; 3]) -> Self {
+        Self {
+            chip,
+            offset,
+            mask,
+            frames_since_update: 0,
+        }
+    }
+}
+
 // ----- Initialization Functions -----
 
 /// Check user signature (up to 512-bytes of data)
@@ -185,7 +283,7 @@ fn check_user_signature(efc: &mut Efc, revision: u16) -> bool {
 /// - Chip ID
 /// - Clocks
 /// - Watchdog
-/// - Flash Controller (EFC0)
+/// - Flash Controller (EFC0), returned for settings storage
 /// - Serial Number
 #[allow(clippy::too_many_arguments)]
 pub fn initial_init(
@@ -209,6 +307,7 @@ pub fn initial_init(
     TimerCounterChannels,
     RealTimeTimer,
     Ports,
+    Efc,
 ) {
     defmt::info!(">>>> Initializing <<<<");
 
@@ -266,10 +365,10 @@ pub fn initial_init(
     rtt.enable_alarm_interrupt();
     defmt::trace!("RTT Timer started");
 
-    (wdt, clocks, chip, tc0_chs, rtt, gpio_ports)
+    (wdt, clocks, chip, tc0_chs, rtt, gpio_ports, efc)
 }
 
-/// Initialize atsam4s UdpBus + kiibohd hid + hid-io
+/// Initialize atsam4s UdpBus + kiibohd hid + hid-io (+ VIA raw hid)
 #[allow(clippy::too_many_arguments)]
 #[allow(clippy::type_complexity)]
 pub fn usb_init(
@@ -279,6 +378,7 @@ pub fn usb_init(
     kbd_queue: &'static mut Queue<kiibohd_usb::KeyState, KBD_QUEUE_SIZE>,
     firmware_commit_count: u16,
     firmware_version: &'static str,
+    #[cfg_attr(not(feature = "via"), allow(unused_variables))] layout_usb_keys: LayoutUsbKeys,
     mouse_queue: &'static mut Queue<kiibohd_usb::MouseState, MOUSE_QUEUE_SIZE>,
     serial_number: &'static String<126>,
     udp: hal::pac::UDP,
@@ -289,6 +389,7 @@ pub fn usb_init(
 ) -> (
     UsbDevice,
     HidInterface,
+    ViaInterface,
     HidioCommandInterface,
     Producer<'static, kiibohd_usb::CtrlState, CTRL_QUEUE_SIZE>,
     Consumer<'static, kiibohd_usb::LedState, KBD_LED_QUEUE_SIZE>,
@@ -304,6 +405,8 @@ pub fn usb_init(
             HidIoCommandId::ManufacturingTest,
             HidIoCommandId::PixelSetting,
             HidIoCommandId::SupportedIds,
+            HidIoCommandId::TerminalCmd,
+            HidIoCommandId::TerminalOut,
             HidIoCommandId::TestPacket,
         ],
         HidioInterface::<MESSAGE_LEN>::new(chip, Some(serial_number.clone()), firmware_version),
@@ -328,6 +431,10 @@ pub fn usb_init(
         mouse_consumer,
         ctrl_consumer,
     );
+    #[cfg(feature = "via")]
+    let via = via::ViaInterface::new(usb_bus, layout_usb_keys);
+    #[cfg(not(feature = "via"))]
+    let via = ();
     let mut usb_dev = UsbDeviceBuilder::new(usb_bus, UsbVidPid(VID, PID))
         .manufacturer(USB_MANUFACTURER)
         .max_packet_size_0(64)
@@ -344,6 +451,7 @@ pub fn usb_init(
     (
         usb_dev,
         usb_hid,
+        via,
         hidio_intf,
         ctrl_producer,
         kbd_led_consumer,
@@ -361,14 +469,43 @@ pub fn macro_process_task<const CSIZE: usize, const MSIZE: usize, MATRIX>(
     ctrl_producer: &mut Producer<'static, kiibohd_usb::CtrlState, CTRL_QUEUE_SIZE>,
     kbd_producer: &mut Producer<'static, kiibohd_usb::KeyState, KBD_QUEUE_SIZE>,
     _mouse_producer: &mut Producer<'static, kiibohd_usb::MouseState, MOUSE_QUEUE_SIZE>,
+    dynamic_macros: &mut dynamic_macro::DynamicMacros,
+    leader: &mut leader::Leader,
+    injected: &[kll_core::TriggerEvent],
     layer_state: &mut LayerState,
     matrix: &mut MATRIX,
 ) where
     MATRIX: kiibohd_keyscanning::KeyScanning<MAX_PER_KEY_EVENTS>,
 {
     // Confirm off-state lookups
+    // Keys injected by the host (see inject_task) are held even if they aren't pressed
     layer_state.process_off_state_lookups::<MAX_LAYER_LOOKUP_SIZE, MAX_PER_KEY_EVENTS>(&|index| {
-        matrix.generate_events(index)
+        let mut events = matrix.generate_events(index);
+        let injected = injected.iter().find(|event| {
+            matches!(event, kll_core::TriggerEvent::Switch { index: i, .. } if *i as usize == index)
+        });
+        let pressed = events.iter().any(|event| {
+            matches!(
+                event,
+                kll_core::TriggerEvent::Switch {
+                    state: kll_core::trigger::Phro::Press | kll_core::trigger::Phro::Hold,
+                    ..
+                }
+            )
+        });
+        if let (Some(event), false) = (injected, pressed) {
+            events.retain(|event| {
+                !matches!(
+                    event,
+                    kll_core::TriggerEvent::Switch {
+                        state: kll_core::trigger::Phro::Off,
+                        ..
+                    }
+                )
+            });
+            events.push(*event).ok();
+        }
+        events
     });
 
     // Finalize triggers to generate CapabilityRun events
@@ -377,10 +514,27 @@ pub fn macro_process_task<const CSIZE: usize, const MSIZE: usize, MATRIX>(
             kll_core::CapabilityRun::NoOp { .. } => {}
             kll_core::CapabilityRun::HidKeyboard { .. }
             | kll_core::CapabilityRun::HidKeyboardState { .. } => {
+                // Keys of a leader sequence are not sent
+                // Captured after finalize_triggers so the layers are applied (see leader.rs)
+                if let kll_core::CapabilityRun::HidKeyboard { state, id } = cap_run {
+                    if leader.capture(id as u8, state) {
+                        continue;
+                    }
+                }
+
                 debug_assert!(
                     kiibohd_usb::enqueue_keyboard_event(cap_run, kbd_producer).is_ok(),
                     "KBD_QUEUE_SIZE too small"
                 );
+
+                // Dynamic macro recording
+                if let kll_core::CapabilityRun::HidKeyboard { state, id } = cap_run {
+                    match state {
+                        kll_core::CapabilityEvent::Initial => dynamic_macros.record(id as u8, true),
+                        kll_core::CapabilityEvent::Last => dynamic_macros.record(id as u8, false),
+                        _ => {}
+                    }
+                }
             }
             kll_core::CapabilityRun::HidProtocol { .. } => {}
             kll_core::CapabilityRun::HidConsumerControl { .. }
@@ -409,6 +563,238 @@ pub fn macro_process_task<const CSIZE: usize, const MSIZE: usize, MATRIX>(
     layer_state.increment_time();
 }
 
+/// Sub-task of macro_process handling dynamic macro record/play requests and playback
+/// Played events are sent directly to the keyboard queue (they are not recorded).
+pub fn dynamic_macro_task(
+    dynamic_macros: &mut dynamic_macro::DynamicMacros,
+    macro_keys: &mut dynamic_macro::MacroKeys,
+    hidio_intf: &mut HidioCommandInterface,
+    kbd_producer: &mut Producer<'static, kiibohd_usb::KeyState, KBD_QUEUE_SIZE>,
+) {
+    let intf = hidio_intf.mut_interface();
+    while let Some(action) = macro_keys.take_request() {
+        match action {
+            dynamic_macro::MacroAction::Record(slot) => {
+                // Any record key stops the current recording
+                if let Some((slot, events)) = dynamic_macros.stop_recording() {
+                    if intf.dynamic_macros.set(slot, events) {
+                        intf.dynamic_macros_save = true;
+                    } else {
+                        defmt::warn!("Not enough space to store dynamic macro {}", slot);
+                    }
+                } else {
+                    dynamic_macros.start_recording(slot);
+                }
+            }
+            dynamic_macro::MacroAction::Play(slot) => {
+                dynamic_macros.play(intf.dynamic_macros.get(slot));
+            }
+        }
+    }
+
+    dynamic_macros.tick(profiling::start(), |key, press| {
+        let state = if press {
+            kiibohd_usb::KeyState::Press(key)
+        } else {
+            kiibohd_usb::KeyState::Release(key)
+        };
+        if kbd_producer.enqueue(state).is_err() {
+            defmt::warn!("KBD_QUEUE_SIZE too small, dropped dynamic macro event");
+        }
+    });
+}
+
+/// Sub-task of macro_process sending the USB HID keyboard codes of remapped keys
+pub fn keymap_task(
+    keymap: &mut keymap::Keymap,
+    kbd_producer: &mut Producer<'static, kiibohd_usb::KeyState, KBD_QUEUE_SIZE>,
+) {
+    while let Some((key, press)) = keymap.take_key() {
+        let state = if press {
+            kiibohd_usb::KeyState::Press(key)
+        } else {
+            kiibohd_usb::KeyState::Release(key)
+        };
+        if kbd_producer.enqueue(state).is_err() {
+            defmt::warn!("KBD_QUEUE_SIZE too small, dropped remapped key {}", key);
+        }
+    }
+}
+
+/// Sub-task of macro_process handling leader keys
+/// Starts sequences, checks the timeout and sends the results of matched sequences.
+pub fn leader_task(
+    leader: &mut leader::Leader,
+    leader_keys: &mut leader::LeaderKeys,
+    hidio_intf: &mut HidioCommandInterface,
+    kbd_producer: &mut Producer<'static, kiibohd_usb::KeyState, KBD_QUEUE_SIZE>,
+) {
+    if let Some(timeout_ms) = leader_keys.take_request() {
+        leader.start(timeout_ms);
+    }
+    leader.tick(profiling::start());
+
+    if let Some((key, press)) = leader.take_key() {
+        let state = if press {
+            kiibohd_usb::KeyState::Press(key)
+        } else {
+            kiibohd_usb::KeyState::Release(key)
+        };
+        if kbd_producer.enqueue(state).is_err() {
+            defmt::warn!("KBD_QUEUE_SIZE too small, dropped leader key {}", key);
+        }
+    }
+
+    if let Some(text) = leader.take_unicode() {
+        let mut string = heapless::String::new();
+        if string.push_str(text).is_err() {
+            defmt::warn!("Leader unicode string too long: {}", text);
+        } else if let Err(err) = hidio_intf.h0017_unicodetext(h0017::Cmd { string }, true) {
+            defmt::warn!("Could not send leader unicode string: {:?}", err);
+        }
+    }
+}
+
+/// Sub-task of macro_process sending host injected key events (inject command)
+/// Events pass through the trigger stages like the events of the scanning interrupt (see
+/// inject.rs).
+/// Returns the hold events of the injected keys, used by macro_process_task for the off-state
+/// lookups.
+pub fn inject_task(
+    hidio_intf: &mut HidioCommandInterface,
+    layer_state: &mut LayerState,
+    stages: &mut stages::TriggerStages,
+) -> heapless::Vec<kll_core::TriggerEvent, MAX_INJECT_KEYS> {
+    let mut events: heapless::Vec<kll_core::TriggerEvent, { inject::Injector::MAX_EVENTS }> =
+        heapless::Vec::new();
+    hidio_intf
+        .mut_interface()
+        .inject
+        .tick(profiling::start(), |event| {
+            if events.push(event).is_err() {
+                defmt::error!("Injector::MAX_EVENTS too small, dropped {:?}", event);
+            }
+        });
+
+    for event in events {
+        stages.process(event, |event| {
+            let hidio_event = HidIoEvent::TriggerEvent(event);
+
+            // Enqueue KLL trigger event
+            let ret = layer_state.process_trigger::<MAX_LAYER_LOOKUP_SIZE>(event);
+            debug_assert!(ret.is_ok(), "Failed to enqueue: {:?} - {:?}", event, ret);
+
+            // Enqueue HID-IO trigger event
+            if let Err(err) = hidio_intf.process_event(hidio_event) {
+                defmt::error!("Hidio TriggerEvent Error: {:?}", err);
+            }
+        });
+    }
+
+    hidio_intf.interface().inject.held_events()
+}
+
+/// Sub-task of macro_process sending layer changes to the host
+/// Layers added to or removed from the layer stack are sent as HID-IO layer trigger events
+/// (TriggerEvent::Layer). The kll-core layer stack doesn't keep how a layer was activated, the
+/// activation (shift, latch or lock) is taken from the layer results of the KLL layout
+/// (LAYER_ACTIVATIONS), layers without a layer result use shift.
+/// last_state is the number of macro_process iterations the layer was in its previous state.
+pub fn layer_notify_task(
+    hidio_intf: &mut HidioCommandInterface,
+    layer_state: &LayerState,
+    activations: &[(u8, LayerActivation)],
+) {
+    let intf = hidio_intf.mut_interface();
+    for cycles in intf.layer_cycles.iter_mut() {
+        *cycles = cycles.saturating_add(1);
+    }
+
+    let stack: heapless::Vec<u8, MAX_ACTIVE_LAYERS> = layer_state
+        .stack()
+        .iter()
+        .map(|layer| *layer as u8)
+        .collect();
+    if stack == intf.layer_stack {
+        return;
+    }
+    defmt::info!("Layer stack: {:?}", stack.as_slice());
+    let previous = core::mem::replace(&mut intf.layer_stack, stack.clone());
+
+    let deactivated = previous
+        .iter()
+        .filter(|layer| !stack.contains(layer))
+        .map(|layer| (*layer, false));
+    let activated = stack
+        .iter()
+        .filter(|layer| !previous.contains(layer))
+        .map(|layer| (*layer, true));
+    for (layer, active) in deactivated.chain(activated) {
+        let activation = activations
+            .iter()
+            .find(|(l, _)| *l == layer)
+            .map_or(LayerActivation::Shift, |(_, activation)| *activation);
+        let last_state = hidio_intf
+            .mut_interface()
+            .layer_cycles
+            .get_mut(layer as usize)
+            .map_or(0, core::mem::take);
+        let event = kll_core::TriggerEvent::Layer {
+            state: activation.state(active),
+            layer,
+            last_state,
+        };
+        if let Err(err) = hidio_intf.process_event(HidIoEvent::TriggerEvent(event)) {
+            defmt::error!("Hidio TriggerEvent Error: {:?}", err);
+        }
+    }
+}
+
+/// Top active layer (0 if no layers are active)
+pub fn active_layer(layer_state: &LayerState) -> u8 {
+    layer_state.stack().last().map_or(0, |layer| *layer as u8)
+}
+
+/// Layers in the layer stack (bit per layer, layer 0 is always set)
+pub fn active_layers(layer_state: &LayerState) -> u16 {
+    layer_state
+        .stack()
+        .iter()
+        .filter(|layer| (**layer as usize) < MAX_LAYERS)
+        .fold(1, |layers, layer| layers | 1 << *layer)
+}
+
+/// USB HID keyboard code of a switch (KLL trigger index) in the compiled layout
+/// Used by boards to check switch indices at compile time (e.g. SOCD_PAIRS).
+pub const fn layout_usb_key(layout: LayoutUsbKeys, layer: u8, switch: u16) -> Option<u8> {
+    let mut i = 0;
+    while i < layout.len() {
+        if layout[i].0 == layer && layout[i].1 == switch {
+            return layout[i].2;
+        }
+        i += 1;
+    }
+    None
+}
+
+/// LED Frame Processing Task (keyboards without an LED driver)
+/// There are no LEDs to update, pending HID-IO LED requests (pixel control, LED settings and
+/// manufacturing LED tests) are dropped each frame so they aren't left pending.
+pub fn led_frame_process_no_leds_task(hidio_intf: &mut HidioCommandInterface) {
+    let intf = hidio_intf.mut_interface();
+    intf.led_control.soft_reset = false;
+    intf.led_control.hard_reset = false;
+    intf.led_control.next_frame = false;
+    intf.led_settings_changed = false;
+
+    let config = &mut intf.manufacturing_config;
+    if config.led_short_test || config.led_open_test {
+        defmt::warn!("LED test requested, no LED driver");
+        config.led_short_test = false;
+        config.led_open_test = false;
+    }
+}
+
 /// Sub-task of macro_process when handling HID LED events
 pub fn macro_process_led_events_task(
     kbd_led_consumer: &mut Consumer<'static, kiibohd_usb::LedState, KBD_LED_QUEUE_SIZE>,
@@ -484,15 +870,31 @@ pub fn usb_process_task(
 pub fn udp_irq(
     usb_dev: &mut UsbDevice,
     usb_hid: &mut HidInterface,
+    #[cfg_attr(not(feature = "via"), allow(unused_variables))] via: &mut ViaInterface,
     hidio_intf: &mut HidioCommandInterface,
 ) {
     // Poll USB endpoints
-    if usb_dev.poll(&mut usb_hid.interfaces()) {
+    #[cfg(not(feature = "via"))]
+    let polled = usb_dev.poll(&mut usb_hid.interfaces());
+    #[cfg(feature = "via")]
+    let polled = {
+        let mut classes: heapless::Vec<&mut dyn usb_device::class::UsbClass<UdpBus>, 8> =
+            usb_hid.interfaces().into_iter().collect();
+        assert!(classes.push(via.raw_hid()).is_ok());
+        usb_dev.poll(&mut classes)
+    };
+    if polled {
         // Retrive HID Lock LED events
         usb_hid.pull();
 
         // Process HID-IO
         usb_hid.pull_hidio(hidio_intf);
+
+        // Process VIA requests
+        #[cfg(feature = "via")]
+        if via.process(hidio_intf) {
+            bootloader();
+        }
     }
     // Attempt to tx any HID-IO packets
     usb_hid.push_hidio(hidio_intf);
@@ -500,11 +902,13 @@ pub fn udp_irq(
 
 // ----- Misc Setup Functions -----
 
+#[cfg(not(test))]
 #[exception]
 unsafe fn HardFault(_ef: &cortex_m_rt::ExceptionFrame) -> ! {
     panic!("HardFault!");
 }
 
+#[cfg(not(test))]
 defmt::timestamp!("{=u64} us", {
     atsam4_hal::timer::DwtTimer::<{ constants::MCU_FREQ }>::now()
         / ((constants::MCU_FREQ / 1_000_000) as u64)
@@ -512,11 +916,27 @@ defmt::timestamp!("{=u64} us", {
 
 // same panicking *behavior* as `panic-probe` but doesn't print a panic message
 // this prevents the panic message being printed *twice* when `defmt::panic` is invoked
+#[cfg(not(test))]
 #[defmt::panic_handler]
 fn panic() -> ! {
     cortex_m::asm::udf()
 }
 
+/// Resets the MCU into the bootloader
+/// Asserts NRST using the reset controller, the same reset used by `cargo make bootloader`
+/// (xtask sam4-bootloader) to enter the DFU bootloader.
+pub fn bootloader() -> ! {
+    defmt::info!("Resetting into bootloader");
+    unsafe {
+        // RSTC_CR: KEY (0xA5) | EXTRST
+        (*hal::pac::RSTC::ptr()).cr.write(|w| w.bits(0xA500_0008));
+    }
+    // NRST is asserted for a few slow clock cycles
+    loop {
+        cortex_m::asm::nop();
+    }
+}
+
 /// Terminates the application and makes `probe-run` exit with exit-code = 0
 pub fn exit() -> ! {
     loop {
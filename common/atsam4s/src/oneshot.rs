@@ -0,0 +1,256 @@
+// Copyright 2026 Jacob Alexander
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! One-shot (sticky) keys
+//!
+//! One-shot keys are written in the KLL files as `S<switch> : OneShot(S<virtual switch>);` and
+//! compiled into the ONE_SHOT_KEYS table by build.rs. The virtual switch is mapped in KLL like any
+//! other switch, e.g. to a modifier or a layer shift:
+//!
+//! ```text
+//! S0x2A : OneShot(S0xB0);
+//! S0xB0 : U"LShift";
+//! S0x39 : OneShot(S0xB1);
+//! S0xB1 : Layer[1];
+//! ```
+//!
+//! - Tap - The virtual switch stays pressed until the next key is released (or
+//!   ONE_SHOT_TIMEOUT_MS has passed)
+//! - Double-tap - Locked, until tapped again
+//! - Held while pressing other keys - Same as a normal key
+
+use crate::constants::*;
+use kll_core::{trigger::Phro, TriggerEvent};
+
+// ----- Enums -----
+
+/// State of a one-shot key
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum OneShotState {
+    Off,
+    /// Switch is pressed, no other key has been pressed yet
+    Held,
+    /// Switch is pressed and used with other keys (released with the switch)
+    HeldUsed,
+    /// Tapped, applies to the next key
+    Pending,
+    /// Double-tapped
+    Locked,
+}
+
+// ----- Structs -----
+
+/// One-shot key, generated by build.rs
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub struct OneShotKey {
+    /// Switch (KLL trigger index)
+    pub switch: u16,
+    /// Virtual switch (KLL trigger index) pressed while the one-shot is active
+    pub virtual_switch: u16,
+}
+
+/// State of every one-shot key (same order as ONE_SHOT_KEYS), used for indicators
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub struct OneShotStatus {
+    states: [OneShotState; MAX_ONE_SHOT_KEYS],
+}
+
+impl OneShotStatus {
+    pub const fn new() -> Self {
+        Self {
+            states: [OneShotState::Off; MAX_ONE_SHOT_KEYS],
+        }
+    }
+
+    pub fn get(&self, key: usize) -> OneShotState {
+        self.states[key]
+    }
+
+    /// Any one-shot key is pending or locked
+    pub fn active(&self) -> bool {
+        self.states
+            .iter()
+            .any(|state| matches!(state, OneShotState::Pending | OneShotState::Locked))
+    }
+}
+
+impl Default for OneShotStatus {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Current state of a one-shot key
+#[derive(Clone, Copy)]
+struct KeyState {
+    state: OneShotState,
+    /// Cycles since the virtual switch was pressed
+    cycles: u32,
+    /// Time since the key was tapped (Pending)
+    elapsed_us: u32,
+    /// Key pressed while Pending, the one-shot ends once it's released
+    used_by: Option<u16>,
+}
+
+/// One-shot evaluation
+pub struct OneShot {
+    keys: &'static [OneShotKey],
+    states: [KeyState; MAX_ONE_SHOT_KEYS],
+}
+
+impl OneShot {
+    pub fn new(keys: &'static [OneShotKey]) -> Self {
+        assert!(
+            keys.len() <= MAX_ONE_SHOT_KEYS,
+            "MAX_ONE_SHOT_KEYS too small"
+        );
+        Self {
+            keys,
+            states: [KeyState {
+                state: OneShotState::Off,
+                cycles: 0,
+                elapsed_us: 0,
+                used_by: None,
+            }; MAX_ONE_SHOT_KEYS],
+        }
+    }
+
+    pub fn keys(&self) -> &'static [OneShotKey] {
+        self.keys
+    }
+
+    pub fn status(&self) -> OneShotStatus {
+        let mut status = OneShotStatus::new();
+        for (state, key) in status.states.iter_mut().zip(self.states.iter()) {
+            *state = key.state;
+        }
+        status
+    }
+
+    /// Passes a trigger event through the one-shot stage
+    pub fn process(&mut self, event: TriggerEvent, emit: &mut impl FnMut(TriggerEvent)) {
+        let (phro, index) = match event {
+            TriggerEvent::Switch { state, index, .. } => (state, index),
+            _ => {
+                emit(event);
+                return;
+            }
+        };
+
+        let key = match self.keys.iter().position(|key| key.switch == index) {
+            Some(key) => key,
+            None => {
+                emit(event);
+                self.other_key(phro, index, emit);
+                return;
+            }
+        };
+
+        let virtual_switch = self.keys[key].virtual_switch;
+        let state = &mut self.states[key];
+        match (state.state, phro) {
+            (OneShotState::Off, Phro::Press) => {
+                state.state = OneShotState::Held;
+                state.cycles = 0;
+                emit(Self::event(Phro::Press, virtual_switch, 0));
+            }
+            (OneShotState::Held, Phro::Release) => {
+                state.state = OneShotState::Pending;
+                state.elapsed_us = 0;
+                state.used_by = None;
+            }
+            (OneShotState::HeldUsed, Phro::Release) => {
+                state.state = OneShotState::Off;
+                emit(Self::event(Phro::Release, virtual_switch, state.cycles));
+            }
+            (OneShotState::Pending, Phro::Press) => {
+                state.state = OneShotState::Locked;
+            }
+            (OneShotState::Locked, Phro::Press) => {
+                // Unlocked, the release of this tap is ignored (Off)
+                state.state = OneShotState::Off;
+                emit(Self::event(Phro::Release, virtual_switch, state.cycles));
+            }
+            // Hold/Off of the switch are replaced by the virtual switch events
+            _ => {}
+        }
+    }
+
+    /// Any other key, pending one-shots end once the next key is released
+    fn other_key(&mut self, phro: Phro, index: u16, emit: &mut impl FnMut(TriggerEvent)) {
+        for (key, state) in self.keys.iter().zip(self.states.iter_mut()) {
+            match (state.state, phro) {
+                (OneShotState::Held, Phro::Press) => {
+                    state.state = OneShotState::HeldUsed;
+                }
+                (OneShotState::Pending, Phro::Press) if state.used_by.is_none() => {
+                    state.used_by = Some(index);
+                }
+                (OneShotState::Pending, Phro::Release) if state.used_by == Some(index) => {
+                    state.state = OneShotState::Off;
+                    emit(Self::event(Phro::Release, key.virtual_switch, state.cycles));
+                }
+                _ => {}
+            }
+        }
+    }
+
+    /// Advances the one-shot timeout, called once per full scan
+    pub fn tick(&mut self, elapsed_us: u32, emit: &mut impl FnMut(TriggerEvent)) {
+        for (key, state) in self.keys.iter().zip(self.states.iter_mut()) {
+            if state.state == OneShotState::Off {
+                continue;
+            }
+
+            // Unused one-shots time out
+            if state.state == OneShotState::Pending && state.used_by.is_none() {
+                state.elapsed_us = state.elapsed_us.saturating_add(elapsed_us);
+                if state.elapsed_us >= ONE_SHOT_TIMEOUT_MS.saturating_mul(1000) {
+                    state.state = OneShotState::Off;
+                    emit(Self::event(Phro::Release, key.virtual_switch, state.cycles));
+                    continue;
+                }
+            }
+
+            state.cycles = state.cycles.saturating_add(1);
+            emit(Self::event(Phro::Hold, key.virtual_switch, state.cycles));
+        }
+    }
+
+    fn event(state: Phro, virtual_switch: u16, cycles: u32) -> TriggerEvent {
+        TriggerEvent::Switch {
+            state,
+            index: virtual_switch,
+            last_state: cycles,
+        }
+    }
+
+    /// Switch is a one-shot key (events are replaced by the virtual switch)
+    pub fn suppressed(&self, index: u16) -> bool {
+        self.keys.iter().any(|key| key.switch == index)
+    }
+
+    /// Index is the virtual switch of a one-shot key
+    pub fn is_virtual(&self, index: u16) -> bool {
+        self.keys.iter().any(|key| key.virtual_switch == index)
+    }
+
+    /// Hold event of an active one-shot (used for off-state lookups)
+    /// Off events are not generated
+    pub fn virtual_event(&self, virtual_switch: u16) -> Option<TriggerEvent> {
+        self.keys
+            .iter()
+            .zip(self.states.iter())
+            .find_map(|(key, state)| {
+                if key.virtual_switch == virtual_switch && state.state != OneShotState::Off {
+                    Some(Self::event(Phro::Hold, virtual_switch, state.cycles))
+                } else {
+                    None
+                }
+            })
+    }
+}
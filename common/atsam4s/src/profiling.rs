@@ -0,0 +1,229 @@
+// Copyright 2026 Jacob Alexander
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! Task profiling using the DWT cycle counter
+//!
+//! Statistics are kept in the HidioInterface (profile) so they can be read over HID-IO (or the
+//! serial console) using the `profile` command.
+
+use crate::constants::*;
+
+use core::fmt;
+use cortex_m::peripheral::{DCB, DWT};
+
+// ----- Constants -----
+
+/// Upper bound (us) of each scan to USB latency histogram bucket, the last bucket is unbounded
+pub const LATENCY_BUCKETS_US: [u32; 7] = [125, 250, 500, 750, 1000, 2000, 4000];
+
+const CYCLES_PER_US: u32 = MCU_FREQ / 1_000_000;
+
+// ----- Enums -----
+
+/// Profiled tasks
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum Task {
+    /// Keyscanning strobe (tc0_irq) or ADC sample processing (adc_irq)
+    Scan,
+    /// Macro processing (macro_process_task)
+    MacroProcess,
+    /// USB HID processing (usb_process_task)
+    UsbProcess,
+}
+
+impl Task {
+    const COUNT: usize = 3;
+
+    fn name(&self) -> &'static str {
+        match self {
+            Task::Scan => "scan",
+            Task::MacroProcess => "macro_process",
+            Task::UsbProcess => "usb_process",
+        }
+    }
+}
+
+/// Task scheduling overruns (the task was still pending when scheduled again)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum Overrun {
+    MacroProcess,
+    UsbProcess,
+}
+
+impl Overrun {
+    const COUNT: usize = 2;
+
+    fn name(&self) -> &'static str {
+        match self {
+            Overrun::MacroProcess => "macro_process",
+            Overrun::UsbProcess => "usb_process",
+        }
+    }
+}
+
+// ----- Structs -----
+
+/// Cycle count statistics for a single task
+#[derive(Clone, Copy)]
+pub struct TaskStats {
+    pub count: u32,
+    pub min: u32,
+    pub max: u32,
+    pub total: u64,
+}
+
+impl TaskStats {
+    const fn new() -> Self {
+        Self {
+            count: 0,
+            min: u32::MAX,
+            max: 0,
+            total: 0,
+        }
+    }
+
+    fn record(&mut self, cycles: u32) {
+        self.count = self.count.saturating_add(1);
+        self.min = self.min.min(cycles);
+        self.max = self.max.max(cycles);
+        self.total = self.total.saturating_add(cycles as u64);
+    }
+
+    pub fn avg(&self) -> u32 {
+        if self.count == 0 {
+            0
+        } else {
+            (self.total / self.count as u64) as u32
+        }
+    }
+}
+
+/// Profiling statistics
+pub struct Profile {
+    tasks: [TaskStats; Task::COUNT],
+    overruns: [u32; Overrun::COUNT],
+    /// Scan to USB latency histogram (see LATENCY_BUCKETS_US)
+    latency: [u32; LATENCY_BUCKETS_US.len() + 1],
+    /// Cycle count at the end of the last full scan, cleared once USB processing has finished
+    scan_done: Option<u32>,
+}
+
+impl Profile {
+    pub const fn new() -> Self {
+        Self {
+            tasks: [TaskStats::new(); Task::COUNT],
+            overruns: [0; Overrun::COUNT],
+            latency: [0; LATENCY_BUCKETS_US.len() + 1],
+            scan_done: None,
+        }
+    }
+
+    pub fn reset(&mut self) {
+        *self = Self::new();
+    }
+
+    /// Records the run time of a task, start is from start()
+    pub fn record(&mut self, task: Task, start: u32) {
+        self.tasks[task as usize].record(DWT::cycle_count().wrapping_sub(start));
+    }
+
+    pub fn overrun(&mut self, overrun: Overrun) {
+        let count = &mut self.overruns[overrun as usize];
+        *count = count.saturating_add(1);
+    }
+
+    /// Marks the end of a full scan cycle
+    /// Only the first scan is tracked until USB processing has finished
+    pub fn scan_complete(&mut self) {
+        if self.scan_done.is_none() {
+            self.scan_done = Some(DWT::cycle_count());
+        }
+    }
+
+    /// Marks the end of USB processing, recording the latency since the last full scan
+    pub fn usb_complete(&mut self) {
+        if let Some(start) = self.scan_done.take() {
+            let us = DWT::cycle_count().wrapping_sub(start) / CYCLES_PER_US;
+            let bucket = LATENCY_BUCKETS_US
+                .iter()
+                .position(|limit| us < *limit)
+                .unwrap_or(LATENCY_BUCKETS_US.len());
+            self.latency[bucket] = self.latency[bucket].saturating_add(1);
+        }
+    }
+
+    pub fn task(&self, task: Task) -> &TaskStats {
+        &self.tasks[task as usize]
+    }
+
+    /// Writes a human readable report (times in us)
+    pub fn report(&self, out: &mut dyn fmt::Write) -> fmt::Result {
+        writeln!(out, "task            count    min    avg    max (us)")?;
+        for task in [Task::Scan, Task::MacroProcess, Task::UsbProcess] {
+            let stats = self.task(task);
+            if stats.count == 0 {
+                writeln!(out, "{:13} {:7}      -      -      -", task.name(), 0)?;
+                continue;
+            }
+            writeln!(
+                out,
+                "{:13} {:7} {:6} {:6} {:6}",
+                task.name(),
+                stats.count,
+                stats.min / CYCLES_PER_US,
+                stats.avg() / CYCLES_PER_US,
+                stats.max / CYCLES_PER_US,
+            )?;
+        }
+
+        writeln!(out, "scan->usb latency (us)")?;
+        let mut lower = 0;
+        for (i, count) in self.latency.iter().enumerate() {
+            match LATENCY_BUCKETS_US.get(i) {
+                Some(upper) => {
+                    writeln!(out, "  {:5}-{:5}: {}", lower, upper, count)?;
+                    lower = *upper;
+                }
+                None => {
+                    writeln!(out, "  {:5}+     : {}", lower, count)?;
+                }
+            }
+        }
+
+        writeln!(out, "overruns")?;
+        for overrun in [Overrun::MacroProcess, Overrun::UsbProcess] {
+            writeln!(
+                out,
+                "  {:13} {}",
+                overrun.name(),
+                self.overruns[overrun as usize]
+            )?;
+        }
+        Ok(())
+    }
+}
+
+impl Default for Profile {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// ----- Initialization Functions -----
+
+/// Enables the DWT cycle counter (also used for defmt timestamps)
+pub fn init(mut dcb: DCB, mut dwt: DWT) {
+    dcb.enable_trace();
+    dwt.enable_cycle_counter();
+}
+
+// ----- Functions -----
+
+/// Current cycle count, pass to Profile::record() once the task has finished
+pub fn start() -> u32 {
+    DWT::cycle_count()
+}
@@ -0,0 +1,130 @@
+// Copyright 2026 Jacob Alexander
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! Raw HID USB class
+//!
+//! Vendor defined HID interface (usage page 0xFF60, usage 0x61) exchanging fixed size reports
+//! over a pair of interrupt endpoints, as expected by the VIA desktop tools (see via.rs).
+//! Added next to the kiibohd-usb HID interfaces with the `via` feature.
+
+use crate::hal::udp::usb_device::{
+    class_prelude::*,
+    control::{Recipient, Request, RequestType},
+    Result,
+};
+
+// ----- Constants -----
+
+/// Size of the input and output reports
+pub const RAW_HID_REPORT_SIZE: usize = 32;
+
+const USB_CLASS_HID: u8 = 0x03;
+const HID_DESC_TYPE_HID: u8 = 0x21;
+const HID_DESC_TYPE_REPORT: u8 = 0x22;
+const HID_REQ_SET_IDLE: u8 = 0x0A;
+
+/// Polling interval of the endpoints (ms)
+const RAW_HID_POLL_MS: u8 = 1;
+
+#[rustfmt::skip]
+const REPORT_DESCRIPTOR: &[u8] = &[
+    0x06, 0x60, 0xFF, // Usage Page (Vendor Defined 0xFF60)
+    0x09, 0x61,       // Usage (0x61)
+    0xA1, 0x01,       // Collection (Application)
+    0x09, 0x62,       //   Usage (0x62)
+    0x15, 0x00,       //   Logical Minimum (0)
+    0x26, 0xFF, 0x00, //   Logical Maximum (255)
+    0x95, RAW_HID_REPORT_SIZE as u8, // Report Count
+    0x75, 0x08,       //   Report Size (8)
+    0x81, 0x02,       //   Input (Data, Variable, Absolute)
+    0x09, 0x63,       //   Usage (0x63)
+    0x15, 0x00,       //   Logical Minimum (0)
+    0x26, 0xFF, 0x00, //   Logical Maximum (255)
+    0x95, RAW_HID_REPORT_SIZE as u8, // Report Count
+    0x75, 0x08,       //   Report Size (8)
+    0x91, 0x02,       //   Output (Data, Variable, Absolute)
+    0xC0,             // End Collection
+];
+
+// ----- Structs -----
+
+/// Raw HID interface
+pub struct RawHid<'a, B: UsbBus> {
+    interface: InterfaceNumber,
+    ep_in: EndpointIn<'a, B>,
+    ep_out: EndpointOut<'a, B>,
+}
+
+impl<B: UsbBus> RawHid<'_, B> {
+    /// Allocates the interface, must be called before the UsbDevice is built
+    pub fn new(alloc: &UsbBusAllocator<B>) -> RawHid<'_, B> {
+        RawHid {
+            interface: alloc.interface(),
+            ep_in: alloc.interrupt(RAW_HID_REPORT_SIZE as u16, RAW_HID_POLL_MS),
+            ep_out: alloc.interrupt(RAW_HID_REPORT_SIZE as u16, RAW_HID_POLL_MS),
+        }
+    }
+
+    /// Reads an output report
+    /// Returns false if no (complete) report is available
+    pub fn read(&mut self, report: &mut [u8; RAW_HID_REPORT_SIZE]) -> bool {
+        matches!(self.ep_out.read(report), Ok(RAW_HID_REPORT_SIZE))
+    }
+
+    /// Writes an input report
+    /// Returns false if the previous report has not been sent yet
+    pub fn write(&mut self, report: &[u8; RAW_HID_REPORT_SIZE]) -> bool {
+        self.ep_in.write(report).is_ok()
+    }
+
+    fn is_interface_request(&self, index: u16, recipient: Recipient) -> bool {
+        recipient == Recipient::Interface && index == u8::from(self.interface) as u16
+    }
+}
+
+impl<B: UsbBus> UsbClass<B> for RawHid<'_, B> {
+    fn get_configuration_descriptors(&self, writer: &mut DescriptorWriter) -> Result<()> {
+        writer.interface(self.interface, USB_CLASS_HID, 0x00, 0x00)?;
+        let len = (REPORT_DESCRIPTOR.len() as u16).to_le_bytes();
+        writer.write(
+            HID_DESC_TYPE_HID,
+            &[
+                0x11, // HID 1.11
+                0x01,
+                0x00, // Not localized
+                0x01, // One class descriptor (report)
+                HID_DESC_TYPE_REPORT,
+                len[0],
+                len[1],
+            ],
+        )?;
+        writer.endpoint(&self.ep_in)?;
+        writer.endpoint(&self.ep_out)?;
+        Ok(())
+    }
+
+    fn control_in(&mut self, xfer: ControlIn<B>) {
+        let req = *xfer.request();
+        if req.request_type == RequestType::Standard
+            && self.is_interface_request(req.index, req.recipient)
+            && req.request == Request::GET_DESCRIPTOR
+            && (req.value >> 8) as u8 == HID_DESC_TYPE_REPORT
+        {
+            xfer.accept_with_static(REPORT_DESCRIPTOR).ok();
+        }
+    }
+
+    fn control_out(&mut self, xfer: ControlOut<B>) {
+        let req = *xfer.request();
+        if req.request_type == RequestType::Class
+            && self.is_interface_request(req.index, req.recipient)
+            && req.request == HID_REQ_SET_IDLE
+        {
+            xfer.accept().ok();
+        }
+    }
+}
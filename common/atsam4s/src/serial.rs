@@ -0,0 +1,202 @@
+// Copyright 2026 Jacob Alexander
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+use crate::console::{Command, LineBuffer, LineEvent, ParseError};
+use crate::constants::*;
+use crate::terminal::{MatrixSnapshot, Status};
+use crate::*;
+
+use core::fmt;
+use hal::clock::{Enabled, Uart0Clock};
+use heapless::Deque;
+
+// ----- Structs -----
+
+/// UART0 serial console
+/// Received characters are echoed and accumulated into lines, output is buffered and sent from the
+/// UART interrupt.
+pub struct SerialConsole {
+    uart: hal::pac::UART0,
+    line: LineBuffer<CONSOLE_LINE_SIZE>,
+    tx: Deque<u8, CONSOLE_TX_BUF_SIZE>,
+    dropped: bool,
+}
+
+impl SerialConsole {
+    fn push(&mut self, byte: u8) {
+        if self.tx.push_back(byte).is_err() {
+            self.dropped = true;
+        }
+    }
+
+    /// Starts sending buffered output
+    fn flush(&mut self) {
+        if !self.tx.is_empty() {
+            self.uart.ier.write(|w| w.txrdy().set_bit());
+        }
+    }
+
+    fn prompt(&mut self) {
+        self.push(b'>');
+        self.push(b' ');
+        self.flush();
+    }
+}
+
+impl fmt::Write for SerialConsole {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for byte in s.bytes() {
+            if byte == b'\n' {
+                self.push(b'\r');
+            }
+            self.push(byte);
+        }
+        self.flush();
+        Ok(())
+    }
+}
+
+// ----- Initialization Functions -----
+
+/// Initializes UART0 as the serial console (8N1)
+pub fn init(
+    uart: hal::pac::UART0,
+    _uart_clock: Uart0Clock<Enabled>,
+    _uart_rx: Pa9<PfA>,
+    _uart_tx: Pa10<PfA>,
+) -> SerialConsole {
+    defmt::trace!("Serial Console initialization");
+
+    // Reset and disable receiver/transmitter
+    uart.cr.write(|w| {
+        w.rstrx()
+            .set_bit()
+            .rsttx()
+            .set_bit()
+            .rxdis()
+            .set_bit()
+            .txdis()
+            .set_bit()
+    });
+
+    // No parity, normal mode
+    uart.mr.write(|w| w.par().no().chmode().normal());
+
+    // Baud = MCK / (16 * CD)
+    uart.brgr
+        .write(|w| unsafe { w.cd().bits((MCU_FREQ / (16 * CONSOLE_BAUD)) as u16) });
+
+    // Enable receiver/transmitter and the receive interrupt
+    uart.idr.write(|w| unsafe { w.bits(0xFFFF_FFFF) });
+    uart.cr.write(|w| w.rxen().set_bit().txen().set_bit());
+    uart.ier.write(|w| w.rxrdy().set_bit());
+
+    let mut console = SerialConsole {
+        uart,
+        line: LineBuffer::new(),
+        tx: Deque::new(),
+        dropped: false,
+    };
+    fmt::Write::write_str(&mut console, "\nkiibohd serial console (type 'help')\n").ok();
+    console.prompt();
+    console
+}
+
+// ----- Software Interrupt Tasks -----
+
+/// Serial Console Processing Task
+/// Runs a parsed console command
+pub fn console_process_task<const MSIZE: usize, MATRIX>(
+    console: &mut SerialConsole,
+    command: Result<Command, ParseError>,
+    hidio_intf: &mut HidioCommandInterface,
+    layer_state: &LayerState,
+    matrix: &MATRIX,
+) where
+    MATRIX: kiibohd_keyscanning::KeyScanning<MAX_PER_KEY_EVENTS> + MatrixSnapshot,
+{
+    use core::fmt::Write;
+
+    if crate::terminal::run_command::<MSIZE, MATRIX>(
+        console,
+        command,
+        hidio_intf,
+        layer_state,
+        matrix,
+    ) == Status::Bootloader
+    {
+        // Give the UART a chance to send the message
+        while !console.tx.is_empty() {
+            if console.uart.sr.read().txrdy().bit_is_set() {
+                let byte = console.tx.pop_front().unwrap();
+                console.uart.thr.write(|w| unsafe { w.txchr().bits(byte) });
+            }
+        }
+        crate::bootloader();
+    }
+
+    if console.dropped {
+        console.dropped = false;
+        writeln!(console, "\n(output truncated)").ok();
+    }
+    console.prompt();
+}
+
+// ----- IRQ Functions -----
+
+/// UART0 Interrupt
+/// Returns a parsed command once a full line has been received
+pub fn uart_irq(console: &mut SerialConsole) -> Option<Result<Command, ParseError>> {
+    let sr = console.uart.sr.read();
+    let mut command = None;
+
+    // Clear receive errors
+    if sr.ovre().bit_is_set() || sr.frame().bit_is_set() || sr.pare().bit_is_set() {
+        console.uart.cr.write(|w| w.rststa().set_bit());
+    }
+
+    // Incoming character
+    if sr.rxrdy().bit_is_set() {
+        let byte = console.uart.rhr.read().rxchr().bits();
+        match console.line.push(byte) {
+            LineEvent::None => {}
+            LineEvent::Char(byte) => {
+                console.push(byte);
+            }
+            LineEvent::Backspace => {
+                for byte in b"\x08 \x08" {
+                    console.push(*byte);
+                }
+            }
+            LineEvent::Line => {
+                console.push(b'\r');
+                console.push(b'\n');
+                command = Some(crate::console::parse(console.line.take_line()));
+            }
+            LineEvent::Overflow => {
+                console.push(b'\r');
+                console.push(b'\n');
+                command = Some(Err(ParseError::LineTooLong));
+            }
+        }
+        console.flush();
+    }
+
+    // Outgoing character
+    if sr.txrdy().bit_is_set() && console.uart.imr.read().txrdy().bit_is_set() {
+        match console.tx.pop_front() {
+            Some(byte) => {
+                console.uart.thr.write(|w| unsafe { w.txchr().bits(byte) });
+            }
+            None => {
+                console.uart.idr.write(|w| w.txrdy().set_bit());
+            }
+        }
+    }
+
+    command
+}
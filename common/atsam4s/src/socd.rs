@@ -0,0 +1,206 @@
+// Copyright 2026 Jacob Alexander
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! SOCD (Simultaneous Opposing Cardinal Directions) cleaning
+//!
+//! Resolves configured pairs of opposing keys (e.g. A/D) before the trigger events reach
+//! kll-core (LayerState). Used by both keyscanning and hall effect matrices.
+//! Pairs are set per keyboard (SOCD_PAIRS in the board constants), the mode of each pair can be
+//! changed at runtime (`set socd`).
+
+use crate::constants::*;
+use kll_core::{trigger::Phro, TriggerEvent};
+
+// ----- Enums -----
+
+/// Resolution of a pair while both keys are pressed
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum SocdMode {
+    /// No cleaning, both keys are pressed
+    Off,
+    /// The last pressed key wins (snap-tap), the other key is pressed again once released
+    LastInput,
+    /// The first pressed key wins
+    FirstInput,
+    /// Neither key is pressed
+    Neutral,
+}
+
+// ----- Structs -----
+
+/// Pair of opposing switches (KLL trigger indices)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub struct SocdPair {
+    pub a: u16,
+    pub b: u16,
+    /// Default mode
+    pub mode: SocdMode,
+}
+
+impl SocdPair {
+    pub const fn new(a: u16, b: u16, mode: SocdMode) -> Self {
+        Self { a, b, mode }
+    }
+}
+
+/// Current state of a pair
+#[derive(Clone, Copy)]
+struct PairState {
+    mode: SocdMode,
+    /// Physical key state (a, b)
+    down: [bool; 2],
+    /// Key state sent to kll-core (a, b)
+    out: [bool; 2],
+    /// Last pressed key (0 - a, 1 - b)
+    last: usize,
+}
+
+impl PairState {
+    /// Key states that should be sent to kll-core
+    fn resolve(&self) -> [bool; 2] {
+        if !(self.down[0] && self.down[1]) {
+            return self.down;
+        }
+        match self.mode {
+            SocdMode::Off => [true, true],
+            SocdMode::LastInput => [self.last == 0, self.last == 1],
+            SocdMode::FirstInput => [self.last == 1, self.last == 0],
+            SocdMode::Neutral => [false, false],
+        }
+    }
+}
+
+/// SOCD cleaning stage
+pub struct Socd {
+    pairs: &'static [SocdPair],
+    states: [PairState; MAX_SOCD_PAIRS],
+}
+
+impl Socd {
+    pub fn new(pairs: &'static [SocdPair]) -> Self {
+        assert!(pairs.len() <= MAX_SOCD_PAIRS, "MAX_SOCD_PAIRS too small");
+        let mut states = [PairState {
+            mode: SocdMode::Off,
+            down: [false; 2],
+            out: [false; 2],
+            last: 0,
+        }; MAX_SOCD_PAIRS];
+        for (pair, state) in pairs.iter().zip(states.iter_mut()) {
+            state.mode = pair.mode;
+        }
+        Self { pairs, states }
+    }
+
+    pub fn pairs(&self) -> &'static [SocdPair] {
+        self.pairs
+    }
+
+    pub fn mode(&self, pair: usize) -> Option<SocdMode> {
+        self.pairs.get(pair).map(|_| self.states[pair].mode)
+    }
+
+    /// Changes the mode of a pair, emitting any resulting key changes
+    /// Returns false if the pair doesn't exist
+    pub fn set_mode(
+        &mut self,
+        pair: usize,
+        mode: SocdMode,
+        mut emit: impl FnMut(TriggerEvent),
+    ) -> bool {
+        if pair >= self.pairs.len() {
+            return false;
+        }
+        self.states[pair].mode = mode;
+        self.update(pair, &mut emit);
+        true
+    }
+
+    /// Passes a trigger event through the SOCD stage
+    /// Events of keys that aren't part of a pair are passed through unchanged.
+    pub fn process(&mut self, event: TriggerEvent, mut emit: impl FnMut(TriggerEvent)) {
+        let (state, index) = match event {
+            TriggerEvent::Switch { state, index, .. } => (state, index),
+            _ => {
+                emit(event);
+                return;
+            }
+        };
+        let (pair, side) = match self.find(index) {
+            Some(found) => found,
+            None => {
+                emit(event);
+                return;
+            }
+        };
+
+        let pair_state = &mut self.states[pair];
+        match state {
+            Phro::Press => {
+                pair_state.down[side] = true;
+                pair_state.last = side;
+            }
+            Phro::Release => {
+                pair_state.down[side] = false;
+            }
+            _ => {}
+        }
+        let out = pair_state.out[side];
+        self.update(pair, &mut emit);
+
+        // Press/Release are generated by update(), other events only pass while unchanged
+        let unchanged = out == self.states[pair].out[side];
+        if !matches!(state, Phro::Press | Phro::Release) && unchanged {
+            if out || state == Phro::Off {
+                emit(event);
+            }
+        }
+    }
+
+    /// Key is pressed, but is being held back by the SOCD stage
+    /// Used to filter off-state lookups.
+    pub fn suppressed(&self, index: u16) -> bool {
+        match self.find(index) {
+            Some((pair, side)) => {
+                let state = &self.states[pair];
+                state.down[side] && !state.out[side]
+            }
+            None => false,
+        }
+    }
+
+    fn find(&self, index: u16) -> Option<(usize, usize)> {
+        self.pairs.iter().enumerate().find_map(|(i, pair)| {
+            if pair.a == index {
+                Some((i, 0))
+            } else if pair.b == index {
+                Some((i, 1))
+            } else {
+                None
+            }
+        })
+    }
+
+    /// Sends the changes of a pair to kll-core (releases first)
+    fn update(&mut self, pair: usize, emit: &mut impl FnMut(TriggerEvent)) {
+        let state = &mut self.states[pair];
+        let indices = [self.pairs[pair].a, self.pairs[pair].b];
+        let out = state.resolve();
+
+        for (phro, active) in [(Phro::Release, false), (Phro::Press, true)] {
+            for side in 0..2 {
+                if state.out[side] != out[side] && out[side] == active {
+                    emit(TriggerEvent::Switch {
+                        state: phro,
+                        index: indices[side],
+                        last_state: 0,
+                    });
+                }
+            }
+        }
+        state.out = out;
+    }
+}
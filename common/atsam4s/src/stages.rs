@@ -0,0 +1,158 @@
+// Copyright 2026 Jacob Alexander
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! Trigger event stages
+//!
+//! Trigger events from the scanning matrix (keyscanning or hall effect) pass through each stage
+//! before they are sent to kll-core (LayerState) and HID-IO:
+//! combo -> tap-hold -> one-shot -> dynamic macro keys -> leader keys -> SOCD -> keymap overlay
+
+use crate::combo::{Combo, Combos};
+use crate::dynamic_macro::{MacroKey, MacroKeys};
+use crate::keymap::Keymap;
+use crate::leader::{LeaderKey, LeaderKeys};
+use crate::oneshot::{OneShot, OneShotKey};
+use crate::socd::{Socd, SocdMode, SocdPair};
+use crate::taphold::{TapHold, TapHoldKey};
+use kll_core::TriggerEvent;
+
+// ----- Structs -----
+
+/// Pending stage settings (console or HID-IO), applied by the scanning interrupt
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub struct StageSettings {
+    /// SOCD pair (index of SOCD_PAIRS) and mode
+    pub socd: Option<(u8, SocdMode)>,
+    /// Tapping term in ms
+    pub tapping_term: Option<u32>,
+    pub permissive_hold: Option<bool>,
+    /// Combo term in ms
+    pub combo_term: Option<u32>,
+}
+
+/// Trigger event stages
+pub struct TriggerStages {
+    pub combos: Combos,
+    pub taphold: TapHold,
+    pub oneshot: OneShot,
+    pub macros: MacroKeys,
+    pub leader: LeaderKeys,
+    pub socd: Socd,
+    pub keymap: Keymap,
+}
+
+impl TriggerStages {
+    pub fn new(
+        combos: &'static [Combo],
+        tap_hold_keys: &'static [TapHoldKey],
+        one_shot_keys: &'static [OneShotKey],
+        macro_keys: &'static [MacroKey],
+        leader_keys: &'static [LeaderKey],
+        socd_pairs: &'static [SocdPair],
+    ) -> Self {
+        Self {
+            combos: Combos::new(combos),
+            taphold: TapHold::new(tap_hold_keys),
+            oneshot: OneShot::new(one_shot_keys),
+            macros: MacroKeys::new(macro_keys),
+            leader: LeaderKeys::new(leader_keys),
+            socd: Socd::new(socd_pairs),
+            keymap: Keymap::new(),
+        }
+    }
+
+    /// Passes a trigger event through each stage
+    pub fn process(&mut self, event: TriggerEvent, mut emit: impl FnMut(TriggerEvent)) {
+        let (taphold, oneshot, macros) = (&mut self.taphold, &mut self.oneshot, &mut self.macros);
+        let (leader, socd, keymap) = (&mut self.leader, &mut self.socd, &mut self.keymap);
+        let mut output = |event: TriggerEvent| {
+            leader.process(event, &mut |event| {
+                socd.process(event, &mut |event| keymap.process(event, &mut emit))
+            })
+        };
+        self.combos.process(event, &mut |event| {
+            taphold.process(event, &mut |event| {
+                oneshot.process(event, &mut |event| macros.process(event, &mut output))
+            })
+        });
+    }
+
+    /// Advances the stage timers, called once per full scan
+    pub fn tick(&mut self, elapsed_us: u32, mut emit: impl FnMut(TriggerEvent)) {
+        let (taphold, oneshot, macros) = (&mut self.taphold, &mut self.oneshot, &mut self.macros);
+        let (leader, socd, keymap) = (&mut self.leader, &mut self.socd, &mut self.keymap);
+        let mut output = |event: TriggerEvent| {
+            leader.process(event, &mut |event| {
+                socd.process(event, &mut |event| keymap.process(event, &mut emit))
+            })
+        };
+        self.combos.tick(elapsed_us, &mut |event| {
+            taphold.process(event, &mut |event| {
+                oneshot.process(event, &mut |event| macros.process(event, &mut output))
+            })
+        });
+        self.taphold.tick(elapsed_us, &mut |event| {
+            oneshot.process(event, &mut |event| macros.process(event, &mut output))
+        });
+        self.oneshot
+            .tick(elapsed_us, &mut |event| macros.process(event, &mut output));
+    }
+
+    /// Applies pending settings, emitting any resulting key changes
+    pub fn apply(&mut self, settings: StageSettings, mut emit: impl FnMut(TriggerEvent)) {
+        if let Some((pair, mode)) = settings.socd {
+            defmt::info!("SOCD pair {}: {}", pair, mode);
+            let keymap = &mut self.keymap;
+            if !self.socd.set_mode(pair as usize, mode, &mut |event| {
+                keymap.process(event, &mut emit)
+            }) {
+                defmt::warn!("Invalid SOCD pair: {}", pair);
+            }
+        }
+        if let Some(ms) = settings.tapping_term {
+            defmt::info!("Tapping term: {} ms", ms);
+            self.taphold.set_tapping_term(ms);
+        }
+        if let Some(enabled) = settings.permissive_hold {
+            defmt::info!("Permissive hold: {}", enabled);
+            self.taphold.set_permissive_hold(enabled);
+        }
+        if let Some(ms) = settings.combo_term {
+            defmt::info!("Combo term: {} ms", ms);
+            self.combos.set_term(ms);
+        }
+    }
+
+    /// Switch is pressed, but its events are being held back (or replaced) by a stage
+    /// Used to filter off-state lookups.
+    pub fn suppressed(&self, index: u16) -> bool {
+        self.combos.suppressed(index)
+            || self.taphold.suppressed(index)
+            || self.oneshot.suppressed(index)
+            || self.macros.suppressed(index)
+            || self.leader.suppressed(index)
+            || self.keymap.suppressed(index)
+            || self.socd.suppressed(index)
+    }
+
+    /// Index is a virtual switch generated by a stage
+    pub fn is_virtual(&self, index: u16) -> bool {
+        self.combos.is_virtual(index)
+            || self.taphold.is_virtual(index)
+            || self.oneshot.is_virtual(index)
+            || self.keymap.is_virtual(index)
+    }
+
+    /// Hold event of a pressed virtual switch (used for off-state lookups)
+    pub fn virtual_event(&self, index: u16) -> Option<TriggerEvent> {
+        self.combos
+            .virtual_event(index)
+            .or_else(|| self.taphold.virtual_event(index))
+            .or_else(|| self.oneshot.virtual_event(index))
+            .or_else(|| self.keymap.virtual_event(index))
+    }
+}
@@ -0,0 +1,159 @@
+// Copyright 2026 Jacob Alexander
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! Settings storage using the flash user signature (512 bytes)
+//!
+//! Layout (u32 words):
+//! - 0: Firmware revision (see check_user_signature)
+//! - DKS_OFFSET: DKS config (DKS_WORDS, hall effect keyboards only)
+//! - MACRO_OFFSET: Dynamic macros (MACRO_WORDS)
+//! - KEYMAP_OFFSET: Keymap overlay (KEYMAP_WORDS)
+//! - LED_SETTINGS_OFFSET: LED brightness and on/off (1 word)
+//!
+//! Writing the user signature erases it first, so the whole signature is always rewritten.
+//! Saves are requested through the HidioInterface and written from the (lowest priority) RTT task.
+
+#[cfg(feature = "hall-effect")]
+use crate::dks::{DksConfig, DKS_WORDS};
+use crate::dynamic_macro::{MacroStore, MACRO_WORDS};
+use crate::hidio::LedSettings;
+use crate::keymap::{KeymapConfig, KEYMAP_WORDS};
+use crate::*;
+use hal::efc::Efc;
+
+// ----- Constants -----
+
+pub const USER_SIGNATURE_WORDS: usize = 512 / core::mem::size_of::<u32>();
+
+pub const DKS_OFFSET: usize = 1;
+#[cfg(feature = "hall-effect")]
+const _: () = assert!(DKS_OFFSET + DKS_WORDS <= MACRO_OFFSET);
+
+/// Reserved for DKS on all keyboards so the layout doesn't depend on the features
+pub const MACRO_OFFSET: usize = DKS_OFFSET + 1 + MAX_DKS_KEYS * 4;
+
+pub const KEYMAP_OFFSET: usize = MACRO_OFFSET + MACRO_WORDS;
+
+pub const LED_SETTINGS_OFFSET: usize = KEYMAP_OFFSET + KEYMAP_WORDS;
+const _: () = assert!(LED_SETTINGS_OFFSET < USER_SIGNATURE_WORDS);
+
+// ----- Structs -----
+
+/// Settings waiting to be written to flash
+#[derive(Default)]
+pub struct PendingSave {
+    #[cfg(feature = "hall-effect")]
+    pub dks: Option<DksConfig>,
+    pub dynamic_macros: Option<MacroStore>,
+    pub keymap: Option<KeymapConfig>,
+    pub led_settings: Option<LedSettings>,
+}
+
+// ----- Functions -----
+
+fn read(efc: &mut Efc) -> [u32; USER_SIGNATURE_WORDS] {
+    let mut sig = [0; USER_SIGNATURE_WORDS];
+    efc.read_user_signature(&mut sig, USER_SIGNATURE_WORDS as _)
+        .unwrap();
+    sig
+}
+
+// ----- Initialization Functions -----
+
+/// Loads the stored settings into the HidioInterface
+/// The settings are applied by the scanning interrupts (same as HID-IO/console changes).
+pub fn load(efc: &mut Efc, hidio_intf: &mut HidioCommandInterface) {
+    let sig = read(efc);
+    let intf = hidio_intf.mut_interface();
+
+    #[cfg(feature = "hall-effect")]
+    if let Some(config) = DksConfig::from_words(&sig[DKS_OFFSET..DKS_OFFSET + DKS_WORDS]) {
+        defmt::info!("Loaded DKS config");
+        intf.dks = config;
+        intf.dks_changed = true;
+    }
+
+    if let Some(store) = MacroStore::from_words(&sig[MACRO_OFFSET..MACRO_OFFSET + MACRO_WORDS]) {
+        defmt::info!("Loaded dynamic macros");
+        intf.dynamic_macros = store;
+    }
+
+    if let Some(config) =
+        KeymapConfig::from_words(&sig[KEYMAP_OFFSET..KEYMAP_OFFSET + KEYMAP_WORDS])
+    {
+        defmt::info!("Loaded keymap overlay");
+        intf.keymap = config;
+        intf.keymap_changed = true;
+    }
+
+    if let Some(settings) = LedSettings::from_word(sig[LED_SETTINGS_OFFSET]) {
+        defmt::info!("Loaded LED settings");
+        intf.led_settings = settings;
+        intf.led_settings_changed = true;
+    }
+}
+
+/// Takes the settings waiting to be saved
+pub fn take_pending(hidio_intf: &mut HidioCommandInterface) -> Option<PendingSave> {
+    let intf = hidio_intf.mut_interface();
+    let mut pending = PendingSave::default();
+    let mut found = false;
+
+    #[cfg(feature = "hall-effect")]
+    if core::mem::take(&mut intf.dks_save) {
+        pending.dks = Some(intf.dks);
+        found = true;
+    }
+
+    if core::mem::take(&mut intf.dynamic_macros_save) {
+        pending.dynamic_macros = Some(intf.dynamic_macros.clone());
+        found = true;
+    }
+
+    // Delayed while the keymap is being edited
+    if intf.keymap_save && intf.keymap_save_delay > 0 {
+        intf.keymap_save_delay -= 1;
+    } else if core::mem::take(&mut intf.keymap_save) {
+        pending.keymap = Some(intf.keymap);
+        found = true;
+    }
+
+    if core::mem::take(&mut intf.led_settings_save) {
+        pending.led_settings = Some(intf.led_settings);
+        found = true;
+    }
+
+    found.then_some(pending)
+}
+
+/// Writes the settings to the user signature, settings that aren't pending keep their stored
+/// value
+/// Flash writes stall the CPU, only call from a low priority task (e.g. RTT) without holding
+/// any locks.
+pub fn save(efc: &mut Efc, pending: &PendingSave) {
+    let mut sig = read(efc);
+
+    #[cfg(feature = "hall-effect")]
+    if let Some(dks) = &pending.dks {
+        dks.to_words(&mut sig[DKS_OFFSET..DKS_OFFSET + DKS_WORDS]);
+    }
+    if let Some(store) = &pending.dynamic_macros {
+        store.to_words(&mut sig[MACRO_OFFSET..MACRO_OFFSET + MACRO_WORDS]);
+    }
+    if let Some(keymap) = &pending.keymap {
+        keymap.to_words(&mut sig[KEYMAP_OFFSET..KEYMAP_OFFSET + KEYMAP_WORDS]);
+    }
+    if let Some(settings) = pending.led_settings {
+        sig[LED_SETTINGS_OFFSET] = settings.to_word();
+    }
+
+    if efc.write_user_signature(&sig).is_ok() {
+        defmt::info!("Saved settings");
+    } else {
+        defmt::error!("Failed to save settings");
+    }
+}
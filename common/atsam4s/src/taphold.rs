@@ -0,0 +1,482 @@
+// Copyright 2026 Jacob Alexander
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! Tap-hold (dual-role) keys
+//!
+//! Tap-hold keys are written in the KLL files as `S<switch> : TapHold(S<tap>, S<hold>[, <ms>]);`
+//! and compiled into the TAP_HOLD_KEYS table by build.rs. Keys without a tapping term use the
+//! runtime tapping term (TAPPING_TERM_MS by default).
+//! Released within the tapping term, the tap virtual switch is tapped. Otherwise the hold virtual
+//! switch is pressed until the key is released, e.g. Escape on tap and Control on hold:
+//!
+//! ```text
+//! S0x29 : TapHold(S0x90, S0x91, 180);
+//! S0x90 : U"Esc";
+//! S0x91 : U"LCtrl";
+//! ```
+//!
+//! Presses of other keys are buffered while a tap-hold key is undecided and replayed once it has
+//! been resolved. With permissive hold, another key pressed and released within the tapping term
+//! resolves the tap-hold key as hold.
+
+use crate::constants::*;
+use heapless::{Deque, Vec};
+use kll_core::{trigger::Phro, TriggerEvent};
+
+// ----- Structs -----
+
+/// Tap-hold key, generated by build.rs
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub struct TapHoldKey {
+    /// Switch (KLL trigger index)
+    pub switch: u16,
+    /// Virtual switch (KLL trigger index) tapped on tap
+    pub tap: u16,
+    /// Virtual switch (KLL trigger index) pressed on hold
+    pub hold: u16,
+    /// Tapping term in ms, None for the runtime tapping term
+    pub tapping_term_ms: Option<u32>,
+}
+
+/// Tap-hold evaluation
+pub struct TapHold {
+    keys: &'static [TapHoldKey],
+    tapping_term_ms: u32,
+    permissive_hold: bool,
+    /// Undecided key (index of keys) and time since it was pressed
+    pending: Option<(usize, u32)>,
+    /// Cycles since each key was resolved as hold
+    held: [Option<u32>; MAX_TAP_HOLD_KEYS],
+    /// Pressed tap virtual switches, released on the second tick (so kll-core sees the press)
+    taps: Vec<(u16, bool), MAX_TAP_HOLD_KEYS>,
+    /// Events of other keys while a key is undecided
+    buffer: Deque<TriggerEvent, TAP_HOLD_BUFFER_SIZE>,
+}
+
+impl TapHold {
+    pub fn new(keys: &'static [TapHoldKey]) -> Self {
+        assert!(
+            keys.len() <= MAX_TAP_HOLD_KEYS,
+            "MAX_TAP_HOLD_KEYS too small"
+        );
+        Self {
+            keys,
+            tapping_term_ms: TAPPING_TERM_MS,
+            permissive_hold: PERMISSIVE_HOLD,
+            pending: None,
+            held: [None; MAX_TAP_HOLD_KEYS],
+            taps: Vec::new(),
+            buffer: Deque::new(),
+        }
+    }
+
+    pub fn keys(&self) -> &'static [TapHoldKey] {
+        self.keys
+    }
+
+    /// Tapping term of keys without their own tapping term
+    pub fn set_tapping_term(&mut self, ms: u32) {
+        self.tapping_term_ms = ms;
+    }
+
+    pub fn set_permissive_hold(&mut self, enabled: bool) {
+        self.permissive_hold = enabled;
+    }
+
+    /// Passes a trigger event through the tap-hold stage
+    /// Events of tap-hold switches are replaced by their virtual switches.
+    pub fn process(&mut self, event: TriggerEvent, emit: &mut impl FnMut(TriggerEvent)) {
+        let (state, index) = match event {
+            TriggerEvent::Switch { state, index, .. } => (state, index),
+            _ => {
+                emit(event);
+                return;
+            }
+        };
+        let key = match self.keys.iter().position(|key| key.switch == index) {
+            Some(key) => key,
+            None => {
+                if self.pending.is_some() {
+                    self.buffer(event, state, index, emit);
+                } else {
+                    emit(event);
+                }
+                return;
+            }
+        };
+
+        match state {
+            Phro::Press => {
+                if self.pending.is_some() {
+                    self.buffer(event, state, index, emit);
+                } else {
+                    self.pending = Some((key, 0));
+                }
+            }
+            Phro::Release => match self.pending {
+                Some((pending, _)) if pending == key => {
+                    self.resolve(false, emit);
+                }
+                Some(_) if self.buffered(index) => {
+                    self.buffer(event, state, index, emit);
+                }
+                _ => {
+                    if self.held[key].take().is_some() {
+                        emit(Self::event(Phro::Release, self.keys[key].hold, 0));
+                    }
+                }
+            },
+            // Hold/Off of the tap-hold switch are replaced by the virtual switch events
+            _ => {}
+        }
+    }
+
+    /// Advances the tapping term timer, called once per full scan
+    pub fn tick(&mut self, elapsed_us: u32, emit: &mut impl FnMut(TriggerEvent)) {
+        let mut i = self.taps.len();
+        while i > 0 {
+            i -= 1;
+            let (tap, ticked) = &mut self.taps[i];
+            if *ticked {
+                emit(Self::event(Phro::Release, *tap, 1));
+                self.taps.swap_remove(i);
+            } else {
+                *ticked = true;
+            }
+        }
+
+        for (key, held) in self.keys.iter().zip(self.held.iter_mut()) {
+            if let Some(cycles) = held {
+                *cycles = cycles.saturating_add(1);
+                emit(Self::event(Phro::Hold, key.hold, *cycles));
+            }
+        }
+
+        if let Some((key, elapsed)) = self.pending {
+            let elapsed = elapsed.saturating_add(elapsed_us);
+            self.pending = Some((key, elapsed));
+            if elapsed >= self.tapping_term_ms(key).saturating_mul(1000) {
+                self.resolve(true, emit);
+            }
+        }
+    }
+
+    fn tapping_term_ms(&self, key: usize) -> u32 {
+        self.keys[key]
+            .tapping_term_ms
+            .unwrap_or(self.tapping_term_ms)
+    }
+
+    /// Buffers an event of another key while a key is undecided
+    fn buffer(
+        &mut self,
+        event: TriggerEvent,
+        state: Phro,
+        index: u16,
+        emit: &mut impl FnMut(TriggerEvent),
+    ) {
+        match state {
+            Phro::Press => {
+                if self.buffer.is_full() {
+                    self.resolve(true, emit);
+                    self.process(event, emit);
+                    return;
+                }
+                self.buffer.push_back(event).ok();
+            }
+            Phro::Release if self.buffered(index) => {
+                if self.buffer.is_full() {
+                    self.resolve(true, emit);
+                    self.process(event, emit);
+                    return;
+                }
+                self.buffer.push_back(event).ok();
+                // Pressed and released within the tapping term
+                if self.permissive_hold {
+                    self.resolve(true, emit);
+                }
+            }
+            // Hold events of buffered presses are dropped (regenerated by the scan once replayed)
+            _ if self.buffered(index) => {}
+            // Keys pressed before the tap-hold key
+            _ => {
+                emit(event);
+            }
+        }
+    }
+
+    /// Press of the switch is waiting in the buffer
+    fn buffered(&self, index: u16) -> bool {
+        self.buffer.iter().any(|event| {
+            matches!(event, TriggerEvent::Switch { state: Phro::Press, index: i, .. } if *i == index)
+        })
+    }
+
+    /// Resolves the undecided key and replays the buffered events
+    fn resolve(&mut self, hold: bool, emit: &mut impl FnMut(TriggerEvent)) {
+        let key = match self.pending.take() {
+            Some((key, _)) => key,
+            None => {
+                return;
+            }
+        };
+        let tap_hold = self.keys[key];
+        if hold {
+            self.held[key] = Some(0);
+            emit(Self::event(Phro::Press, tap_hold.hold, 0));
+        } else {
+            emit(Self::event(Phro::Press, tap_hold.tap, 0));
+            if self.taps.push((tap_hold.tap, false)).is_err() {
+                emit(Self::event(Phro::Release, tap_hold.tap, 0));
+            }
+        }
+
+        // Replayed events may start another undecided key
+        let mut buffer = core::mem::replace(&mut self.buffer, Deque::new());
+        while let Some(event) = buffer.pop_front() {
+            self.process(event, emit);
+        }
+    }
+
+    fn event(state: Phro, virtual_switch: u16, cycles: u32) -> TriggerEvent {
+        TriggerEvent::Switch {
+            state,
+            index: virtual_switch,
+            last_state: cycles,
+        }
+    }
+
+    /// Switch events are handled by the tap-hold stage (tap-hold switch or buffered press)
+    pub fn suppressed(&self, index: u16) -> bool {
+        self.keys.iter().any(|key| key.switch == index) || self.buffered(index)
+    }
+
+    /// Index is the tap or hold virtual switch of a tap-hold key
+    pub fn is_virtual(&self, index: u16) -> bool {
+        self.keys
+            .iter()
+            .any(|key| key.tap == index || key.hold == index)
+    }
+
+    /// Hold event of a pressed virtual switch (used for off-state lookups)
+    /// Off events are not generated
+    pub fn virtual_event(&self, virtual_switch: u16) -> Option<TriggerEvent> {
+        let held = self
+            .keys
+            .iter()
+            .zip(self.held.iter())
+            .find_map(|(key, held)| match held {
+                Some(cycles) if key.hold == virtual_switch => Some(*cycles),
+                _ => None,
+            });
+        let tapped = self
+            .taps
+            .iter()
+            .any(|(tap, _)| *tap == virtual_switch)
+            .then_some(0);
+        held.or(tapped)
+            .map(|cycles| Self::event(Phro::Hold, virtual_switch, cycles))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    extern crate std;
+    use std::vec::Vec;
+
+    /// Switch 1 with its own tapping term, switch 2 with the runtime tapping term
+    const KEYS: &[TapHoldKey] = &[
+        TapHoldKey {
+            switch: 1,
+            tap: 100,
+            hold: 101,
+            tapping_term_ms: Some(50),
+        },
+        TapHoldKey {
+            switch: 2,
+            tap: 102,
+            hold: 103,
+            tapping_term_ms: None,
+        },
+    ];
+
+    fn switch(state: Phro, index: u16) -> TriggerEvent {
+        TriggerEvent::Switch {
+            state,
+            index,
+            last_state: 0,
+        }
+    }
+
+    /// Switch events of a timeline step (state, index)
+    fn events(out: Vec<TriggerEvent>) -> Vec<(Phro, u16)> {
+        out.into_iter()
+            .filter_map(|event| match event {
+                TriggerEvent::Switch { state, index, .. } => Some((state, index)),
+                _ => None,
+            })
+            .collect()
+    }
+
+    fn process(taphold: &mut TapHold, state: Phro, index: u16) -> Vec<(Phro, u16)> {
+        let mut out = Vec::new();
+        taphold.process(switch(state, index), &mut |event| out.push(event));
+        events(out)
+    }
+
+    fn tick(taphold: &mut TapHold, elapsed_ms: u32) -> Vec<(Phro, u16)> {
+        let mut out = Vec::new();
+        taphold.tick(elapsed_ms * 1000, &mut |event| out.push(event));
+        events(out)
+    }
+
+    fn taphold(permissive_hold: bool) -> TapHold {
+        let mut taphold = TapHold::new(KEYS);
+        taphold.set_tapping_term(200);
+        taphold.set_permissive_hold(permissive_hold);
+        taphold
+    }
+
+    #[test]
+    fn tap_released_on_second_tick() {
+        let mut taphold = taphold(false);
+        assert_eq!(process(&mut taphold, Phro::Press, 1), []);
+        assert_eq!(process(&mut taphold, Phro::Hold, 1), []);
+        assert_eq!(tick(&mut taphold, 10), []);
+        assert_eq!(
+            process(&mut taphold, Phro::Release, 1),
+            [(Phro::Press, 100)]
+        );
+        // kll-core sees the press for a full scan before the release
+        assert_eq!(taphold.virtual_event(100), Some(switch(Phro::Hold, 100)));
+        assert_eq!(tick(&mut taphold, 10), []);
+        assert_eq!(tick(&mut taphold, 10), [(Phro::Release, 100)]);
+        assert_eq!(taphold.virtual_event(100), None);
+        assert_eq!(tick(&mut taphold, 10), []);
+    }
+
+    #[test]
+    fn hold_after_tapping_term() {
+        let mut taphold = taphold(false);
+        assert_eq!(process(&mut taphold, Phro::Press, 1), []);
+        assert_eq!(tick(&mut taphold, 49), []);
+        assert_eq!(tick(&mut taphold, 1), [(Phro::Press, 101)]);
+        assert_eq!(tick(&mut taphold, 1), [(Phro::Hold, 101)]);
+        assert!(taphold.virtual_event(101).is_some());
+        assert_eq!(process(&mut taphold, Phro::Hold, 1), []);
+        assert_eq!(
+            process(&mut taphold, Phro::Release, 1),
+            [(Phro::Release, 101)]
+        );
+        assert_eq!(tick(&mut taphold, 1), []);
+        assert_eq!(taphold.virtual_event(101), None);
+    }
+
+    #[test]
+    fn runtime_tapping_term() {
+        let mut taphold = taphold(false);
+        assert_eq!(process(&mut taphold, Phro::Press, 2), []);
+        assert_eq!(tick(&mut taphold, 199), []);
+        assert_eq!(tick(&mut taphold, 1), [(Phro::Press, 103)]);
+        assert_eq!(
+            process(&mut taphold, Phro::Release, 2),
+            [(Phro::Release, 103)]
+        );
+
+        taphold.set_tapping_term(100);
+        assert_eq!(process(&mut taphold, Phro::Press, 2), []);
+        assert_eq!(tick(&mut taphold, 100), [(Phro::Press, 103)]);
+    }
+
+    #[test]
+    fn buffered_until_resolved() {
+        let mut taphold = taphold(false);
+        assert_eq!(process(&mut taphold, Phro::Press, 1), []);
+        assert_eq!(process(&mut taphold, Phro::Press, 5), []);
+        assert!(taphold.suppressed(5));
+        // Regenerated by the scan once replayed
+        assert_eq!(process(&mut taphold, Phro::Hold, 5), []);
+        assert_eq!(tick(&mut taphold, 10), []);
+        assert_eq!(
+            process(&mut taphold, Phro::Release, 1),
+            [(Phro::Press, 100), (Phro::Press, 5)]
+        );
+        assert!(!taphold.suppressed(5));
+        assert_eq!(process(&mut taphold, Phro::Hold, 5), [(Phro::Hold, 5)]);
+        assert_eq!(
+            process(&mut taphold, Phro::Release, 5),
+            [(Phro::Release, 5)]
+        );
+    }
+
+    #[test]
+    fn pressed_before_tap_hold_key() {
+        let mut taphold = taphold(false);
+        assert_eq!(process(&mut taphold, Phro::Press, 5), [(Phro::Press, 5)]);
+        assert_eq!(process(&mut taphold, Phro::Press, 1), []);
+        assert_eq!(process(&mut taphold, Phro::Hold, 5), [(Phro::Hold, 5)]);
+        assert_eq!(
+            process(&mut taphold, Phro::Release, 5),
+            [(Phro::Release, 5)]
+        );
+        assert_eq!(
+            process(&mut taphold, Phro::Release, 1),
+            [(Phro::Press, 100)]
+        );
+    }
+
+    #[test]
+    fn interrupted_tap_without_permissive_hold() {
+        let mut taphold = taphold(false);
+        assert_eq!(process(&mut taphold, Phro::Press, 1), []);
+        assert_eq!(process(&mut taphold, Phro::Press, 5), []);
+        assert_eq!(process(&mut taphold, Phro::Release, 5), []);
+        assert_eq!(
+            process(&mut taphold, Phro::Release, 1),
+            [(Phro::Press, 100), (Phro::Press, 5), (Phro::Release, 5)]
+        );
+    }
+
+    #[test]
+    fn permissive_hold() {
+        let mut taphold = taphold(true);
+        assert_eq!(process(&mut taphold, Phro::Press, 1), []);
+        assert_eq!(process(&mut taphold, Phro::Press, 5), []);
+        assert_eq!(tick(&mut taphold, 10), []);
+        // Pressed and released within the tapping term
+        assert_eq!(
+            process(&mut taphold, Phro::Release, 5),
+            [(Phro::Press, 101), (Phro::Press, 5), (Phro::Release, 5)]
+        );
+        assert_eq!(
+            process(&mut taphold, Phro::Release, 1),
+            [(Phro::Release, 101)]
+        );
+
+        // Still pressed when the tap-hold key is released
+        assert_eq!(process(&mut taphold, Phro::Press, 1), []);
+        assert_eq!(process(&mut taphold, Phro::Press, 5), []);
+        assert_eq!(
+            process(&mut taphold, Phro::Release, 1),
+            [(Phro::Press, 100), (Phro::Press, 5)]
+        );
+    }
+
+    #[test]
+    fn full_buffer_resolves_as_hold() {
+        let mut taphold = taphold(false);
+        assert_eq!(process(&mut taphold, Phro::Press, 1), []);
+        for index in 0..TAP_HOLD_BUFFER_SIZE as u16 {
+            assert_eq!(process(&mut taphold, Phro::Press, 10 + index), []);
+        }
+        let out = process(&mut taphold, Phro::Press, 50);
+        assert_eq!(out.len(), TAP_HOLD_BUFFER_SIZE + 2);
+        assert_eq!(out[0], (Phro::Press, 101));
+        assert_eq!(out[TAP_HOLD_BUFFER_SIZE + 1], (Phro::Press, 50));
+    }
+}
@@ -0,0 +1,792 @@
+// Copyright 2026 Jacob Alexander
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! Console command handling shared by the serial console and HID-IO terminal commands
+//! (h0031 Terminal Command / h0034 Terminal Output).
+//!
+//! Each HID-IO terminal response ends with a status line so hosts can parse replies without
+//! scraping the human readable output:
+//!
+//! ```text
+//! %<version> ok|unsupported|error <code>[ truncated]
+//! ```
+//!
+//! `<version>` is [`TERMINAL_PROTOCOL_VERSION`], `<code>` is a stable error identifier (e.g.
+//! `invalid_argument`) and `truncated` is set if the output did not fit into the terminal buffer.
+//! Output lines never start with `%`.
+
+use crate::console::{
+    Command, HallStatsArg, InjectAction, InjectArg, KeymapActionArg, KeymapArg, LedControlMode,
+    LedResetMode, LedTestMode, MacroArg, ParseError, Setting, SocdModeArg,
+};
+use crate::constants::*;
+use crate::keymap::{KeymapAction, KeymapConfig, KeymapEntry};
+use crate::socd::SocdMode;
+use crate::*;
+
+use core::fmt;
+use heapless::Deque;
+use kll_core::{trigger::Phro, TriggerEvent};
+
+#[cfg(feature = "hall-effect")]
+use crate::console::{AdcClockMode, AdcTraceArg, DksArg, HallMode, SampleFilterMode};
+#[cfg(feature = "hall-effect")]
+use crate::dks::{DksAction, DksKey};
+#[cfg(feature = "hall-effect")]
+use crate::hall_effect::{
+    AdcClock, SampleFilter, SensorMode, SILO_ATSAM4S_LC605_GAIN_2X, SILO_ATSAM4S_LC605_GAIN_4X,
+};
+
+// ----- Constants -----
+
+/// Version of the HID-IO terminal response format (status line and command output)
+/// Must be incremented when the format of existing output changes.
+pub const TERMINAL_PROTOCOL_VERSION: u8 = 2;
+
+/// Bytes kept free in the terminal buffer for the status line
+const STATUS_LINE_SIZE: usize = 48;
+
+// ----- Enums -----
+
+/// Command result, reported in the status line of HID-IO terminal responses
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum Status {
+    Ok,
+    /// Command or arguments were rejected (stable error code)
+    Error(&'static str),
+    /// Not supported by this keyboard
+    Unsupported,
+    /// Ok, the caller should reset into the bootloader once the output (including the status
+    /// line) has been sent
+    Bootloader,
+}
+
+impl Status {
+    /// Writes the status line ending a HID-IO terminal response
+    pub fn write_line(&self, out: &mut dyn fmt::Write, truncated: bool) -> fmt::Result {
+        write!(out, "%{} ", TERMINAL_PROTOCOL_VERSION)?;
+        match self {
+            Status::Ok | Status::Bootloader => write!(out, "ok")?,
+            Status::Error(code) => write!(out, "error {}", code)?,
+            Status::Unsupported => write!(out, "unsupported")?,
+        }
+        if truncated {
+            write!(out, " truncated")?;
+        }
+        writeln!(out)
+    }
+}
+
+// ----- Structs -----
+
+/// Point in time state of a single key
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, defmt::Format)]
+pub struct KeySnapshot {
+    /// Key is currently pressed (or held)
+    pub pressed: bool,
+    /// Key state is still being debounced
+    pub debouncing: bool,
+    /// Key has not changed state for the idle period
+    pub idle: bool,
+    /// Calibration status (hall effect sensors only)
+    pub calibration: Option<u8>,
+    /// Travel distance (hall effect sensors only)
+    pub distance: Option<i16>,
+}
+
+/// Buffered terminal output, sent over HID-IO in h0034 sized chunks
+/// Command output is truncated STATUS_LINE_SIZE bytes early so the status line always fits.
+pub struct TerminalBuffer {
+    buf: Deque<u8, TERMINAL_OUT_BUF_SIZE>,
+    dropped: bool,
+}
+
+impl TerminalBuffer {
+    pub const fn new() -> Self {
+        Self {
+            buf: Deque::new(),
+            dropped: false,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.buf.is_empty()
+    }
+
+    /// Ends the response with the status line, reporting (and clearing) truncated output
+    pub fn finish(&mut self, status: Status) {
+        let truncated = core::mem::take(&mut self.dropped);
+        let mut line: heapless::String<STATUS_LINE_SIZE> = heapless::String::new();
+        // Truncated output may end mid-line
+        if matches!(self.buf.back(), Some(byte) if *byte != b'\n') {
+            line.push('\n').ok();
+        }
+        status.write_line(&mut line, truncated).ok();
+        for byte in line.bytes() {
+            // Space is reserved by write_str
+            self.buf.push_back(byte).ok();
+        }
+    }
+}
+
+impl Default for TerminalBuffer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl fmt::Write for TerminalBuffer {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for byte in s.bytes() {
+            if self.buf.len() >= TERMINAL_OUT_BUF_SIZE - STATUS_LINE_SIZE {
+                self.dropped = true;
+                return Err(fmt::Error);
+            }
+            self.buf.push_back(byte).ok();
+        }
+        Ok(())
+    }
+}
+
+// ----- Traits -----
+
+/// Matrix state introspection
+pub trait MatrixSnapshot {
+    /// Current state of the key at the given matrix index
+    fn key_snapshot(&self, index: usize) -> KeySnapshot;
+
+    /// Dumps sensor calibration, if the matrix has any
+    fn dump_calibration(&self, out: &mut dyn fmt::Write) -> fmt::Result {
+        writeln!(out, "No calibration data for this matrix")
+    }
+
+    /// Dumps per-key sensor statistics, if the matrix has any
+    /// None only shows keys flagged as bad, otherwise a page of keys starting at the index.
+    fn dump_stats(&self, out: &mut dyn fmt::Write, _first: Option<usize>) -> fmt::Result {
+        writeln!(out, "No sensor statistics for this matrix")
+    }
+}
+
+// ----- Functions -----
+
+/// Determines whether a key is pressed from its generated trigger events
+pub fn pressed<I: IntoIterator<Item = TriggerEvent>>(events: I) -> bool {
+    events.into_iter().any(|event| {
+        matches!(
+            event,
+            TriggerEvent::Switch {
+                state: Phro::Press | Phro::Hold,
+                ..
+            }
+        )
+    })
+}
+
+/// Runs a console command, writing the response to out
+/// Errors and unsupported commands are also written to out as human readable messages.
+pub fn run_command<const MSIZE: usize, MATRIX>(
+    out: &mut dyn fmt::Write,
+    command: Result<Command, ParseError>,
+    hidio_intf: &mut HidioCommandInterface,
+    layer_state: &LayerState,
+    matrix: &MATRIX,
+) -> Status
+where
+    MATRIX: kiibohd_keyscanning::KeyScanning<MAX_PER_KEY_EVENTS> + MatrixSnapshot,
+{
+    let command = match command {
+        Ok(command) => command,
+        Err(ParseError::Empty) => {
+            return Status::Error(ParseError::Empty.code());
+        }
+        Err(err) => {
+            writeln!(out, "{}", err.as_str()).ok();
+            return Status::Error(err.code());
+        }
+    };
+    defmt::debug!("Terminal: {:?}", command);
+
+    match command {
+        Command::Help => {
+            out.write_str(crate::console::HELP).ok();
+            #[cfg(feature = "noise-cancel")]
+            out.write_str(crate::console::HELP_EXPERIMENTAL).ok();
+            Status::Ok
+        }
+        Command::Matrix => {
+            // Only keys with active events are shown
+            for index in 0..MSIZE {
+                let mut events = matrix.generate_events(index).into_iter().peekable();
+                if events.peek().is_none() {
+                    continue;
+                }
+                write!(out, "{:3}:", index).ok();
+                for event in events {
+                    write!(out, " {:?}", event).ok();
+                }
+                writeln!(out).ok();
+            }
+            Status::Ok
+        }
+        Command::Keystate => {
+            // <index>:<P|->[D][I][:<calibration>:<distance>], 8 keys per line
+            for index in 0..MSIZE {
+                let key = matrix.key_snapshot(index);
+                write!(out, "{}:{}", index, if key.pressed { 'P' } else { '-' }).ok();
+                if key.debouncing {
+                    write!(out, "D").ok();
+                }
+                if key.idle {
+                    write!(out, "I").ok();
+                }
+                if let (Some(cal), Some(distance)) = (key.calibration, key.distance) {
+                    write!(out, ":{}:{}", cal, distance).ok();
+                }
+                if index % 8 == 7 || index == MSIZE - 1 {
+                    writeln!(out).ok();
+                } else {
+                    write!(out, " ").ok();
+                }
+            }
+            writeln!(out, "L:{:?}", layer_state.stack()).ok();
+            // Host injected switches (KLL trigger indices)
+            write!(out, "H:[").ok();
+            for (i, switch) in hidio_intf.interface().inject.held().enumerate() {
+                write!(out, "{}{}", if i > 0 { ", " } else { "" }, switch).ok();
+            }
+            writeln!(out, "]").ok();
+            Status::Ok
+        }
+        Command::Layers => {
+            writeln!(out, "Layer stack: {:?}", layer_state.stack()).ok();
+            writeln!(out, "Active layer: {}", crate::active_layer(layer_state)).ok();
+            Status::Ok
+        }
+        Command::Leds(chip) => {
+            let buffer = &hidio_intf.interface().led_buffer;
+            for (i, chunk) in buffer.chunks(ISSI_DRIVER_CHANNELS).enumerate() {
+                if chip.map_or(false, |chip| chip as usize != i) {
+                    continue;
+                }
+                writeln!(out, "Chip {}:", i).ok();
+                for (row, data) in chunk.chunks(16).enumerate() {
+                    write!(out, "  {:3}:", row * 16).ok();
+                    for val in data {
+                        write!(out, " {:02x}", val).ok();
+                    }
+                    writeln!(out).ok();
+                }
+            }
+            Status::Ok
+        }
+        Command::Hall => {
+            matrix.dump_calibration(out).ok();
+            Status::Ok
+        }
+        Command::HallStats(HallStatsArg::Bad) => {
+            matrix.dump_stats(out, None).ok();
+            Status::Ok
+        }
+        Command::HallStats(HallStatsArg::From(index)) => {
+            matrix.dump_stats(out, Some(index as usize)).ok();
+            Status::Ok
+        }
+        #[cfg(feature = "hall-effect")]
+        Command::HallStats(HallStatsArg::Reset) => {
+            hidio_intf
+                .mut_interface()
+                .manufacturing_config
+                .hall_stats_reset = true;
+            writeln!(out, "OK").ok();
+            Status::Ok
+        }
+        #[cfg(not(feature = "hall-effect"))]
+        Command::HallStats(HallStatsArg::Reset) => {
+            writeln!(out, "Not supported by this keyboard").ok();
+            Status::Unsupported
+        }
+        Command::Profile(reset) => {
+            let profile = &mut hidio_intf.mut_interface().profile;
+            if reset {
+                profile.reset();
+                writeln!(out, "OK").ok();
+            } else {
+                profile.report(out).ok();
+            }
+            Status::Ok
+        }
+        #[cfg(feature = "hall-effect")]
+        Command::AdcNoise(reset) => {
+            let adc_noise = &mut hidio_intf.mut_interface().adc_noise;
+            if reset {
+                adc_noise.reset();
+                writeln!(out, "OK").ok();
+            } else {
+                adc_noise.report(out).ok();
+            }
+            Status::Ok
+        }
+        #[cfg(not(feature = "hall-effect"))]
+        Command::AdcNoise(_) => {
+            writeln!(out, "Not supported by this keyboard").ok();
+            Status::Unsupported
+        }
+        #[cfg(feature = "hall-effect")]
+        Command::AdcTrace(arg) => {
+            let adc_trace = &mut hidio_intf.mut_interface().adc_trace;
+            match arg {
+                AdcTraceArg::Start(strobe) => {
+                    adc_trace.start(strobe as usize);
+                    writeln!(out, "OK").ok();
+                }
+                AdcTraceArg::From(index) => {
+                    adc_trace.report(out, index as usize).ok();
+                }
+            }
+            Status::Ok
+        }
+        #[cfg(not(feature = "hall-effect"))]
+        Command::AdcTrace(_) => {
+            writeln!(out, "Not supported by this keyboard").ok();
+            Status::Unsupported
+        }
+        #[cfg(feature = "hall-effect")]
+        Command::Dks(arg) => dks_command(out, hidio_intf, arg),
+        #[cfg(not(feature = "hall-effect"))]
+        Command::Dks(_) => {
+            writeln!(out, "Not supported by this keyboard").ok();
+            Status::Unsupported
+        }
+        Command::Macro(arg) => macro_command(out, hidio_intf, arg),
+        Command::Keymap(arg) => keymap_command(out, hidio_intf, arg),
+        Command::Inject(arg) => inject_command(out, hidio_intf, arg),
+        Command::Set(setting) => {
+            if apply_setting(hidio_intf, setting) {
+                writeln!(out, "OK").ok();
+                Status::Ok
+            } else {
+                writeln!(out, "Not supported by this keyboard").ok();
+                Status::Unsupported
+            }
+        }
+        Command::Bootloader => {
+            writeln!(out, "Resetting into bootloader...").ok();
+            Status::Bootloader
+        }
+    }
+}
+
+/// Handles the macro command
+/// Deleted macros are saved by the RTT task (dynamic_macros_save)
+fn macro_command(
+    out: &mut dyn fmt::Write,
+    hidio_intf: &mut HidioCommandInterface,
+    arg: MacroArg,
+) -> Status {
+    let intf = hidio_intf.mut_interface();
+    match arg {
+        MacroArg::List => {
+            intf.dynamic_macros.report(out).ok();
+            Status::Ok
+        }
+        MacroArg::Delete(slot) => {
+            if intf.dynamic_macros.delete(slot) {
+                intf.dynamic_macros_save = true;
+                writeln!(out, "OK").ok();
+                Status::Ok
+            } else {
+                writeln!(out, "{}", ParseError::InvalidArgument.as_str()).ok();
+                Status::Error(ParseError::InvalidArgument.code())
+            }
+        }
+    }
+}
+
+/// Handles the keymap command
+/// Changes are applied by the scanning interrupt (keymap_changed) and saved by the RTT task
+/// (keymap_save)
+fn keymap_command(
+    out: &mut dyn fmt::Write,
+    hidio_intf: &mut HidioCommandInterface,
+    arg: KeymapArg,
+) -> Status {
+    let intf = hidio_intf.mut_interface();
+    let ok = match arg {
+        KeymapArg::List => {
+            intf.keymap.report(out).ok();
+            return Status::Ok;
+        }
+        KeymapArg::Get { layer, switch } => {
+            let mut config = KeymapConfig::new();
+            if let Some(entry) = intf.keymap.get(layer, switch) {
+                config.set(*entry);
+            }
+            config.report(out).ok();
+            return Status::Ok;
+        }
+        KeymapArg::Set {
+            layer,
+            switch,
+            action,
+        } => {
+            let action = match action {
+                KeymapActionArg::Switch(switch) => KeymapAction::Switch(switch),
+                KeymapActionArg::Key(key) => KeymapAction::Key(key),
+                KeymapActionArg::Disabled => KeymapAction::Disabled,
+            };
+            // Remapped switches must fit into the layout
+            let valid = (layer as usize) < MAX_LAYERS
+                && (switch as usize) < LAYOUT_SIZE
+                && !matches!(action, KeymapAction::Switch(switch) if switch as usize >= LAYOUT_SIZE);
+            valid
+                && intf.keymap.set(KeymapEntry {
+                    layer,
+                    switch,
+                    action,
+                })
+        }
+        KeymapArg::Reset(Some((layer, switch))) => intf.keymap.reset(layer, switch),
+        KeymapArg::Reset(None) => {
+            intf.keymap.reset_all();
+            true
+        }
+        KeymapArg::Save => {
+            intf.keymap_save = true;
+            intf.keymap_save_delay = 0;
+            writeln!(out, "OK").ok();
+            return Status::Ok;
+        }
+    };
+
+    if ok {
+        intf.keymap_changed = true;
+        writeln!(out, "OK").ok();
+        Status::Ok
+    } else {
+        writeln!(out, "{}", ParseError::InvalidArgument.as_str()).ok();
+        Status::Error(ParseError::InvalidArgument.code())
+    }
+}
+
+/// Handles the inject command
+/// Events are sent to kll-core by inject_task (see inject.rs)
+fn inject_command(
+    out: &mut dyn fmt::Write,
+    hidio_intf: &mut HidioCommandInterface,
+    arg: InjectArg,
+) -> Status {
+    let inject = &mut hidio_intf.mut_interface().inject;
+    let ret = match arg {
+        InjectArg::Status => {
+            inject.report(out).ok();
+            return Status::Ok;
+        }
+        InjectArg::Switch(switch, InjectAction::Press) => inject.request(switch, true),
+        InjectArg::Switch(switch, InjectAction::Release) => inject.request(switch, false),
+        InjectArg::Switch(switch, InjectAction::Tap) => inject
+            .request(switch, true)
+            .and_then(|_| inject.request(switch, false)),
+        InjectArg::ReleaseAll => {
+            inject.release_all();
+            Ok(())
+        }
+    };
+
+    match ret {
+        Ok(()) => {
+            writeln!(out, "OK").ok();
+            Status::Ok
+        }
+        Err(err) => {
+            writeln!(out, "{}", err.as_str()).ok();
+            Status::Error(err.code())
+        }
+    }
+}
+
+/// Handles the dks command
+/// Changes are applied by the ADC interrupt (dks_changed) and saved by the RTT task (dks_save)
+#[cfg(feature = "hall-effect")]
+fn dks_command(
+    out: &mut dyn fmt::Write,
+    hidio_intf: &mut HidioCommandInterface,
+    arg: DksArg,
+) -> Status {
+    let intf = hidio_intf.mut_interface();
+    let ok = match arg {
+        DksArg::List => {
+            intf.dks.report(out).ok();
+            return Status::Ok;
+        }
+        DksArg::Set {
+            switch,
+            actuation,
+            bottom_out,
+            actions,
+        } => {
+            // Virtual switches must fit into the layout
+            let valid = bottom_out > actuation
+                && (switch as usize) < LAYOUT_SIZE
+                && actions
+                    .iter()
+                    .flatten()
+                    .all(|action| (action.virtual_switch as usize) < LAYOUT_SIZE);
+            valid
+                && intf.dks.set(DksKey {
+                    switch,
+                    actuation,
+                    bottom_out,
+                    actions: actions.map(|action| {
+                        action.map(|action| DksAction {
+                            virtual_switch: action.virtual_switch,
+                            hold: action.hold,
+                        })
+                    }),
+                })
+        }
+        DksArg::Clear(switch) => intf.dks.clear(switch),
+        DksArg::Save => {
+            intf.dks_save = true;
+            writeln!(out, "OK").ok();
+            return Status::Ok;
+        }
+    };
+
+    if ok {
+        intf.dks_changed = true;
+        writeln!(out, "OK").ok();
+        Status::Ok
+    } else {
+        writeln!(out, "{}", ParseError::InvalidArgument.as_str()).ok();
+        Status::Error(ParseError::InvalidArgument.code())
+    }
+}
+
+/// Applies a console setting using the same state as the HID-IO commands
+/// Returns false if the setting is not supported
+fn apply_setting(hidio_intf: &mut HidioCommandInterface, setting: Setting) -> bool {
+    let intf = hidio_intf.mut_interface();
+    match setting {
+        Setting::LedControl(mode) => {
+            intf.led_control.control = match mode {
+                LedControlMode::Disable => h0021::args::Control::Disable,
+                LedControlMode::Start => h0021::args::Control::EnableStart,
+                LedControlMode::Pause => h0021::args::Control::EnablePause,
+            };
+        }
+        Setting::LedReset(LedResetMode::Soft) => {
+            intf.led_control.soft_reset = true;
+        }
+        Setting::LedReset(LedResetMode::Hard) => {
+            intf.led_control.hard_reset = true;
+        }
+        Setting::LedTest(LedTestMode::Short) => {
+            intf.manufacturing_config.led_short_test = true;
+        }
+        Setting::LedTest(LedTestMode::Open) => {
+            intf.manufacturing_config.led_open_test = true;
+        }
+        #[cfg(feature = "hall-effect")]
+        Setting::HallMode(mode) => {
+            intf.manufacturing_config.hall_effect_mode_switch = Some(match mode {
+                HallMode::Normal => SensorMode::Normal(&SILO_ATSAM4S_LC605_GAIN_4X),
+                HallMode::LowLatency => SensorMode::LowLatency(&SILO_ATSAM4S_LC605_GAIN_4X),
+                HallMode::Test => SensorMode::Test(&SILO_ATSAM4S_LC605_GAIN_2X),
+            });
+        }
+        #[cfg(not(feature = "hall-effect"))]
+        Setting::HallMode(_) => {
+            return false;
+        }
+        #[cfg(feature = "hall-effect")]
+        Setting::AdcClock(clock) => {
+            intf.manufacturing_config.hall_effect_adc_clock = Some(match clock {
+                AdcClockMode::Mhz12 => AdcClock::Mhz12,
+                AdcClockMode::Mhz20 => AdcClock::Mhz20,
+                AdcClockMode::Mhz30 => AdcClock::Mhz30,
+            });
+        }
+        #[cfg(not(feature = "hall-effect"))]
+        Setting::AdcClock(_) => {
+            return false;
+        }
+        #[cfg(feature = "noise-cancel")]
+        Setting::NoiseCancel(enabled) => {
+            intf.manufacturing_config.hall_noise_cancel = Some(enabled);
+        }
+        #[cfg(feature = "hall-effect")]
+        Setting::SampleFilter(filter) => {
+            intf.manufacturing_config.hall_sample_filter = Some(match filter {
+                SampleFilterMode::Mean => SampleFilter::Mean,
+                SampleFilterMode::Median => SampleFilter::Median,
+                SampleFilterMode::TrimmedMean => SampleFilter::TrimmedMean,
+            });
+        }
+        #[cfg(not(feature = "hall-effect"))]
+        Setting::SampleFilter(_) => {
+            return false;
+        }
+        Setting::Socd(pair, mode) => {
+            intf.stage_settings.socd = Some((
+                pair,
+                match mode {
+                    SocdModeArg::Off => SocdMode::Off,
+                    SocdModeArg::LastInput => SocdMode::LastInput,
+                    SocdModeArg::FirstInput => SocdMode::FirstInput,
+                    SocdModeArg::Neutral => SocdMode::Neutral,
+                },
+            ));
+        }
+        Setting::TappingTerm(ms) => {
+            intf.stage_settings.tapping_term = Some(ms);
+        }
+        Setting::PermissiveHold(enabled) => {
+            intf.stage_settings.permissive_hold = Some(enabled);
+        }
+        Setting::ComboTerm(ms) => {
+            intf.stage_settings.combo_term = Some(ms);
+        }
+        #[cfg(feature = "keyscanning")]
+        Setting::Debounce(_) | Setting::KeyDebounce(..) | Setting::Idle(_) => {
+            // Only one change can be pending at a time
+            if intf.matrix_setting.is_some() {
+                return false;
+            }
+            intf.matrix_setting = Some(setting);
+        }
+        #[cfg(not(feature = "keyscanning"))]
+        Setting::Debounce(_) | Setting::KeyDebounce(..) | Setting::Idle(_) => {
+            return false;
+        }
+    }
+    true
+}
+
+// ----- Software Interrupt Tasks -----
+
+/// HID-IO Terminal Task
+/// Runs any pending h0031 terminal command and sends buffered output (ending with the status
+/// line) using h0034.
+/// Output is sent in chunks as the HID-IO tx buffer is smaller than most responses, call
+/// regularly (e.g. from macro_process) until all output has been sent.
+/// The bootloader command resets TERMINAL_BOOTLOADER_DELAY calls after its response has been
+/// queued, giving USB time to send the last packets.
+pub fn hidio_terminal_task<const MSIZE: usize, MATRIX>(
+    hidio_intf: &mut HidioCommandInterface,
+    layer_state: &LayerState,
+    matrix: &MATRIX,
+) where
+    MATRIX: kiibohd_keyscanning::KeyScanning<MAX_PER_KEY_EVENTS> + MatrixSnapshot,
+{
+    // Run pending command
+    // Only one command is processed at a time, the next is run once all output has been sent
+    if hidio_intf.interface().terminal_out.is_empty()
+        && hidio_intf.interface().terminal_bootloader.is_none()
+    {
+        if let Some(command) = hidio_intf.mut_interface().terminal_command.take() {
+            let mut out = core::mem::take(&mut hidio_intf.mut_interface().terminal_out);
+            let status =
+                run_command::<MSIZE, MATRIX>(&mut out, command, hidio_intf, layer_state, matrix);
+
+            // Reset once the output has been sent
+            if status == Status::Bootloader {
+                hidio_intf.mut_interface().terminal_bootloader = Some(TERMINAL_BOOTLOADER_DELAY);
+            }
+
+            if out.dropped {
+                defmt::warn!("HID-IO terminal output truncated");
+            }
+            out.finish(status);
+            hidio_intf.mut_interface().terminal_out = out;
+        }
+    }
+
+    // Send as much buffered output as possible
+    while !hidio_intf.interface().terminal_out.is_empty() {
+        let mut output = heapless::String::new();
+        for byte in hidio_intf.interface().terminal_out.buf.iter() {
+            // Only ascii is written to the buffer
+            if output.push(*byte as char).is_err() {
+                break;
+            }
+        }
+
+        // Only remove the chunk once it has been queued (HID-IO tx buffer may be full)
+        let len = output.len();
+        if hidio_intf
+            .h0034_terminalout(h0034::Cmd { output }, true)
+            .is_err()
+        {
+            break;
+        }
+        let buf = &mut hidio_intf.mut_interface().terminal_out.buf;
+        for _ in 0..len {
+            buf.pop_front();
+        }
+    }
+
+    // Reset into the bootloader after the response has been sent
+    if hidio_intf.interface().terminal_out.is_empty() {
+        if let Some(delay) = hidio_intf.mut_interface().terminal_bootloader.as_mut() {
+            if *delay == 0 {
+                crate::bootloader();
+            }
+            *delay -= 1;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::fmt::Write;
+
+    extern crate std;
+    use std::string::String;
+
+    fn contents(out: &TerminalBuffer) -> String {
+        out.buf.iter().map(|byte| *byte as char).collect()
+    }
+
+    #[test]
+    fn status_line() {
+        let mut out = TerminalBuffer::new();
+        writeln!(out, "OK").unwrap();
+        out.finish(Status::Ok);
+        assert_eq!(contents(&out), "OK\n%2 ok\n");
+
+        for (status, line) in [
+            (Status::Bootloader, "%2 ok\n"),
+            (Status::Unsupported, "%2 unsupported\n"),
+            (
+                Status::Error(ParseError::InvalidArgument.code()),
+                "%2 error invalid_argument\n",
+            ),
+        ] {
+            let mut out = TerminalBuffer::new();
+            out.finish(status);
+            assert_eq!(contents(&out), line);
+        }
+    }
+
+    #[test]
+    fn truncated_output() {
+        let mut out = TerminalBuffer::new();
+        while writeln!(out, "0123456789abcdef").is_ok() {}
+
+        // The status line still fits and reports the truncation
+        out.finish(Status::Ok);
+        let output = contents(&out);
+        assert!(output.len() <= TERMINAL_OUT_BUF_SIZE);
+        assert!(output.ends_with("\n%2 ok truncated\n"));
+
+        // Only the response that overflowed is flagged
+        while out.buf.pop_front().is_some() {}
+        out.finish(Status::Ok);
+        assert_eq!(contents(&out), "%2 ok\n");
+    }
+}
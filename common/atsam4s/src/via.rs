@@ -0,0 +1,912 @@
+// Copyright 2026 Jacob Alexander
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! VIA configuration protocol
+//!
+//! Handles the VIA (protocol version 12) requests received on the raw HID interface
+//! (raw_hid.rs), enabled with the `via` feature. Vial tools work using the VIA commands, the
+//! Vial specific commands are not supported.
+//!
+//! Requests are translated into the existing settings (HidioInterface):
+//! - Keymap - Keymap overlay entries (keymap.rs), saved in flash once VIA stops editing
+//!   (KEYMAP_SAVE_DELAY_TICKS) or on CUSTOM_SAVE
+//! - Macros - Dynamic macros (dynamic_macro.rs)
+//! - Lighting - qmk_backlight channel, global LED brightness and on/off (LedSettings)
+//!
+//! VIA addresses keys by matrix position, switches (KLL trigger indices) are exposed as a matrix
+//! of VIA_MATRIX_COLS columns: `switch = row * VIA_MATRIX_COLS + col` (see the VIA keyboard
+//! definitions in the board directories).
+//! Keys without an overlay entry are reported using the compiled KLL layout (LAYOUT_USB_KEYS,
+//! generated by build.rs):
+//! - USB key - Keycode of the key
+//! - Other result (e.g. a function or a combination) - QK_KB + switch (the key itself)
+//! - Unbound - KC_NO on layer 0, KC_TRNS on the other layers
+//!
+//! Setting the keycode of the compiled layout removes the overlay entry.
+
+use crate::constants::*;
+use crate::dynamic_macro::{MacroEvent, MacroStore};
+use crate::hal::udp::usb_device::bus::{UsbBus, UsbBusAllocator};
+use crate::hidio::LedSettings;
+use crate::keymap::{KeymapAction, KeymapConfig, KeymapEntry};
+use crate::raw_hid::{RawHid, RAW_HID_REPORT_SIZE};
+use crate::{HidioCommandInterface, LayoutUsbKeys};
+use heapless::Vec;
+
+// ----- Constants -----
+
+const VIA_PROTOCOL_VERSION: u16 = 0x000C;
+
+/// Rows of the VIA matrix
+pub const VIA_MATRIX_ROWS: usize = LAYOUT_SIZE / VIA_MATRIX_COLS;
+const _: () = assert!(VIA_MATRIX_ROWS * VIA_MATRIX_COLS == LAYOUT_SIZE);
+const _: () = assert!(VIA_LAYERS <= MAX_LAYERS);
+
+// Commands
+const GET_PROTOCOL_VERSION: u8 = 0x01;
+const GET_KEYBOARD_VALUE: u8 = 0x02;
+const SET_KEYBOARD_VALUE: u8 = 0x03;
+const DYNAMIC_KEYMAP_GET_KEYCODE: u8 = 0x04;
+const DYNAMIC_KEYMAP_SET_KEYCODE: u8 = 0x05;
+const DYNAMIC_KEYMAP_RESET: u8 = 0x06;
+const CUSTOM_SET_VALUE: u8 = 0x07;
+const CUSTOM_GET_VALUE: u8 = 0x08;
+const CUSTOM_SAVE: u8 = 0x09;
+const EEPROM_RESET: u8 = 0x0A;
+const BOOTLOADER_JUMP: u8 = 0x0B;
+const DYNAMIC_KEYMAP_MACRO_GET_COUNT: u8 = 0x0C;
+const DYNAMIC_KEYMAP_MACRO_GET_BUFFER_SIZE: u8 = 0x0D;
+const DYNAMIC_KEYMAP_MACRO_GET_BUFFER: u8 = 0x0E;
+const DYNAMIC_KEYMAP_MACRO_SET_BUFFER: u8 = 0x0F;
+const DYNAMIC_KEYMAP_MACRO_RESET: u8 = 0x10;
+const DYNAMIC_KEYMAP_GET_LAYER_COUNT: u8 = 0x11;
+const DYNAMIC_KEYMAP_GET_BUFFER: u8 = 0x12;
+const DYNAMIC_KEYMAP_SET_BUFFER: u8 = 0x13;
+const UNHANDLED: u8 = 0xFF;
+
+// Keyboard values
+const LAYOUT_OPTIONS: u8 = 0x02;
+const DEVICE_INDICATION: u8 = 0x05;
+
+// Lighting channel and values
+const QMK_BACKLIGHT_CHANNEL: u8 = 0x01;
+const QMK_BACKLIGHT_BRIGHTNESS: u8 = 0x01;
+const QMK_BACKLIGHT_EFFECT: u8 = 0x02;
+
+// Keycodes
+const KC_NO: u16 = 0x0000;
+const KC_TRNS: u16 = 0x0001;
+const KC_LSHIFT: u8 = 0xE1;
+/// Custom keyboard keycodes (QK_KB_0), used for Switch overlay entries
+const QK_KB: u16 = 0x7E00;
+
+// Macro buffer codes (QMK send_string)
+const SS_QMK_PREFIX: u8 = 0x01;
+const SS_TAP_CODE: u8 = 0x01;
+const SS_DOWN_CODE: u8 = 0x02;
+const SS_UP_CODE: u8 = 0x03;
+const SS_DELAY_CODE: u8 = 0x04;
+const SS_DELAY_END: u8 = b'|';
+
+/// Payload of the buffer commands (command, offset and size)
+const BUFFER_DATA_SIZE: usize = RAW_HID_REPORT_SIZE - 4;
+
+// ----- Structs -----
+
+/// Settings edited by VIA (HidioInterface fields)
+pub struct ViaSettings<'a> {
+    pub keymap: &'a mut KeymapConfig,
+    pub dynamic_macros: &'a mut MacroStore,
+    pub led_settings: &'a mut LedSettings,
+}
+
+/// Settings changed by a request
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct ViaChanges {
+    pub keymap: bool,
+    pub dynamic_macros: bool,
+    pub led_settings: bool,
+    /// Save the LED settings and the keymap overlay now (CUSTOM_SAVE)
+    pub save: bool,
+    /// Reset into the bootloader
+    pub bootloader: bool,
+}
+
+/// VIA request handling
+pub struct Via {
+    /// Macro buffer being written by VIA, parsed once the write has finished
+    macro_buffer: [u8; VIA_MACRO_BUFFER_SIZE],
+    /// USB keys of the compiled layout
+    layout: LayoutUsbKeys,
+}
+
+impl Via {
+    pub const fn new(layout: LayoutUsbKeys) -> Self {
+        Self {
+            macro_buffer: [0; VIA_MACRO_BUFFER_SIZE],
+            layout,
+        }
+    }
+
+    /// Handles a request, the report is replaced by the response
+    pub fn process(
+        &mut self,
+        report: &mut [u8; RAW_HID_REPORT_SIZE],
+        settings: ViaSettings,
+    ) -> ViaChanges {
+        let mut changes = ViaChanges::default();
+        match report[0] {
+            GET_PROTOCOL_VERSION => {
+                report[1..3].copy_from_slice(&VIA_PROTOCOL_VERSION.to_be_bytes());
+            }
+            GET_KEYBOARD_VALUE if report[1] == LAYOUT_OPTIONS => {
+                report[2..6].fill(0);
+            }
+            SET_KEYBOARD_VALUE if matches!(report[1], LAYOUT_OPTIONS | DEVICE_INDICATION) => {}
+            DYNAMIC_KEYMAP_GET_KEYCODE => {
+                if let Some((layer, switch)) = position(report[1], report[2], report[3]) {
+                    let keycode = self.keycode(settings.keymap, layer, switch);
+                    report[4..6].copy_from_slice(&keycode.to_be_bytes());
+                }
+            }
+            DYNAMIC_KEYMAP_SET_KEYCODE => {
+                if let Some((layer, switch)) = position(report[1], report[2], report[3]) {
+                    let keycode = u16::from_be_bytes([report[4], report[5]]);
+                    changes.keymap |= self.set_keycode(settings.keymap, layer, switch, keycode);
+                }
+            }
+            DYNAMIC_KEYMAP_RESET => {
+                settings.keymap.reset_all();
+                changes.keymap = true;
+            }
+            CUSTOM_SET_VALUE if report[1] == QMK_BACKLIGHT_CHANNEL => match report[2] {
+                QMK_BACKLIGHT_BRIGHTNESS => {
+                    settings.led_settings.brightness = report[3];
+                    changes.led_settings = true;
+                }
+                QMK_BACKLIGHT_EFFECT => {
+                    settings.led_settings.enabled = report[3] != 0;
+                    changes.led_settings = true;
+                }
+                _ => {
+                    report[0] = UNHANDLED;
+                }
+            },
+            CUSTOM_GET_VALUE if report[1] == QMK_BACKLIGHT_CHANNEL => match report[2] {
+                QMK_BACKLIGHT_BRIGHTNESS => {
+                    report[3] = settings.led_settings.brightness;
+                }
+                QMK_BACKLIGHT_EFFECT => {
+                    report[3] = settings.led_settings.enabled as u8;
+                }
+                _ => {
+                    report[0] = UNHANDLED;
+                }
+            },
+            CUSTOM_SAVE if report[1] == QMK_BACKLIGHT_CHANNEL => {
+                changes.save = true;
+            }
+            EEPROM_RESET => {
+                settings.keymap.reset_all();
+                changes.keymap = true;
+                *settings.dynamic_macros = MacroStore::new();
+                changes.dynamic_macros = true;
+            }
+            BOOTLOADER_JUMP => {
+                changes.bootloader = true;
+            }
+            DYNAMIC_KEYMAP_MACRO_GET_COUNT => {
+                report[1] = MAX_DYNAMIC_MACROS as u8;
+            }
+            DYNAMIC_KEYMAP_MACRO_GET_BUFFER_SIZE => {
+                report[1..3].copy_from_slice(&(VIA_MACRO_BUFFER_SIZE as u16).to_be_bytes());
+            }
+            DYNAMIC_KEYMAP_MACRO_GET_BUFFER => {
+                if let Some((offset, size)) = buffer_range(report, VIA_MACRO_BUFFER_SIZE) {
+                    let mut buffer = [0; VIA_MACRO_BUFFER_SIZE];
+                    serialize_macros(settings.dynamic_macros, &mut buffer);
+                    report[4..4 + size].copy_from_slice(&buffer[offset..offset + size]);
+                }
+            }
+            DYNAMIC_KEYMAP_MACRO_SET_BUFFER => {
+                if let Some((offset, size)) = buffer_range(report, VIA_MACRO_BUFFER_SIZE) {
+                    self.macro_buffer[offset..offset + size].copy_from_slice(&report[4..4 + size]);
+
+                    // VIA clears the last byte of the buffer once the macros have been written
+                    if offset + size == VIA_MACRO_BUFFER_SIZE
+                        && self.macro_buffer[VIA_MACRO_BUFFER_SIZE - 1] == 0
+                    {
+                        match parse_macros(&self.macro_buffer) {
+                            Some(store) => {
+                                *settings.dynamic_macros = store;
+                                changes.dynamic_macros = true;
+                            }
+                            None => {
+                                defmt::warn!("Unsupported or too long VIA macros");
+                            }
+                        }
+                    }
+                }
+            }
+            DYNAMIC_KEYMAP_MACRO_RESET => {
+                self.macro_buffer.fill(0);
+                *settings.dynamic_macros = MacroStore::new();
+                changes.dynamic_macros = true;
+            }
+            DYNAMIC_KEYMAP_GET_LAYER_COUNT => {
+                report[1] = VIA_LAYERS as u8;
+            }
+            DYNAMIC_KEYMAP_GET_BUFFER => {
+                if let Some((offset, size)) = keymap_buffer_range(report) {
+                    for key in offset / 2..(offset + size) / 2 {
+                        let (layer, switch) =
+                            ((key / LAYOUT_SIZE) as u8, (key % LAYOUT_SIZE) as u16);
+                        let keycode = self.keycode(settings.keymap, layer, switch);
+                        let pos = 4 + key * 2 - offset;
+                        report[pos..pos + 2].copy_from_slice(&keycode.to_be_bytes());
+                    }
+                }
+            }
+            DYNAMIC_KEYMAP_SET_BUFFER => {
+                if let Some((offset, size)) = keymap_buffer_range(report) {
+                    for key in offset / 2..(offset + size) / 2 {
+                        let (layer, switch) =
+                            ((key / LAYOUT_SIZE) as u8, (key % LAYOUT_SIZE) as u16);
+                        let pos = 4 + key * 2 - offset;
+                        let keycode = u16::from_be_bytes([report[pos], report[pos + 1]]);
+                        changes.keymap |= self.set_keycode(settings.keymap, layer, switch, keycode);
+                    }
+                }
+            }
+            _ => {
+                report[0] = UNHANDLED;
+            }
+        }
+        changes
+    }
+
+    /// VIA keycode of the compiled KLL layout
+    fn layout_keycode(&self, layer: u8, switch: u16) -> u16 {
+        match self
+            .layout
+            .iter()
+            .find(|(l, s, _)| *l == layer && *s == switch)
+        {
+            Some((_, _, Some(key))) => *key as u16,
+            Some((_, _, None)) => QK_KB + switch,
+            None if layer == 0 => KC_NO,
+            None => KC_TRNS,
+        }
+    }
+
+    /// VIA keycode of a key, the keymap overlay entry or the compiled layout
+    fn keycode(&self, keymap: &KeymapConfig, layer: u8, switch: u16) -> u16 {
+        match keymap.get(layer, switch).map(|entry| entry.action) {
+            None => self.layout_keycode(layer, switch),
+            Some(KeymapAction::Disabled) => KC_NO,
+            Some(KeymapAction::Key(key)) => key as u16,
+            Some(KeymapAction::Switch(switch)) => QK_KB + switch,
+        }
+    }
+
+    /// Updates the keymap overlay entry of a key
+    /// KC_TRNS and the keycode of the compiled layout remove the entry.
+    /// Returns false if the keymap is unchanged, the keycode is not supported or the overlay is
+    /// full.
+    fn set_keycode(&self, keymap: &mut KeymapConfig, layer: u8, switch: u16, keycode: u16) -> bool {
+        if keycode == KC_TRNS || keycode == self.layout_keycode(layer, switch) {
+            return keymap.reset(layer, switch);
+        }
+        let action = match keycode {
+            KC_NO => KeymapAction::Disabled,
+            0x0004..=0x00A4 | 0x00E0..=0x00E7 => KeymapAction::Key(keycode as u8),
+            _ if (QK_KB..QK_KB + LAYOUT_SIZE as u16).contains(&keycode) => {
+                KeymapAction::Switch(keycode - QK_KB)
+            }
+            _ => {
+                defmt::warn!("Unsupported VIA keycode: {:#06x}", keycode);
+                return false;
+            }
+        };
+        let entry = KeymapEntry {
+            layer,
+            switch,
+            action,
+        };
+        if keymap.get(layer, switch) == Some(&entry) {
+            false
+        } else if keymap.set(entry) {
+            true
+        } else {
+            defmt::warn!("MAX_KEYMAP_ENTRIES reached, VIA keycode ignored");
+            false
+        }
+    }
+}
+
+/// VIA raw HID interface
+pub struct ViaInterface<'a, B: UsbBus> {
+    raw_hid: RawHid<'a, B>,
+    via: Via,
+}
+
+impl<'a, B: UsbBus> ViaInterface<'a, B> {
+    /// Allocates the raw HID interface, must be called before the UsbDevice is built
+    pub fn new(alloc: &'a UsbBusAllocator<B>, layout: LayoutUsbKeys) -> Self {
+        Self {
+            raw_hid: RawHid::new(alloc),
+            via: Via::new(layout),
+        }
+    }
+
+    pub fn raw_hid(&mut self) -> &mut RawHid<'a, B> {
+        &mut self.raw_hid
+    }
+
+    /// Handles the next request (if any) and sends the response
+    /// Returns true if the keyboard should reset into the bootloader
+    pub fn process(&mut self, hidio_intf: &mut HidioCommandInterface) -> bool {
+        let mut report = [0; RAW_HID_REPORT_SIZE];
+        if !self.raw_hid.read(&mut report) {
+            return false;
+        }
+        let intf = hidio_intf.mut_interface();
+        let settings = ViaSettings {
+            keymap: &mut intf.keymap,
+            dynamic_macros: &mut intf.dynamic_macros,
+            led_settings: &mut intf.led_settings,
+        };
+        let changes = self.via.process(&mut report, settings);
+        if !self.raw_hid.write(&report) {
+            defmt::warn!("VIA response dropped: {:#04x}", report[0]);
+        }
+
+        // Keymap edits are saved once VIA stops editing, VIA sends every key of a layout
+        if changes.keymap {
+            intf.keymap_changed = true;
+            intf.keymap_save = true;
+            intf.keymap_save_delay = KEYMAP_SAVE_DELAY_TICKS;
+        }
+        if changes.save {
+            intf.keymap_save_delay = 0;
+            intf.led_settings_save = true;
+        }
+        if changes.dynamic_macros {
+            intf.dynamic_macros_save = true;
+        }
+        if changes.led_settings {
+            intf.led_settings_changed = true;
+        }
+        changes.bootloader
+    }
+}
+
+// ----- Functions -----
+
+/// Layer and switch of a VIA matrix position
+fn position(layer: u8, row: u8, col: u8) -> Option<(u8, u16)> {
+    ((layer as usize) < VIA_LAYERS
+        && (row as usize) < VIA_MATRIX_ROWS
+        && (col as usize) < VIA_MATRIX_COLS)
+        .then_some((layer, row as u16 * VIA_MATRIX_COLS as u16 + col as u16))
+}
+
+/// Offset and size of a buffer command, None if out of bounds
+fn buffer_range(report: &[u8; RAW_HID_REPORT_SIZE], len: usize) -> Option<(usize, usize)> {
+    let offset = u16::from_be_bytes([report[1], report[2]]) as usize;
+    let size = report[3] as usize;
+    (size <= BUFFER_DATA_SIZE && offset + size <= len).then_some((offset, size))
+}
+
+/// Offset and size of a keymap buffer command (2 bytes per keycode)
+fn keymap_buffer_range(report: &[u8; RAW_HID_REPORT_SIZE]) -> Option<(usize, usize)> {
+    buffer_range(report, VIA_LAYERS * LAYOUT_SIZE * 2)
+        .filter(|(offset, size)| offset % 2 == 0 && size % 2 == 0)
+}
+
+/// Serializes the dynamic macros into the VIA macro buffer (QMK send_string codes)
+fn serialize_macros(store: &MacroStore, buffer: &mut [u8]) {
+    let mut pos = 0;
+    let mut push = |byte: u8| {
+        if let Some(b) = buffer.get_mut(pos) {
+            *b = byte;
+        }
+        pos += 1;
+    };
+    for slot in 0..MAX_DYNAMIC_MACROS as u8 {
+        for event in store.get(slot) {
+            if event.delay_ms > 0 {
+                push(SS_QMK_PREFIX);
+                push(SS_DELAY_CODE);
+                let mut digits = [0; 5];
+                let mut delay = event.delay_ms;
+                let mut len = 0;
+                while delay > 0 {
+                    digits[len] = b'0' + (delay % 10) as u8;
+                    delay /= 10;
+                    len += 1;
+                }
+                for digit in digits[..len].iter().rev() {
+                    push(*digit);
+                }
+                push(SS_DELAY_END);
+            }
+            push(SS_QMK_PREFIX);
+            push(if event.press {
+                SS_DOWN_CODE
+            } else {
+                SS_UP_CODE
+            });
+            push(event.key);
+        }
+        push(0);
+    }
+}
+
+/// Parses the VIA macro buffer into dynamic macros
+/// Returns None if a macro uses unsupported codes or the macros don't fit into the store
+fn parse_macros(buffer: &[u8]) -> Option<MacroStore> {
+    let mut store = MacroStore::new();
+    let mut events: Vec<MacroEvent, MAX_MACRO_EVENTS> = Vec::new();
+    let mut delay_ms: u16 = 0;
+    let mut bytes = buffer.iter().copied();
+    let mut slot = 0;
+    while slot < MAX_DYNAMIC_MACROS as u8 {
+        let mut push = |key: u8, press: bool, delay_ms: &mut u16| {
+            let event = MacroEvent {
+                delay_ms: core::mem::take(delay_ms),
+                key,
+                press,
+            };
+            events.push(event).ok()
+        };
+        match bytes.next()? {
+            0 => {
+                if !store.set(slot, &events) {
+                    return None;
+                }
+                events.clear();
+                delay_ms = 0;
+                slot += 1;
+            }
+            SS_QMK_PREFIX => match bytes.next()? {
+                SS_TAP_CODE => {
+                    let key = bytes.next()?;
+                    push(key, true, &mut delay_ms)?;
+                    push(key, false, &mut delay_ms)?;
+                }
+                SS_DOWN_CODE => push(bytes.next()?, true, &mut delay_ms)?,
+                SS_UP_CODE => push(bytes.next()?, false, &mut delay_ms)?,
+                SS_DELAY_CODE => {
+                    let mut delay: u16 = 0;
+                    loop {
+                        match bytes.next()? {
+                            SS_DELAY_END => break,
+                            digit @ b'0'..=b'9' => {
+                                delay =
+                                    delay.checked_mul(10)?.checked_add((digit - b'0') as u16)?;
+                            }
+                            _ => return None,
+                        }
+                    }
+                    delay_ms = delay_ms.saturating_add(delay);
+                }
+                _ => return None,
+            },
+            char => {
+                let (key, shift) = ascii_key(char)?;
+                if shift {
+                    push(KC_LSHIFT, true, &mut delay_ms)?;
+                }
+                push(key, true, &mut delay_ms)?;
+                push(key, false, &mut delay_ms)?;
+                if shift {
+                    push(KC_LSHIFT, false, &mut delay_ms)?;
+                }
+            }
+        }
+    }
+    Some(store)
+}
+
+/// USB HID keyboard code (US layout) and shift of a macro text character
+fn ascii_key(char: u8) -> Option<(u8, bool)> {
+    Some(match char {
+        b'a'..=b'z' => (0x04 + char - b'a', false),
+        b'A'..=b'Z' => (0x04 + char - b'A', true),
+        b'1'..=b'9' => (0x1E + char - b'1', false),
+        b'0' => (0x27, false),
+        b'\n' => (0x28, false),
+        b'\t' => (0x2B, false),
+        b' ' => (0x2C, false),
+        b'-' => (0x2D, false),
+        b'=' => (0x2E, false),
+        b'[' => (0x2F, false),
+        b']' => (0x30, false),
+        b'\\' => (0x31, false),
+        b';' => (0x33, false),
+        b'\'' => (0x34, false),
+        b'`' => (0x35, false),
+        b',' => (0x36, false),
+        b'.' => (0x37, false),
+        b'/' => (0x38, false),
+        _ => return None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KC_A: u16 = 0x04;
+    const KC_B: u16 = 0x05;
+    const KC_C: u16 = 0x06;
+    const KC_ESC: u16 = 0x29;
+
+    /// S0 Esc, S1 A and S2 bound to another result on layer 0, S1 B on layer 1
+    const LAYOUT: LayoutUsbKeys = &[
+        (0, 0, Some(KC_ESC as u8)),
+        (0, 1, Some(KC_A as u8)),
+        (0, 2, None),
+        (1, 1, Some(KC_B as u8)),
+    ];
+
+    /// Keymap buffer size (all layers)
+    const KEYMAP_BUFFER_SIZE: usize = VIA_LAYERS * LAYOUT_SIZE * 2;
+
+    struct Device {
+        via: Via,
+        keymap: KeymapConfig,
+        dynamic_macros: MacroStore,
+        led_settings: LedSettings,
+    }
+
+    impl Device {
+        fn new() -> Self {
+            Self {
+                via: Via::new(LAYOUT),
+                keymap: KeymapConfig::new(),
+                dynamic_macros: MacroStore::new(),
+                led_settings: LedSettings {
+                    brightness: 255,
+                    enabled: true,
+                },
+            }
+        }
+
+        /// Sends a request, returns the response and the changed settings
+        fn request(&mut self, data: &[u8]) -> ([u8; RAW_HID_REPORT_SIZE], ViaChanges) {
+            let mut report = [0; RAW_HID_REPORT_SIZE];
+            report[..data.len()].copy_from_slice(data);
+            let settings = ViaSettings {
+                keymap: &mut self.keymap,
+                dynamic_macros: &mut self.dynamic_macros,
+                led_settings: &mut self.led_settings,
+            };
+            let changes = self.via.process(&mut report, settings);
+            (report, changes)
+        }
+
+        fn get_keycode(&mut self, layer: u8, row: u8, col: u8) -> u16 {
+            let (report, _) = self.request(&[DYNAMIC_KEYMAP_GET_KEYCODE, layer, row, col]);
+            u16::from_be_bytes([report[4], report[5]])
+        }
+
+        fn set_keycode(&mut self, layer: u8, row: u8, col: u8, keycode: u16) -> ViaChanges {
+            let [high, low] = keycode.to_be_bytes();
+            let request = [DYNAMIC_KEYMAP_SET_KEYCODE, layer, row, col, high, low];
+            self.request(&request).1
+        }
+
+        /// Buffer command with a payload
+        fn buffer(
+            &mut self,
+            command: u8,
+            offset: usize,
+            data: &[u8],
+        ) -> ([u8; RAW_HID_REPORT_SIZE], ViaChanges) {
+            let mut request = [0; RAW_HID_REPORT_SIZE];
+            request[0] = command;
+            request[1..3].copy_from_slice(&(offset as u16).to_be_bytes());
+            request[3] = data.len() as u8;
+            request[4..4 + data.len()].copy_from_slice(data);
+            self.request(&request)
+        }
+
+        /// Writes the macro buffer the way VIA does (every chunk, last byte cleared last)
+        fn write_macros(&mut self, macros: &[u8]) -> ViaChanges {
+            let mut buffer = [0; VIA_MACRO_BUFFER_SIZE];
+            buffer[..macros.len()].copy_from_slice(macros);
+            let mut changes = ViaChanges::default();
+            for (i, chunk) in buffer.chunks(BUFFER_DATA_SIZE).enumerate() {
+                let (_, chunk_changes) =
+                    self.buffer(DYNAMIC_KEYMAP_MACRO_SET_BUFFER, i * BUFFER_DATA_SIZE, chunk);
+                changes.dynamic_macros |= chunk_changes.dynamic_macros;
+            }
+            changes
+        }
+
+        /// Reads the macro buffer the way VIA does
+        fn read_macros(&mut self) -> [u8; VIA_MACRO_BUFFER_SIZE] {
+            let mut buffer = [0; VIA_MACRO_BUFFER_SIZE];
+            for (i, chunk) in buffer.chunks_mut(BUFFER_DATA_SIZE).enumerate() {
+                let offset = i * BUFFER_DATA_SIZE;
+                let request = [
+                    DYNAMIC_KEYMAP_MACRO_GET_BUFFER,
+                    (offset >> 8) as u8,
+                    offset as u8,
+                    chunk.len() as u8,
+                ];
+                let (report, _) = self.request(&request);
+                chunk.copy_from_slice(&report[4..4 + chunk.len()]);
+            }
+            buffer
+        }
+    }
+
+    fn event(key: u8, press: bool, delay_ms: u16) -> MacroEvent {
+        MacroEvent {
+            delay_ms,
+            key,
+            press,
+        }
+    }
+
+    #[test]
+    fn protocol_version() {
+        let mut device = Device::new();
+        let (report, _) = device.request(&[GET_PROTOCOL_VERSION]);
+        assert_eq!(report[1..3], VIA_PROTOCOL_VERSION.to_be_bytes());
+        let (report, _) = device.request(&[0x55]);
+        assert_eq!(report[0], UNHANDLED);
+    }
+
+    #[test]
+    fn get_compiled_keycodes() {
+        let mut device = Device::new();
+        assert_eq!(device.get_keycode(0, 0, 0), KC_ESC);
+        assert_eq!(device.get_keycode(0, 0, 1), KC_A);
+        assert_eq!(device.get_keycode(0, 0, 2), QK_KB + 2);
+        assert_eq!(device.get_keycode(0, 0, 3), KC_NO);
+        assert_eq!(device.get_keycode(1, 0, 0), KC_TRNS);
+        assert_eq!(device.get_keycode(1, 0, 1), KC_B);
+    }
+
+    #[test]
+    fn set_keycode() {
+        let mut device = Device::new();
+
+        // USB key, switch and disabled entries
+        assert!(device.set_keycode(0, 0, 1, KC_C).keymap);
+        assert!(device.set_keycode(1, 0, 3, QK_KB + 1).keymap);
+        assert!(device.set_keycode(0, 0, 0, KC_NO).keymap);
+        assert_eq!(device.get_keycode(0, 0, 1), KC_C);
+        assert_eq!(device.get_keycode(1, 0, 3), QK_KB + 1);
+        assert_eq!(device.get_keycode(0, 0, 0), KC_NO);
+        assert_eq!(
+            device.keymap.get(1, 3).map(|entry| entry.action),
+            Some(KeymapAction::Switch(1))
+        );
+        assert_eq!(device.keymap.entries().count(), 3);
+
+        // Setting the same keycode again changes nothing
+        assert!(!device.set_keycode(0, 0, 1, KC_C).keymap);
+
+        // The compiled keycode and KC_TRNS remove the entry
+        assert!(device.set_keycode(0, 0, 1, KC_A).keymap);
+        assert!(device.set_keycode(1, 0, 3, KC_TRNS).keymap);
+        assert!(!device.set_keycode(1, 0, 1, KC_B).keymap);
+        assert_eq!(device.get_keycode(0, 0, 1), KC_A);
+        assert_eq!(device.get_keycode(1, 0, 3), KC_TRNS);
+        assert_eq!(device.keymap.entries().count(), 1);
+
+        // Unsupported keycodes and positions outside of the VIA matrix
+        assert!(!device.set_keycode(0, 0, 4, 0x5220).keymap);
+        assert!(!device.set_keycode(VIA_LAYERS as u8, 0, 4, KC_C).keymap);
+        assert!(!device.set_keycode(0, VIA_MATRIX_ROWS as u8, 0, KC_C).keymap);
+        assert!(!device.set_keycode(0, 0, VIA_MATRIX_COLS as u8, KC_C).keymap);
+        assert_eq!(device.keymap.entries().count(), 1);
+    }
+
+    #[test]
+    fn get_keymap_buffer() {
+        let mut device = Device::new();
+        device.set_keycode(0, 0, 3, KC_C);
+
+        let (report, _) = device.request(&[DYNAMIC_KEYMAP_GET_BUFFER, 0, 0, 8]);
+        let keycodes: [u16; 4] =
+            core::array::from_fn(|i| u16::from_be_bytes([report[4 + i * 2], report[5 + i * 2]]));
+        assert_eq!(keycodes, [KC_ESC, KC_A, QK_KB + 2, KC_C]);
+
+        // Switch 1 of layer 1
+        let offset = (LAYOUT_SIZE + 1) * 2;
+        let (report, _) = device.buffer(DYNAMIC_KEYMAP_GET_BUFFER, offset, &[0xFF; 2]);
+        assert_eq!(report[4..6], KC_B.to_be_bytes());
+
+        // Odd and out of range offsets and sizes are not answered
+        for (offset, size) in [
+            (1, 2),
+            (0, 3),
+            (KEYMAP_BUFFER_SIZE - 2, 4),
+            (KEYMAP_BUFFER_SIZE, 2),
+            (0, BUFFER_DATA_SIZE + 2),
+        ] {
+            let [high, low] = (offset as u16).to_be_bytes();
+            let request = [DYNAMIC_KEYMAP_GET_BUFFER, high, low, size as u8, 0xFF, 0xFF];
+            let (report, _) = device.request(&request);
+            assert_eq!(report[4..6], [0xFF; 2], "offset {offset} size {size}");
+        }
+        let (report, _) = device.buffer(
+            DYNAMIC_KEYMAP_GET_BUFFER,
+            KEYMAP_BUFFER_SIZE - 2,
+            &[0xFF; 2],
+        );
+        assert_eq!(report[4..6], KC_TRNS.to_be_bytes());
+    }
+
+    #[test]
+    fn set_keymap_buffer() {
+        let mut device = Device::new();
+
+        // Writing back the compiled layout adds no entries
+        let (_, changes) =
+            device.buffer(DYNAMIC_KEYMAP_SET_BUFFER, 0, &[0, 0x29, 0, 0x04, 0x7E, 2]);
+        assert!(!changes.keymap);
+        assert_eq!(device.keymap.entries().count(), 0);
+
+        let (_, changes) = device.buffer(DYNAMIC_KEYMAP_SET_BUFFER, 2, &[0, 0x06, 0, 0x04]);
+        assert!(changes.keymap);
+        assert_eq!(device.get_keycode(0, 0, 1), KC_C);
+        assert_eq!(device.get_keycode(0, 0, 2), KC_A);
+
+        // Last key of the last layer
+        let (_, changes) = device.buffer(
+            DYNAMIC_KEYMAP_SET_BUFFER,
+            KEYMAP_BUFFER_SIZE - 2,
+            &[0, 0x06],
+        );
+        assert!(changes.keymap);
+        let last = (LAYOUT_SIZE - 1) as u16;
+        assert!(device.keymap.get(VIA_LAYERS as u8 - 1, last).is_some());
+
+        // Odd and out of range offsets and sizes are ignored
+        for (offset, data) in [
+            (1, &[0, 0x06][..]),
+            (4, &[0, 0x06, 0][..]),
+            (KEYMAP_BUFFER_SIZE - 2, &[0, 0x06, 0, 0x06][..]),
+            (KEYMAP_BUFFER_SIZE, &[0, 0x06][..]),
+        ] {
+            let (_, changes) = device.buffer(DYNAMIC_KEYMAP_SET_BUFFER, offset, data);
+            assert!(!changes.keymap, "offset {offset}");
+        }
+        assert_eq!(device.keymap.entries().count(), 3);
+    }
+
+    #[test]
+    fn macro_buffer_round_trip() {
+        let mut device = Device::new();
+        let (report, _) = device.request(&[DYNAMIC_KEYMAP_MACRO_GET_BUFFER_SIZE]);
+        assert_eq!(report[1..3], (VIA_MACRO_BUFFER_SIZE as u16).to_be_bytes());
+
+        // Text with a shifted letter, then a delayed tap
+        let macros = b"Hi\0\x01\x04100|\x01\x01\x29\0";
+        assert!(device.write_macros(macros).dynamic_macros);
+        let shift = KC_LSHIFT;
+        assert_eq!(
+            device.dynamic_macros.get(0),
+            [
+                event(shift, true, 0),
+                event(0x0B, true, 0),
+                event(0x0B, false, 0),
+                event(shift, false, 0),
+                event(0x0C, true, 0),
+                event(0x0C, false, 0),
+            ]
+        );
+        assert_eq!(
+            device.dynamic_macros.get(1),
+            [event(0x29, true, 100), event(0x29, false, 0)]
+        );
+        assert!(device.dynamic_macros.get(2).is_empty());
+
+        // The serialized macros parse into the same store
+        let store = device.dynamic_macros.clone();
+        let buffer = device.read_macros();
+        assert_eq!(parse_macros(&buffer), Some(store.clone()));
+        let mut device = Device::new();
+        assert!(device.write_macros(&buffer).dynamic_macros);
+        assert_eq!(device.dynamic_macros, store);
+
+        // Out of range reads are not answered
+        let (report, _) = device.buffer(
+            DYNAMIC_KEYMAP_MACRO_GET_BUFFER,
+            VIA_MACRO_BUFFER_SIZE - 1,
+            &[0xFF; 2],
+        );
+        assert_eq!(report[4..6], [0xFF; 2]);
+    }
+
+    #[test]
+    fn macro_buffer_partial_write() {
+        let mut device = Device::new();
+
+        // Macros are only parsed once the last byte has been cleared
+        let (_, changes) = device.buffer(
+            DYNAMIC_KEYMAP_MACRO_SET_BUFFER,
+            VIA_MACRO_BUFFER_SIZE - 1,
+            &[0xFF],
+        );
+        assert!(!changes.dynamic_macros);
+        let (_, changes) = device.buffer(DYNAMIC_KEYMAP_MACRO_SET_BUFFER, 0, b"a\0");
+        assert!(!changes.dynamic_macros);
+        let (_, changes) = device.buffer(
+            DYNAMIC_KEYMAP_MACRO_SET_BUFFER,
+            VIA_MACRO_BUFFER_SIZE - 1,
+            &[0],
+        );
+        assert!(changes.dynamic_macros);
+        assert_eq!(
+            device.dynamic_macros.get(0),
+            [event(0x04, true, 0), event(0x04, false, 0)]
+        );
+
+        // Unsupported codes keep the previous macros
+        let mut device = Device::new();
+        assert!(!device.write_macros(b"\x01\x05\x04\0").dynamic_macros);
+        assert_eq!(device.dynamic_macros, MacroStore::new());
+    }
+
+    #[test]
+    fn lighting() {
+        let mut device = Device::new();
+        let channel = QMK_BACKLIGHT_CHANNEL;
+
+        let (_, changes) =
+            device.request(&[CUSTOM_SET_VALUE, channel, QMK_BACKLIGHT_BRIGHTNESS, 100]);
+        assert!(changes.led_settings);
+        let (_, changes) = device.request(&[CUSTOM_SET_VALUE, channel, QMK_BACKLIGHT_EFFECT, 0]);
+        assert!(changes.led_settings);
+        assert_eq!(
+            device.led_settings,
+            LedSettings {
+                brightness: 100,
+                enabled: false,
+            }
+        );
+
+        let (report, _) = device.request(&[CUSTOM_GET_VALUE, channel, QMK_BACKLIGHT_BRIGHTNESS]);
+        assert_eq!(report[3], 100);
+        let (report, _) = device.request(&[CUSTOM_GET_VALUE, channel, QMK_BACKLIGHT_EFFECT]);
+        assert_eq!(report[3], 0);
+
+        let (_, changes) = device.request(&[CUSTOM_SAVE, channel]);
+        assert!(changes.save);
+
+        // Unknown values and channels
+        let (report, changes) = device.request(&[CUSTOM_SET_VALUE, channel, 0x03, 1]);
+        assert_eq!((report[0], changes), (UNHANDLED, ViaChanges::default()));
+        let (report, _) = device.request(&[CUSTOM_GET_VALUE, 0x02, QMK_BACKLIGHT_BRIGHTNESS]);
+        assert_eq!(report[0], UNHANDLED);
+    }
+
+    #[test]
+    fn eeprom_reset() {
+        let mut device = Device::new();
+        device.set_keycode(0, 0, 1, KC_C);
+        device.write_macros(b"a\0");
+        assert!(!device.dynamic_macros.get(0).is_empty());
+
+        let (_, changes) = device.request(&[EEPROM_RESET]);
+        assert!(changes.keymap && changes.dynamic_macros);
+        assert_eq!(device.keymap.entries().count(), 0);
+        assert_eq!(device.dynamic_macros, MacroStore::new());
+        assert_eq!(device.get_keycode(0, 0, 1), KC_A);
+
+        let (_, changes) = device.request(&[BOOTLOADER_JUMP]);
+        assert!(changes.bootloader);
+    }
+}
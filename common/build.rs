@@ -6,13 +6,18 @@
 // http://opensource.org/licenses/MIT>, at your option. This file may not be
 // copied, modified, or distributed except according to those terms.
 
+use std::collections::BTreeMap;
 use std::env;
 use std::fs::File;
 use std::io::Write;
-use std::path::PathBuf;
+use std::ops::Range;
+use std::path::{Component, Path, PathBuf};
 
 use kll_compiler::{Filestore, KllGroups, Layouts};
 
+/// Keys per combo (kiibohd_atsam4s::constants::MAX_COMBO_KEYS, also checked by the generated code)
+const MAX_COMBO_KEYS: usize = 8;
+
 fn main() {
     println!("cargo:rerun-if-changed=build.rs");
 
@@ -69,10 +74,13 @@ fn main() {
 
     // Generate Rust code from KLL files
     let mut filestore = Filestore::new();
+    let mut firmware_triggers = FirmwareTriggers::default();
 
     // Add basemap
+    // The groups use the loaded files (filtered copies if firmware statements were removed)
     let basemap_file = PathBuf::from(env::var("KLL_BASEMAP").unwrap());
-    filestore.load_file(&basemap_file);
+    let basemap_kll = extract_firmware_triggers(&basemap_file, None, out, &mut firmware_triggers);
+    filestore.load_file(&basemap_kll);
     println!(
         "cargo:rerun-if-changed={}",
         basemap_file.as_path().display()
@@ -88,10 +96,12 @@ fn main() {
                 let file = PathBuf::from(layer_file);
                 // Make sure the file exists
                 assert!(file.is_file(), "{:?} does not exist", file);
-                filestore.load_file(&file);
+                let kll_file =
+                    extract_firmware_triggers(&file, Some(i as u8), out, &mut firmware_triggers);
+                filestore.load_file(&kll_file);
                 println!("cargo:rerun-if-changed={}", file.as_path().display());
                 if i == 0 {
-                    defaultmap_files.push(file);
+                    defaultmap_files.push(kll_file);
                 } else {
                     // TODO
                 }
@@ -106,10 +116,991 @@ fn main() {
     // TODO Handle layers
     //      Figure out how to merge files
     //      Figure out how to pass multiple layers
-    let groups = KllGroups::new(&filestore, &[], &[basemap_file], &defaultmap_files, &[]);
+    let groups = KllGroups::new(&filestore, &[], &[basemap_kll], &defaultmap_files, &[]);
 
     // Verify and generate rust
     kll_compiler::emitters::kllcore::verify(&groups).unwrap();
     let outfile = out.join("generated_kll.rs");
     kll_compiler::emitters::kllcore::write(&outfile, &groups, &mut layouts);
+
+    // Append analog triggers
+    let mut generated = std::fs::OpenOptions::new()
+        .append(true)
+        .open(&outfile)
+        .unwrap();
+    writeln!(generated).unwrap();
+    writeln!(
+        generated,
+        "/// Analog (depth) triggers: S<switch>(<distance>) : S<virtual switch>;"
+    )
+    .unwrap();
+    writeln!(
+        generated,
+        "pub const ANALOG_TRIGGERS: &[kiibohd_atsam4s::analog::AnalogTrigger] = &["
+    )
+    .unwrap();
+    for (switch, distance, virtual_switch) in firmware_triggers.analog {
+        writeln!(
+            generated,
+            "    kiibohd_atsam4s::analog::AnalogTrigger {{ switch: {}, distance: {}, virtual_switch: {} }},",
+            switch, distance, virtual_switch
+        )
+        .unwrap();
+    }
+    writeln!(generated, "];").unwrap();
+
+    // Append tap-hold keys
+    writeln!(generated).unwrap();
+    writeln!(
+        generated,
+        "/// Tap-hold keys: S<switch> : TapHold(S<tap>, S<hold>[, <ms>]);"
+    )
+    .unwrap();
+    writeln!(
+        generated,
+        "pub const TAP_HOLD_KEYS: &[kiibohd_atsam4s::taphold::TapHoldKey] = &["
+    )
+    .unwrap();
+    for (switch, tap, hold, tapping_term_ms) in firmware_triggers.tap_hold {
+        writeln!(
+            generated,
+            "    kiibohd_atsam4s::taphold::TapHoldKey {{ switch: {}, tap: {}, hold: {}, tapping_term_ms: {:?} }},",
+            switch, tap, hold, tapping_term_ms
+        )
+        .unwrap();
+    }
+    writeln!(generated, "];").unwrap();
+
+    // Append combos
+    writeln!(generated).unwrap();
+    writeln!(
+        generated,
+        "/// Combos: S<switch> + S<switch> [+ ...] : S<virtual switch>;"
+    )
+    .unwrap();
+    writeln!(
+        generated,
+        "pub const COMBOS: &[kiibohd_atsam4s::combo::Combo] = &["
+    )
+    .unwrap();
+    for (keys, virtual_switch, layer) in firmware_triggers.combos {
+        writeln!(
+            generated,
+            "    kiibohd_atsam4s::combo::Combo {{ keys: &{:?}, virtual_switch: {}, layer: {} }},",
+            keys, virtual_switch, layer
+        )
+        .unwrap();
+    }
+    writeln!(generated, "];").unwrap();
+    writeln!(
+        generated,
+        "const _: () = assert!(COMBOS.len() <= kiibohd_atsam4s::constants::MAX_COMBOS, \"MAX_COMBOS too small\");"
+    )
+    .unwrap();
+    writeln!(
+        generated,
+        "const _: () = assert!({} == kiibohd_atsam4s::constants::MAX_COMBO_KEYS, \"build.rs MAX_COMBO_KEYS out of sync\");",
+        MAX_COMBO_KEYS
+    )
+    .unwrap();
+
+    // Append one-shot keys
+    writeln!(generated).unwrap();
+    writeln!(
+        generated,
+        "/// One-shot keys: S<switch> : OneShot(S<virtual switch>);"
+    )
+    .unwrap();
+    writeln!(
+        generated,
+        "pub const ONE_SHOT_KEYS: &[kiibohd_atsam4s::oneshot::OneShotKey] = &["
+    )
+    .unwrap();
+    for (switch, virtual_switch) in firmware_triggers.one_shot {
+        writeln!(
+            generated,
+            "    kiibohd_atsam4s::oneshot::OneShotKey {{ switch: {}, virtual_switch: {} }},",
+            switch, virtual_switch
+        )
+        .unwrap();
+    }
+    writeln!(generated, "];").unwrap();
+
+    // Append dynamic macro keys
+    writeln!(generated).unwrap();
+    writeln!(
+        generated,
+        "/// Dynamic macro keys: S<switch> : MacroRecord(<slot>); S<switch> : MacroPlay(<slot>);"
+    )
+    .unwrap();
+    writeln!(
+        generated,
+        "pub const DYNAMIC_MACRO_KEYS: &[kiibohd_atsam4s::dynamic_macro::MacroKey] = &["
+    )
+    .unwrap();
+    for (switch, action, slot) in firmware_triggers.dynamic_macro {
+        writeln!(
+            generated,
+            "    kiibohd_atsam4s::dynamic_macro::MacroKey {{ switch: {}, action: kiibohd_atsam4s::dynamic_macro::MacroAction::{}({}) }},",
+            switch, action, slot
+        )
+        .unwrap();
+    }
+    writeln!(generated, "];").unwrap();
+
+    // Append leader keys and sequences
+    writeln!(generated).unwrap();
+    writeln!(generated, "/// Leader keys: S<switch> : Leader[(<ms>)];").unwrap();
+    writeln!(
+        generated,
+        "pub const LEADER_KEYS: &[kiibohd_atsam4s::leader::LeaderKey] = &["
+    )
+    .unwrap();
+    for (switch, timeout_ms) in firmware_triggers.leader_keys {
+        writeln!(
+            generated,
+            "    kiibohd_atsam4s::leader::LeaderKey {{ switch: {}, timeout_ms: {:?} }},",
+            switch, timeout_ms
+        )
+        .unwrap();
+    }
+    writeln!(generated, "];").unwrap();
+    writeln!(generated).unwrap();
+    writeln!(
+        generated,
+        "/// Leader sequences: Leader(U\"<key>\"[, ...]) : \"<text>\"; or u\"<unicode text>\";"
+    )
+    .unwrap();
+    writeln!(
+        generated,
+        "pub const LEADER_SEQUENCES: &[kiibohd_atsam4s::leader::LeaderSequence] = &["
+    )
+    .unwrap();
+    for (keys, result) in firmware_triggers.leader_sequences {
+        let result = match result {
+            LeaderResult::Keyboard(events) => format!("Keyboard(&{:?})", events),
+            LeaderResult::Unicode(text) => format!("Unicode({:?})", text),
+        };
+        writeln!(
+            generated,
+            "    kiibohd_atsam4s::leader::LeaderSequence {{ keys: &{:?}, result: kiibohd_atsam4s::leader::LeaderResult::{} }},",
+            keys, result
+        )
+        .unwrap();
+    }
+    writeln!(generated, "];").unwrap();
+
+    // Append layer LED schemes, resolved into channel values using the pixel mapping
+    // (P[<pixel>](<channel>:<width>, ...) : S<switch>;)
+    let mut layer_leds: BTreeMap<u8, BTreeMap<u16, u8>> = BTreeMap::new();
+    for target in [
+        LayerLedTarget::Background,
+        LayerLedTarget::Bound,
+        LayerLedTarget::Switch(0),
+    ] {
+        for (layer, led_target, color) in &firmware_triggers.layer_leds {
+            if std::mem::discriminant(led_target) != std::mem::discriminant(&target) {
+                continue;
+            }
+            let channels = layer_leds.entry(*layer).or_default();
+            for (switch, pixel) in &firmware_triggers.pixels {
+                let set = match led_target {
+                    LayerLedTarget::Background => true,
+                    LayerLedTarget::Bound => firmware_triggers.bound.contains(&(*layer, *switch)),
+                    LayerLedTarget::Switch(led_switch) => led_switch == switch,
+                };
+                if !set {
+                    continue;
+                }
+                // A single value applies to every channel of the pixel
+                for (i, channel) in pixel.iter().enumerate() {
+                    let value = color.get(i).or(color.last()).unwrap();
+                    channels.insert(*channel, *value);
+                }
+            }
+        }
+    }
+    writeln!(generated).unwrap();
+    writeln!(
+        generated,
+        "/// Layer LED schemes: LayerLed[<layer>] : Background(<r>, <g>, <b>); Bound(...); S<switch>(...);"
+    )
+    .unwrap();
+    writeln!(
+        generated,
+        "pub const LAYER_LEDS: &[kiibohd_atsam4s::layer_led::LayerLeds] = &["
+    )
+    .unwrap();
+    for (layer, channels) in layer_leds {
+        writeln!(
+            generated,
+            "    kiibohd_atsam4s::layer_led::LayerLeds {{ layer: {}, channels: &{:?} }},",
+            layer,
+            channels.into_iter().collect::<Vec<_>>()
+        )
+        .unwrap();
+    }
+    writeln!(generated, "];").unwrap();
+
+    // Append the layer activations (layer notifications), the first result of a layer is used
+    let mut layer_activations: BTreeMap<u8, &str> = BTreeMap::new();
+    for (layer, activation) in &firmware_triggers.layer_activations {
+        let first = *layer_activations.entry(*layer).or_insert(activation);
+        if first != *activation {
+            println!(
+                "cargo:warning=Layer {} is activated as {} and {}, layer notifications use {}",
+                layer, first, activation, first
+            );
+        }
+    }
+    writeln!(generated).unwrap();
+    writeln!(
+        generated,
+        "/// Layer activations: Layer[<layer>] (shift), LayerShift[<layer>], LayerLatch[<layer>] and LayerLock[<layer>] results"
+    )
+    .unwrap();
+    writeln!(
+        generated,
+        "pub const LAYER_ACTIVATIONS: &[(u8, kiibohd_atsam4s::LayerActivation)] = &["
+    )
+    .unwrap();
+    for (layer, activation) in layer_activations {
+        writeln!(
+            generated,
+            "    ({}, kiibohd_atsam4s::LayerActivation::{}),",
+            layer, activation
+        )
+        .unwrap();
+    }
+    writeln!(generated, "];").unwrap();
+
+    // Append the USB keys of the compiled layout, U"<key>" triggers of the layers are resolved
+    // using the basemap (the default map is layer 0)
+    let mut basemap: BTreeMap<u16, u8> = BTreeMap::new();
+    let mut layout_keys: BTreeMap<(u8, u16), Option<u8>> = BTreeMap::new();
+    for (layer, trigger, key) in &firmware_triggers.layout {
+        let switches = match trigger {
+            LayoutTrigger::Switch(switch) => vec![*switch],
+            LayoutTrigger::Usb(usb) => basemap
+                .iter()
+                .filter(|(_, basemap_key)| *basemap_key == usb)
+                .map(|(switch, _)| *switch)
+                .collect(),
+        };
+        for switch in switches {
+            if let (None, Some(key)) = (layer, key) {
+                basemap.insert(switch, *key);
+            }
+            layout_keys.insert((layer.unwrap_or(0), switch), *key);
+        }
+    }
+    writeln!(generated).unwrap();
+    writeln!(
+        generated,
+        "/// USB keys of the compiled layout (layer, switch, USB HID keyboard code), None if the switch is bound to another result"
+    )
+    .unwrap();
+    writeln!(
+        generated,
+        "pub const LAYOUT_USB_KEYS: &[(u8, u16, Option<u8>)] = &["
+    )
+    .unwrap();
+    for ((layer, switch), key) in layout_keys {
+        writeln!(generated, "    ({}, {}, {:?}),", layer, switch, key).unwrap();
+    }
+    writeln!(generated, "];").unwrap();
+}
+
+/// KLL statements evaluated by the firmware (not handled by the KLL compiler)
+#[derive(Default)]
+struct FirmwareTriggers {
+    /// Analog triggers (switch, distance, virtual switch)
+    analog: Vec<(u16, i16, u16)>,
+    /// Tap-hold keys (switch, tap, hold, tapping term in ms)
+    tap_hold: Vec<(u16, u16, u16, Option<u32>)>,
+    /// Combos (switches, virtual switch, layer)
+    combos: Vec<(Vec<u16>, u16, u8)>,
+    /// One-shot keys (switch, virtual switch)
+    one_shot: Vec<(u16, u16)>,
+    /// Dynamic macro keys (switch, MacroAction variant, slot)
+    dynamic_macro: Vec<(u16, &'static str, u8)>,
+    /// Leader keys (switch, timeout in ms)
+    leader_keys: Vec<(u16, Option<u32>)>,
+    /// Leader sequences (USB HID keyboard codes, result)
+    leader_sequences: Vec<(Vec<u8>, LeaderResult)>,
+    /// Pixels (switch, LED channels), kept in the KLL file
+    pixels: Vec<(u16, Vec<u16>)>,
+    /// Layer results (layer, LayerActivation variant), kept in the KLL file
+    layer_activations: Vec<(u8, &'static str)>,
+    /// Switches bound on each layer (layer, switch)
+    bound: Vec<(u8, u16)>,
+    /// Layer LED schemes (layer, target, channel values)
+    layer_leds: Vec<(u8, LayerLedTarget, Vec<u8>)>,
+    /// Keys of the layout (layer, trigger, USB HID keyboard code), None for the basemap and
+    /// None if the result is not a single USB key
+    layout: Vec<(Option<u8>, LayoutTrigger, Option<u8>)>,
+}
+
+/// Trigger of a layout statement
+enum LayoutTrigger {
+    /// S<switch>
+    Switch(u16),
+    /// U"<key>", every switch mapped to the key by the basemap
+    Usb(u8),
+}
+
+/// LEDs set by a layer LED statement
+enum LayerLedTarget {
+    /// Every pixel
+    Background,
+    /// Pixels of the switches bound on the layer
+    Bound,
+    /// Pixel of a switch
+    Switch(u16),
+}
+
+/// Result of a leader sequence
+enum LeaderResult {
+    /// USB HID keyboard events (code, press)
+    Keyboard(Vec<(u8, bool)>),
+    /// Unicode string (HID-IO)
+    Unicode(String),
+}
+
+/// Removes the statements evaluated by the firmware from a KLL file
+/// - Analog triggers (kiibohd_atsam4s::analog): S<switch>(<distance>) : S<virtual switch>;
+/// - Tap-hold keys (kiibohd_atsam4s::taphold): S<switch> : TapHold(S<tap>, S<hold>[, <ms>]);
+/// - Combos (kiibohd_atsam4s::combo): S<switch> + S<switch> [+ ...] : S<virtual switch>;
+/// - One-shot keys (kiibohd_atsam4s::oneshot): S<switch> : OneShot(S<virtual switch>);
+/// - Dynamic macro keys (kiibohd_atsam4s::dynamic_macro): S<switch> : MacroRecord(<slot>);
+///   and S<switch> : MacroPlay(<slot>);
+/// - Leader keys (kiibohd_atsam4s::leader): S<switch> : Leader[(<ms>)];
+/// - Leader sequences (kiibohd_atsam4s::leader): Leader(U"<key>"[, ...]) : "<text>";
+///   and Leader(U"<key>"[, ...]) : u"<unicode text>";
+/// - Layer LED schemes (kiibohd_atsam4s::layer_led): LayerLed[<layer>] : Background(<r>, <g>, <b>);
+///   LayerLed[<layer>] : Bound(<r>, <g>, <b>); and LayerLed[<layer>] : S<switch>(<r>, <g>, <b>);
+///
+/// These are not handled by the KLL compiler, they are blanked in a copy of the file (keeping the
+/// line numbering) written under OUT_DIR/kll/<full path of the file>.
+/// Pixel mappings, layout keys and the switches bound on the layer (if the file is a layer) are
+/// also gathered.
+/// Returns the path of the KLL file to load (the original file if nothing was removed).
+fn extract_firmware_triggers(
+    file: &Path,
+    layer: Option<u8>,
+    out: &Path,
+    triggers: &mut FirmwareTriggers,
+) -> PathBuf {
+    let contents =
+        std::fs::read_to_string(file).unwrap_or_else(|_| panic!("Unable to read file: {:?}", file));
+
+    let mut removed = Vec::new();
+    for KllStatement { text, line, range } in kll_statements(&contents) {
+        let statement = text.as_str();
+        if let Some(pixel) = parse_pixel(statement) {
+            triggers.pixels.push(pixel);
+        }
+        if let (Some(layer), Some(switch)) = (layer, parse_bound_switch(statement)) {
+            triggers.bound.push((layer, switch));
+        }
+        if let Some((trigger, key)) = parse_layout_key(statement) {
+            triggers.layout.push((layer, trigger, key));
+        }
+        triggers
+            .layer_activations
+            .extend(parse_layer_activations(statement));
+        if let Some(trigger) = parse_analog_trigger(statement) {
+            let trigger = trigger.unwrap_or_else(|| {
+                panic!("{:?}:{} invalid analog trigger: {}", file, line, statement)
+            });
+            triggers.analog.push(trigger);
+            removed.push(range);
+        } else if let Some(tap_hold) = parse_tap_hold(statement) {
+            let tap_hold = tap_hold.unwrap_or_else(|| {
+                panic!("{:?}:{} invalid tap-hold key: {}", file, line, statement)
+            });
+            triggers.tap_hold.push(tap_hold);
+            removed.push(range);
+        } else if let Some((keys, virtual_switch)) = parse_combo(statement) {
+            assert!(
+                keys.len() <= MAX_COMBO_KEYS,
+                "{:?}:{} combo has more than {} keys: {}",
+                file,
+                line,
+                MAX_COMBO_KEYS,
+                statement
+            );
+            triggers
+                .combos
+                .push((keys, virtual_switch, layer.unwrap_or(0)));
+            removed.push(range);
+        } else if let Some(one_shot) = parse_one_shot(statement) {
+            let one_shot = one_shot.unwrap_or_else(|| {
+                panic!("{:?}:{} invalid one-shot key: {}", file, line, statement)
+            });
+            triggers.one_shot.push(one_shot);
+            removed.push(range);
+        } else if let Some(dynamic_macro) = parse_dynamic_macro(statement) {
+            let dynamic_macro = dynamic_macro.unwrap_or_else(|| {
+                panic!(
+                    "{:?}:{} invalid dynamic macro key: {}",
+                    file, line, statement
+                )
+            });
+            triggers.dynamic_macro.push(dynamic_macro);
+            removed.push(range);
+        } else if let Some(leader_key) = parse_leader_key(statement) {
+            let leader_key = leader_key
+                .unwrap_or_else(|| panic!("{:?}:{} invalid leader key: {}", file, line, statement));
+            triggers.leader_keys.push(leader_key);
+            removed.push(range);
+        } else if let Some(sequence) = parse_leader_sequence(statement) {
+            let sequence = sequence.unwrap_or_else(|| {
+                panic!("{:?}:{} invalid leader sequence: {}", file, line, statement)
+            });
+            triggers.leader_sequences.push(sequence);
+            removed.push(range);
+        } else if let Some(layer_led) = parse_layer_led(statement) {
+            let layer_led = layer_led
+                .unwrap_or_else(|| panic!("{:?}:{} invalid layer LED: {}", file, line, statement));
+            triggers.layer_leds.push(layer_led);
+            removed.push(range);
+        }
+    }
+
+    if removed.is_empty() {
+        return file.to_path_buf();
+    }
+
+    // Blank the removed statements, newlines are kept so errors point to the original lines
+    let mut filtered = contents.into_bytes();
+    for range in removed {
+        for byte in &mut filtered[range] {
+            if *byte != b'\n' {
+                *byte = b' ';
+            }
+        }
+    }
+
+    // Keyed by the full path, layer files with the same name may be in different directories
+    let filtered_file = file
+        .canonicalize()
+        .unwrap_or_else(|_| file.to_path_buf())
+        .components()
+        .filter_map(|component| match component {
+            Component::Normal(component) => Some(component),
+            _ => None,
+        })
+        .fold(out.join("kll"), |path, component| path.join(component));
+    std::fs::create_dir_all(filtered_file.parent().unwrap()).unwrap();
+    std::fs::write(&filtered_file, filtered).unwrap();
+    filtered_file
+}
+
+/// KLL statement, see kll_statements
+struct KllStatement {
+    /// Statement without comments, whitespace outside of strings is collapsed
+    text: String,
+    /// Line number (1-based) of the start of the statement
+    line: usize,
+    /// Byte range of the statement in the file (including the ;)
+    range: Range<usize>,
+}
+
+/// Splits the contents of a KLL file into statements
+/// Statements end with ; and may span several lines, # starts a comment. Both are ignored inside
+/// strings ("...", U"...", u"...", with \ escapes).
+fn kll_statements(contents: &str) -> Vec<KllStatement> {
+    let mut statements = Vec::new();
+    let mut text = String::new();
+    let mut start = None;
+    let mut line = 1;
+    let mut start_line = 1;
+    let mut in_string = false;
+    let mut in_comment = false;
+    let mut escaped = false;
+    for (pos, char) in contents.char_indices() {
+        if in_comment {
+            in_comment = char != '\n';
+        } else if in_string {
+            text.push(char);
+            if escaped {
+                escaped = false;
+            } else if char == '\\' {
+                escaped = true;
+            } else if char == '"' {
+                in_string = false;
+            }
+        } else if char == '#' {
+            in_comment = true;
+        } else if char.is_whitespace() {
+            if !text.is_empty() && !text.ends_with(' ') {
+                text.push(' ');
+            }
+        } else {
+            if start.is_none() {
+                start = Some(pos);
+                start_line = line;
+            }
+            text.push(char);
+            in_string = char == '"';
+            if char == ';' {
+                statements.push(KllStatement {
+                    text: std::mem::take(&mut text),
+                    line: start_line,
+                    range: start.take().unwrap()..pos + 1,
+                });
+            }
+        }
+        if char == '\n' {
+            line += 1;
+        }
+    }
+    statements
+}
+
+/// Parses an analog trigger statement
+/// Returns None if this is not an analog trigger (e.g. S1(P) is a state, not a distance),
+/// Some(None) if the statement is malformed (the result must be a single virtual switch).
+#[allow(clippy::option_option)]
+fn parse_analog_trigger(statement: &str) -> Option<Option<(u16, i16, u16)>> {
+    let (trigger, result) = statement.strip_suffix(';')?.split_once(':')?;
+    let (switch, distance) = trigger.trim().strip_prefix('S')?.split_once('(')?;
+    let distance: i16 = distance.trim().strip_suffix(')')?.trim().parse().ok()?;
+
+    let parse = || {
+        let virtual_switch = result.trim().strip_prefix('S')?;
+        Some((
+            parse_number(switch)?.try_into().ok()?,
+            distance,
+            parse_number(virtual_switch)?.try_into().ok()?,
+        ))
+    };
+    Some(parse())
+}
+
+/// Parses a tap-hold statement
+/// Returns None if this is not a tap-hold statement, Some(None) if the statement is malformed.
+#[allow(clippy::option_option)]
+#[allow(clippy::type_complexity)]
+fn parse_tap_hold(statement: &str) -> Option<Option<(u16, u16, u16, Option<u32>)>> {
+    let (trigger, result) = statement.strip_suffix(';')?.split_once(':')?;
+    let args = result
+        .trim()
+        .strip_prefix("TapHold(")?
+        .trim_end()
+        .strip_suffix(')');
+
+    let parse = || {
+        let switch = trigger.trim().strip_prefix('S')?;
+        let mut args = args?.split(',').map(str::trim);
+        let tap = args.next()?.strip_prefix('S')?;
+        let hold = args.next()?.strip_prefix('S')?;
+        let tapping_term = match args.next() {
+            Some(ms) => Some(ms.strip_suffix("ms").unwrap_or(ms).trim().parse().ok()?),
+            None => None,
+        };
+        if args.next().is_some() {
+            return None;
+        }
+        Some((
+            parse_number(switch)?.try_into().ok()?,
+            parse_number(tap)?.try_into().ok()?,
+            parse_number(hold)?.try_into().ok()?,
+            tapping_term,
+        ))
+    };
+    Some(parse())
+}
+
+/// Parses a combo statement
+/// Returns None if this is not a combo of switches with a single virtual switch as the result
+/// (other combos are left to the KLL compiler).
+fn parse_combo(statement: &str) -> Option<(Vec<u16>, u16)> {
+    let (trigger, result) = statement.strip_suffix(';')?.split_once(':')?;
+    if !trigger.contains('+') {
+        return None;
+    }
+    let keys = trigger
+        .split('+')
+        .map(|key| parse_number(key.trim().strip_prefix('S')?)?.try_into().ok())
+        .collect::<Option<Vec<u16>>>()?;
+    let virtual_switch = parse_number(result.trim().strip_prefix('S')?)?
+        .try_into()
+        .ok()?;
+    Some((keys, virtual_switch))
+}
+
+/// Parses a one-shot statement
+/// Returns None if this is not a one-shot statement, Some(None) if the statement is malformed.
+#[allow(clippy::option_option)]
+fn parse_one_shot(statement: &str) -> Option<Option<(u16, u16)>> {
+    let (trigger, result) = statement.strip_suffix(';')?.split_once(':')?;
+    let virtual_switch = result
+        .trim()
+        .strip_prefix("OneShot(")?
+        .trim_end()
+        .strip_suffix(')');
+
+    let parse = || {
+        let switch = trigger.trim().strip_prefix('S')?;
+        let virtual_switch = virtual_switch?.trim().strip_prefix('S')?;
+        Some((
+            parse_number(switch)?.try_into().ok()?,
+            parse_number(virtual_switch)?.try_into().ok()?,
+        ))
+    };
+    Some(parse())
+}
+
+/// Parses a dynamic macro record/play statement
+/// Returns None if this is not a dynamic macro statement, Some(None) if the statement is malformed.
+#[allow(clippy::option_option)]
+fn parse_dynamic_macro(statement: &str) -> Option<Option<(u16, &'static str, u8)>> {
+    let (trigger, result) = statement.strip_suffix(';')?.split_once(':')?;
+    let result = result.trim();
+    let (action, slot) = if let Some(slot) = result.strip_prefix("MacroRecord(") {
+        ("Record", slot)
+    } else {
+        ("Play", result.strip_prefix("MacroPlay(")?)
+    };
+
+    let parse = || {
+        let switch = trigger.trim().strip_prefix('S')?;
+        let slot = slot.trim_end().strip_suffix(')')?;
+        Some((
+            parse_number(switch)?.try_into().ok()?,
+            action,
+            parse_number(slot)?.try_into().ok()?,
+        ))
+    };
+    Some(parse())
+}
+
+/// Parses a leader key statement
+/// Returns None if this is not a leader key statement, Some(None) if the statement is malformed.
+#[allow(clippy::option_option)]
+fn parse_leader_key(statement: &str) -> Option<Option<(u16, Option<u32>)>> {
+    let (trigger, result) = statement.strip_suffix(';')?.split_once(':')?;
+    let result = result.trim();
+    let timeout = match result.strip_prefix("Leader") {
+        Some("") => None,
+        Some(timeout) => Some(timeout.strip_prefix('(')?.strip_suffix(')')),
+        None => return None,
+    };
+
+    let parse = || {
+        let switch = trigger.trim().strip_prefix('S')?;
+        let timeout_ms = match timeout {
+            Some(ms) => {
+                let ms = ms?.trim();
+                Some(ms.strip_suffix("ms").unwrap_or(ms).trim().parse().ok()?)
+            }
+            None => None,
+        };
+        Some((parse_number(switch)?.try_into().ok()?, timeout_ms))
+    };
+    Some(parse())
+}
+
+/// Parses a leader sequence statement
+/// Returns None if this is not a leader sequence, Some(None) if the statement is malformed.
+#[allow(clippy::option_option)]
+fn parse_leader_sequence(statement: &str) -> Option<Option<(Vec<u8>, LeaderResult)>> {
+    let statement = statement.strip_suffix(';')?.strip_prefix("Leader(")?;
+    let (keys, result) = statement.split_once(')')?;
+
+    let parse = || {
+        let keys = keys
+            .split(',')
+            .map(parse_usb_code)
+            .collect::<Option<Vec<u8>>>()?;
+        let result = result.trim().strip_prefix(':')?.trim();
+        let result = match result.strip_prefix('u') {
+            Some(text) => LeaderResult::Unicode(parse_string(text)?),
+            None => {
+                let mut events = Vec::new();
+                for char in parse_string(result)?.chars() {
+                    let (key, shift) = ascii_key(char)?;
+                    if shift {
+                        events.push((0xE1, true));
+                    }
+                    events.push((key, true));
+                    events.push((key, false));
+                    if shift {
+                        events.push((0xE1, false));
+                    }
+                }
+                LeaderResult::Keyboard(events)
+            }
+        };
+        Some((keys, result))
+    };
+    Some(parse())
+}
+
+/// Parses a KLL USB HID keyboard code: U<number>, U"<letter or digit>" or U"<name>"
+fn parse_usb_code(key: &str) -> Option<u8> {
+    let key = key.trim().strip_prefix('U')?;
+    let name = match key.strip_prefix('"').and_then(|k| k.strip_suffix('"')) {
+        Some(name) => name,
+        None => return parse_number(key)?.try_into().ok(),
+    };
+    let mut chars = name.chars();
+    if let (Some(char), None) = (chars.next(), chars.next()) {
+        return match ascii_key(char.to_ascii_lowercase()) {
+            Some((key, false)) => Some(key),
+            _ => None,
+        };
+    }
+    // Numbered keys (1-9 unless noted)
+    let number = |prefix: &str| -> Option<u8> { name.strip_prefix(prefix)?.parse().ok() };
+    match (number("F"), number("International"), number("LANG")) {
+        (Some(num @ 1..=12), _, _) => return Some(0x3A + num - 1),
+        (Some(num @ 13..=24), _, _) => return Some(0x68 + num - 13),
+        (_, Some(num @ 1..=9), _) => return Some(0x87 + num - 1),
+        (_, _, Some(num @ 1..=9)) => return Some(0x90 + num - 1),
+        _ => {}
+    }
+    if let Some(num @ 1..=9) = number("Keypad ").or_else(|| number("P")) {
+        return Some(0x59 + num - 1);
+    }
+    Some(match name {
+        "Enter" => 0x28,
+        "Esc" => 0x29,
+        "Backspace" => 0x2A,
+        "Tab" => 0x2B,
+        "Space" => 0x2C,
+        "Minus" => 0x2D,
+        "Equal" | "Equals" => 0x2E,
+        "LBrace" | "Left Bracket" => 0x2F,
+        "RBrace" | "Right Bracket" => 0x30,
+        "Backslash" => 0x31,
+        "Semicolon" => 0x33,
+        "Quote" => 0x34,
+        "Backtick" => 0x35,
+        "Comma" => 0x36,
+        "Period" => 0x37,
+        "Slash" => 0x38,
+        "Caps Lock" | "CapsLock" => 0x39,
+        "Print Screen" | "PrintScreen" | "Print" => 0x46,
+        "Scroll Lock" | "ScrollLock" => 0x47,
+        "Pause" => 0x48,
+        "Insert" => 0x49,
+        "Home" => 0x4A,
+        "Page Up" | "PageUp" => 0x4B,
+        "Delete" => 0x4C,
+        "End" => 0x4D,
+        "Page Down" | "PageDown" => 0x4E,
+        "Right" => 0x4F,
+        "Left" => 0x50,
+        "Down" => 0x51,
+        "Up" => 0x52,
+        "NumLock" => 0x53,
+        "Keypad Slash" | "P/" => 0x54,
+        "Keypad Asterisk" | "P*" => 0x55,
+        "Keypad Minus" | "P-" => 0x56,
+        "Keypad Plus" | "P+" => 0x57,
+        "Keypad Enter" | "PEnter" => 0x58,
+        "Keypad 0" | "P0" => 0x62,
+        "Keypad Period" | "P." => 0x63,
+        "ISO Slash" => 0x64,
+        "App" => 0x65,
+        "Menu" => 0x76,
+        "Mute" => 0x7F,
+        "Volume Up" | "VolumeUp" => 0x80,
+        "Volume Down" | "VolumeDown" => 0x81,
+        "LCtrl" => 0xE0,
+        "LShift" => 0xE1,
+        "LAlt" => 0xE2,
+        "LGUI" => 0xE3,
+        "RCtrl" => 0xE4,
+        "RShift" | "Right Shift" => 0xE5,
+        "RAlt" => 0xE6,
+        "RGUI" => 0xE7,
+        _ => return None,
+    })
+}
+
+/// Parses a quoted KLL string (supports \n, \t, \" and \\ escapes)
+fn parse_string(val: &str) -> Option<String> {
+    let val = val.strip_prefix('"')?.strip_suffix('"')?;
+    let mut string = String::new();
+    let mut chars = val.chars();
+    while let Some(char) = chars.next() {
+        string.push(match char {
+            '\\' => match chars.next()? {
+                'n' => '\n',
+                't' => '\t',
+                escaped @ ('"' | '\\') => escaped,
+                _ => return None,
+            },
+            _ => char,
+        });
+    }
+    Some(string)
+}
+
+/// USB HID keyboard code (US layout) and shift of a text character
+fn ascii_key(char: char) -> Option<(u8, bool)> {
+    let key = |first: char, code: u8| code + (char as u8 - first as u8);
+    Some(match char {
+        'a'..='z' => (key('a', 0x04), false),
+        'A'..='Z' => (key('A', 0x04), true),
+        '1'..='9' => (key('1', 0x1E), false),
+        '0' => (0x27, false),
+        '\n' => (0x28, false),
+        '\t' => (0x2B, false),
+        ' ' => (0x2C, false),
+        '-' => (0x2D, false),
+        '_' => (0x2D, true),
+        '=' => (0x2E, false),
+        '+' => (0x2E, true),
+        '[' => (0x2F, false),
+        '{' => (0x2F, true),
+        ']' => (0x30, false),
+        '}' => (0x30, true),
+        '\\' => (0x31, false),
+        '|' => (0x31, true),
+        ';' => (0x33, false),
+        ':' => (0x33, true),
+        '\'' => (0x34, false),
+        '"' => (0x34, true),
+        '`' => (0x35, false),
+        '~' => (0x35, true),
+        ',' => (0x36, false),
+        '<' => (0x36, true),
+        '.' => (0x37, false),
+        '>' => (0x37, true),
+        '/' => (0x38, false),
+        '?' => (0x38, true),
+        '!' => (0x1E, true),
+        '@' => (0x1F, true),
+        '#' => (0x20, true),
+        '$' => (0x21, true),
+        '%' => (0x22, true),
+        '^' => (0x23, true),
+        '&' => (0x24, true),
+        '*' => (0x25, true),
+        '(' => (0x26, true),
+        ')' => (0x27, true),
+        _ => return None,
+    })
+}
+
+/// Parses a statement mapping a single switch or USB key (S<switch> : <result>; or
+/// U"<key>" : <result>;)
+/// The key is None if the result is not a single USB key (e.g. a combination or a function).
+fn parse_layout_key(statement: &str) -> Option<(LayoutTrigger, Option<u8>)> {
+    let (trigger, result) = statement.strip_suffix(';')?.split_once(':')?;
+    if result.trim().is_empty() {
+        return None;
+    }
+    let trigger = trigger.trim();
+    let trigger = match trigger.strip_prefix('S') {
+        Some(switch) => LayoutTrigger::Switch(parse_number(switch)?.try_into().ok()?),
+        None => LayoutTrigger::Usb(parse_usb_code(trigger)?),
+    };
+    Some((trigger, parse_usb_code(result)))
+}
+
+/// Parses a pixel mapping statement: P[<pixel>](<channel>:<width>, ...) : S<switch>;
+/// Returns None if this is not a pixel mapped to a switch.
+fn parse_pixel(statement: &str) -> Option<(u16, Vec<u16>)> {
+    // Channels also contain ':' (<channel>:<width>)
+    let (trigger, result) = statement.strip_suffix(';')?.rsplit_once(':')?;
+    let (_, channels) = trigger.trim().strip_prefix("P[")?.split_once('(')?;
+    let channels = channels
+        .split_once(')')?
+        .0
+        .split(',')
+        .map(|channel| parse_number(channel.split(':').next()?)?.try_into().ok())
+        .collect::<Option<Vec<u16>>>()?;
+    let switch = parse_number(result.trim().strip_prefix('S')?)?;
+    Some((switch.try_into().ok()?, channels))
+}
+
+/// Parses the switch of a statement mapping a single switch (S<switch> : <result>;)
+fn parse_bound_switch(statement: &str) -> Option<u16> {
+    let (trigger, result) = statement.strip_suffix(';')?.split_once(':')?;
+    if result.trim().is_empty() {
+        return None;
+    }
+    parse_number(trigger.trim().strip_prefix('S')?)?
+        .try_into()
+        .ok()
+}
+
+/// Parses the layer results of a statement (layer, LayerActivation variant)
+/// e.g. U"RCtrl" + U"RShift" : Layer[1]; or S0x40 : LayerLock[2];
+fn parse_layer_activations(statement: &str) -> Vec<(u8, &'static str)> {
+    let mut activations = Vec::new();
+    let result = match statement.strip_suffix(';').and_then(|s| s.split_once(':')) {
+        Some((_, result)) => result,
+        None => {
+            return activations;
+        }
+    };
+    for (capability, activation) in [
+        ("Layer[", "Shift"),
+        ("LayerShift[", "Shift"),
+        ("LayerLatch[", "Latch"),
+        ("LayerLock[", "Lock"),
+    ] {
+        for (pos, _) in result.match_indices(capability) {
+            // Skip longer capability names (e.g. LayerLed[)
+            if result[..pos].ends_with(|c: char| c.is_ascii_alphanumeric()) {
+                continue;
+            }
+            let layer = result[pos + capability.len()..]
+                .split_once(']')
+                .and_then(|(layer, _)| parse_number(layer))
+                .and_then(|layer| layer.try_into().ok());
+            if let Some(layer) = layer {
+                activations.push((layer, activation));
+            }
+        }
+    }
+    activations
+}
+
+/// Parses a layer LED statement
+/// Returns None if this is not a layer LED statement, Some(None) if the statement is malformed.
+#[allow(clippy::option_option)]
+fn parse_layer_led(statement: &str) -> Option<Option<(u8, LayerLedTarget, Vec<u8>)>> {
+    let (trigger, result) = statement.strip_suffix(';')?.split_once(':')?;
+    let layer = trigger.trim().strip_prefix("LayerLed")?;
+
+    let parse = || {
+        let layer = parse_number(layer)?.try_into().ok()?;
+        let (target, color) = result.trim().split_once('(')?;
+        let target = match target.trim() {
+            "Background" => LayerLedTarget::Background,
+            "Bound" => LayerLedTarget::Bound,
+            switch => {
+                LayerLedTarget::Switch(parse_number(switch.strip_prefix('S')?)?.try_into().ok()?)
+            }
+        };
+        let color = color
+            .strip_suffix(')')?
+            .split(',')
+            .map(|value| parse_number(value)?.try_into().ok())
+            .collect::<Option<Vec<u8>>>()?;
+        Some((layer, target, color))
+    };
+    Some(parse())
+}
+
+/// Parses a decimal or hex (0x) KLL number, optionally in brackets (e.g. [0x10])
+fn parse_number(val: &str) -> Option<u32> {
+    let val = val.trim();
+    let val = val
+        .strip_prefix('[')
+        .and_then(|v| v.strip_suffix(']'))
+        .unwrap_or(val)
+        .trim();
+    match val.strip_prefix("0x") {
+        Some(hex) => u32::from_str_radix(hex, 16).ok(),
+        None => val.parse().ok(),
+    }
 }
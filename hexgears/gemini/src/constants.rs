@@ -6,6 +6,10 @@
 // copied, modified, or distributed except according to those terms.
 
 use const_env::from_env;
+use kiibohd_atsam4s::ghosting::GhostMode;
+use kiibohd_atsam4s::socd::SocdPair;
+#[cfg(feature = "issi-i2c")]
+use kiibohd_atsam4s::{constants::ISSI_DRIVER_CHIPS, issi_i2c::IssiChip, LedMask};
 
 // ----- Constants -----
 
@@ -121,6 +125,21 @@ pub const SWITCH_REMAP: &[u8] = &[
 ];
 
 pub const SCAN_PERIOD_US: u32 = 1000 / CSIZE as u32; // Scan all strobes within 1 ms (1000 Hz) for USB
+pub const GHOST_MODE: GhostMode = GhostMode::Off; // Switches have diodes
+pub const SOCD_PAIRS: &[SocdPair] = &[]; // Opposing keys, e.g. SocdPair::new(a, d, SocdMode::LastInput)
+
+// ISSI defaults
+#[cfg(feature = "issi-i2c")]
+pub const ISSI_CHIP: IssiChip = IssiChip::Is31fl3737;
+#[cfg(feature = "issi-i2c")]
+pub const ISSI_I2C_ADDR: [u8; ISSI_DRIVER_CHIPS] = [0x50, 0x50]; // One chip per TWI bus
+#[cfg(feature = "issi-i2c")]
+pub const ISSI_DEFAULT_BRIGHTNESS: u8 = 255; // TODO flash default
+#[cfg(feature = "issi-i2c")]
+pub const ISSI_DEFAULT_ENABLE: bool = true; // TODO flash default
+// Indicator LEDs
+#[cfg(feature = "issi-i2c")]
+pub const LED_LOCK_MASK: [LedMask; 0] = []; // TODO Indicator LED positions
 
 #[from_env]
 pub const VERGEN_GIT_SEMVER: &str = "N/A";
@@ -6,6 +6,12 @@
 // copied, modified, or distributed except according to those terms.
 
 use const_env::from_env;
+use kiibohd_atsam4s::{
+    constants::LED_MASK_SIZE,
+    layout_usb_key,
+    socd::{SocdMode, SocdPair},
+    LedMask,
+};
 
 // ----- Constants -----
 
@@ -152,6 +158,30 @@ pub const SWITCH_REMAP: &[u8] = &[
     0,   // C22;R6:131
 ];
 
+// SOCD cleaning (A/D), see set socd
+// KLL trigger indices of the keys (scancode_map.kll), checked against the compiled base layer
+const SWITCH_A: u16 = 53; // S53 : U"A";
+const SWITCH_D: u16 = 55; // S55 : U"D";
+const _: () = assert!(matches!(
+    layout_usb_key(keystonefs::kll::LAYOUT_USB_KEYS, 0, SWITCH_A),
+    Some(0x04) // A
+));
+const _: () = assert!(matches!(
+    layout_usb_key(keystonefs::kll::LAYOUT_USB_KEYS, 0, SWITCH_D),
+    Some(0x07) // D
+));
+pub const SOCD_PAIRS: &[SocdPair] = &[SocdPair::new(SWITCH_A, SWITCH_D, SocdMode::Off)];
+
+// ISSI defaults
+pub const ISSI_DEFAULT_BRIGHTNESS: u8 = 255; // TODO flash default
+pub const ISSI_DEFAULT_ENABLE: bool = true; // TODO flash default
+
+// Indicator LEDs
+pub const LED_LOCK_MASK: [LedMask; LED_MASK_SIZE] = [
+    LedMask::new(0, 33, [0, 0, 0]),
+    LedMask::new(0, 51, [0, 0, 0]),
+];
+
 #[from_env]
 pub const VERGEN_GIT_SEMVER: &str = "N/A";
 #[from_env]